@@ -0,0 +1,192 @@
+//! Derive macros for the backend's `FromD1Row` and `ToD1Params` traits.
+//!
+//! Generated code refers to `crate::models`, so the derives are meant to be
+//! used inside the backend crate only.
+//!
+//! Field attributes (all under `#[d1(...)]`):
+//!
+//! - `rename = "column"`: read the value from `column` instead of the field name.
+//! - `bool_int`: an `INTEGER` column constrained to `0`/`1`.
+//! - `list`: a list column stored as a JSON array, a JSON string or a CSV string
+//!   (e.g. `GROUP_CONCAT` output).
+//! - `default`: use `Default::default()` when the column is missing or `NULL`.
+//! - `flatten`: decode the field from the same row with its own `FromD1Row` impl.
+//! - `readonly`: read from rows but leave out of `to_d1_params()`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Field, Fields, LitStr, parse_macro_input, spanned::Spanned};
+
+#[derive(Default)]
+struct FieldAttrs {
+    rename: Option<String>,
+    bool_int: bool,
+    list: bool,
+    default: bool,
+    flatten: bool,
+    readonly: bool,
+}
+
+impl FieldAttrs {
+    fn parse(field: &Field) -> syn::Result<Self> {
+        let mut attrs = Self::default();
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("d1")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    attrs.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("bool_int") {
+                    attrs.bool_int = true;
+                } else if meta.path.is_ident("list") {
+                    attrs.list = true;
+                } else if meta.path.is_ident("default") {
+                    attrs.default = true;
+                } else if meta.path.is_ident("flatten") {
+                    attrs.flatten = true;
+                } else if meta.path.is_ident("readonly") {
+                    attrs.readonly = true;
+                } else {
+                    return Err(meta.error("unsupported d1 attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        if attrs.bool_int && attrs.list {
+            return Err(syn::Error::new(
+                field.span(),
+                "`bool_int` and `list` cannot be combined",
+            ));
+        }
+        if attrs.flatten
+            && (attrs.rename.is_some() || attrs.bool_int || attrs.list || attrs.default)
+        {
+            return Err(syn::Error::new(
+                field.span(),
+                "`flatten` cannot be combined with other column attributes",
+            ));
+        }
+        Ok(attrs)
+    }
+}
+
+fn named_fields(input: &DeriveInput) -> syn::Result<Vec<(&Field, FieldAttrs)>> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "D1 row derives only support structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            input.span(),
+            "D1 row derives require named fields",
+        ));
+    };
+    fields
+        .named
+        .iter()
+        .map(|field| FieldAttrs::parse(field).map(|attrs| (field, attrs)))
+        .collect()
+}
+
+#[proc_macro_derive(FromD1Row, attributes(d1))]
+pub fn derive_from_d1_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_d1_row(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_from_d1_row(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let inits = named_fields(input)?
+        .into_iter()
+        .map(|(field, attrs)| {
+            let ident = field.ident.as_ref().expect("named field");
+            let ty = &field.ty;
+
+            if attrs.flatten {
+                return quote! {
+                    #ident: <#ty as crate::models::FromD1Row>::from_d1_row(row)?
+                };
+            }
+
+            let column = attrs.rename.unwrap_or_else(|| ident.to_string());
+            let decode = if attrs.bool_int {
+                quote! { <#ty as crate::models::FromD1BoolInt>::from_d1_bool_int(value, #column)? }
+            } else if attrs.list {
+                quote! { <#ty as crate::models::FromD1List>::from_d1_list(value, #column)? }
+            } else {
+                quote! { <#ty as crate::models::FromD1Value>::from_d1_value(value, #column)? }
+            };
+
+            if attrs.default {
+                quote! {
+                    #ident: match row.get(#column) {
+                        ::core::option::Option::None
+                        | ::core::option::Option::Some(::serde_json::Value::Null) => ::core::default::Default::default(),
+                        value => #decode,
+                    }
+                }
+            } else {
+                quote! {
+                    #ident: {
+                        let value = row.get(#column);
+                        #decode
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(quote! {
+        impl #impl_generics crate::models::FromD1Row for #name #ty_generics #where_clause {
+            fn from_d1_row(row: &crate::models::D1Row) -> ::core::result::Result<Self, crate::models::ModelError> {
+                ::core::result::Result::Ok(Self {
+                    #(#inits,)*
+                })
+            }
+        }
+    })
+}
+
+#[proc_macro_derive(ToD1Params, attributes(d1))]
+pub fn derive_to_d1_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_to_d1_params(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_to_d1_params(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let pushes = named_fields(input)?
+        .into_iter()
+        .filter(|(_, attrs)| !attrs.readonly)
+        .map(|(field, attrs)| {
+            let ident = field.ident.as_ref().expect("named field");
+            if attrs.flatten {
+                quote! { params.extend(crate::models::ToD1Params::to_d1_params(&self.#ident)); }
+            } else if attrs.list {
+                quote! { params.push(crate::models::FromD1List::to_d1_list_param(&self.#ident)); }
+            } else {
+                quote! { params.push(crate::models::ToD1Param::to_d1_param(&self.#ident)); }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(quote! {
+        impl #impl_generics crate::models::ToD1Params for #name #ty_generics #where_clause {
+            fn to_d1_params(&self) -> ::std::vec::Vec<crate::models::D1Param> {
+                let mut params = ::std::vec::Vec::new();
+                #(#pushes)*
+                params
+            }
+        }
+    })
+}
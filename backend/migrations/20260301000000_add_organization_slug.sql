@@ -0,0 +1,7 @@
+-- Organization slugs identify the organization at login, since usernames are
+-- only unique within an organization.
+ALTER TABLE organizations ADD COLUMN slug TEXT;
+
+UPDATE organizations SET slug = 'org-' || id WHERE slug IS NULL;
+
+CREATE UNIQUE INDEX idx_organizations_slug ON organizations (slug);
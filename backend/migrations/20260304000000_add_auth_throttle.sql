@@ -0,0 +1,8 @@
+-- Failed-attempt counters for login and token lookups, keyed by scope and
+-- account or client IP. Times are Unix seconds.
+CREATE TABLE auth_throttle (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at INTEGER NOT NULL,
+    locked_until INTEGER
+);
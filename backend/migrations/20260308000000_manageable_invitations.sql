@@ -0,0 +1,20 @@
+-- Invitations are kept after use so they can be listed, revoked and
+-- redeemed up to `max_uses` times. `email`, when set, is the only address
+-- that can join with the invitation.
+ALTER TABLE invitations ADD COLUMN email TEXT;
+ALTER TABLE invitations ADD COLUMN max_uses INTEGER NOT NULL DEFAULT 1 CHECK (max_uses >= 1);
+ALTER TABLE invitations ADD COLUMN created_by INTEGER REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE invitations ADD COLUMN revoked_at TEXT;
+
+-- One row per account created from an invitation; the row count is the
+-- invitation's use count, so it survives the member being deleted.
+CREATE TABLE invitation_redemptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invitation_id INTEGER NOT NULL,
+    user_id INTEGER,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (invitation_id) REFERENCES invitations(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_invitation_redemptions_invitation ON invitation_redemptions (invitation_id);
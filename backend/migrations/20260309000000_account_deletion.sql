@@ -0,0 +1,3 @@
+-- Self-service deletion: the account is purged by the cron trigger once
+-- this time has passed, unless the owner cancels first.
+ALTER TABLE users ADD COLUMN deletion_scheduled_at TEXT;
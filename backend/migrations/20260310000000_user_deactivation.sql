@@ -0,0 +1,3 @@
+-- Departed members are deactivated rather than deleted so their tasks, time
+-- logs, reports and activity stay in the history.
+ALTER TABLE users ADD COLUMN deactivated_at TEXT;
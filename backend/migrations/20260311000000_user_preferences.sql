@@ -0,0 +1,3 @@
+-- Per-member display preferences; NULL falls back to the client default.
+ALTER TABLE users ADD COLUMN locale TEXT;
+ALTER TABLE users ADD COLUMN timezone TEXT;
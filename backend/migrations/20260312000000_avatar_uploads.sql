@@ -0,0 +1,2 @@
+-- R2 key of an uploaded avatar; NULL when avatar_url points elsewhere.
+ALTER TABLE users ADD COLUMN avatar_key TEXT;
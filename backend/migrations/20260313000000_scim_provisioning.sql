@@ -0,0 +1,22 @@
+-- Organization-level bearer tokens for the SCIM provisioning API. Only the
+-- SHA-256 of the token is stored; changes made with a token are logged as
+-- the admin who created it.
+CREATE TABLE scim_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_by INTEGER NOT NULL,
+    last_used_at TEXT,
+    revoked_at TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_scim_tokens_org ON scim_tokens (organization_id);
+
+-- The directory's id for members provisioned through SCIM.
+ALTER TABLE users ADD COLUMN external_id TEXT;
+
+CREATE UNIQUE INDEX idx_users_external_id ON users (organization_id, external_id);
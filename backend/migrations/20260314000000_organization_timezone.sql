@@ -0,0 +1,3 @@
+-- IANA timezone that days and weeks are counted in; members can override it
+-- with users.timezone. Existing organizations keep the previous JST behavior.
+ALTER TABLE organizations ADD COLUMN timezone TEXT NOT NULL DEFAULT 'Asia/Tokyo';
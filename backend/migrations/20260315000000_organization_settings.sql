@@ -0,0 +1,13 @@
+-- Per-organization settings that replace hard-coded defaults. Existing
+-- organizations keep the previous behavior.
+ALTER TABLE organizations ADD COLUMN week_start_day TEXT NOT NULL DEFAULT 'monday'
+    CHECK (week_start_day IN ('monday', 'tuesday', 'wednesday', 'thursday', 'friday', 'saturday', 'sunday'));
+ALTER TABLE organizations ADD COLUMN default_task_status TEXT NOT NULL DEFAULT 'todo';
+-- JSON array of the statuses the task report shows when none are requested.
+ALTER TABLE organizations ADD COLUMN report_statuses TEXT NOT NULL DEFAULT '["doing","done"]';
+ALTER TABLE organizations ADD COLUMN invitation_expiration_days INTEGER NOT NULL DEFAULT 7;
+ALTER TABLE organizations ADD COLUMN password_min_length INTEGER NOT NULL DEFAULT 8;
+ALTER TABLE organizations ADD COLUMN password_require_uppercase INTEGER NOT NULL DEFAULT 1 CHECK (password_require_uppercase IN (0, 1));
+ALTER TABLE organizations ADD COLUMN password_require_lowercase INTEGER NOT NULL DEFAULT 1 CHECK (password_require_lowercase IN (0, 1));
+ALTER TABLE organizations ADD COLUMN password_require_digit INTEGER NOT NULL DEFAULT 1 CHECK (password_require_digit IN (0, 1));
+ALTER TABLE organizations ADD COLUMN password_require_symbol INTEGER NOT NULL DEFAULT 1 CHECK (password_require_symbol IN (0, 1));
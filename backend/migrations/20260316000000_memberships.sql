@@ -0,0 +1,26 @@
+-- Accounts can belong to several organizations. A member's role and
+-- deactivation are per organization and move from users to memberships;
+-- users.organization_id remains the organization the account was created in.
+CREATE TABLE memberships (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    organization_id INTEGER NOT NULL,
+    role TEXT NOT NULL DEFAULT 'user',
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    deactivated_at TEXT,
+    UNIQUE (user_id, organization_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_memberships_organization ON memberships (organization_id, role);
+
+INSERT INTO memberships (user_id, organization_id, role, created_at, deactivated_at)
+SELECT id, organization_id, role, created_at, deactivated_at FROM users;
+
+ALTER TABLE users DROP COLUMN role;
+ALTER TABLE users DROP COLUMN deactivated_at;
+
+-- A password login picks one of the account's organizations before 2FA, so
+-- the challenge remembers which one the session is for.
+ALTER TABLE login_challenges ADD COLUMN organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
@@ -0,0 +1,8 @@
+-- Deadlines and time estimates on tasks. `estimate_exceeded_at` records when
+-- the assignee was told the logged time passed the estimate, so they are told
+-- once each time it does.
+ALTER TABLE tasks ADD COLUMN due_date TEXT;
+ALTER TABLE tasks ADD COLUMN estimated_minutes INTEGER CHECK (estimated_minutes IS NULL OR estimated_minutes > 0);
+ALTER TABLE tasks ADD COLUMN estimate_exceeded_at TEXT;
+
+CREATE INDEX idx_tasks_due_date ON tasks (organization_id, due_date);
@@ -0,0 +1,3 @@
+-- JSON array of the statuses that count as finished: tasks in them are
+-- neither overdue nor due this week.
+ALTER TABLE organizations ADD COLUMN closed_task_statuses TEXT NOT NULL DEFAULT '["done"]';
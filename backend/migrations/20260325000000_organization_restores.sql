@@ -0,0 +1,10 @@
+-- One row per organization while an archive restore is running. It stays
+-- behind when a failed restore could not remove what it wrote, or the Worker
+-- stopped mid-run, and blocks further restores until an admin clears the
+-- partial data.
+CREATE TABLE organization_restores (
+    organization_id INTEGER PRIMARY KEY,
+    importer_id INTEGER NOT NULL,
+    started_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
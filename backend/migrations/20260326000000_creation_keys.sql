@@ -0,0 +1,7 @@
+-- A random key set when a task or display group is created, so statements
+-- batched with its INSERT can look up the new id.
+ALTER TABLE tasks ADD COLUMN creation_key TEXT;
+CREATE UNIQUE INDEX idx_tasks_creation_key ON tasks (creation_key);
+
+ALTER TABLE display_groups ADD COLUMN creation_key TEXT;
+CREATE UNIQUE INDEX idx_display_groups_creation_key ON display_groups (creation_key);
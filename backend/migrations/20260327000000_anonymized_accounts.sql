@@ -0,0 +1,4 @@
+-- Purged accounts are anonymized rather than deleted, so their tasks, time
+-- logs, reports and activity stay in every organization's history. This is
+-- when that happened; such accounts cannot be reactivated.
+ALTER TABLE users ADD COLUMN deleted_at TEXT;
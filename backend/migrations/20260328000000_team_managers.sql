@@ -0,0 +1,5 @@
+-- The member a membership reports to. Managers see the task report and
+-- analytics of the members whose manager they are, not the whole
+-- organization.
+ALTER TABLE memberships ADD COLUMN manager_id INTEGER REFERENCES users(id) ON DELETE SET NULL;
+CREATE INDEX idx_memberships_manager ON memberships (organization_id, manager_id);
@@ -0,0 +1,11 @@
+-- Tokens emailed to the address an invitation is bound to. A new account
+-- only redeems such an invitation by bringing one back, which proves the
+-- person joining reads mail there.
+CREATE TABLE join_verifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invitation_id INTEGER NOT NULL REFERENCES invitations(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
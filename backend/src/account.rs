@@ -0,0 +1,424 @@
+//! Self-service account data: the personal data export and scheduled
+//! account deletion.
+//!
+//! Deleting an account only schedules it; the cron trigger purges it once
+//! the grace period has passed, and the owner can cancel until then. Purging
+//! anonymizes the account and ends its memberships rather than deleting the
+//! row, so its tasks, time logs, reports and activity stay in the history.
+//! One account can belong to several organizations, so both are
+//! account-wide: the export covers every membership, and a deletion request
+//! must acknowledge every organization in [`organizations_of`], not just the
+//! one it is made from.
+
+use crate::avatars::{self, ObjectStore};
+use crate::db::Database;
+use crate::models::{
+    ActivityLog, D1Param, D1Statement, DailyReport, FromD1Row, MembershipSummary, ModelError,
+    Notification, Task, TaskTimeLog, User, d1_batch, d1_execute, d1_query_all, d1_query_one,
+};
+use crate::provisioning::unusable_password_hash;
+use chrono::{Duration, Utc};
+use serde::Serialize;
+
+pub const DELETION_GRACE_DAYS: i64 = 14;
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// Everything stored about one account, across all of its organizations.
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub format_version: u32,
+    pub exported_at: String,
+    /// The account as a member of the organization the export was made from.
+    pub profile: User,
+    /// Every organization the account belongs to, deactivated ones included.
+    pub memberships: Vec<MembershipSummary>,
+    pub tasks: Vec<Task>,
+    pub time_logs: Vec<TaskTimeLog>,
+    pub daily_reports: Vec<DailyReport>,
+    pub notifications: Vec<Notification>,
+    pub activity: Vec<ActivityLog>,
+}
+
+pub async fn collect_export(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Option<AccountExport>, ModelError> {
+    let Some(profile) = d1_query_one::<User>(
+        db,
+        "SELECT u.id, m.organization_id, u.name, u.username, u.email, u.pending_email, u.avatar_url, u.locale, u.timezone,
+                m.role, u.email_verified, u.created_at
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND u.id = ?2
+         LIMIT 1",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let params = [D1Param::Integer(user_id)];
+    let memberships = d1_query_all::<MembershipSummary>(
+        db,
+        "SELECT m.organization_id, o.slug, o.name, m.role,
+                (m.organization_id = ?2) AS current
+         FROM memberships m
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE m.user_id = ?1
+         ORDER BY m.created_at ASC, m.id ASC",
+        &[D1Param::Integer(user_id), D1Param::Integer(organization_id)],
+    )
+    .await?;
+
+    let tasks = d1_query_all::<Task>(
+        db,
+        "SELECT t.id, t.organization_id, t.member_id, t.title, t.description, t.status, t.progress_rate,
+                t.due_date, t.estimated_minutes,
+                NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS tags,
+                t.created_at, t.updated_at,
+                COALESCE((SELECT SUM(l.duration_minutes) FROM task_time_logs l WHERE l.task_id = t.id), 0)
+                    AS total_duration_minutes
+         FROM tasks t
+         LEFT JOIN task_tags tt ON t.id = tt.task_id
+         LEFT JOIN tags tg ON tt.tag_id = tg.id
+         WHERE t.member_id = ?1
+         GROUP BY t.id
+         ORDER BY t.id ASC",
+        &params,
+    )
+    .await?;
+    let time_logs = d1_query_all::<TaskTimeLog>(
+        db,
+        "SELECT l.id, l.organization_id, l.user_id, l.task_id, l.start_at, l.end_at, l.duration_minutes,
+                l.created_at, t.title AS task_title
+         FROM task_time_logs l
+         LEFT JOIN tasks t ON t.id = l.task_id
+         WHERE l.user_id = ?1
+         ORDER BY l.start_at ASC, l.id ASC",
+        &params,
+    )
+    .await?;
+    let daily_reports = d1_query_all::<DailyReport>(
+        db,
+        "SELECT id, organization_id, user_id, report_date, content, created_at
+         FROM daily_reports
+         WHERE user_id = ?1
+         ORDER BY report_date ASC, organization_id ASC",
+        &params,
+    )
+    .await?;
+    let notifications = d1_query_all::<Notification>(
+        db,
+        "SELECT id, organization_id, user_id, title, body, category, target_type, target_id, is_read, created_at
+         FROM notifications
+         WHERE user_id = ?1
+         ORDER BY created_at ASC, id ASC",
+        &params,
+    )
+    .await?;
+    let activity = d1_query_all::<ActivityLog>(
+        db,
+        "SELECT l.id, l.organization_id, l.user_id, u.name AS user_name,
+                l.action, l.target_type, l.target_id, l.details, l.created_at
+         FROM activity_logs l
+         JOIN users u ON u.id = l.user_id
+         WHERE l.user_id = ?1
+         ORDER BY l.created_at ASC, l.id ASC",
+        &params,
+    )
+    .await?;
+
+    Ok(Some(AccountExport {
+        format_version: EXPORT_FORMAT_VERSION,
+        exported_at: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        profile,
+        memberships,
+        tasks,
+        time_logs,
+        daily_reports,
+        notifications,
+        activity,
+    }))
+}
+
+impl AccountExport {
+    /// The export as a ZIP archive with one JSON file per section.
+    pub fn to_zip(&self) -> Result<Vec<u8>, ModelError> {
+        let files = [
+            (
+                "export.json",
+                serde_json::to_vec_pretty(&serde_json::json!({
+                    "format_version": self.format_version,
+                    "exported_at": self.exported_at,
+                }))?,
+            ),
+            ("profile.json", serde_json::to_vec_pretty(&self.profile)?),
+            (
+                "memberships.json",
+                serde_json::to_vec_pretty(&self.memberships)?,
+            ),
+            ("tasks.json", serde_json::to_vec_pretty(&self.tasks)?),
+            (
+                "time_logs.json",
+                serde_json::to_vec_pretty(&self.time_logs)?,
+            ),
+            (
+                "daily_reports.json",
+                serde_json::to_vec_pretty(&self.daily_reports)?,
+            ),
+            (
+                "notifications.json",
+                serde_json::to_vec_pretty(&self.notifications)?,
+            ),
+            ("activity.json", serde_json::to_vec_pretty(&self.activity)?),
+        ];
+        Ok(stored_zip(&files))
+    }
+}
+
+#[derive(FromD1Row)]
+struct DeletionRow {
+    deletion_scheduled_at: Option<String>,
+}
+
+/// When the account will be purged, if its deletion is scheduled.
+pub async fn deletion_scheduled_at(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Option<String>, ModelError> {
+    Ok(d1_query_one::<DeletionRow>(
+        db,
+        "SELECT u.deletion_scheduled_at
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND u.id = ?2
+         LIMIT 1",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    .and_then(|row| row.deletion_scheduled_at))
+}
+
+#[derive(FromD1Row)]
+struct OrganizationNameRow {
+    name: String,
+}
+
+/// Names of every organization the account is a member of, deactivated
+/// memberships included: all of them end when the account is purged.
+pub async fn organizations_of(db: &dyn Database, user_id: i64) -> Result<Vec<String>, ModelError> {
+    Ok(d1_query_all::<OrganizationNameRow>(
+        db,
+        "SELECT o.name
+         FROM memberships m
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE m.user_id = ?1
+         ORDER BY m.created_at ASC, m.id ASC",
+        &[D1Param::Integer(user_id)],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.name)
+    .collect())
+}
+
+/// Schedules the account for purging after the grace period and returns the
+/// purge time. An already scheduled deletion keeps its original time.
+pub async fn schedule_deletion(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<String, ModelError> {
+    let scheduled_at = (Utc::now() + Duration::days(DELETION_GRACE_DAYS))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    d1_execute(
+        db,
+        "UPDATE users SET deletion_scheduled_at = COALESCE(deletion_scheduled_at, ?3)
+         WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(scheduled_at),
+        ],
+    )
+    .await?;
+    deletion_scheduled_at(db, organization_id, user_id)
+        .await?
+        .ok_or_else(|| ModelError::Database("user not found".to_string()))
+}
+
+/// Cancels a scheduled deletion; returns whether one was pending.
+pub async fn cancel_deletion(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<bool, ModelError> {
+    Ok(d1_execute(
+        db,
+        "UPDATE users SET deletion_scheduled_at = NULL
+         WHERE id = ?2 AND deletion_scheduled_at IS NOT NULL
+           AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    .changes
+        > 0)
+}
+
+/// Purges every account whose grace period has passed and returns how many
+/// there were. The account keeps its row, so its tasks, time logs, reports
+/// and activity stay in each organization's history, but its profile is
+/// anonymized, its credentials, sessions, notifications and uploaded avatar
+/// are removed and every membership is ended.
+pub async fn purge_due_deletions(
+    db: &dyn Database,
+    avatars: &dyn ObjectStore,
+) -> Result<u64, ModelError> {
+    const DUE: &str = "SELECT id FROM users
+         WHERE deletion_scheduled_at IS NOT NULL
+           AND datetime(deletion_scheduled_at) <= datetime('now')";
+
+    let avatar_keys = avatars::keys_of_due_deletions(db).await?;
+    let mut statements: Vec<D1Statement> = [
+        "sessions",
+        "api_tokens",
+        "recovery_codes",
+        "login_challenges",
+        "password_resets",
+        "user_identities",
+        "notifications",
+    ]
+    .iter()
+    .map(|table| {
+        D1Statement::new(
+            format!("DELETE FROM {table} WHERE user_id IN ({DUE})"),
+            vec![],
+        )
+    })
+    .collect();
+    statements.push(D1Statement::new(
+        format!(
+            "UPDATE memberships SET deactivated_at = COALESCE(deactivated_at, CURRENT_TIMESTAMP)
+             WHERE user_id IN ({DUE})"
+        ),
+        vec![],
+    ));
+    // Last, since it clears `deletion_scheduled_at` that the others select by.
+    statements.push(D1Statement::new(
+        format!(
+            "UPDATE users
+             SET name = 'Deleted user', username = 'deleted-' || id, email = NULL,
+                 pending_email = NULL, email_verified = 0, email_verification_token = NULL,
+                 password_hash = ?1, avatar_url = NULL, avatar_key = NULL, totp_secret = NULL,
+                 totp_enabled = 0, totp_last_used_step = NULL, locale = NULL, timezone = NULL,
+                 external_id = NULL, deletion_scheduled_at = NULL, deleted_at = CURRENT_TIMESTAMP
+             WHERE id IN ({DUE})"
+        ),
+        vec![D1Param::Text(unusable_password_hash()?)],
+    ));
+    let purged = d1_batch(db, &statements)
+        .await?
+        .last()
+        .map_or(0, |result| result.changes);
+    for key in avatar_keys {
+        avatars.delete(&key).await?;
+    }
+    Ok(purged)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A ZIP archive of uncompressed ("stored") entries. The exports are small
+/// JSON files, so compression is not worth a dependency.
+fn stored_zip(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let offset = archive.len() as u32;
+        let crc = crc32(data);
+        let size = data.len() as u32;
+        let name = name.as_bytes();
+
+        // Version 2.0, no flags, stored, DOS time/date 1980-01-01 00:00.
+        let common = |out: &mut Vec<u8>| {
+            out.extend_from_slice(&20u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0x0021u16.to_le_bytes());
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+        };
+
+        archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        common(&mut archive);
+        archive.extend_from_slice(name);
+        archive.extend_from_slice(data);
+
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        common(&mut directory);
+        // Comment length, disk number, internal and external attributes.
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name);
+    }
+
+    let directory_offset = archive.len() as u32;
+    let entries = files.len() as u16;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&entries.to_le_bytes());
+    archive.extend_from_slice(&entries.to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+    archive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, stored_zip};
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn stored_zip_lays_out_entries_and_directory() {
+        let archive = stored_zip(&[("a.json", b"{}".to_vec()), ("b.json", b"[]".to_vec())]);
+        assert_eq!(&archive[..4], b"PK\x03\x04");
+        assert_eq!(&archive[30..36], b"a.json");
+        assert_eq!(&archive[36..38], b"{}");
+
+        let end = archive.len() - 22;
+        assert_eq!(&archive[end..end + 4], b"PK\x05\x06");
+        let entries = u16::from_le_bytes([archive[end + 10], archive[end + 11]]);
+        assert_eq!(entries, 2);
+        let offset = u32::from_le_bytes(archive[end + 16..end + 20].try_into().unwrap()) as usize;
+        assert_eq!(&archive[offset..offset + 4], b"PK\x01\x02");
+    }
+}
@@ -0,0 +1,278 @@
+//! Personal access tokens: named, scoped, expiring credentials for scripts
+//! and integrations, accepted by every handler's `extract_claims` next to
+//! session JWTs.
+
+use crate::db::Database;
+use crate::models::{Claims, D1Param, FromD1Row, ModelError, d1_execute, d1_query_one};
+use crate::utils::{generate_token, hash_token};
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::fmt;
+
+/// Distinguishes access tokens from JWTs in the `Authorization` header.
+pub const TOKEN_PREFIX: &str = "gfp_";
+pub const DEFAULT_EXPIRATION_DAYS: i64 = 90;
+pub const MAX_EXPIRATION_DAYS: i64 = 365;
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// Every scope a token can be granted.
+pub const SCOPES: &[&str] = &[
+    "tasks:read",
+    "tasks:write",
+    "time_logs:read",
+    "time_logs:write",
+    "reports:read",
+    "reports:write",
+    "logs:read",
+    "notifications:read",
+    "notifications:write",
+    "analytics:read",
+    "users:read",
+    "users:write",
+    "groups:read",
+    "groups:write",
+];
+
+fn generate() -> String {
+    format!("{TOKEN_PREFIX}{}", generate_token())
+}
+
+/// Stores a new token for `user_id` and returns its id and secret; only the
+/// hash of the secret is kept.
+pub async fn create(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+    name: &str,
+    scopes: &[String],
+    expires_in_days: i64,
+) -> Result<(i64, String), ModelError> {
+    let secret = generate();
+    let expires_at = (Utc::now() + Duration::days(expires_in_days))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    let id = d1_execute(
+        db,
+        "INSERT INTO api_tokens (organization_id, user_id, name, token_hash, scopes, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(name.to_string()),
+            D1Param::Text(hash_token(&secret)),
+            D1Param::Text(serde_json::to_string(scopes)?),
+            D1Param::Text(expires_at),
+        ],
+    )
+    .await?
+    .inserted_id()?;
+    Ok((id, secret))
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Scope needed to call `method` on `path` with an access token.
+///
+/// `None` means the endpoint only accepts interactive sessions: sign-in and
+/// session management, 2FA, the caller's own credentials (including tokens
+/// themselves), invitations and organization administration.
+pub fn required_scope(method: &str, path: &str) -> Option<String> {
+    let segments: Vec<&str> = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let resource = match segments.as_slice() {
+        ["api", "tasks", "time-logs", ..] => "time_logs",
+        ["api", "tasks", ..] => "tasks",
+        ["api", "reports", ..] => "reports",
+        ["api", "logs", ..] => "logs",
+        ["api", "notifications", ..] => "notifications",
+        ["api", "analytics", ..] => "analytics",
+        ["api", "users", "me", ..] => return None,
+        ["api", "users", ..] => "users",
+        ["api", "display-groups", ..] => "groups",
+        _ => return None,
+    };
+    let access = if method.eq_ignore_ascii_case("GET") {
+        "read"
+    } else {
+        "write"
+    };
+    let scope = format!("{resource}:{access}");
+    SCOPES.contains(&scope.as_str()).then_some(scope)
+}
+
+/// Validates requested scopes and returns them sorted and de-duplicated.
+pub fn normalize_scopes(scopes: &[String]) -> Result<Vec<String>, ModelError> {
+    let mut normalized: Vec<String> = scopes.iter().map(|s| s.trim().to_string()).collect();
+    if let Some(unknown) = normalized.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return Err(ModelError::InvalidValue {
+            field: "scopes",
+            message: format!("unknown scope: {unknown}"),
+        });
+    }
+    normalized.sort();
+    normalized.dedup();
+    if normalized.is_empty() {
+        return Err(ModelError::InvalidValue {
+            field: "scopes",
+            message: "at least one scope is required".to_string(),
+        });
+    }
+    Ok(normalized)
+}
+
+#[derive(Debug)]
+pub enum TokenAuthError {
+    /// Unknown, revoked or expired token.
+    Invalid,
+    /// The token is valid but may not call this endpoint.
+    Forbidden(Option<String>),
+    Database(ModelError),
+}
+
+impl TokenAuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Invalid => 401,
+            Self::Forbidden(_) => 403,
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for TokenAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "Invalid token"),
+            Self::Forbidden(Some(scope)) => write!(f, "Token is missing the {scope} scope"),
+            Self::Forbidden(None) => write!(f, "Access tokens cannot be used for this endpoint"),
+            Self::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ModelError> for TokenAuthError {
+    fn from(value: ModelError) -> Self {
+        Self::Database(value)
+    }
+}
+
+#[derive(FromD1Row)]
+struct TokenOwnerRow {
+    id: i64,
+    user_id: i64,
+    organization_id: i64,
+    username: Option<String>,
+    role: String,
+    #[d1(list)]
+    scopes: Vec<String>,
+    expires_at: String,
+}
+
+/// Resolves an access token into claims for a request to `method` `path`.
+///
+/// The claims carry the token's scopes and `sid` 0, since no session is involved.
+pub async fn authenticate(
+    db: &dyn Database,
+    token: &str,
+    method: &str,
+    path: &str,
+) -> Result<Claims, TokenAuthError> {
+    let owner = d1_query_one::<TokenOwnerRow>(
+        db,
+        "SELECT t.id, t.user_id, t.organization_id, u.username, t.scopes, t.expires_at,
+                CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM api_tokens t
+         JOIN users u ON u.id = t.user_id
+         JOIN memberships m ON m.user_id = t.user_id AND m.organization_id = t.organization_id
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE t.token_hash = ?1
+           AND t.revoked_at IS NULL
+           AND m.deactivated_at IS NULL
+           AND datetime(t.expires_at) > datetime('now')
+         LIMIT 1",
+        &[D1Param::Text(hash_token(token))],
+    )
+    .await?
+    .ok_or(TokenAuthError::Invalid)?;
+
+    let scope = required_scope(method, path);
+    if !scope
+        .as_ref()
+        .is_some_and(|scope| owner.scopes.contains(scope))
+    {
+        return Err(TokenAuthError::Forbidden(scope));
+    }
+
+    // Coarse on purpose: one write per token per minute is enough for the list view.
+    d1_execute(
+        db,
+        "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP
+         WHERE id = ?1
+           AND (last_used_at IS NULL OR datetime(last_used_at) < datetime('now', '-1 minute'))",
+        &[D1Param::Integer(owner.id)],
+    )
+    .await?;
+
+    let exp = NaiveDateTime::parse_from_str(&owner.expires_at, "%Y-%m-%d %H:%M:%S")
+        .map(|at| at.and_utc().timestamp() as usize)
+        .unwrap_or(0);
+    Ok(Claims {
+        sub: owner.username.unwrap_or_default(),
+        user_id: owner.user_id,
+        organization_id: owner.organization_id,
+        role: owner.role,
+        exp,
+        sid: 0,
+        scopes: Some(owner.scopes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, is_api_token, normalize_scopes, required_scope};
+
+    #[test]
+    fn maps_routes_to_scopes() {
+        assert_eq!(
+            required_scope("POST", "/api/tasks/time-logs").as_deref(),
+            Some("time_logs:write")
+        );
+        assert_eq!(
+            required_scope("GET", "/api/tasks/report/export").as_deref(),
+            Some("tasks:read")
+        );
+        assert_eq!(
+            required_scope("DELETE", "/api/users/4").as_deref(),
+            Some("users:write")
+        );
+        assert_eq!(required_scope("GET", "/api/users/me/tokens"), None);
+        assert_eq!(required_scope("POST", "/api/auth/logout"), None);
+        assert_eq!(required_scope("PATCH", "/api/logs"), None);
+    }
+
+    #[test]
+    fn validates_scopes_and_token_format() {
+        assert_eq!(
+            normalize_scopes(&[
+                "tasks:read".into(),
+                " tasks:read".into(),
+                "logs:read".into()
+            ])
+            .expect("valid"),
+            vec!["logs:read".to_string(), "tasks:read".to_string()]
+        );
+        assert!(normalize_scopes(&[]).is_err());
+        assert!(normalize_scopes(&["admin".into()]).is_err());
+
+        let token = generate();
+        assert!(is_api_token(&token));
+        assert!(!is_api_token("eyJhbGciOiJIUzI1NiJ9.e30.sig"));
+    }
+}
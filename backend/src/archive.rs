@@ -0,0 +1,1198 @@
+//! Organization backups.
+//!
+//! An archive is NDJSON: a `header` line with the format version and the
+//! organization's settings, one `{"type": ..., "data": ...}` line per record,
+//! and an `end` line with the number of records of each kind, so a truncated
+//! file is rejected instead of half restored. Records are the API models read
+//! through `FromD1Row`; accounts are exported without password hashes, 2FA
+//! secrets or sessions.
+//!
+//! [`restore`] loads an archive into an organization that has no data of its
+//! own yet. Every record gets a new id and references between records are
+//! rewritten; restored members have no usable password until they reset it
+//! or sign in with SSO. A restore that could not be undone leaves the
+//! organization marked in `organization_restores` until
+//! [`clear_partial_restore`] removes what it wrote.
+
+use crate::db::Database;
+use crate::memberships::{self, MEMBER_COLUMNS};
+use crate::models::{
+    ActivityLog, CountRow, D1Param, D1Statement, DailyReport, DisplayGroup, FromD1Row, IdRow,
+    ModelError, Notification, OrganizationSettings, Role, Tag, Task, TaskTimeLog, User, d1_batch,
+    d1_execute, d1_query_all, d1_query_one,
+};
+use crate::permissions::{self, builtin_permissions, is_valid_role_name};
+use crate::provisioning::unusable_password_hash;
+use crate::settings;
+use chrono::Utc;
+use futures::TryStreamExt;
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// Records read per query while exporting.
+const PAGE_SIZE: i64 = 500;
+/// Statements per D1 batch while restoring.
+const BATCH_SIZE: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveHeader {
+    pub format_version: u32,
+    pub exported_at: String,
+    pub organization: OrganizationSettings,
+}
+
+/// Number of records of each kind in an archive.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveCounts {
+    pub roles: usize,
+    pub users: usize,
+    pub tags: usize,
+    pub tasks: usize,
+    pub time_logs: usize,
+    pub daily_reports: usize,
+    pub display_groups: usize,
+    pub notifications: usize,
+    pub activity_logs: usize,
+}
+
+/// One NDJSON line, e.g. `{"type":"task","data":{...}}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ArchiveLine {
+    Header(ArchiveHeader),
+    /// Custom roles only; built-in roles exist everywhere.
+    Role(Role),
+    User(User),
+    Tag(Tag),
+    Task(Task),
+    TimeLog(TaskTimeLog),
+    DailyReport(DailyReport),
+    DisplayGroup(DisplayGroup),
+    Notification(Notification),
+    ActivityLog(ActivityLog),
+    End(ArchiveCounts),
+}
+
+/// A whole archive in memory, which is also the `?format=json` export.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrganizationArchive {
+    #[serde(flatten)]
+    pub header: ArchiveHeader,
+    pub roles: Vec<Role>,
+    pub users: Vec<User>,
+    pub tags: Vec<Tag>,
+    pub tasks: Vec<Task>,
+    pub time_logs: Vec<TaskTimeLog>,
+    pub daily_reports: Vec<DailyReport>,
+    pub display_groups: Vec<DisplayGroup>,
+    pub notifications: Vec<Notification>,
+    pub activity_logs: Vec<ActivityLog>,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// Not an archive, cut short, or referring to records it does not contain.
+    Invalid(String),
+    UnsupportedVersion(u32),
+    /// The organization already has members or data of its own.
+    NotEmpty,
+    /// An earlier restore is running, or stopped before it finished.
+    RestoreInProgress,
+    /// No unfinished restore to clear.
+    NoPartialRestore,
+    /// The restore failed and so did deleting the rows it had written.
+    Incomplete {
+        cause: ModelError,
+        cleanup: ModelError,
+    },
+    Database(ModelError),
+}
+
+impl ArchiveError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Invalid(_) => 400,
+            Self::UnsupportedVersion(_) => 422,
+            Self::NotEmpty | Self::RestoreInProgress => 409,
+            Self::NoPartialRestore => 404,
+            Self::Incomplete { .. } | Self::Database(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(message) => write!(f, "Invalid archive: {message}"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Archive format version {version} is not supported; this server reads version {ARCHIVE_FORMAT_VERSION}"
+            ),
+            Self::NotEmpty => write!(
+                f,
+                "Archives can only be restored into an organization without other members or data"
+            ),
+            Self::RestoreInProgress => write!(
+                f,
+                "Another restore of this organization is running or did not finish; clear its partial data before trying again"
+            ),
+            Self::NoPartialRestore => write!(f, "No unfinished restore to clear"),
+            Self::Incomplete { cause, cleanup } => write!(
+                f,
+                "Restore failed ({cause}) and its partial data could not be removed ({cleanup}); clear it before trying again"
+            ),
+            Self::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<ModelError> for ArchiveError {
+    fn from(value: ModelError) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl ArchiveCounts {
+    fn record(&mut self, line: &ArchiveLine) {
+        match line {
+            ArchiveLine::Role(_) => self.roles += 1,
+            ArchiveLine::User(_) => self.users += 1,
+            ArchiveLine::Tag(_) => self.tags += 1,
+            ArchiveLine::Task(_) => self.tasks += 1,
+            ArchiveLine::TimeLog(_) => self.time_logs += 1,
+            ArchiveLine::DailyReport(_) => self.daily_reports += 1,
+            ArchiveLine::DisplayGroup(_) => self.display_groups += 1,
+            ArchiveLine::Notification(_) => self.notifications += 1,
+            ArchiveLine::ActivityLog(_) => self.activity_logs += 1,
+            ArchiveLine::Header(_) | ArchiveLine::End(_) => {}
+        }
+    }
+}
+
+// =============================
+// Export
+// =============================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Section {
+    Header,
+    Roles,
+    Users,
+    Tags,
+    Tasks,
+    TimeLogs,
+    DailyReports,
+    DisplayGroups,
+    Notifications,
+    ActivityLogs,
+    End,
+    Done,
+}
+
+impl Section {
+    fn next(self) -> Self {
+        match self {
+            Self::Header => Self::Roles,
+            Self::Roles => Self::Users,
+            Self::Users => Self::Tags,
+            Self::Tags => Self::Tasks,
+            Self::Tasks => Self::TimeLogs,
+            Self::TimeLogs => Self::DailyReports,
+            Self::DailyReports => Self::DisplayGroups,
+            Self::DisplayGroups => Self::Notifications,
+            Self::Notifications => Self::ActivityLogs,
+            Self::ActivityLogs => Self::End,
+            Self::End | Self::Done => Self::Done,
+        }
+    }
+}
+
+struct ExportCursor {
+    section: Section,
+    /// Records are read in id order; the last id of the previous page.
+    after_id: i64,
+    counts: ArchiveCounts,
+}
+
+impl ExportCursor {
+    fn advance(&mut self) {
+        self.section = self.section.next();
+        self.after_id = 0;
+    }
+}
+
+/// The archive in chunks of lines, one page of records at a time, so an
+/// organization is never held in memory as a whole.
+pub fn lines(
+    db: Arc<dyn Database>,
+    organization_id: i64,
+) -> impl Stream<Item = Result<Vec<ArchiveLine>, ModelError>> {
+    let cursor = ExportCursor {
+        section: Section::Header,
+        after_id: 0,
+        counts: ArchiveCounts::default(),
+    };
+    stream::try_unfold(cursor, move |mut cursor| {
+        let db = db.clone();
+        async move {
+            if cursor.section == Section::Done {
+                return Ok(None);
+            }
+            let lines = next_chunk(&db, organization_id, &mut cursor).await?;
+            lines.iter().for_each(|line| cursor.counts.record(line));
+            Ok(Some((lines, cursor)))
+        }
+    })
+}
+
+/// [`lines`] serialized as NDJSON.
+pub fn ndjson(
+    db: Arc<dyn Database>,
+    organization_id: i64,
+) -> impl Stream<Item = Result<Vec<u8>, ModelError>> {
+    lines(db, organization_id).and_then(|lines| async move {
+        let mut bytes = Vec::new();
+        for line in &lines {
+            serde_json::to_writer(&mut bytes, line)?;
+            bytes.push(b'\n');
+        }
+        Ok(bytes)
+    })
+}
+
+/// The whole archive, for the JSON export.
+pub async fn collect(
+    db: Arc<dyn Database>,
+    organization_id: i64,
+) -> Result<OrganizationArchive, ArchiveError> {
+    let chunks: Vec<Vec<ArchiveLine>> = lines(db, organization_id).try_collect().await?;
+    OrganizationArchive::from_lines(chunks.into_iter().flatten().map(Ok))
+}
+
+async fn next_chunk(
+    db: &dyn Database,
+    organization_id: i64,
+    cursor: &mut ExportCursor,
+) -> Result<Vec<ArchiveLine>, ModelError> {
+    let lines = match cursor.section {
+        Section::Header => {
+            let organization = settings::load(db, organization_id)
+                .await?
+                .ok_or(ModelError::MissingField("organization"))?;
+            cursor.advance();
+            vec![ArchiveLine::Header(ArchiveHeader {
+                format_version: ARCHIVE_FORMAT_VERSION,
+                exported_at: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                organization,
+            })]
+        }
+        Section::Roles => {
+            let roles = permissions::list_roles(db, organization_id).await?;
+            cursor.advance();
+            roles
+                .into_iter()
+                .filter(|role| !role.builtin)
+                .map(ArchiveLine::Role)
+                .collect()
+        }
+        Section::Users => {
+            let sql = format!(
+                "SELECT {MEMBER_COLUMNS}
+                 FROM users u
+                 JOIN memberships m ON m.user_id = u.id
+                 WHERE m.organization_id = ?1 AND u.id > ?2
+                 ORDER BY u.id ASC
+                 LIMIT ?3"
+            );
+            page(db, &sql, organization_id, cursor, |u: &User| u.id, ArchiveLine::User).await?
+        }
+        Section::Tags => {
+            page(
+                db,
+                "SELECT id, organization_id, name, created_at
+                 FROM tags
+                 WHERE organization_id = ?1 AND id > ?2
+                 ORDER BY id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |t: &Tag| t.id,
+                ArchiveLine::Tag,
+            )
+            .await?
+        }
+        Section::Tasks => {
+            page(
+                db,
+                "SELECT t.id, t.organization_id, t.member_id, t.title, t.description, t.status, t.progress_rate,
+                        t.due_date, t.estimated_minutes,
+                        NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS tags,
+                        t.created_at, t.updated_at,
+                        COALESCE((SELECT SUM(l.duration_minutes) FROM task_time_logs l WHERE l.task_id = t.id), 0)
+                            AS total_duration_minutes
+                 FROM tasks t
+                 LEFT JOIN task_tags tt ON t.id = tt.task_id
+                 LEFT JOIN tags tg ON tt.tag_id = tg.id
+                 WHERE t.organization_id = ?1 AND t.id > ?2
+                 GROUP BY t.id
+                 ORDER BY t.id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |t: &Task| t.id,
+                ArchiveLine::Task,
+            )
+            .await?
+        }
+        Section::TimeLogs => {
+            page(
+                db,
+                "SELECT id, organization_id, user_id, task_id, start_at, end_at, duration_minutes, created_at
+                 FROM task_time_logs
+                 WHERE organization_id = ?1 AND id > ?2
+                 ORDER BY id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |l: &TaskTimeLog| l.id,
+                ArchiveLine::TimeLog,
+            )
+            .await?
+        }
+        Section::DailyReports => {
+            page(
+                db,
+                "SELECT id, organization_id, user_id, report_date, content, created_at
+                 FROM daily_reports
+                 WHERE organization_id = ?1 AND id > ?2
+                 ORDER BY id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |r: &DailyReport| r.id,
+                ArchiveLine::DailyReport,
+            )
+            .await?
+        }
+        Section::DisplayGroups => {
+            page(
+                db,
+                "SELECT g.id, g.organization_id, g.user_id, g.name,
+                        COALESCE(NULLIF(GROUP_CONCAT(m.member_id), ''), '') AS member_ids,
+                        g.created_at
+                 FROM display_groups g
+                 LEFT JOIN display_group_members m ON g.id = m.group_id
+                 WHERE g.organization_id = ?1 AND g.id > ?2
+                 GROUP BY g.id
+                 ORDER BY g.id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |g: &DisplayGroup| g.id,
+                ArchiveLine::DisplayGroup,
+            )
+            .await?
+        }
+        Section::Notifications => {
+            page(
+                db,
+                "SELECT id, organization_id, user_id, title, body, category, target_type, target_id, is_read, created_at
+                 FROM notifications
+                 WHERE organization_id = ?1 AND id > ?2
+                 ORDER BY id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |n: &Notification| n.id,
+                ArchiveLine::Notification,
+            )
+            .await?
+        }
+        Section::ActivityLogs => {
+            page(
+                db,
+                "SELECT l.id, l.organization_id, l.user_id, u.name AS user_name,
+                        l.action, l.target_type, l.target_id, l.details, l.created_at
+                 FROM activity_logs l
+                 JOIN users u ON u.id = l.user_id
+                 WHERE l.organization_id = ?1 AND l.id > ?2
+                 ORDER BY l.id ASC
+                 LIMIT ?3",
+                organization_id,
+                cursor,
+                |l: &ActivityLog| l.id,
+                ArchiveLine::ActivityLog,
+            )
+            .await?
+        }
+        Section::End => {
+            cursor.advance();
+            vec![ArchiveLine::End(cursor.counts.clone())]
+        }
+        Section::Done => Vec::new(),
+    };
+    Ok(lines)
+}
+
+/// Reads the page after `cursor.after_id` with `sql`, which binds the
+/// organization, the last id and the page size, and moves to the next
+/// section after a short page.
+async fn page<T: FromD1Row>(
+    db: &dyn Database,
+    sql: &str,
+    organization_id: i64,
+    cursor: &mut ExportCursor,
+    id: fn(&T) -> i64,
+    line: fn(T) -> ArchiveLine,
+) -> Result<Vec<ArchiveLine>, ModelError> {
+    let rows = d1_query_all::<T>(
+        db,
+        sql,
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(cursor.after_id),
+            D1Param::Integer(PAGE_SIZE),
+        ],
+    )
+    .await?;
+    match rows.last() {
+        Some(last) if rows.len() as i64 == PAGE_SIZE => cursor.after_id = id(last),
+        _ => cursor.advance(),
+    }
+    Ok(rows.into_iter().map(line).collect())
+}
+
+// =============================
+// Parsing
+// =============================
+
+impl OrganizationArchive {
+    /// Parses an NDJSON archive; blank lines are ignored.
+    pub fn from_ndjson(text: &str) -> Result<Self, ArchiveError> {
+        Self::from_lines(
+            text.lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| {
+                    serde_json::from_str::<ArchiveLine>(line)
+                        .map_err(|e| ArchiveError::Invalid(format!("line {}: {e}", index + 1)))
+                }),
+        )
+    }
+
+    /// Assembles an archive from its lines, which must start with the header
+    /// and end with counts matching the records in between.
+    pub fn from_lines(
+        lines: impl IntoIterator<Item = Result<ArchiveLine, ArchiveError>>,
+    ) -> Result<Self, ArchiveError> {
+        let mut lines = lines.into_iter();
+        let header = match lines.next().transpose()? {
+            Some(ArchiveLine::Header(header)) => header,
+            _ => return Err(ArchiveError::Invalid("missing header".to_string())),
+        };
+        check_version(header.format_version)?;
+
+        let mut archive = Self {
+            header,
+            roles: Vec::new(),
+            users: Vec::new(),
+            tags: Vec::new(),
+            tasks: Vec::new(),
+            time_logs: Vec::new(),
+            daily_reports: Vec::new(),
+            display_groups: Vec::new(),
+            notifications: Vec::new(),
+            activity_logs: Vec::new(),
+        };
+        let mut end = None;
+        for line in lines {
+            let line = line?;
+            if end.is_some() {
+                return Err(ArchiveError::Invalid("records after the end".to_string()));
+            }
+            match line {
+                ArchiveLine::Header(_) => {
+                    return Err(ArchiveError::Invalid("more than one header".to_string()));
+                }
+                ArchiveLine::Role(role) => archive.roles.push(role),
+                ArchiveLine::User(user) => archive.users.push(user),
+                ArchiveLine::Tag(tag) => archive.tags.push(tag),
+                ArchiveLine::Task(task) => archive.tasks.push(task),
+                ArchiveLine::TimeLog(log) => archive.time_logs.push(log),
+                ArchiveLine::DailyReport(report) => archive.daily_reports.push(report),
+                ArchiveLine::DisplayGroup(group) => archive.display_groups.push(group),
+                ArchiveLine::Notification(notification) => archive.notifications.push(notification),
+                ArchiveLine::ActivityLog(log) => archive.activity_logs.push(log),
+                ArchiveLine::End(counts) => end = Some(counts),
+            }
+        }
+        match end {
+            Some(counts) if counts == archive.counts() => Ok(archive),
+            Some(_) => Err(ArchiveError::Invalid(
+                "record counts do not match the end line".to_string(),
+            )),
+            None => Err(ArchiveError::Invalid(
+                "missing end line; the archive is incomplete".to_string(),
+            )),
+        }
+    }
+
+    pub fn counts(&self) -> ArchiveCounts {
+        ArchiveCounts {
+            roles: self.roles.len(),
+            users: self.users.len(),
+            tags: self.tags.len(),
+            tasks: self.tasks.len(),
+            time_logs: self.time_logs.len(),
+            daily_reports: self.daily_reports.len(),
+            display_groups: self.display_groups.len(),
+            notifications: self.notifications.len(),
+            activity_logs: self.activity_logs.len(),
+        }
+    }
+
+    /// Checks that every reference points at a record in the archive, so a
+    /// restore does not fail halfway through.
+    pub fn validate(&self) -> Result<(), ArchiveError> {
+        check_version(self.header.format_version)?;
+
+        let mut roles = HashSet::new();
+        for role in &self.roles {
+            if !is_valid_role_name(&role.name)
+                || builtin_permissions(&role.name).is_some()
+                || !roles.insert(role.name.as_str())
+            {
+                return Err(invalid(format!("role {} cannot be restored", role.name)));
+            }
+        }
+        let users = unique_ids("user", self.users.iter().map(|u| u.id))?;
+        for user in &self.users {
+            if builtin_permissions(&user.role).is_none() && !roles.contains(user.role.as_str()) {
+                return Err(invalid(format!(
+                    "user {} has role {}, which is not in the archive",
+                    user.id, user.role
+                )));
+            }
+        }
+        let tags: HashSet<&str> = self.tags.iter().map(|t| t.name.as_str()).collect();
+        let tasks = unique_ids("task", self.tasks.iter().map(|t| t.id))?;
+        unique_ids("time log", self.time_logs.iter().map(|l| l.id))?;
+        unique_ids("daily report", self.daily_reports.iter().map(|r| r.id))?;
+        unique_ids("display group", self.display_groups.iter().map(|g| g.id))?;
+
+        let user_refs = self
+            .tasks
+            .iter()
+            .map(|t| ("task", t.id, t.member_id))
+            .chain(self.time_logs.iter().map(|l| ("time log", l.id, l.user_id)))
+            .chain(
+                self.daily_reports
+                    .iter()
+                    .map(|r| ("daily report", r.id, r.user_id)),
+            )
+            .chain(self.display_groups.iter().flat_map(|g| {
+                std::iter::once(g.user_id)
+                    .chain(g.member_ids.iter().copied())
+                    .map(move |user_id| ("display group", g.id, user_id))
+            }))
+            .chain(
+                self.notifications
+                    .iter()
+                    .map(|n| ("notification", n.id, n.user_id)),
+            )
+            .chain(
+                self.activity_logs
+                    .iter()
+                    .map(|l| ("activity log", l.id, l.user_id)),
+            );
+        for (kind, id, user_id) in user_refs {
+            if !users.contains(&user_id) {
+                return Err(invalid(format!(
+                    "{kind} {id} refers to user {user_id}, which is not in the archive"
+                )));
+            }
+        }
+        for log in &self.time_logs {
+            if !tasks.contains(&log.task_id) {
+                return Err(invalid(format!(
+                    "time log {} refers to task {}, which is not in the archive",
+                    log.id, log.task_id
+                )));
+            }
+        }
+        for task in &self.tasks {
+            if let Some(tag) = task
+                .tags
+                .iter()
+                .flatten()
+                .find(|tag| !tags.contains(tag.as_str()))
+            {
+                return Err(invalid(format!(
+                    "task {} has tag {tag}, which is not in the archive",
+                    task.id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> ArchiveError {
+    ArchiveError::Invalid(message)
+}
+
+fn check_version(version: u32) -> Result<(), ArchiveError> {
+    if version == 0 || version > ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn unique_ids(kind: &str, ids: impl Iterator<Item = i64>) -> Result<HashSet<i64>, ArchiveError> {
+    let mut seen = HashSet::new();
+    for id in ids {
+        if !seen.insert(id) {
+            return Err(invalid(format!("{kind} {id} appears more than once")));
+        }
+    }
+    Ok(seen)
+}
+
+// =============================
+// Restore
+// =============================
+
+/// Restores `archive` into `organization_id`, which may have no members
+/// besides `importer_id` and no data. The archived member with the
+/// importer's username becomes the importer; everyone else gets a new
+/// account. The organization keeps its name and slug but takes the
+/// archive's other settings.
+///
+/// D1 batches are limited in size, so records are written in several
+/// batches; if one fails, the records written before it are deleted again.
+/// The organization is marked as restoring meanwhile, and stays marked if
+/// that deletion fails too.
+pub async fn restore(
+    db: &dyn Database,
+    organization_id: i64,
+    importer_id: i64,
+    archive: &OrganizationArchive,
+) -> Result<ArchiveCounts, ArchiveError> {
+    archive.validate()?;
+    let started = d1_execute(
+        db,
+        "INSERT INTO organization_restores (organization_id, importer_id)
+         SELECT ?1, ?2
+         WHERE NOT EXISTS (SELECT 1 FROM organization_restores WHERE organization_id = ?1)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(importer_id),
+        ],
+    )
+    .await?;
+    if started.changes == 0 {
+        return Err(ArchiveError::RestoreInProgress);
+    }
+
+    let result = async {
+        ensure_empty(db, organization_id, importer_id).await?;
+        let importer = memberships::load_member(db, organization_id, importer_id)
+            .await?
+            .ok_or(ModelError::MissingField("importer"))?;
+
+        let mut restore = Restore {
+            db,
+            organization_id,
+            inserted: Vec::new(),
+            users: HashMap::new(),
+            tasks: HashMap::new(),
+            time_logs: HashMap::new(),
+            reports: HashMap::new(),
+        };
+        if let Err(cause) = restore.run(archive, &importer).await {
+            if let Err(cleanup) = restore.discard().await {
+                return Err(ArchiveError::Incomplete { cause, cleanup });
+            }
+            return Err(cause.into());
+        }
+        Ok(archive.counts())
+    }
+    .await;
+
+    // Rows that could not be deleted keep the organization marked.
+    if !matches!(result, Err(ArchiveError::Incomplete { .. })) {
+        d1_execute(
+            db,
+            "DELETE FROM organization_restores WHERE organization_id = ?1",
+            &[D1Param::Integer(organization_id)],
+        )
+        .await?;
+    }
+    result
+}
+
+/// Deletes what an unfinished restore wrote into `organization_id` and lifts
+/// its mark. Everything but the importer's membership goes, which is all a
+/// restore could have written since it only starts on an empty organization;
+/// accounts that also belong to another organization keep their account and
+/// lose only this membership.
+pub async fn clear_partial_restore(
+    db: &dyn Database,
+    organization_id: i64,
+) -> Result<(), ArchiveError> {
+    let importer_id = d1_query_one::<IdRow>(
+        db,
+        "SELECT importer_id AS id FROM organization_restores WHERE organization_id = ?1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    .ok_or(ArchiveError::NoPartialRestore)?
+    .id;
+    let org = D1Param::Integer(organization_id);
+    let importer = D1Param::Integer(importer_id);
+
+    let mut statements = Vec::new();
+    for table in RESTORED_TABLES {
+        statements.push(match *table {
+            "users" => D1Statement::new(
+                "DELETE FROM users
+                 WHERE id != ?2
+                   AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)
+                   AND NOT EXISTS (
+                       SELECT 1 FROM memberships m
+                       WHERE m.user_id = users.id AND m.organization_id != ?1
+                   )",
+                vec![org.clone(), importer.clone()],
+            ),
+            table => D1Statement::new(
+                format!("DELETE FROM {table} WHERE organization_id = ?1"),
+                vec![org.clone()],
+            ),
+        });
+    }
+    statements.push(D1Statement::new(
+        "DELETE FROM memberships WHERE organization_id = ?1 AND user_id != ?2",
+        vec![org.clone(), importer],
+    ));
+    statements.push(D1Statement::new(
+        "DELETE FROM organization_restores WHERE organization_id = ?1",
+        vec![org],
+    ));
+    d1_batch(db, &statements).await?;
+    Ok(())
+}
+
+async fn ensure_empty(
+    db: &dyn Database,
+    organization_id: i64,
+    importer_id: i64,
+) -> Result<(), ArchiveError> {
+    let count = d1_query_one::<CountRow>(
+        db,
+        "SELECT (SELECT COUNT(*) FROM memberships WHERE organization_id = ?1 AND user_id != ?2)
+              + (SELECT COUNT(*) FROM roles WHERE organization_id = ?1)
+              + (SELECT COUNT(*) FROM tags WHERE organization_id = ?1)
+              + (SELECT COUNT(*) FROM tasks WHERE organization_id = ?1)
+              + (SELECT COUNT(*) FROM task_time_logs WHERE organization_id = ?1)
+              + (SELECT COUNT(*) FROM daily_reports WHERE organization_id = ?1)
+              + (SELECT COUNT(*) FROM display_groups WHERE organization_id = ?1) AS count",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(importer_id),
+        ],
+    )
+    .await?
+    .map_or(0, |row| row.count);
+    if count > 0 {
+        return Err(ArchiveError::NotEmpty);
+    }
+    Ok(())
+}
+
+/// Tables rows are restored into, in the order they are deleted again.
+const RESTORED_TABLES: &[&str] = &[
+    "activity_logs",
+    "notifications",
+    "display_groups",
+    "daily_reports",
+    "task_time_logs",
+    "tasks",
+    "tags",
+    "users",
+    "roles",
+];
+
+struct Restore<'a> {
+    db: &'a dyn Database,
+    organization_id: i64,
+    /// Every row inserted so far, by table.
+    inserted: Vec<(&'static str, i64)>,
+    /// Archive ids to new ids.
+    users: HashMap<i64, i64>,
+    tasks: HashMap<i64, i64>,
+    time_logs: HashMap<i64, i64>,
+    reports: HashMap<i64, i64>,
+}
+
+impl Restore<'_> {
+    async fn run(
+        &mut self,
+        archive: &OrganizationArchive,
+        importer: &User,
+    ) -> Result<(), ModelError> {
+        let org = D1Param::Integer(self.organization_id);
+
+        let mut statements = Vec::new();
+        for role in &archive.roles {
+            statements.push(D1Statement::new(
+                "INSERT INTO roles (organization_id, name, permissions) VALUES (?1, ?2, ?3)",
+                vec![
+                    org.clone(),
+                    D1Param::Text(role.name.clone()),
+                    D1Param::Text(serde_json::to_string(&role.permissions)?),
+                ],
+            ));
+        }
+        self.insert("roles", statements).await?;
+
+        // Members of the source organization may come from different home
+        // organizations and share a username, which must be unique here.
+        let mut usernames: HashSet<String> = importer.username.iter().cloned().collect();
+        let mut importer_mapped = false;
+        let mut new = Vec::new();
+        for user in &archive.users {
+            let username = user
+                .username
+                .clone()
+                .unwrap_or_else(|| format!("user-{}", user.id));
+            if user.username.is_some() && user.username == importer.username && !importer_mapped {
+                self.users.insert(user.id, importer.id);
+                importer_mapped = true;
+            } else if usernames.insert(username.clone()) {
+                new.push((user, username));
+            } else {
+                let username = format!("{username}-{}", user.id);
+                usernames.insert(username.clone());
+                new.push((user, username));
+            }
+        }
+        // One hash for all of them: nobody knows the secret behind it.
+        let password_hash = unusable_password_hash()?;
+        let mut statements = Vec::new();
+        for (user, username) in &new {
+            statements.push(D1Statement::new(
+                "INSERT INTO users (organization_id, name, username, email, email_verified,
+                                    password_hash, avatar_url, locale, timezone, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, COALESCE(?10, CURRENT_TIMESTAMP))",
+                vec![
+                    org.clone(),
+                    D1Param::Text(user.name.clone()),
+                    D1Param::Text(username.clone()),
+                    text(&user.email),
+                    D1Param::Integer(user.email_verified),
+                    D1Param::Text(password_hash.clone()),
+                    // Uploaded avatars stay in the source organization's bucket.
+                    text(
+                        &user
+                            .avatar_url
+                            .clone()
+                            .filter(|url| !url.contains("/api/avatars/")),
+                    ),
+                    text(&user.locale),
+                    text(&user.timezone),
+                    text(&user.created_at),
+                ],
+            ));
+        }
+        let ids = self.insert("users", statements).await?;
+        for ((user, _), id) in new.iter().zip(ids) {
+            self.users.insert(user.id, id);
+        }
+        // Managers are mapped once every member has its new id.
+        let mut statements = Vec::new();
+        for (user, _) in &new {
+            let manager_id = user
+                .manager_id
+                .and_then(|manager_id| self.users.get(&manager_id).copied());
+            statements.push(D1Statement::new(
+                "INSERT INTO memberships (user_id, organization_id, role, deactivated_at, manager_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                vec![
+                    D1Param::Integer(self.users[&user.id]),
+                    org.clone(),
+                    D1Param::Text(user.role.clone()),
+                    text(&user.deactivated_at),
+                    manager_id.map_or(D1Param::Null, D1Param::Integer),
+                ],
+            ));
+        }
+        self.execute(statements).await?;
+
+        let mut statements = Vec::new();
+        for tag in &archive.tags {
+            statements.push(D1Statement::new(
+                "INSERT INTO tags (organization_id, name, created_at) VALUES (?1, ?2, ?3)",
+                vec![
+                    org.clone(),
+                    D1Param::Text(tag.name.clone()),
+                    D1Param::Text(tag.created_at.clone()),
+                ],
+            ));
+        }
+        self.insert("tags", statements).await?;
+
+        let mut statements = Vec::new();
+        for task in &archive.tasks {
+            statements.push(D1Statement::new(
+                "INSERT INTO tasks (organization_id, member_id, title, description, status,
+                                    progress_rate, created_at, updated_at, due_date, estimated_minutes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, COALESCE(?8, ?7), ?9, ?10)",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&task.member_id]),
+                    D1Param::Text(task.title.clone()),
+                    text(&task.description),
+                    D1Param::Text(task.status.clone()),
+                    D1Param::Integer(task.progress_rate),
+                    D1Param::Text(task.created_at.clone()),
+                    text(&task.updated_at),
+                    text(&task.due_date),
+                    task.estimated_minutes
+                        .map(D1Param::Integer)
+                        .unwrap_or(D1Param::Null),
+                ],
+            ));
+        }
+        let ids = self.insert("tasks", statements).await?;
+        let mut statements = Vec::new();
+        for (task, id) in archive.tasks.iter().zip(ids) {
+            self.tasks.insert(task.id, id);
+            for tag in task.tags.iter().flatten() {
+                statements.push(D1Statement::new(
+                    "INSERT INTO task_tags (task_id, tag_id)
+                     SELECT ?1, id FROM tags WHERE organization_id = ?2 AND name = ?3",
+                    vec![
+                        D1Param::Integer(id),
+                        org.clone(),
+                        D1Param::Text(tag.clone()),
+                    ],
+                ));
+            }
+        }
+        self.execute(statements).await?;
+
+        let mut statements = Vec::new();
+        for log in &archive.time_logs {
+            // `duration_minutes` is generated from the two timestamps.
+            statements.push(D1Statement::new(
+                "INSERT INTO task_time_logs (organization_id, user_id, task_id, start_at, end_at, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, COALESCE(?6, CURRENT_TIMESTAMP))",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&log.user_id]),
+                    D1Param::Integer(self.tasks[&log.task_id]),
+                    D1Param::Text(log.start_at.clone()),
+                    D1Param::Text(log.end_at.clone()),
+                    text(&log.created_at),
+                ],
+            ));
+        }
+        let ids = self.insert("task_time_logs", statements).await?;
+        self.time_logs = archive.time_logs.iter().map(|l| l.id).zip(ids).collect();
+
+        let mut statements = Vec::new();
+        for report in &archive.daily_reports {
+            statements.push(D1Statement::new(
+                "INSERT INTO daily_reports (organization_id, user_id, report_date, content, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&report.user_id]),
+                    D1Param::Text(report.report_date.clone()),
+                    D1Param::Text(report.content.clone()),
+                    D1Param::Text(report.created_at.clone()),
+                ],
+            ));
+        }
+        let ids = self.insert("daily_reports", statements).await?;
+        self.reports = archive
+            .daily_reports
+            .iter()
+            .map(|r| r.id)
+            .zip(ids)
+            .collect();
+
+        let mut statements = Vec::new();
+        for group in &archive.display_groups {
+            statements.push(D1Statement::new(
+                "INSERT INTO display_groups (organization_id, user_id, name, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&group.user_id]),
+                    D1Param::Text(group.name.clone()),
+                    D1Param::Text(group.created_at.clone()),
+                ],
+            ));
+        }
+        let ids = self.insert("display_groups", statements).await?;
+        let mut statements = Vec::new();
+        for (group, id) in archive.display_groups.iter().zip(ids) {
+            for member_id in &group.member_ids {
+                statements.push(D1Statement::new(
+                    "INSERT OR IGNORE INTO display_group_members (group_id, member_id) VALUES (?1, ?2)",
+                    vec![
+                        D1Param::Integer(id),
+                        D1Param::Integer(self.users[member_id]),
+                    ],
+                ));
+            }
+        }
+        self.execute(statements).await?;
+
+        let mut statements = Vec::new();
+        for notification in &archive.notifications {
+            statements.push(D1Statement::new(
+                "INSERT INTO notifications (organization_id, user_id, title, body, category,
+                                            target_type, target_id, is_read, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&notification.user_id]),
+                    D1Param::Text(notification.title.clone()),
+                    text(&notification.body),
+                    D1Param::Text(notification.category.clone()),
+                    text(&notification.target_type),
+                    self.target(notification.target_type.as_deref(), notification.target_id),
+                    D1Param::Integer(notification.is_read),
+                    D1Param::Text(notification.created_at.clone()),
+                ],
+            ));
+        }
+        self.insert("notifications", statements).await?;
+
+        let mut statements = Vec::new();
+        for log in &archive.activity_logs {
+            statements.push(D1Statement::new(
+                "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id,
+                                            details, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                vec![
+                    org.clone(),
+                    D1Param::Integer(self.users[&log.user_id]),
+                    D1Param::Text(log.action.clone()),
+                    D1Param::Text(log.target_type.clone()),
+                    self.target(Some(&log.target_type), log.target_id),
+                    text(&log.details),
+                    D1Param::Text(log.created_at.clone()),
+                ],
+            ));
+        }
+        self.insert("activity_logs", statements).await?;
+
+        let settings = &archive.header.organization;
+        let policy = &settings.password_policy;
+        d1_execute(
+            self.db,
+            "UPDATE organizations
+             SET timezone = ?2, week_start_day = ?3, default_task_status = ?4, report_statuses = ?5,
+                 invitation_expiration_days = ?6, password_min_length = ?7,
+                 password_require_uppercase = ?8, password_require_lowercase = ?9,
+                 password_require_digit = ?10, password_require_symbol = ?11,
+                 closed_task_statuses = ?12
+             WHERE id = ?1",
+            &[
+                org,
+                D1Param::Text(settings.timezone.clone()),
+                D1Param::Text(settings.week_start_day.clone()),
+                D1Param::Text(settings.default_task_status.clone()),
+                D1Param::Text(serde_json::to_string(&settings.report_statuses)?),
+                D1Param::Integer(settings.invitation_expiration_days),
+                D1Param::Integer(policy.min_length),
+                D1Param::Integer(policy.require_uppercase),
+                D1Param::Integer(policy.require_lowercase),
+                D1Param::Integer(policy.require_digit),
+                D1Param::Integer(policy.require_symbol),
+                D1Param::Text(serde_json::to_string(&settings.closed_task_statuses)?),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The new id of a notification's or activity log's target; targets that
+    /// were not archived, such as API tokens, are dropped.
+    fn target(&self, target_type: Option<&str>, target_id: Option<i64>) -> D1Param {
+        let ids = match target_type {
+            Some("user") => &self.users,
+            Some("task") => &self.tasks,
+            Some("task_time_log") => &self.time_logs,
+            Some("report") => &self.reports,
+            _ => return D1Param::Null,
+        };
+        target_id
+            .and_then(|id| ids.get(&id).copied())
+            .map(D1Param::Integer)
+            .unwrap_or(D1Param::Null)
+    }
+
+    /// Runs `statements`, each inserting one row into `table`, and returns
+    /// the new ids in order.
+    async fn insert(
+        &mut self,
+        table: &'static str,
+        statements: Vec<D1Statement>,
+    ) -> Result<Vec<i64>, ModelError> {
+        let mut ids = Vec::with_capacity(statements.len());
+        for chunk in statements.chunks(BATCH_SIZE) {
+            for result in d1_batch(self.db, chunk).await? {
+                let id = result.inserted_id()?;
+                self.inserted.push((table, id));
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Runs statements whose rows are deleted along with the ones they
+    /// link, such as memberships and task tags.
+    async fn execute(&self, statements: Vec<D1Statement>) -> Result<(), ModelError> {
+        for chunk in statements.chunks(BATCH_SIZE) {
+            d1_batch(self.db, chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn discard(&self) -> Result<(), ModelError> {
+        let mut statements = Vec::new();
+        for table in RESTORED_TABLES {
+            let ids: Vec<i64> = self
+                .inserted
+                .iter()
+                .filter(|(inserted_table, _)| inserted_table == table)
+                .map(|(_, id)| *id)
+                .collect();
+            if !ids.is_empty() {
+                statements.push(D1Statement::new(
+                    format!("DELETE FROM {table} WHERE id IN (SELECT value FROM json_each(?1))"),
+                    vec![D1Param::Text(serde_json::to_string(&ids)?)],
+                ));
+            }
+        }
+        if statements.is_empty() {
+            return Ok(());
+        }
+        d1_batch(self.db, &statements).await.map(|_| ())
+    }
+}
+
+fn text(value: &Option<String>) -> D1Param {
+    value.clone().map(D1Param::Text).unwrap_or(D1Param::Null)
+}
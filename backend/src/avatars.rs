@@ -0,0 +1,423 @@
+//! Uploaded avatar images.
+//!
+//! Images are stored in the `AVATARS` R2 bucket under
+//! `orgs/<organization>/avatars/<user>/<id>.<ext>` and served back by the
+//! worker at `/api/avatars/<organization>/<user>/<id>.<ext>`. Every upload
+//! gets a fresh id, so the served URL never changes content and can be cached
+//! indefinitely; the previous object is removed when an avatar is replaced or
+//! cleared. `wrangler dev` emulates the bucket locally.
+
+use crate::db::Database;
+use crate::models::{D1Param, FromD1Row, ModelError, d1_execute, d1_query_all, d1_query_one};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use worker::{Bucket, HttpMetadata};
+
+pub const MAX_AVATAR_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+}
+
+impl ImageFormat {
+    /// Identifies the image by its signature rather than a client-supplied
+    /// content type.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(Self::WebP)
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::WebP => "webp",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredObject {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+/// Blob storage for avatar images.
+#[async_trait(?Send)]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ModelError>;
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ModelError>;
+
+    /// Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<(), ModelError>;
+}
+
+#[async_trait(?Send)]
+impl<T: ObjectStore + ?Sized> ObjectStore for Arc<T> {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ModelError> {
+        (**self).put(key, bytes, content_type).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ModelError> {
+        (**self).get(key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ModelError> {
+        (**self).delete(key).await
+    }
+}
+
+/// [`ObjectStore`] backed by an R2 bucket binding.
+pub struct R2ObjectStore {
+    bucket: Bucket,
+}
+
+impl R2ObjectStore {
+    pub fn new(bucket: Bucket) -> Self {
+        Self { bucket }
+    }
+}
+
+// Workers run each isolate on a single thread, as with `D1Database`.
+unsafe impl Send for R2ObjectStore {}
+unsafe impl Sync for R2ObjectStore {}
+
+#[async_trait(?Send)]
+impl ObjectStore for R2ObjectStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ModelError> {
+        self.bucket
+            .put(key, bytes)
+            .http_metadata(HttpMetadata {
+                content_type: Some(content_type.to_string()),
+                ..HttpMetadata::default()
+            })
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ModelError> {
+        let Some(object) = self.bucket.get(key).execute().await? else {
+            return Ok(None);
+        };
+        let content_type = object.http_metadata().content_type;
+        let Some(body) = object.body() else {
+            return Ok(None);
+        };
+        Ok(Some(StoredObject {
+            bytes: body.bytes().await?,
+            content_type,
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ModelError> {
+        Ok(self.bucket.delete(key).await?)
+    }
+}
+
+/// In-process [`ObjectStore`] for native tests.
+#[derive(Default)]
+pub struct MemoryObjectStore {
+    objects: Mutex<HashMap<String, StoredObject>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> Result<MutexGuard<'_, HashMap<String, StoredObject>>, ModelError> {
+        self.objects
+            .lock()
+            .map_err(|_| ModelError::Database("object store mutex poisoned".to_string()))
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .objects()
+            .map(|objects| objects.keys().cloned().collect())
+            .unwrap_or_default();
+        keys.sort();
+        keys
+    }
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for MemoryObjectStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ModelError> {
+        self.objects()?.insert(
+            key.to_string(),
+            StoredObject {
+                bytes,
+                content_type: Some(content_type.to_string()),
+            },
+        );
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ModelError> {
+        Ok(self.objects()?.get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ModelError> {
+        self.objects()?.remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum AvatarError {
+    TooLarge,
+    UnsupportedType,
+    Storage(ModelError),
+}
+
+impl AvatarError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::TooLarge => 413,
+            Self::UnsupportedType => 415,
+            Self::Storage(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge => write!(
+                f,
+                "Avatar images must be at most {} MB",
+                MAX_AVATAR_BYTES / (1024 * 1024)
+            ),
+            Self::UnsupportedType => write!(f, "Avatar images must be PNG, JPEG or WebP"),
+            Self::Storage(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ModelError> for AvatarError {
+    fn from(value: ModelError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+fn object_key(organization_id: i64, user_id: i64, file_name: &str) -> String {
+    format!("orgs/{organization_id}/avatars/{user_id}/{file_name}")
+}
+
+/// Upload file names: a v4 UUID and one of the image extensions.
+fn is_valid_file_name(file_name: &str) -> bool {
+    let Some((id, extension)) = file_name.split_once('.') else {
+        return false;
+    };
+    uuid::Uuid::parse_str(id).is_ok()
+        && id.len() == 36
+        && ["png", "jpg", "webp"].contains(&extension)
+}
+
+#[derive(FromD1Row)]
+struct AvatarKeyRow {
+    avatar_key: Option<String>,
+}
+
+/// Key of the member's uploaded avatar, if the current avatar is an upload.
+pub async fn uploaded_key(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Option<String>, ModelError> {
+    Ok(d1_query_one::<AvatarKeyRow>(
+        db,
+        "SELECT avatar_key FROM users
+         WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)
+         LIMIT 1",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    .and_then(|row| row.avatar_key))
+}
+
+/// Key of the uploaded avatar that setting `avatar_url` to `new_url` would
+/// replace; saving the upload's own URL again replaces nothing.
+pub async fn superseded_key(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+    new_url: &D1Param,
+) -> Result<Option<String>, ModelError> {
+    Ok(d1_query_one::<AvatarKeyRow>(
+        db,
+        "SELECT avatar_key FROM users
+         WHERE id = ?2 AND avatar_url IS NOT ?3
+           AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)
+         LIMIT 1",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            new_url.clone(),
+        ],
+    )
+    .await?
+    .and_then(|row| row.avatar_key))
+}
+
+/// Stores `bytes` as the member's avatar, points `avatar_url` at it and
+/// removes the previous upload. Returns the new URL; `base_url` is the
+/// worker's origin.
+pub async fn replace(
+    db: &dyn Database,
+    store: &dyn ObjectStore,
+    organization_id: i64,
+    user_id: i64,
+    bytes: Vec<u8>,
+    base_url: &str,
+) -> Result<String, AvatarError> {
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(AvatarError::TooLarge);
+    }
+    let format = ImageFormat::detect(&bytes).ok_or(AvatarError::UnsupportedType)?;
+
+    let file_name = format!("{}.{}", uuid::Uuid::new_v4(), format.extension());
+    let key = object_key(organization_id, user_id, &file_name);
+    let url = format!("{base_url}/api/avatars/{organization_id}/{user_id}/{file_name}");
+    let previous = uploaded_key(db, organization_id, user_id).await?;
+
+    store.put(&key, bytes, format.content_type()).await?;
+    let updated = d1_execute(
+        db,
+        "UPDATE users SET avatar_url = ?3, avatar_key = ?4
+         WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(url.clone()),
+            D1Param::Text(key.clone()),
+        ],
+    )
+    .await?;
+    if updated.changes == 0 {
+        store.delete(&key).await?;
+        return Err(ModelError::Database("user not found".to_string()).into());
+    }
+
+    if let Some(previous) = previous {
+        store.delete(&previous).await?;
+    }
+    Ok(url)
+}
+
+/// Clears the member's avatar and removes the uploaded image, if any.
+/// Returns whether there was an avatar to clear.
+pub async fn remove(
+    db: &dyn Database,
+    store: &dyn ObjectStore,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<bool, AvatarError> {
+    let previous = uploaded_key(db, organization_id, user_id).await?;
+    let cleared = d1_execute(
+        db,
+        "UPDATE users SET avatar_url = NULL, avatar_key = NULL
+         WHERE id = ?2 AND avatar_url IS NOT NULL
+           AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    .changes
+        > 0;
+    if let Some(previous) = previous {
+        store.delete(&previous).await?;
+    }
+    Ok(cleared)
+}
+
+/// Loads an uploaded avatar for serving; unknown or malformed names yield
+/// `None`.
+pub async fn load(
+    store: &dyn ObjectStore,
+    organization_id: i64,
+    user_id: i64,
+    file_name: &str,
+) -> Result<Option<StoredObject>, ModelError> {
+    if !is_valid_file_name(file_name) {
+        return Ok(None);
+    }
+    store
+        .get(&object_key(organization_id, user_id, file_name))
+        .await
+}
+
+/// Keys of the uploads belonging to accounts whose deletion is due, collected
+/// before the rows are purged.
+pub async fn keys_of_due_deletions(db: &dyn Database) -> Result<Vec<String>, ModelError> {
+    Ok(d1_query_all::<AvatarKeyRow>(
+        db,
+        "SELECT avatar_key FROM users
+         WHERE avatar_key IS NOT NULL
+           AND deletion_scheduled_at IS NOT NULL
+           AND datetime(deletion_scheduled_at) <= datetime('now')",
+        &[],
+    )
+    .await?
+    .into_iter()
+    .filter_map(|row| row.avatar_key)
+    .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageFormat, is_valid_file_name};
+
+    #[test]
+    fn detects_images_by_signature() {
+        assert_eq!(
+            ImageFormat::detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::detect(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::detect(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(ImageFormat::WebP)
+        );
+        assert_eq!(ImageFormat::detect(b"GIF89a"), None);
+        assert_eq!(ImageFormat::detect(b"<svg xmlns="), None);
+        assert_eq!(ImageFormat::detect(b"RIFF\0\0\0\0WAVE"), None);
+    }
+
+    #[test]
+    fn accepts_only_generated_file_names() {
+        assert!(is_valid_file_name(
+            "6f1c2a9e-3b7d-4c55-9a0e-2d4b8f1e7c30.png"
+        ));
+        assert!(!is_valid_file_name(
+            "6f1c2a9e-3b7d-4c55-9a0e-2d4b8f1e7c30.svg"
+        ));
+        assert!(!is_valid_file_name("../../secrets.png"));
+        assert!(!is_valid_file_name("6f1c2a9e3b7d4c559a0e2d4b8f1e7c30.png"));
+    }
+}
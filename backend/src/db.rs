@@ -0,0 +1,236 @@
+use crate::models::{D1ExecResult, D1Param, D1Row, D1Statement, ModelError};
+use async_trait::async_trait;
+use serde_json::Value;
+use worker::{D1Database, D1PreparedStatement, D1Result, wasm_bindgen::JsValue};
+
+/// Storage backend used by the model layer.
+///
+/// Production runs against Cloudflare D1; native builds can use
+/// [`SqliteDatabase`] so the same SQL runs in-process under `cargo test`.
+#[async_trait(?Send)]
+pub trait Database: Send + Sync {
+    async fn query_all(&self, sql: &str, params: &[D1Param]) -> Result<Vec<D1Row>, ModelError>;
+
+    async fn execute(&self, sql: &str, params: &[D1Param]) -> Result<D1ExecResult, ModelError>;
+
+    /// Executes all statements atomically: either every statement commits or none do.
+    async fn batch(&self, statements: &[D1Statement]) -> Result<Vec<D1ExecResult>, ModelError>;
+}
+
+#[async_trait(?Send)]
+impl<T: Database + ?Sized> Database for std::sync::Arc<T> {
+    async fn query_all(&self, sql: &str, params: &[D1Param]) -> Result<Vec<D1Row>, ModelError> {
+        (**self).query_all(sql, params).await
+    }
+
+    async fn execute(&self, sql: &str, params: &[D1Param]) -> Result<D1ExecResult, ModelError> {
+        (**self).execute(sql, params).await
+    }
+
+    async fn batch(&self, statements: &[D1Statement]) -> Result<Vec<D1ExecResult>, ModelError> {
+        (**self).batch(statements).await
+    }
+}
+
+fn as_js_value(param: &D1Param) -> JsValue {
+    match param {
+        D1Param::Null => JsValue::NULL,
+        D1Param::Integer(v) => JsValue::from_f64(*v as f64),
+        D1Param::Real(v) => JsValue::from_f64(*v),
+        D1Param::Text(v) => JsValue::from_str(v),
+    }
+}
+
+fn d1_prepare(
+    db: &D1Database,
+    sql: &str,
+    params: &[D1Param],
+) -> Result<D1PreparedStatement, ModelError> {
+    let mut stmt: D1PreparedStatement = db.prepare(sql);
+    if !params.is_empty() {
+        let js_params: Vec<JsValue> = params.iter().map(as_js_value).collect();
+        stmt = stmt.bind(&js_params)?;
+    }
+    Ok(stmt)
+}
+
+fn d1_exec_result(result: &D1Result) -> Result<D1ExecResult, ModelError> {
+    let meta = result.meta()?;
+    Ok(D1ExecResult {
+        changes: meta.as_ref().and_then(|m| m.changes).unwrap_or(0) as u64,
+        last_row_id: meta.and_then(|m| m.last_row_id).filter(|id| *id > 0),
+    })
+}
+
+#[async_trait(?Send)]
+impl Database for D1Database {
+    async fn query_all(&self, sql: &str, params: &[D1Param]) -> Result<Vec<D1Row>, ModelError> {
+        let stmt = d1_prepare(self, sql, params)?;
+
+        let raw: D1Result = stmt.all().await?;
+        let rows: Vec<Value> = raw.results::<Value>()?;
+        rows.into_iter()
+            .map(|value| match value {
+                Value::Object(map) => Ok(map),
+                _ => Err(ModelError::InvalidType {
+                    field: "row",
+                    expected: "object",
+                }),
+            })
+            .collect()
+    }
+
+    async fn execute(&self, sql: &str, params: &[D1Param]) -> Result<D1ExecResult, ModelError> {
+        let stmt = d1_prepare(self, sql, params)?;
+
+        let raw: D1Result = stmt.run().await?;
+        d1_exec_result(&raw)
+    }
+
+    /// D1 runs a batch inside a single implicit transaction: statements execute
+    /// in order, and if any of them fails the whole batch is rolled back.
+    async fn batch(&self, statements: &[D1Statement]) -> Result<Vec<D1ExecResult>, ModelError> {
+        if statements.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let prepared = statements
+            .iter()
+            .map(|stmt| d1_prepare(self, &stmt.sql, &stmt.params))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        D1Database::batch(self, prepared)
+            .await?
+            .iter()
+            .map(d1_exec_result)
+            .collect()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use sqlite::SqliteDatabase;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod sqlite {
+    use super::Database;
+    use crate::models::{D1ExecResult, D1Param, D1Row, D1Statement, ModelError};
+    use async_trait::async_trait;
+    use rusqlite::types::{Value as SqlValue, ValueRef};
+    use rusqlite::{Connection, params_from_iter};
+    use serde_json::{Number, Value};
+    use std::path::Path;
+    use std::sync::{Mutex, MutexGuard};
+
+    const D1_SCHEMA: &str = include_str!("../../d1_schema.sql");
+
+    /// In-process SQLite backend that speaks the same SQL dialect as D1.
+    pub struct SqliteDatabase {
+        conn: Mutex<Connection>,
+    }
+
+    impl SqliteDatabase {
+        /// Opens a fresh in-memory database with `d1_schema.sql` applied.
+        pub fn open_in_memory() -> Result<Self, ModelError> {
+            Self::from_connection(Connection::open_in_memory()?)
+        }
+
+        /// Opens an in-memory database with no tables, e.g. to replay migrations.
+        pub fn open_in_memory_empty() -> Result<Self, ModelError> {
+            Ok(Self {
+                conn: Mutex::new(Connection::open_in_memory()?),
+            })
+        }
+
+        /// Opens (or creates) a database file and applies `d1_schema.sql`,
+        /// dropping any existing tables.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, ModelError> {
+            Self::from_connection(Connection::open(path)?)
+        }
+
+        fn from_connection(conn: Connection) -> Result<Self, ModelError> {
+            conn.execute_batch(D1_SCHEMA)?;
+            Ok(Self {
+                conn: Mutex::new(conn),
+            })
+        }
+
+        fn lock(&self) -> Result<MutexGuard<'_, Connection>, ModelError> {
+            self.conn
+                .lock()
+                .map_err(|_| ModelError::Database("sqlite connection mutex poisoned".to_string()))
+        }
+    }
+
+    fn to_sql_value(param: &D1Param) -> SqlValue {
+        match param {
+            D1Param::Null => SqlValue::Null,
+            D1Param::Integer(v) => SqlValue::Integer(*v),
+            D1Param::Real(v) => SqlValue::Real(*v),
+            D1Param::Text(v) => SqlValue::Text(v.clone()),
+        }
+    }
+
+    fn to_json_value(value: ValueRef<'_>) -> Value {
+        match value {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(v) => Value::Number(v.into()),
+            ValueRef::Real(v) => Number::from_f64(v)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            ValueRef::Text(v) => Value::String(String::from_utf8_lossy(v).into_owned()),
+            // D1 returns BLOB columns as arrays of bytes.
+            ValueRef::Blob(v) => Value::Array(v.iter().map(|b| Value::from(*b)).collect()),
+        }
+    }
+
+    fn execute_on(
+        conn: &Connection,
+        sql: &str,
+        params: &[D1Param],
+    ) -> Result<D1ExecResult, ModelError> {
+        let mut stmt = conn.prepare(sql)?;
+        let changes = stmt.execute(params_from_iter(params.iter().map(to_sql_value)))?;
+        let last_row_id = conn.last_insert_rowid();
+
+        Ok(D1ExecResult {
+            changes: changes as u64,
+            last_row_id: (last_row_id > 0).then_some(last_row_id),
+        })
+    }
+
+    #[async_trait(?Send)]
+    impl Database for SqliteDatabase {
+        async fn query_all(&self, sql: &str, params: &[D1Param]) -> Result<Vec<D1Row>, ModelError> {
+            let conn = self.lock()?;
+            let mut stmt = conn.prepare(sql)?;
+            let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+            let mut rows = stmt.query(params_from_iter(params.iter().map(to_sql_value)))?;
+            let mut result = Vec::new();
+            while let Some(row) = rows.next()? {
+                let mut map = D1Row::new();
+                for (index, column) in columns.iter().enumerate() {
+                    map.insert(column.clone(), to_json_value(row.get_ref(index)?));
+                }
+                result.push(map);
+            }
+            Ok(result)
+        }
+
+        async fn execute(&self, sql: &str, params: &[D1Param]) -> Result<D1ExecResult, ModelError> {
+            let conn = self.lock()?;
+            execute_on(&conn, sql, params)
+        }
+
+        async fn batch(&self, statements: &[D1Statement]) -> Result<Vec<D1ExecResult>, ModelError> {
+            let mut conn = self.lock()?;
+            let tx = conn.transaction()?;
+            let results = statements
+                .iter()
+                .map(|stmt| execute_on(&tx, &stmt.sql, &stmt.params))
+                .collect::<Result<Vec<_>, _>>()?;
+            tx.commit()?;
+            Ok(results)
+        }
+    }
+}
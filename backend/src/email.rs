@@ -15,6 +15,22 @@ pub trait EmailService: Send + Sync {
         group_name: &str,
     ) -> Result<(), String>;
     async fn send_verification_email(&self, to: &str, token: &str) -> Result<(), String>;
+    /// Proves that someone joining with an invitation bound to `to` reads mail
+    /// there; `email_token` is sent back with the join form.
+    async fn send_join_verification_email(
+        &self,
+        to: &str,
+        invitation_token: &str,
+        email_token: &str,
+    ) -> Result<(), String>;
+    /// Tells a provisioned member their account exists; `token` is a
+    /// set-password token accepted by the password reset flow.
+    async fn send_welcome_email(
+        &self,
+        to: &str,
+        token: &str,
+        organization_name: &str,
+    ) -> Result<(), String>;
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +54,13 @@ impl StdoutEmailProvider {
     fn verification_link(&self, token: &str) -> String {
         format!("{}/verify-email?token={token}", self.frontend_url)
     }
+
+    fn join_verification_link(&self, invitation_token: &str, email_token: &str) -> String {
+        format!(
+            "{}/join?token={invitation_token}&email_token={email_token}",
+            self.frontend_url
+        )
+    }
 }
 
 #[async_trait(?Send)]
@@ -70,6 +93,32 @@ impl EmailService for StdoutEmailProvider {
         );
         Ok(())
     }
+
+    async fn send_join_verification_email(
+        &self,
+        to: &str,
+        invitation_token: &str,
+        email_token: &str,
+    ) -> Result<(), String> {
+        println!(
+            "【参加確認メール送信】宛先: {to}, 確認コード: {email_token}, リンク: {}",
+            self.join_verification_link(invitation_token, email_token)
+        );
+        Ok(())
+    }
+
+    async fn send_welcome_email(
+        &self,
+        to: &str,
+        token: &str,
+        organization_name: &str,
+    ) -> Result<(), String> {
+        println!(
+            "【アカウント作成メール送信】宛先: {to}, 組織: {organization_name}, リンク: {}",
+            self.reset_link(token)
+        );
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -102,6 +151,13 @@ impl ResendEmailProvider {
         format!("{}/verify-email?token={token}", self.frontend_url)
     }
 
+    fn join_verification_link(&self, invitation_token: &str, email_token: &str) -> String {
+        format!(
+            "{}/join?token={invitation_token}&email_token={email_token}",
+            self.frontend_url
+        )
+    }
+
     #[cfg(target_arch = "wasm32")]
     async fn send_email(&self, to: &str, subject: &str, text: &str) -> Result<(), String> {
         let body = serde_json::json!({
@@ -186,4 +242,38 @@ impl EmailService for ResendEmailProvider {
         self.send_email(to, "メールアドレスの認証をお願いします", &text)
             .await
     }
+
+    async fn send_join_verification_email(
+        &self,
+        to: &str,
+        invitation_token: &str,
+        email_token: &str,
+    ) -> Result<(), String> {
+        let text = format!(
+            "招待を受けて参加するには、以下のリンクを開くか、参加フォームに確認コードを入力してください（{}時間有効）:\n\n{}\n\n確認コード: {}",
+            crate::auth::JOIN_VERIFICATION_EXPIRATION_HOURS,
+            self.join_verification_link(invitation_token, email_token),
+            email_token
+        );
+
+        self.send_email(to, "招待への参加を確認してください", &text)
+            .await
+    }
+
+    async fn send_welcome_email(
+        &self,
+        to: &str,
+        token: &str,
+        organization_name: &str,
+    ) -> Result<(), String> {
+        let text = format!(
+            "{} にあなたのアカウントが作成されました。\n\n以下のリンクからパスワードを設定してください（{}日間有効）:\n\n{}",
+            organization_name,
+            crate::provisioning::WELCOME_LINK_EXPIRATION_DAYS,
+            self.reset_link(token)
+        );
+
+        self.send_email(to, "アカウントが作成されました", &text)
+            .await
+    }
 }
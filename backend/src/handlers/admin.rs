@@ -0,0 +1,363 @@
+use crate::AppState;
+use crate::api_tokens;
+use crate::archive::{self, ArchiveError, OrganizationArchive};
+use crate::migrations;
+use crate::models::{Claims, D1Param, ModelError, RoleRow, d1_execute, d1_query_one};
+use crate::permissions::{self, Permission};
+use crate::routing::{Request, Response, RouteContext};
+use crate::utils::hash_token;
+use chrono::Utc;
+use futures::TryStreamExt;
+use serde::Serialize;
+use worker::Result as WorkerResult;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+
+    fn into_response(self) -> WorkerResult<Response> {
+        Response::from_json(&ErrorBody {
+            error: self.message,
+        })
+        .map(|response| response.with_status(self.status))
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(value: ModelError) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<worker::Error> for ApiError {
+    fn from(value: worker::Error) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<ArchiveError> for ApiError {
+    fn from(value: ArchiveError) -> Self {
+        Self::new(value.status(), value.to_string())
+    }
+}
+
+fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
+    Response::from_json(value)
+        .map(|response| response.with_status(status))
+        .map_err(ApiError::from)
+}
+
+fn db_error_to_response(err: ApiError) -> WorkerResult<Response> {
+    err.into_response()
+}
+
+fn extract_bearer_token(req: &Request) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.to_string()));
+
+    if header_token.is_some() {
+        return header_token;
+    }
+
+    req.url().ok().and_then(|url| {
+        url.query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(k, v)| (k == "token" && !v.is_empty()).then_some(v.to_string()))
+        })
+    })
+}
+
+async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<Claims, ApiError> {
+    let token = extract_bearer_token(req)
+        .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
+
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
+
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
+    let latest_role = d1_query_one::<RoleRow>(
+        &ctx.data.db,
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+    claims.role = latest_role.role;
+    Ok(claims)
+}
+
+async fn require_permission(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+    permission: Permission,
+) -> Result<Claims, ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    permissions::authorize(&ctx.data.db, &claims, permission)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok(claims)
+}
+
+async fn log_activity_d1(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    action: &str,
+    target_type: &str,
+    target_id: Option<i64>,
+    details: Option<String>,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Text(target_type.to_string()),
+            target_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
+            details.map(D1Param::Text).unwrap_or(D1Param::Null),
+        ],
+    )
+    .await;
+}
+
+#[derive(Serialize)]
+struct ApplyMigrationsResponse {
+    applied: Vec<migrations::PendingMigration>,
+    status: migrations::SchemaStatus,
+}
+
+/// Migrations are global DDL, so no organization role is enough: the caller
+/// must present `OPERATOR_TOKEN` as its bearer token. Only the header is
+/// read, never `?token=`, to keep the secret out of logged URLs.
+fn require_operator(req: &Request, ctx: &RouteContext<AppState>) -> Result<(), ApiError> {
+    let Some(expected) = ctx.data.operator_token_hash.as_deref() else {
+        return Err(ApiError::new(
+            403,
+            "Migrations are disabled until OPERATOR_TOKEN is configured",
+        ));
+    };
+    let provided = req
+        .headers()
+        .get("Authorization")?
+        .and_then(|value| value.strip_prefix("Bearer ").map(str::to_string))
+        .ok_or_else(|| ApiError::new(401, "Missing operator token"))?;
+    // Comparing digests keeps the timing independent of the secret itself.
+    if hash_token(&provided) != expected {
+        return Err(ApiError::new(403, "Operator token required"));
+    }
+    Ok(())
+}
+
+pub async fn get_migrations(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        require_operator(&req, &ctx)?;
+
+        let status = migrations::status(&ctx.data.db).await?;
+        json_with_status(&status, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn apply_migrations(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        require_operator(&req, &ctx)?;
+
+        let applied = migrations::apply_pending(&ctx.data.db).await?;
+        let status = migrations::status(&ctx.data.db).await?;
+        json_with_status(&ApplyMigrationsResponse { applied, status }, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// `GET /api/admin/archive`: the whole organization as NDJSON, streamed page
+/// by page, or as one JSON document with `?format=json`. A download cut short
+/// by an error lacks the `end` line and is rejected on import.
+pub async fn export_archive(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let format = req
+            .url()?
+            .query_pairs()
+            .find(|(key, _)| key == "format")
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_else(|| "ndjson".to_string());
+        let file_name = format!(
+            "organization-{}-{}",
+            claims.organization_id,
+            Utc::now().format("%Y%m%d")
+        );
+
+        let mut response = match format.as_str() {
+            "ndjson" => {
+                let stream = archive::ndjson(ctx.data.db.clone(), claims.organization_id)
+                    .map_err(|e| worker::Error::RustError(e.to_string()));
+                let mut response = Response::from_stream(stream)?;
+                let headers = response.headers_mut();
+                headers.set("Content-Type", "application/x-ndjson")?;
+                headers.set(
+                    "Content-Disposition",
+                    &format!("attachment; filename=\"{file_name}.ndjson\""),
+                )?;
+                response
+            }
+            "json" => {
+                let archive = archive::collect(ctx.data.db.clone(), claims.organization_id).await?;
+                let mut response = Response::from_json(&archive)?;
+                response.headers_mut().set(
+                    "Content-Disposition",
+                    &format!("attachment; filename=\"{file_name}.json\""),
+                )?;
+                response
+            }
+            _ => return Err(ApiError::new(400, "format must be ndjson or json")),
+        };
+        response.headers_mut().set("Cache-Control", "no-store")?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "organization_exported",
+            "organization",
+            Some(claims.organization_id),
+            Some(format),
+        )
+        .await;
+
+        Ok(response)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// `POST /api/admin/archive`: restores an NDJSON archive, or a JSON one sent
+/// as `application/json`, into the requesting admin's organization, which
+/// must not have other members or data yet.
+pub async fn import_archive(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+        let body = req.text().await?;
+        let archive = if content_type.starts_with("application/json") {
+            serde_json::from_str::<OrganizationArchive>(&body)
+                .map_err(|e| ArchiveError::Invalid(e.to_string()))?
+        } else {
+            OrganizationArchive::from_ndjson(&body)?
+        };
+
+        let counts = archive::restore(
+            &ctx.data.db,
+            claims.organization_id,
+            claims.user_id,
+            &archive,
+        )
+        .await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "organization_restored",
+            "organization",
+            Some(claims.organization_id),
+            serde_json::to_string(&counts).ok(),
+        )
+        .await;
+
+        json_with_status(&counts, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// `DELETE /api/admin/archive`: removes the partial data of a restore that
+/// failed without cleaning up after itself, or was cut off, so the archive
+/// can be imported again.
+pub async fn clear_partial_restore(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        archive::clear_partial_restore(&ctx.data.db, claims.organization_id).await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "organization_restore_cleared",
+            "organization",
+            Some(claims.organization_id),
+            None,
+        )
+        .await;
+
+        Ok(Response::empty()?.with_status(204))
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
@@ -1,12 +1,18 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    AnalyticsResponse, Claims, D1Param, D1Row, HeatmapDay, ModelError, ReportStats, StatusCount,
-    TaskStats, d1_query_all, d1_query_one,
+    AnalyticsResponse, Claims, CountRow, D1Param, FromD1Row, HeatmapDay, ModelError, ReportStats,
+    RoleRow, StatusCount, TaskStats, d1_query_all, d1_query_one,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::permissions::{self, Permission, Scope};
+use crate::routing::{Request, Response, RouteContext};
+use crate::settings;
+use crate::timezone;
+use chrono::Duration;
 use serde::Serialize;
-use serde_json::Value;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
+
+const HEATMAP_DAYS: i64 = 30;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -51,83 +57,18 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, FromD1Row)]
 struct NameRow {
     name: String,
 }
 
-impl crate::models::FromD1Row for NameRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let name = row
-            .get("name")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("name"))?
-            .to_string();
-        Ok(Self { name })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct CountRow {
-    count: i64,
-}
-
-impl crate::models::FromD1Row for CountRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let count = row
-            .get("count")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("count"))?;
-        Ok(Self { count })
-    }
-}
-
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, FromD1Row)]
 struct TaskCompletionStats {
     total_completed: i64,
     completed_this_week: i64,
     completed_last_week: i64,
 }
 
-impl crate::models::FromD1Row for TaskCompletionStats {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let total_completed = row
-            .get("total_completed")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("total_completed"))?;
-        let completed_this_week = row
-            .get("completed_this_week")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("completed_this_week"))?;
-        let completed_last_week = row
-            .get("completed_last_week")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("completed_last_week"))?;
-
-        Ok(Self {
-            total_completed,
-            completed_this_week,
-            completed_last_week,
-        })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -164,21 +105,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -188,6 +146,9 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
+/// Statistics for `user_id`, with days counted in that member's own timezone
+/// (whoever is looking) and weeks starting on the organization's
+/// `week_start_day`.
 async fn fetch_user_analytics(
     state: &AppState,
     organization_id: i64,
@@ -195,43 +156,57 @@ async fn fetch_user_analytics(
 ) -> Result<AnalyticsResponse, ApiError> {
     let user_name = d1_query_one::<NameRow>(
         &state.db,
-        "SELECT name FROM users WHERE organization_id = ?1 AND id = ?2 LIMIT 1",
+        "SELECT u.name
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND u.id = ?2
+         LIMIT 1",
         &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
     )
     .await?
     .ok_or_else(|| ApiError::new(404, "User not found"))?
     .name;
 
+    let tz = timezone::for_member(&state.db, organization_id, user_id).await?;
+    let settings = settings::load(&state.db, organization_id)
+        .await?
+        .ok_or_else(|| ApiError::new(404, "Organization not found"))?;
+    let first_day = settings.week_start();
+    let today = timezone::today(tz);
+    let this_week = timezone::week_start(today, first_day);
+    let last_week_start = timezone::day_start(tz, this_week - Duration::days(7));
+    let this_week_start = timezone::day_start(tz, this_week);
+    let next_week_start = timezone::day_start(tz, this_week + Duration::days(7));
     let task_completion = d1_query_one::<TaskCompletionStats>(
         &state.db,
-        "WITH jst AS (
-             SELECT date(
-                 'now',
-                 '+9 hours',
-                 printf('-%d days', (CAST(strftime('%w', 'now', '+9 hours') AS INTEGER) + 6) % 7)
-             ) AS week_start
-         )
-         SELECT
-             COALESCE(SUM(CASE WHEN status = 'done' THEN 1 ELSE 0 END), 0) AS total_completed,
+        "SELECT
+             COALESCE(SUM(CASE WHEN status IN (SELECT value FROM json_each(?6)) THEN 1 ELSE 0 END), 0) AS total_completed,
              COALESCE(SUM(
                  CASE
-                     WHEN status = 'done'
-                      AND date(datetime(updated_at, '+9 hours')) >= (SELECT week_start FROM jst)
-                      AND date(datetime(updated_at, '+9 hours')) < date((SELECT week_start FROM jst), '+7 days')
+                     WHEN status IN (SELECT value FROM json_each(?6))
+                      AND datetime(updated_at) >= ?4
+                      AND datetime(updated_at) < ?5
                      THEN 1 ELSE 0
                  END
              ), 0) AS completed_this_week,
              COALESCE(SUM(
                  CASE
-                     WHEN status = 'done'
-                      AND date(datetime(updated_at, '+9 hours')) >= date((SELECT week_start FROM jst), '-7 days')
-                      AND date(datetime(updated_at, '+9 hours')) < (SELECT week_start FROM jst)
+                     WHEN status IN (SELECT value FROM json_each(?6))
+                      AND datetime(updated_at) >= ?3
+                      AND datetime(updated_at) < ?4
                      THEN 1 ELSE 0
                  END
              ), 0) AS completed_last_week
          FROM tasks
          WHERE organization_id = ?1 AND member_id = ?2",
-        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(last_week_start),
+            D1Param::Text(this_week_start),
+            D1Param::Text(next_week_start),
+            settings.closed_statuses_param(),
+        ],
     )
     .await?
     .ok_or_else(|| ApiError::internal("failed to compute task completion stats"))?;
@@ -258,26 +233,40 @@ async fn fetch_user_analytics(
     .ok_or_else(|| ApiError::internal("failed to count reports"))?
     .count;
 
+    // The last 30 local days, each with the UTC range it covers.
+    let days: Vec<_> = (0..HEATMAP_DAYS)
+        .rev()
+        .map(|ago| today - Duration::days(ago))
+        .collect();
+    let mut params = vec![D1Param::Integer(organization_id), D1Param::Integer(user_id)];
+    for day in &days {
+        let (start, end) = timezone::day_range(tz, *day);
+        params.push(D1Param::Text(day.format("%Y-%m-%d").to_string()));
+        params.push(D1Param::Text(start));
+        params.push(D1Param::Text(end));
+    }
     let heatmap = d1_query_all::<HeatmapDay>(
         &state.db,
-        "WITH RECURSIVE days(day) AS (
-             SELECT date('now', '+9 hours', '-29 days')
-             UNION ALL
-             SELECT date(day, '+1 day')
+        &format!(
+            "WITH days(day, starts_at, ends_at) AS (VALUES {})
+             SELECT
+                 day AS date,
+                 COALESCE(COUNT(al.id), 0) AS count
              FROM days
-             WHERE day < date('now', '+9 hours')
-         )
-         SELECT
-             day AS date,
-             COALESCE(COUNT(al.id), 0) AS count
-         FROM days
-         LEFT JOIN activity_logs al
-             ON al.organization_id = ?1
-            AND al.user_id = ?2
-            AND date(datetime(al.created_at, '+9 hours')) = day
-         GROUP BY day
-         ORDER BY day ASC",
-        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+             LEFT JOIN activity_logs al
+                 ON al.organization_id = ?1
+                AND al.user_id = ?2
+                AND datetime(al.created_at) >= starts_at
+                AND datetime(al.created_at) < ends_at
+             GROUP BY day
+             ORDER BY day ASC",
+            // Numbered throughout: an anonymous `?` after `?1`/`?2` would reuse them.
+            (0..days.len())
+                .map(|i| format!("(?{}, ?{}, ?{})", 3 + i * 3, 4 + i * 3, 5 + i * 3))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        &params,
     )
     .await?;
 
@@ -323,8 +312,20 @@ pub async fn get_user_analytics(
             .parse::<i64>()
             .map_err(|_| ApiError::new(400, "Invalid user id"))?;
 
-        if claims.role != "admin" && claims.user_id != id {
-            return Err(ApiError::new(403, "Forbidden"));
+        if claims.user_id != id {
+            let scope = permissions::authorize_scope(
+                &ctx.data.db,
+                &claims,
+                Permission::ViewMemberAnalytics,
+            )
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+            if scope == Scope::Team
+                && !permissions::manages(&ctx.data.db, claims.organization_id, claims.user_id, id)
+                    .await?
+            {
+                return Err(ApiError::new(403, "This member is not on your team"));
+            }
         }
 
         let analytics = fetch_user_analytics(&ctx.data, claims.organization_id, id).await?;
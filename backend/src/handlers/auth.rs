@@ -1,28 +1,56 @@
 use crate::AppState;
+use crate::api_tokens;
+use crate::memberships;
 use crate::models::{
-    Claims, D1Param, D1Row, ForgotPasswordInput, Invitation, JoinInput, LoginInput, LoginResponse,
-    ModelError, RegisterInput, ResetPasswordInput, User, VerifyEmailInput, d1_execute,
-    d1_query_one,
+    Claims, CountRow, D1Param, D1Statement, ForgotPasswordInput, FromD1Row, IdRow, Invitation,
+    JoinInput, LoginInput, LoginResponse, ModelError, OrganizationSelectionResponse,
+    OrganizationSummary, PasswordPolicy, RefreshResponse, RefreshTokenInput, RegisterInput,
+    ResetPasswordInput, RoleRow, Session, SsoCallbackInput, SsoStartInput, SsoStartResponse,
+    SwitchOrganizationInput, TwoFactorChallengeResponse, User, VerifyEmailInput,
+    VerifyTwoFactorInput, d1_batch, d1_execute, d1_query_all, d1_query_one,
+};
+use crate::oidc::{self, OidcError};
+use crate::routing::{Request, Response, RouteContext};
+use crate::settings;
+use crate::throttle::AttemptKeys;
+use crate::totp;
+use crate::utils::{
+    generate_token, hash_token, is_secure_password, is_valid_slug, is_valid_username, slugify,
 };
-use crate::utils::{is_secure_password, is_valid_username};
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
 };
 use chrono::{Duration, Utc};
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use serde::Serialize;
-use serde_json::{Value, json};
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use serde_json::json;
+use std::collections::HashMap;
+use worker::Result as WorkerResult;
 
-const JWT_EXPIRATION_HOURS: i64 = 24;
+const ACCESS_TOKEN_EXPIRATION_MINUTES: i64 = 15;
+const REFRESH_TOKEN_EXPIRATION_DAYS: i64 = 30;
 const PASSWORD_RESET_EXPIRATION_HOURS: i64 = 1;
+pub(crate) const JOIN_VERIFICATION_EXPIRATION_HOURS: i64 = 1;
+const LOGIN_CHALLENGE_EXPIRATION_MINUTES: i64 = 5;
+const MAX_LOGIN_CHALLENGE_ATTEMPTS: i64 = 5;
 
 const INVALID_CREDENTIALS_MESSAGE: &str = "ユーザー名またはパスワードが正しくありません";
 const INVALID_USERNAME_MESSAGE: &str =
     "ユーザー名は3文字以上30文字以内で、英数字、アンダースコア、ハイフンのみ使用できます";
 const INVALID_PASSWORD_MESSAGE: &str =
     "パスワードは8文字以上で、英大文字、小文字、数字、記号を含む必要があります";
+const INVALID_SLUG_MESSAGE: &str =
+    "組織IDは3文字以上40文字以内で、英小文字、数字、ハイフンのみ使用できます";
+const SLUG_TAKEN_MESSAGE: &str = "この組織IDは既に使用されています";
+const ORGANIZATION_SELECTION_MESSAGE: &str =
+    "複数の組織にアカウントがあります。ログインする組織を選択してください";
+const INVALID_CHALLENGE_MESSAGE: &str = "認証の有効期限が切れました。もう一度ログインしてください";
+const INVALID_TWO_FACTOR_MESSAGE: &str = "認証コードが正しくありません";
+const PASSWORD_LOGIN_DISABLED_MESSAGE: &str =
+    "この組織ではシングルサインオンでログインしてください";
+const SSO_NOT_AVAILABLE_MESSAGE: &str = "この組織ではシングルサインオンを利用できません";
+const TOO_MANY_ATTEMPTS_MESSAGE: &str =
+    "試行回数が上限を超えました。しばらく時間をおいてから再度お試しください";
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -33,6 +61,7 @@ struct ErrorBody {
 struct ApiError {
     status: u16,
     message: String,
+    retry_after: Option<i64>,
 }
 
 impl ApiError {
@@ -40,6 +69,7 @@ impl ApiError {
         Self {
             status,
             message: message.into(),
+            retry_after: None,
         }
     }
 
@@ -47,11 +77,24 @@ impl ApiError {
         Self::new(500, message)
     }
 
+    fn too_many_attempts(retry_after: i64) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(429, TOO_MANY_ATTEMPTS_MESSAGE)
+        }
+    }
+
     fn into_response(self) -> WorkerResult<Response> {
-        Response::from_json(&ErrorBody {
+        let mut response = Response::from_json(&ErrorBody {
             error: self.message,
-        })
-        .map(|response| response.with_status(self.status))
+        })?
+        .with_status(self.status);
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .set("Retry-After", &seconds.to_string())?;
+        }
+        Ok(response)
     }
 }
 
@@ -67,157 +110,111 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct UserPasswordRow {
+#[derive(Clone, Debug, FromD1Row)]
+struct LoginCandidateRow {
+    #[d1(flatten)]
+    user: User,
     password_hash: String,
+    organization_slug: Option<String>,
+    organization_name: String,
+    #[d1(bool_int)]
+    totp_enabled: i64,
+    #[d1(bool_int)]
+    two_factor_setup_required: i64,
+    #[d1(bool_int)]
+    password_login_disabled: i64,
 }
 
-impl crate::models::FromD1Row for UserPasswordRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let password_hash = row
-            .get("password_hash")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("password_hash"))?
-            .to_string();
-        Ok(Self { password_hash })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct IdRow {
+#[derive(Clone, Debug, FromD1Row)]
+struct ResetRow {
     id: i64,
+    user_id: i64,
+    organization_id: i64,
 }
 
-impl crate::models::FromD1Row for IdRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let id = row
-            .get("id")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("id"))?;
-        Ok(Self { id })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
+#[derive(Clone, Debug, FromD1Row)]
+struct VerificationTargetRow {
+    email: Option<String>,
+    pending_email: Option<String>,
+    email_verified: i64,
 }
 
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
+#[derive(Clone, Debug, FromD1Row)]
+struct VerifyEmailRow {
+    email: Option<String>,
+    pending_email: Option<String>,
 }
 
-#[derive(Clone, Debug)]
-struct ResetRow {
+#[derive(Clone, Debug, FromD1Row)]
+struct RefreshSessionRow {
     id: i64,
     user_id: i64,
+    organization_id: i64,
+    #[d1(bool_int)]
+    is_current: i64,
 }
 
-impl crate::models::FromD1Row for ResetRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let id = row
-            .get("id")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("id"))?;
-        let user_id = row
-            .get("user_id")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("user_id"))?;
-        Ok(Self { id, user_id })
-    }
+#[derive(Clone, Debug, FromD1Row)]
+struct LoginChallengeRow {
+    id: i64,
+    user_id: i64,
+    organization_id: i64,
 }
 
-#[derive(Clone, Debug)]
-struct VerificationTargetRow {
-    email: Option<String>,
-    pending_email: Option<String>,
-    email_verified: i64,
+fn client_ip(req: &Request) -> Option<String> {
+    req.headers().get("CF-Connecting-IP").ok().flatten()
 }
 
-impl crate::models::FromD1Row for VerificationTargetRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let email = match row.get("email") {
-            None | Some(Value::Null) => None,
-            Some(Value::String(v)) => Some(v.clone()),
-            _ => {
-                return Err(ModelError::InvalidType {
-                    field: "email",
-                    expected: "text|null",
-                });
-            }
-        };
-
-        let pending_email = match row.get("pending_email") {
-            None | Some(Value::Null) => None,
-            Some(Value::String(v)) => Some(v.clone()),
-            _ => {
-                return Err(ModelError::InvalidType {
-                    field: "pending_email",
-                    expected: "text|null",
-                });
-            }
-        };
-
-        let email_verified = row
-            .get("email_verified")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("email_verified"))?;
-
-        Ok(Self {
-            email,
-            pending_email,
-            email_verified,
-        })
+async fn ensure_not_locked(state: &AppState, keys: &AttemptKeys, now: i64) -> Result<(), ApiError> {
+    match keys.retry_after(&state.attempts, now).await? {
+        Some(seconds) => Err(ApiError::too_many_attempts(seconds)),
+        None => Ok(()),
     }
 }
 
-#[derive(Clone, Debug)]
-struct VerifyEmailRow {
-    email: Option<String>,
-    pending_email: Option<String>,
+/// Lockout keys for a password sign-in as `username`. Usernames are only
+/// unique per organization, so a sign-in that names one is counted per
+/// organization: failures against `admin` of one organization never lock out
+/// `admin` of another. Sign-ins without an organization have their own
+/// counter, which only ever locks out that way of signing in.
+fn login_attempt_keys(req: &Request, organization: Option<&str>, username: &str) -> AttemptKeys {
+    let account = match organization {
+        Some(slug) => format!("{slug}/{username}"),
+        None => username.to_string(),
+    };
+    AttemptKeys::new("login", Some(&account), client_ip(req).as_deref())
 }
 
-impl crate::models::FromD1Row for VerifyEmailRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let email = match row.get("email") {
-            None | Some(Value::Null) => None,
-            Some(Value::String(v)) => Some(v.clone()),
-            _ => {
-                return Err(ModelError::InvalidType {
-                    field: "email",
-                    expected: "text|null",
-                });
-            }
-        };
-
-        let pending_email = match row.get("pending_email") {
-            None | Some(Value::Null) => None,
-            Some(Value::String(v)) => Some(v.clone()),
-            _ => {
-                return Err(ModelError::InvalidType {
-                    field: "pending_email",
-                    expected: "text|null",
-                });
-            }
-        };
-
-        Ok(Self {
-            email,
-            pending_email,
-        })
+/// Charges a failed attempt and, if it locked the account, records that in
+/// the activity log of every user the identifier belongs to.
+async fn record_failed_attempt(
+    state: &AppState,
+    keys: &AttemptKeys,
+    now: i64,
+    users: &[(i64, i64)],
+) -> Result<(), ApiError> {
+    let Some(seconds) = keys.record_failure(&state.attempts, now).await? else {
+        return Ok(());
+    };
+    for (organization_id, user_id) in users {
+        let _ = d1_execute(
+            &state.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+             VALUES (?1, ?2, 'account_locked', 'user', ?2, ?3)",
+            &[
+                D1Param::Integer(*organization_id),
+                D1Param::Integer(*user_id),
+                D1Param::Text(format!("locked for {seconds}s after repeated failed sign-ins")),
+            ],
+        )
+        .await;
     }
+    Ok(())
 }
 
-fn build_claims(user: &User) -> Claims {
+fn build_claims(user: &User, session_id: i64) -> Claims {
     let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(JWT_EXPIRATION_HOURS))
+        .checked_add_signed(Duration::minutes(ACCESS_TOKEN_EXPIRATION_MINUTES))
         .expect("valid timestamp")
         .timestamp() as usize;
 
@@ -227,16 +224,86 @@ fn build_claims(user: &User) -> Claims {
         organization_id: user.organization_id,
         role: user.role.clone(),
         exp: expiration,
+        sid: session_id,
+        scopes: None,
     }
 }
 
-fn encode_token(jwt_secret: &str, claims: &Claims) -> Result<String, ApiError> {
-    encode(
-        &Header::default(),
-        claims,
-        &EncodingKey::from_secret(jwt_secret.as_ref()),
+/// Creates a session for `user` and returns its access and refresh tokens.
+async fn start_session(
+    req: &Request,
+    state: &AppState,
+    user: &User,
+) -> Result<(String, String), ApiError> {
+    let refresh_token = generate_token();
+    let expires_at = (Utc::now() + Duration::days(REFRESH_TOKEN_EXPIRATION_DAYS))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+
+    let session_id = d1_execute(
+        &state.db,
+        "INSERT INTO sessions (organization_id, user_id, refresh_token_hash, user_agent, ip_address, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(user.organization_id),
+            D1Param::Integer(user.id),
+            D1Param::Text(hash_token(&refresh_token)),
+            header("User-Agent")
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+            header("CF-Connecting-IP")
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+            D1Param::Text(expires_at),
+        ],
     )
-    .map_err(|e| ApiError::internal(e.to_string()))
+    .await?
+    .inserted_id()?;
+
+    let token = encode_token(state, &build_claims(user, session_id))?;
+    Ok((token, refresh_token))
+}
+
+fn encode_token(state: &AppState, claims: &Claims) -> Result<String, ApiError> {
+    state
+        .jwt_keys
+        .sign(claims)
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+/// [`INVALID_PASSWORD_MESSAGE`] for an organization's own password policy.
+fn invalid_password_message(policy: &PasswordPolicy) -> String {
+    let classes = policy
+        .required_classes()
+        .into_iter()
+        .map(|class| match class {
+            "uppercase" => "英大文字",
+            "lowercase" => "小文字",
+            "number" => "数字",
+            _ => "記号",
+        })
+        .collect::<Vec<_>>();
+    if classes.is_empty() {
+        format!(
+            "パスワードは{}文字以上である必要があります",
+            policy.min_length
+        )
+    } else {
+        format!(
+            "パスワードは{}文字以上で、{}を含む必要があります",
+            policy.min_length,
+            classes.join("、")
+        )
+    }
+}
+
+fn verify_password(password: &str, password_hash: &str) -> Result<bool, ApiError> {
+    let parsed_hash = PasswordHash::new(password_hash)
+        .map_err(|_| ApiError::internal("Invalid password hash in DB"))?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
 }
 
 fn hash_password(password: &str) -> Result<String, ApiError> {
@@ -250,6 +317,46 @@ fn hash_password(password: &str) -> Result<String, ApiError> {
         .map(|hash| hash.to_string())
 }
 
+async fn slug_taken(state: &AppState, slug: &str) -> Result<bool, ApiError> {
+    let count = d1_query_one::<CountRow>(
+        &state.db,
+        "SELECT COUNT(*) AS count FROM organizations WHERE slug = ?1",
+        &[D1Param::Text(slug.to_string())],
+    )
+    .await?
+    .map(|row| row.count)
+    .unwrap_or(0);
+    Ok(count > 0)
+}
+
+/// Picks the slug for a new organization: the requested one if given,
+/// otherwise one derived from the name with a random suffix on collision.
+async fn resolve_new_slug(state: &AppState, input: &RegisterInput) -> Result<String, ApiError> {
+    if let Some(requested) = input
+        .organization_slug
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        if !is_valid_slug(requested) {
+            return Err(ApiError::new(400, INVALID_SLUG_MESSAGE));
+        }
+        if slug_taken(state, requested).await? {
+            return Err(ApiError::new(409, SLUG_TAKEN_MESSAGE));
+        }
+        return Ok(requested.to_string());
+    }
+
+    let base = slugify(&input.organization_name).unwrap_or_else(|| "org".to_string());
+    if !slug_taken(state, &base).await? {
+        return Ok(base);
+    }
+
+    let suffix = &uuid::Uuid::new_v4().simple().to_string()[..6];
+    let base = base[..base.len().min(33)].trim_end_matches('-');
+    Ok(format!("{base}-{suffix}"))
+}
+
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -286,20 +393,37 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -309,6 +433,131 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
+/// Signs in as `username` of the organization `slug` to link that account to
+/// an invitation. This is a password sign-in, so it shares the lockout and
+/// the SSO-only restriction with `login`.
+async fn existing_account(
+    req: &Request,
+    state: &AppState,
+    slug: &str,
+    username: &str,
+    password: &str,
+) -> Result<User, ApiError> {
+    let now = Utc::now().timestamp();
+    let attempt_keys = login_attempt_keys(req, Some(slug), username);
+    ensure_not_locked(state, &attempt_keys, now).await?;
+
+    let candidate = d1_query_one::<LoginCandidateRow>(
+        &state.db,
+        "SELECT u.id, m.organization_id, u.name, u.username, u.email, u.pending_email, u.avatar_url, u.locale, u.timezone,
+                m.role, u.email_verified, u.created_at, u.password_hash,
+                o.slug AS organization_slug, o.name AS organization_name, u.totp_enabled,
+                (m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0)
+                    AS two_factor_setup_required,
+                (o.password_login_disabled = 1 AND m.role != 'admin') AS password_login_disabled
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE u.username = ?1 AND o.slug = ?2 AND m.deactivated_at IS NULL
+         LIMIT 1",
+        &[
+            D1Param::Text(username.to_string()),
+            D1Param::Text(slug.to_string()),
+        ],
+    )
+    .await?;
+
+    let Some(candidate) = candidate else {
+        record_failed_attempt(state, &attempt_keys, now, &[]).await?;
+        return Err(ApiError::new(401, INVALID_CREDENTIALS_MESSAGE));
+    };
+    if !verify_password(password, &candidate.password_hash)? {
+        let user = (candidate.user.organization_id, candidate.user.id);
+        record_failed_attempt(state, &attempt_keys, now, &[user]).await?;
+        return Err(ApiError::new(401, INVALID_CREDENTIALS_MESSAGE));
+    }
+    attempt_keys.clear_account(&state.attempts).await?;
+    if candidate.password_login_disabled == 1 {
+        return Err(ApiError::new(403, PASSWORD_LOGIN_DISABLED_MESSAGE));
+    }
+    Ok(candidate.user)
+}
+
+/// Signs in as the account's active membership in `organization_id`.
+async fn load_candidate(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Option<LoginCandidateRow>, ApiError> {
+    Ok(d1_query_one::<LoginCandidateRow>(
+        &state.db,
+        "SELECT u.id, m.organization_id, u.name, u.username, u.email, u.pending_email, u.avatar_url, u.locale, u.timezone,
+                m.role, u.email_verified, u.created_at, u.password_hash,
+                o.slug AS organization_slug, o.name AS organization_name, u.totp_enabled,
+                (m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0)
+                    AS two_factor_setup_required,
+                (o.password_login_disabled = 1 AND m.role != 'admin') AS password_login_disabled
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE u.id = ?1 AND m.organization_id = ?2 AND m.deactivated_at IS NULL
+         LIMIT 1",
+        &[D1Param::Integer(user_id), D1Param::Integer(organization_id)],
+    )
+    .await?)
+}
+
+/// Signs in `candidate` once its first factor has been checked.
+///
+/// With 2FA enabled the first factor alone is not enough: the caller gets a
+/// short-lived challenge that `verify_two_factor` exchanges for a session.
+async fn finish_login(
+    req: &Request,
+    state: &AppState,
+    candidate: LoginCandidateRow,
+) -> Result<Response, ApiError> {
+    if candidate.totp_enabled == 1 {
+        let challenge_token = generate_token();
+        let expires_at = (Utc::now() + Duration::minutes(LOGIN_CHALLENGE_EXPIRATION_MINUTES))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        d1_execute(
+            &state.db,
+            "INSERT INTO login_challenges (user_id, organization_id, token_hash, expires_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            &[
+                D1Param::Integer(candidate.user.id),
+                D1Param::Integer(candidate.user.organization_id),
+                D1Param::Text(hash_token(&challenge_token)),
+                D1Param::Text(expires_at),
+            ],
+        )
+        .await?;
+
+        return json_with_status(
+            &TwoFactorChallengeResponse {
+                challenge_token,
+                methods: vec!["totp".to_string(), "recovery_code".to_string()],
+            },
+            202,
+        );
+    }
+
+    let user = candidate.user;
+    let (token, refresh_token) = start_session(req, state, &user).await?;
+
+    json_with_status(
+        &LoginResponse {
+            token,
+            refresh_token,
+            user,
+            organization_slug: candidate.organization_slug,
+            two_factor_setup_required: candidate.two_factor_setup_required == 1,
+        },
+        200,
+    )
+}
+
 pub async fn login(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
     let input: LoginInput = match req.json().await {
         Ok(v) => v,
@@ -316,36 +565,99 @@ pub async fn login(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResul
     };
 
     let result = async {
-        let user = d1_query_one::<User>(
+        let organization = input
+            .organization
+            .as_deref()
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty());
+
+        let now = Utc::now().timestamp();
+        let attempt_keys = login_attempt_keys(&req, organization.as_deref(), &input.username);
+        ensure_not_locked(&ctx.data, &attempt_keys, now).await?;
+
+        // Usernames are only unique per organization and an account can be a
+        // member of several, so without a slug the identifier may match
+        // memberships in several organizations.
+        let candidates = d1_query_all::<LoginCandidateRow>(
             &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE username = ?1 OR email = ?1
-             LIMIT 1",
-            &[D1Param::Text(input.username.clone())],
+            "SELECT u.id, m.organization_id, u.name, u.username, u.email, u.pending_email, u.avatar_url, u.locale, u.timezone,
+                    m.role, u.email_verified, u.created_at, u.password_hash,
+                    o.slug AS organization_slug, o.name AS organization_name, u.totp_enabled,
+                    (m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0)
+                        AS two_factor_setup_required,
+                    (o.password_login_disabled = 1 AND m.role != 'admin') AS password_login_disabled
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             JOIN organizations o ON o.id = m.organization_id
+             WHERE (u.username = ?1 OR u.email = ?1)
+               AND (?2 IS NULL OR o.slug = ?2)
+               AND m.deactivated_at IS NULL
+             ORDER BY m.organization_id ASC",
+            &[
+                D1Param::Text(input.username.clone()),
+                organization.map(D1Param::Text).unwrap_or(D1Param::Null),
+            ],
         )
-        .await?
-        .ok_or_else(|| ApiError::new(401, INVALID_CREDENTIALS_MESSAGE))?;
+        .await?;
 
-        let stored_hash = d1_query_one::<UserPasswordRow>(
-            &ctx.data.db,
-            "SELECT password_hash FROM users WHERE id = ?1 LIMIT 1",
-            &[D1Param::Integer(user.id)],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("Missing password hash"))?;
+        let candidate_users: Vec<(i64, i64)> = candidates
+            .iter()
+            .map(|c| (c.user.organization_id, c.user.id))
+            .collect();
+
+        // Only organizations where the password matches are offered, so the
+        // selection never reveals accounts the caller cannot sign in to.
+        let mut matches = Vec::new();
+        let mut verified: HashMap<i64, bool> = HashMap::new();
+        for candidate in candidates {
+            let password_matches = match verified.get(&candidate.user.id) {
+                Some(result) => *result,
+                None => {
+                    let result = verify_password(&input.password, &candidate.password_hash)?;
+                    verified.insert(candidate.user.id, result);
+                    result
+                }
+            };
+            if password_matches {
+                matches.push(candidate);
+            }
+        }
 
-        let parsed_hash = PasswordHash::new(&stored_hash.password_hash)
-            .map_err(|_| ApiError::internal("Invalid password hash in DB"))?;
+        if matches.is_empty() {
+            record_failed_attempt(&ctx.data, &attempt_keys, now, &candidate_users).await?;
+            return Err(ApiError::new(401, INVALID_CREDENTIALS_MESSAGE));
+        }
+        attempt_keys.clear_account(&ctx.data.attempts).await?;
 
-        Argon2::default()
-            .verify_password(input.password.as_bytes(), &parsed_hash)
-            .map_err(|_| ApiError::new(401, INVALID_CREDENTIALS_MESSAGE))?;
+        // Organizations that sign in through SSO only keep password login
+        // for admins, so a broken provider cannot lock everyone out.
+        if matches.iter().all(|c| c.password_login_disabled == 1) {
+            return Err(ApiError::new(403, PASSWORD_LOGIN_DISABLED_MESSAGE));
+        }
+        matches.retain(|c| c.password_login_disabled == 0);
+
+        if matches.len() > 1 {
+            let organizations = matches
+                .into_iter()
+                .map(|candidate| OrganizationSummary {
+                    slug: candidate.organization_slug.unwrap_or_default(),
+                    name: candidate.organization_name,
+                })
+                .collect();
+            return json_with_status(
+                &OrganizationSelectionResponse {
+                    error: ORGANIZATION_SELECTION_MESSAGE.to_string(),
+                    organizations,
+                },
+                409,
+            );
+        }
 
-        let claims = build_claims(&user);
-        let token = encode_token(&ctx.data.jwt_secret, &claims)?;
+        let candidate = matches
+            .pop()
+            .ok_or_else(|| ApiError::new(401, INVALID_CREDENTIALS_MESSAGE))?;
 
-        json_with_status(&LoginResponse { token, user }, 200)
+        finish_login(&req, &ctx.data, candidate).await
     }
     .await;
 
@@ -366,63 +678,74 @@ pub async fn register(mut req: Request, ctx: RouteContext<AppState>) -> WorkerRe
             return Err(ApiError::new(400, INVALID_PASSWORD_MESSAGE));
         }
 
-        d1_execute(
-            &ctx.data.db,
-            "INSERT INTO organizations (name) VALUES (?1)",
-            &[D1Param::Text(input.organization_name.clone())],
-        )
-        .await?;
-
-        let org = d1_query_one::<IdRow>(
-            &ctx.data.db,
-            "SELECT id FROM organizations WHERE name = ?1 ORDER BY id DESC LIMIT 1",
-            &[D1Param::Text(input.organization_name.clone())],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("Failed to load created organization"))?;
-
+        let slug = resolve_new_slug(&ctx.data, &input).await?;
         let password_hash = hash_password(&input.password)?;
         let email_verification_token = uuid::Uuid::new_v4().to_string();
 
-        d1_execute(
+        // The organization and its first admin are created together so a failed
+        // user insert never leaves an empty organization behind.
+        let results = d1_batch(
             &ctx.data.db,
-            "INSERT INTO users (organization_id, name, username, email, pending_email, password_hash, role, email_verified, email_verification_token)
-             VALUES (?1, ?2, ?3, NULL, ?4, ?5, 'admin', 0, ?6)",
             &[
-                D1Param::Integer(org.id),
-                D1Param::Text(input.admin_name.clone()),
-                D1Param::Text(input.username.clone()),
-                D1Param::Text(input.email.clone()),
-                D1Param::Text(password_hash),
-                D1Param::Text(email_verification_token.clone()),
+                D1Statement::new(
+                    "INSERT INTO organizations (name, slug) VALUES (?1, ?2)",
+                    vec![
+                        D1Param::Text(input.organization_name.clone()),
+                        D1Param::Text(slug.clone()),
+                    ],
+                ),
+                D1Statement::new(
+                    "INSERT INTO users (organization_id, name, username, email, pending_email, password_hash, email_verified, email_verification_token)
+                     SELECT id, ?2, ?3, NULL, ?4, ?5, 0, ?6 FROM organizations WHERE slug = ?1",
+                    vec![
+                        D1Param::Text(slug.clone()),
+                        D1Param::Text(input.admin_name.clone()),
+                        D1Param::Text(input.username.clone()),
+                        D1Param::Text(input.email.clone()),
+                        D1Param::Text(password_hash),
+                        D1Param::Text(email_verification_token.clone()),
+                    ],
+                ),
+                D1Statement::new(
+                    "INSERT INTO memberships (user_id, organization_id, role)
+                     SELECT u.id, o.id, 'admin'
+                     FROM organizations o
+                     JOIN users u ON u.organization_id = o.id AND u.username = ?2
+                     WHERE o.slug = ?1",
+                    vec![
+                        D1Param::Text(slug.clone()),
+                        D1Param::Text(input.username.clone()),
+                    ],
+                ),
             ],
         )
         .await?;
 
+        let organization_id = results[0].inserted_id()?;
+        let user_id = results[1].inserted_id()?;
+
         ctx.data
             .email_service
             .send_verification_email(&input.email, &email_verification_token)
             .await
             .map_err(ApiError::internal)?;
 
-        let user = d1_query_one::<User>(
-            &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE organization_id = ?1 AND username = ?2
-             LIMIT 1",
-            &[
-                D1Param::Integer(org.id),
-                D1Param::Text(input.username.clone()),
-            ],
+        let user = memberships::load_member(&ctx.data.db, organization_id, user_id)
+            .await?
+            .ok_or_else(|| ApiError::internal("Failed to load created user"))?;
+
+        let (token, refresh_token) = start_session(&req, &ctx.data, &user).await?;
+
+        json_with_status(
+            &LoginResponse {
+                token,
+                refresh_token,
+                user,
+                organization_slug: Some(slug),
+                two_factor_setup_required: false,
+            },
+            201,
         )
-        .await?
-        .ok_or_else(|| ApiError::internal("Failed to load created user"))?;
-
-        let claims = build_claims(&user);
-        let token = encode_token(&ctx.data.jwt_secret, &claims)?;
-
-        json_with_status(&LoginResponse { token, user }, 201)
     }
     .await;
 
@@ -439,72 +762,325 @@ pub async fn join(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult
         if !is_valid_username(&input.username) {
             return Err(ApiError::new(400, INVALID_USERNAME_MESSAGE));
         }
-        if !is_secure_password(&input.password) {
-            return Err(ApiError::new(400, INVALID_PASSWORD_MESSAGE));
-        }
 
-        let invitation = d1_query_one::<Invitation>(
+        // Shares the invitation lookup limit with `GET /api/invitations/:token`.
+        let now = Utc::now().timestamp();
+        let attempt_keys = AttemptKeys::new("invitation", None, client_ip(&req).as_deref());
+        ensure_not_locked(&ctx.data, &attempt_keys, now).await?;
+
+        let Some(invitation) = d1_query_one::<Invitation>(
             &ctx.data.db,
-            "SELECT i.id, i.organization_id, o.name AS org_name, i.token, i.role, i.expires_at, i.created_at
+            "SELECT i.id, i.organization_id, o.name AS org_name, o.slug AS org_slug, i.token, i.role,
+                    i.email, i.max_uses, COUNT(r.id) AS use_count, i.expires_at, i.created_at
              FROM invitations i
              JOIN organizations o ON i.organization_id = o.id
-             WHERE i.token = ?1 AND datetime(i.expires_at) > datetime('now')
+             LEFT JOIN invitation_redemptions r ON r.invitation_id = i.id
+             WHERE i.token = ?1
+               AND i.revoked_at IS NULL
+               AND datetime(i.expires_at) > datetime('now')
+             GROUP BY i.id
+             HAVING COUNT(r.id) < i.max_uses
              LIMIT 1",
             &[D1Param::Text(input.token.clone())],
         )
         .await?
-        .ok_or_else(|| ApiError::new(404, "Invalid or expired invitation token"))?;
+        else {
+            attempt_keys.record_failure(&ctx.data.attempts, now).await?;
+            return Err(ApiError::new(404, "Invalid or expired invitation token"));
+        };
+
+        // Someone who already has an account says so and signs in with it,
+        // rather than creating a second username and password. A failed
+        // sign-in never falls back to creating an account.
+        let existing_organization = input
+            .existing_organization
+            .as_deref()
+            .map(|slug| slug.trim().to_lowercase())
+            .filter(|slug| !slug.is_empty());
+        let account = match existing_organization {
+            Some(slug) => Some(
+                existing_account(&req, &ctx.data, &slug, &input.username, &input.password).await?,
+            ),
+            None => None,
+        };
+        if account.is_none() && (input.name.trim().is_empty() || input.email.trim().is_empty()) {
+            return Err(ApiError::new(400, "name and email are required"));
+        }
+        let email = match &account {
+            Some(account) => account.email.as_deref(),
+            None => Some(input.email.trim()),
+        };
+        if invitation
+            .email
+            .as_deref()
+            .is_some_and(|bound| !email.is_some_and(|email| bound.eq_ignore_ascii_case(email)))
+        {
+            return Err(ApiError::new(
+                403,
+                "This invitation was sent to a different email address",
+            ));
+        }
+
+        if let Some(account) = account {
+            let already_member = d1_query_one::<CountRow>(
+                &ctx.data.db,
+                "SELECT COUNT(*) AS count FROM memberships WHERE user_id = ?1 AND organization_id = ?2",
+                &[
+                    D1Param::Integer(account.id),
+                    D1Param::Integer(invitation.organization_id),
+                ],
+            )
+            .await?
+            .is_some_and(|row| row.count > 0);
+            if already_member {
+                return Err(ApiError::new(
+                    409,
+                    "You are already a member of this organization",
+                ));
+            }
+            let username = account.username.clone().unwrap_or_default();
+            if memberships::username_taken(&ctx.data.db, invitation.organization_id, &username)
+                .await?
+            {
+                return Err(ApiError::new(
+                    409,
+                    format!("Another member of this organization already has the username {username}"),
+                ));
+            }
+
+            let results = d1_batch(
+                &ctx.data.db,
+                &[
+                    D1Statement::new(
+                        "INSERT INTO memberships (user_id, organization_id, role)
+                         SELECT ?2, i.organization_id, i.role
+                         FROM invitations i
+                         WHERE i.id = ?1
+                           AND i.revoked_at IS NULL
+                           AND datetime(i.expires_at) > datetime('now')
+                           AND (SELECT COUNT(*) FROM invitation_redemptions r WHERE r.invitation_id = i.id) < i.max_uses",
+                        vec![
+                            D1Param::Integer(invitation.id),
+                            D1Param::Integer(account.id),
+                        ],
+                    ),
+                    D1Statement::new(
+                        "INSERT INTO invitation_redemptions (invitation_id, user_id)
+                         SELECT i.id, ?2
+                         FROM invitations i
+                         WHERE i.id = ?1
+                           AND i.revoked_at IS NULL
+                           AND datetime(i.expires_at) > datetime('now')
+                           AND (SELECT COUNT(*) FROM invitation_redemptions r WHERE r.invitation_id = i.id) < i.max_uses",
+                        vec![
+                            D1Param::Integer(invitation.id),
+                            D1Param::Integer(account.id),
+                        ],
+                    ),
+                ],
+            )
+            .await?;
+            if results[0].changes == 0 {
+                return Err(ApiError::new(410, "This invitation has already been used"));
+            }
+
+            // The account may have 2FA, so this signs in like `login` does.
+            let candidate = load_candidate(&ctx.data, invitation.organization_id, account.id)
+                .await?
+                .ok_or_else(|| ApiError::internal("Failed to load joined user"))?;
+            return finish_login(&req, &ctx.data, candidate).await;
+        }
+
+        if memberships::username_taken(&ctx.data.db, invitation.organization_id, &input.username)
+            .await?
+        {
+            return Err(ApiError::new(
+                409,
+                "This username is already taken in this organization",
+            ));
+        }
+        let policy = settings::password_policy(&ctx.data.db, invitation.organization_id).await?;
+        if !policy.allows(&input.password) {
+            return Err(ApiError::new(400, invalid_password_message(&policy)));
+        }
+
+        // An invitation bound to an address is only redeemed by someone who
+        // reads mail there: the first request emails a token to it, and the
+        // account is created when the form comes back with that token.
+        let email_proof = match invitation.email.as_deref() {
+            None => None,
+            Some(bound) => {
+                let email_token = input
+                    .email_token
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|token| !token.is_empty());
+                let Some(email_token) = email_token else {
+                    // Each request sends mail, so each one counts.
+                    attempt_keys.record_failure(&ctx.data.attempts, now).await?;
+                    let email_token = generate_token();
+                    let expires_at =
+                        (Utc::now() + Duration::hours(JOIN_VERIFICATION_EXPIRATION_HOURS))
+                            .format("%Y-%m-%d %H:%M:%S")
+                            .to_string();
+                    d1_batch(
+                        &ctx.data.db,
+                        &[
+                            D1Statement::new(
+                                "DELETE FROM join_verifications WHERE datetime(expires_at) <= datetime('now')",
+                                vec![],
+                            ),
+                            D1Statement::new(
+                                "INSERT INTO join_verifications (invitation_id, email, token_hash, expires_at)
+                                 VALUES (?1, ?2, ?3, ?4)",
+                                vec![
+                                    D1Param::Integer(invitation.id),
+                                    D1Param::Text(bound.to_string()),
+                                    D1Param::Text(hash_token(&email_token)),
+                                    D1Param::Text(expires_at),
+                                ],
+                            ),
+                        ],
+                    )
+                    .await?;
+                    ctx.data
+                        .email_service
+                        .send_join_verification_email(bound, &invitation.token, &email_token)
+                        .await
+                        .map_err(ApiError::internal)?;
+                    return json_with_status(&json!({ "email_verification_required": true }), 202);
+                };
+                let Some(proof) = d1_query_one::<IdRow>(
+                    &ctx.data.db,
+                    "SELECT id FROM join_verifications
+                     WHERE token_hash = ?1 AND invitation_id = ?2 AND email = ?3
+                       AND datetime(expires_at) > datetime('now')
+                     LIMIT 1",
+                    &[
+                        D1Param::Text(hash_token(email_token)),
+                        D1Param::Integer(invitation.id),
+                        D1Param::Text(bound.to_string()),
+                    ],
+                )
+                .await?
+                else {
+                    attempt_keys.record_failure(&ctx.data.attempts, now).await?;
+                    return Err(ApiError::new(
+                        403,
+                        "Invalid or expired email verification token",
+                    ));
+                };
+                Some((proof.id, bound.to_string()))
+            }
+        };
 
         let password_hash = hash_password(&input.password)?;
         let email_verification_token = uuid::Uuid::new_v4().to_string();
-
-        d1_execute(
-            &ctx.data.db,
-            "INSERT INTO users (organization_id, name, username, email, pending_email, password_hash, role, email_verified, email_verification_token)
-             VALUES (?1, ?2, ?3, NULL, ?4, ?5, ?6, 0, ?7)",
-            &[
-                D1Param::Integer(invitation.organization_id),
-                D1Param::Text(input.name.clone()),
-                D1Param::Text(input.username.clone()),
+        // A proven address is verified already; any other one waits for the
+        // verification link.
+        let (email, pending_email, email_verified, verification_token, proof_id) = match &email_proof
+        {
+            Some((proof_id, email)) => (
+                D1Param::Text(email.clone()),
+                D1Param::Null,
+                1,
+                D1Param::Null,
+                D1Param::Integer(*proof_id),
+            ),
+            None => (
+                D1Param::Null,
                 D1Param::Text(input.email.clone()),
-                D1Param::Text(password_hash),
-                D1Param::Text(invitation.role.clone()),
+                0,
                 D1Param::Text(email_verification_token.clone()),
-            ],
-        )
-        .await?;
-
-        ctx.data
-            .email_service
-            .send_verification_email(&input.email, &email_verification_token)
-            .await
-            .map_err(ApiError::internal)?;
+                D1Param::Null,
+            ),
+        };
 
-        let user = d1_query_one::<User>(
-            &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE organization_id = ?1 AND username = ?2
-             LIMIT 1",
-            &[
-                D1Param::Integer(invitation.organization_id),
-                D1Param::Text(input.username.clone()),
-            ],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("Failed to load joined user"))?;
+        // Every statement re-checks the invitation, so concurrent joins cannot
+        // redeem it more than `max_uses` times, and the email proof is used up
+        // with it.
+        let redeemable = "FROM invitations i
+             WHERE i.id = ?1
+               AND i.revoked_at IS NULL
+               AND datetime(i.expires_at) > datetime('now')
+               AND (SELECT COUNT(*) FROM invitation_redemptions r WHERE r.invitation_id = i.id) < i.max_uses";
+        let new_user = "(SELECT id FROM users WHERE organization_id = i.organization_id AND username = ?2)";
+        let mut statements = vec![
+            D1Statement::new(
+                format!(
+                    "INSERT INTO users (organization_id, name, username, email, pending_email, password_hash, email_verified, email_verification_token)
+                     SELECT i.organization_id, ?3, ?2, ?4, ?5, ?6, ?7, ?8 {redeemable}
+                       AND (?9 IS NULL OR EXISTS (SELECT 1 FROM join_verifications v WHERE v.id = ?9))"
+                ),
+                vec![
+                    D1Param::Integer(invitation.id),
+                    D1Param::Text(input.username.clone()),
+                    D1Param::Text(input.name.clone()),
+                    email,
+                    pending_email,
+                    D1Param::Text(password_hash),
+                    D1Param::Integer(email_verified),
+                    verification_token,
+                    proof_id.clone(),
+                ],
+            ),
+            D1Statement::new(
+                format!(
+                    "INSERT INTO memberships (user_id, organization_id, role)
+                     SELECT {new_user}, i.organization_id, i.role {redeemable} AND {new_user} IS NOT NULL"
+                ),
+                vec![
+                    D1Param::Integer(invitation.id),
+                    D1Param::Text(input.username.clone()),
+                ],
+            ),
+            D1Statement::new(
+                format!(
+                    "INSERT INTO invitation_redemptions (invitation_id, user_id)
+                     SELECT i.id, {new_user} {redeemable} AND {new_user} IS NOT NULL"
+                ),
+                vec![
+                    D1Param::Integer(invitation.id),
+                    D1Param::Text(input.username.clone()),
+                ],
+            ),
+        ];
+        if email_proof.is_some() {
+            statements.push(D1Statement::new(
+                "DELETE FROM join_verifications WHERE id = ?1",
+                vec![proof_id],
+            ));
+        }
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(410, "This invitation has already been used"));
+        }
+        let user_id = results[0].inserted_id()?;
+
+        if email_proof.is_none() {
+            ctx.data
+                .email_service
+                .send_verification_email(&input.email, &email_verification_token)
+                .await
+                .map_err(ApiError::internal)?;
+        }
 
-        let _ = d1_execute(
-            &ctx.data.db,
-            "DELETE FROM invitations WHERE id = ?1",
-            &[D1Param::Integer(invitation.id)],
+        let candidate = load_candidate(&ctx.data, invitation.organization_id, user_id)
+            .await?
+            .ok_or_else(|| ApiError::internal("Failed to load joined user"))?;
+        let user = candidate.user;
+        let (token, refresh_token) = start_session(&req, &ctx.data, &user).await?;
+
+        json_with_status(
+            &LoginResponse {
+                token,
+                refresh_token,
+                user,
+                organization_slug: invitation.org_slug,
+                two_factor_setup_required: candidate.two_factor_setup_required == 1,
+            },
+            201,
         )
-        .await;
-
-        let claims = build_claims(&user);
-        let token = encode_token(&ctx.data.jwt_secret, &claims)?;
-
-        json_with_status(&LoginResponse { token, user }, 201)
     }
     .await;
 
@@ -521,19 +1097,35 @@ pub async fn forgot_password(
     };
 
     let result = async {
+        // Every request counts: this endpoint sends email, so it is limited
+        // whether or not the identity exists.
+        let now = Utc::now().timestamp();
+        let attempt_keys = AttemptKeys::new(
+            "forgot-password",
+            Some(&input.identity),
+            client_ip(&req).as_deref(),
+        );
+        ensure_not_locked(&ctx.data, &attempt_keys, now).await?;
+        attempt_keys.record_failure(&ctx.data.attempts, now).await?;
+
         let user_opt = d1_query_one::<User>(
             &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE username = ?1 OR email = ?1
-             LIMIT 1",
+            &format!(
+                "SELECT {}
+                 FROM users u
+                 JOIN memberships m ON m.user_id = u.id
+                 WHERE (u.username = ?1 OR u.email = ?1) AND m.deactivated_at IS NULL
+                 LIMIT 1",
+                memberships::MEMBER_COLUMNS
+            ),
             &[D1Param::Text(input.identity.clone())],
         )
         .await?;
 
         if let Some(user) = user_opt {
             let token = uuid::Uuid::new_v4().to_string();
-            let expires_at = (Utc::now() + Duration::hours(PASSWORD_RESET_EXPIRATION_HOURS)).to_rfc3339();
+            let expires_at =
+                (Utc::now() + Duration::hours(PASSWORD_RESET_EXPIRATION_HOURS)).to_rfc3339();
 
             d1_execute(
                 &ctx.data.db,
@@ -553,7 +1145,8 @@ pub async fn forgot_password(
                 .unwrap_or_default();
 
             if !recipient.is_empty() {
-                let _ = ctx.data
+                let _ = ctx
+                    .data
                     .email_service
                     .send_password_reset_email(&recipient, &token)
                     .await;
@@ -578,39 +1171,51 @@ pub async fn reset_password(
     };
 
     let result = async {
-        if !is_secure_password(&input.new_password) {
-            return Err(ApiError::new(400, INVALID_PASSWORD_MESSAGE));
-        }
-
         let reset = d1_query_one::<ResetRow>(
             &ctx.data.db,
-            "SELECT id, user_id
-             FROM password_resets
-             WHERE token = ?1 AND datetime(expires_at) > datetime('now')
+            "SELECT r.id, r.user_id, u.organization_id
+             FROM password_resets r
+             JOIN users u ON u.id = r.user_id
+             WHERE r.token = ?1 AND datetime(r.expires_at) > datetime('now')
              LIMIT 1",
             &[D1Param::Text(input.token.clone())],
         )
         .await?
         .ok_or_else(|| ApiError::new(404, "Invalid or expired reset token"))?;
 
+        let policy = settings::password_policy(&ctx.data.db, reset.organization_id).await?;
+        if !policy.allows(&input.new_password) {
+            return Err(ApiError::new(400, invalid_password_message(&policy)));
+        }
+
         let password_hash = hash_password(&input.new_password)?;
 
-        d1_execute(
+        let results = d1_batch(
             &ctx.data.db,
-            "UPDATE users SET password_hash = ?1 WHERE id = ?2",
             &[
-                D1Param::Text(password_hash),
-                D1Param::Integer(reset.user_id),
+                D1Statement::new(
+                    "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+                    vec![
+                        D1Param::Text(password_hash),
+                        D1Param::Integer(reset.user_id),
+                    ],
+                ),
+                D1Statement::new(
+                    "DELETE FROM password_resets WHERE id = ?1",
+                    vec![D1Param::Integer(reset.id)],
+                ),
+                // Whoever triggered the reset may not be the only one holding a session.
+                D1Statement::new(
+                    "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+                     WHERE user_id = ?1 AND revoked_at IS NULL",
+                    vec![D1Param::Integer(reset.user_id)],
+                ),
             ],
         )
         .await?;
-
-        let _ = d1_execute(
-            &ctx.data.db,
-            "DELETE FROM password_resets WHERE id = ?1",
-            &[D1Param::Integer(reset.id)],
-        )
-        .await;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
 
         json_with_status(&json!({ "status": "ok" }), 200)
     }
@@ -626,19 +1231,27 @@ pub async fn verify_email(mut req: Request, ctx: RouteContext<AppState>) -> Work
     };
 
     let result = async {
-        let verification_target = d1_query_one::<VerifyEmailRow>(
+        // Tokens are not tied to an account here, so only the client IP is limited.
+        let now = Utc::now().timestamp();
+        let attempt_keys = AttemptKeys::new("verify-email", None, client_ip(&req).as_deref());
+        ensure_not_locked(&ctx.data, &attempt_keys, now).await?;
+
+        let Some(verification_target) = d1_query_one::<VerifyEmailRow>(
             &ctx.data.db,
             "SELECT email, pending_email FROM users WHERE email_verification_token = ?1 LIMIT 1",
             &[D1Param::Text(input.token.clone())],
         )
         .await?
-        .ok_or_else(|| ApiError::new(404, "Invalid or expired verification token"))?;
+        else {
+            attempt_keys.record_failure(&ctx.data.attempts, now).await?;
+            return Err(ApiError::new(404, "Invalid or expired verification token"));
+        };
 
         if verification_target.pending_email.is_none() && verification_target.email.is_none() {
             return Err(ApiError::new(400, "No email to verify"));
         }
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE users
              SET email = COALESCE(pending_email, email),
@@ -649,6 +1262,9 @@ pub async fn verify_email(mut req: Request, ctx: RouteContext<AppState>) -> Work
             &[D1Param::Text(input.token.clone())],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "Invalid or expired verification token"));
+        }
 
         json_with_status(&json!({ "status": "ok" }), 200)
     }
@@ -668,7 +1284,7 @@ pub async fn resend_verification(
             &ctx.data.db,
             "SELECT email, pending_email, email_verified
              FROM users
-             WHERE id = ?1 AND organization_id = ?2
+             WHERE id = ?1 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)
              LIMIT 1",
             &[
                 D1Param::Integer(claims.user_id),
@@ -690,11 +1306,11 @@ pub async fn resend_verification(
 
         let token = uuid::Uuid::new_v4().to_string();
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE users
              SET email_verification_token = ?1
-             WHERE id = ?2 AND organization_id = ?3",
+             WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?3)",
             &[
                 D1Param::Text(token.clone()),
                 D1Param::Integer(claims.user_id),
@@ -702,6 +1318,9 @@ pub async fn resend_verification(
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
 
         ctx.data
             .email_service
@@ -715,3 +1334,408 @@ pub async fn resend_verification(
 
     result.or_else(db_error_to_response)
 }
+
+/// Second login step: exchanges a challenge from `login` and a TOTP or
+/// recovery code for a session.
+pub async fn verify_two_factor(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: VerifyTwoFactorInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let challenge = d1_query_one::<LoginChallengeRow>(
+            &ctx.data.db,
+            "SELECT c.id, c.user_id, COALESCE(c.organization_id, u.organization_id) AS organization_id
+             FROM login_challenges c
+             JOIN users u ON u.id = c.user_id
+             WHERE c.token_hash = ?1 AND datetime(c.expires_at) > datetime('now')
+             LIMIT 1",
+            &[D1Param::Text(hash_token(&input.challenge_token))],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(401, INVALID_CHALLENGE_MESSAGE))?;
+
+        // Challenges are cheap to obtain with the password, so failures are
+        // also charged to the user across challenges.
+        let now = Utc::now().timestamp();
+        let attempt_keys = AttemptKeys::new(
+            "two-factor",
+            Some(&challenge.user_id.to_string()),
+            client_ip(&req).as_deref(),
+        );
+        ensure_not_locked(&ctx.data, &attempt_keys, now).await?;
+
+        // Count the attempt before checking the code so concurrent guesses
+        // cannot get around the limit.
+        let counted = d1_execute(
+            &ctx.data.db,
+            "UPDATE login_challenges SET attempts = attempts + 1 WHERE id = ?1 AND attempts < ?2",
+            &[
+                D1Param::Integer(challenge.id),
+                D1Param::Integer(MAX_LOGIN_CHALLENGE_ATTEMPTS),
+            ],
+        )
+        .await?;
+        if counted.changes == 0 {
+            return Err(ApiError::new(401, INVALID_CHALLENGE_MESSAGE));
+        }
+
+        let verified = totp::consume_second_factor(
+            &ctx.data.db,
+            challenge.user_id,
+            input.code.as_deref(),
+            input.recovery_code.as_deref(),
+            now,
+        )
+        .await?;
+        if !verified {
+            let users = [(challenge.organization_id, challenge.user_id)];
+            record_failed_attempt(&ctx.data, &attempt_keys, now, &users).await?;
+            return Err(ApiError::new(401, INVALID_TWO_FACTOR_MESSAGE));
+        }
+        attempt_keys.clear_account(&ctx.data.attempts).await?;
+
+        d1_execute(
+            &ctx.data.db,
+            "DELETE FROM login_challenges
+             WHERE id = ?1 OR datetime(expires_at) <= datetime('now')",
+            &[D1Param::Integer(challenge.id)],
+        )
+        .await?;
+
+        let candidate = load_candidate(&ctx.data, challenge.organization_id, challenge.user_id)
+            .await?
+            .ok_or_else(|| ApiError::new(401, INVALID_CHALLENGE_MESSAGE))?;
+
+        let user = candidate.user;
+        let (token, refresh_token) = start_session(&req, &ctx.data, &user).await?;
+
+        json_with_status(
+            &LoginResponse {
+                token,
+                refresh_token,
+                user,
+                organization_slug: candidate.organization_slug,
+                two_factor_setup_required: candidate.two_factor_setup_required == 1,
+            },
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Starts single sign-on for an organization and returns the identity
+/// provider URL to redirect the browser to.
+pub async fn sso_start(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: SsoStartInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let organization = d1_query_one::<IdRow>(
+            &ctx.data.db,
+            "SELECT id FROM organizations WHERE slug = ?1 LIMIT 1",
+            &[D1Param::Text(input.organization.trim().to_lowercase())],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(404, SSO_NOT_AVAILABLE_MESSAGE))?;
+
+        let authorization_url = oidc::begin(
+            &ctx.data.db,
+            &*ctx.data.oidc_http,
+            organization.id,
+            &input.redirect_uri,
+        )
+        .await
+        .map_err(|e| match e {
+            OidcError::NotConfigured => ApiError::new(404, SSO_NOT_AVAILABLE_MESSAGE),
+            other => ApiError::new(other.status(), other.to_string()),
+        })?;
+
+        json_with_status(&SsoStartResponse { authorization_url }, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Completes single sign-on with the `code` and `state` the identity provider
+/// redirected back with; responds like `login`.
+pub async fn sso_callback(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: SsoCallbackInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let login = oidc::complete(&ctx.data.db, &*ctx.data.oidc_http, &input.state, &input.code)
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+
+        if login.provisioned {
+            let _ = d1_execute(
+                &ctx.data.db,
+                "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+                 VALUES (?1, ?2, 'user_provisioned', 'user', ?2, 'created by single sign-on')",
+                &[
+                    D1Param::Integer(login.organization_id),
+                    D1Param::Integer(login.user_id),
+                ],
+            )
+            .await;
+        }
+
+        let candidate = load_candidate(&ctx.data, login.organization_id, login.user_id)
+            .await?
+            .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+        finish_login(&req, &ctx.data, candidate).await
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn refresh(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: RefreshTokenInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let presented_hash = hash_token(&input.refresh_token);
+        let session = d1_query_one::<RefreshSessionRow>(
+            &ctx.data.db,
+            "SELECT id, user_id, organization_id, (refresh_token_hash = ?1) AS is_current
+             FROM sessions
+             WHERE (refresh_token_hash = ?1 OR previous_refresh_token_hash = ?1)
+               AND revoked_at IS NULL
+               AND datetime(expires_at) > datetime('now')
+             LIMIT 1",
+            &[D1Param::Text(presented_hash.clone())],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(401, "Invalid refresh token"))?;
+
+        // A rotated-out token coming back means it was copied; end the session
+        // so neither the thief nor the owner can keep using it.
+        if session.is_current == 0 {
+            d1_execute(
+                &ctx.data.db,
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                &[D1Param::Integer(session.id)],
+            )
+            .await?;
+            return Err(ApiError::new(401, "Invalid refresh token"));
+        }
+
+        let refresh_token = generate_token();
+        let rotated = d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions
+             SET previous_refresh_token_hash = refresh_token_hash,
+                 refresh_token_hash = ?1,
+                 last_used_at = CURRENT_TIMESTAMP
+             WHERE id = ?2 AND refresh_token_hash = ?3",
+            &[
+                D1Param::Text(hash_token(&refresh_token)),
+                D1Param::Integer(session.id),
+                D1Param::Text(presented_hash),
+            ],
+        )
+        .await?;
+        if rotated.changes == 0 {
+            return Err(ApiError::new(401, "Invalid refresh token"));
+        }
+
+        let user = memberships::load_member(&ctx.data.db, session.organization_id, session.user_id)
+            .await?
+            .filter(|user| user.deactivated_at.is_none())
+            .ok_or_else(|| ApiError::new(401, "Invalid refresh token"))?;
+
+        let token = encode_token(&ctx.data, &build_claims(&user, session.id))?;
+        json_with_status(
+            &RefreshResponse {
+                token,
+                refresh_token,
+            },
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn logout(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND revoked_at IS NULL",
+            &[D1Param::Integer(claims.sid)],
+        )
+        .await?;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn get_sessions(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let sessions = d1_query_all::<Session>(
+            &ctx.data.db,
+            "SELECT id, user_agent, ip_address, created_at, last_used_at, expires_at,
+                    (id = ?2) AS current
+             FROM sessions
+             WHERE user_id = ?1
+               AND revoked_at IS NULL
+               AND datetime(expires_at) > datetime('now')
+             ORDER BY last_used_at DESC, id DESC",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.sid),
+            ],
+        )
+        .await?;
+
+        json_with_status(&sessions, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Signs out every device, including the one making the request.
+pub async fn revoke_all_sessions(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let revoked = d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND revoked_at IS NULL",
+            &[D1Param::Integer(claims.user_id)],
+        )
+        .await?;
+
+        json_with_status(&json!({ "revoked": revoked.changes }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn revoke_session(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let session_id = ctx
+            .param("id")
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| ApiError::new(400, "Invalid session id"))?;
+
+        let revoked = d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND user_id = ?2 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(session_id),
+                D1Param::Integer(claims.user_id),
+            ],
+        )
+        .await?;
+        if revoked.changes == 0 {
+            return Err(ApiError::new(404, "Session not found"));
+        }
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Lists the organizations the caller belongs to, marking the one the
+/// current token is scoped to.
+pub async fn get_organizations(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let organizations =
+            memberships::list(&ctx.data.db, claims.user_id, claims.organization_id).await?;
+
+        json_with_status(&organizations, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Moves the caller to another of their organizations: the current session
+/// ends and a new one scoped to `organization_id` starts. Responds like
+/// `login`.
+pub async fn switch_organization(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: SwitchOrganizationInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let candidate = load_candidate(&ctx.data, input.organization_id, claims.user_id)
+            .await?
+            .ok_or_else(|| ApiError::new(404, "Organization not found"))?;
+        // Switching is not a way around an organization's SSO requirement.
+        if candidate.password_login_disabled == 1 {
+            return Err(ApiError::new(403, PASSWORD_LOGIN_DISABLED_MESSAGE));
+        }
+
+        let user = candidate.user;
+        let (token, refresh_token) = start_session(&req, &ctx.data, &user).await?;
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND revoked_at IS NULL",
+            &[D1Param::Integer(claims.sid)],
+        )
+        .await?;
+
+        json_with_status(
+            &LoginResponse {
+                token,
+                refresh_token,
+                user,
+                organization_slug: candidate.organization_slug,
+                two_factor_setup_required: candidate.two_factor_setup_required == 1,
+            },
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
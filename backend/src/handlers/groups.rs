@@ -1,12 +1,13 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    Claims, CreateDisplayGroupInput, D1Param, D1Row, DisplayGroup, ModelError, d1_execute,
-    d1_query_all, d1_query_one,
+    Claims, CreateDisplayGroupInput, D1Param, D1Statement, DisplayGroup, IdRow, ModelError,
+    PageCursor, PageQuery, Paginated, RoleRow, d1_batch, d1_query_all, d1_query_one,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::routing::{Request, Response, RouteContext};
 use serde::Serialize;
-use serde_json::Value;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use std::collections::HashMap;
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -51,36 +52,34 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
+/// Identifies the display group that member rows should point at.
+enum GroupRef {
+    Id(i64),
+    /// The group inserted with this `creation_key` by an earlier statement of
+    /// the same batch.
+    InsertedInBatch {
+        creation_key: String,
+    },
 }
 
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct GroupExistsRow {
-    #[allow(dead_code)]
-    id: i64,
-}
-
-impl crate::models::FromD1Row for GroupExistsRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let id = row
-            .get("id")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("id"))?;
-        Ok(Self { id })
-    }
+fn member_insert_statements(group_ref: &GroupRef, member_ids: &[i64]) -> Vec<D1Statement> {
+    member_ids
+        .iter()
+        .map(|member_id| match group_ref {
+            GroupRef::Id(group_id) => D1Statement::new(
+                "INSERT INTO display_group_members (group_id, member_id) VALUES (?1, ?2)",
+                vec![D1Param::Integer(*group_id), D1Param::Integer(*member_id)],
+            ),
+            GroupRef::InsertedInBatch { creation_key } => D1Statement::new(
+                "INSERT INTO display_group_members (group_id, member_id)
+                 VALUES ((SELECT id FROM display_groups WHERE creation_key = ?1), ?2)",
+                vec![
+                    D1Param::Text(creation_key.clone()),
+                    D1Param::Integer(*member_id),
+                ],
+            ),
+        })
+        .collect()
 }
 
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
@@ -119,21 +118,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -143,12 +159,46 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
+fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ApiError> {
+    let url = req
+        .url()
+        .map_err(|e| ApiError::new(400, format!("invalid url: {e}")))?;
+
+    let mut pairs = HashMap::new();
+    for (k, v) in url.query_pairs() {
+        pairs.insert(k.into_owned(), v.into_owned());
+    }
+    Ok(pairs)
+}
+
+fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i64>, ApiError> {
+    match value {
+        None => Ok(None),
+        Some(v) if v.trim().is_empty() => Ok(None),
+        Some(v) => v
+            .parse::<i64>()
+            .map(Some)
+            .map_err(|_| ApiError::new(400, format!("invalid {field}"))),
+    }
+}
+
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
+    let pairs = query_pairs(req)?;
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
 pub async fn get_display_groups(
     req: Request,
     ctx: RouteContext<AppState>,
 ) -> WorkerResult<Response> {
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
+        let page = parse_page_query(&req)?;
+        let [cursor_created_at, cursor_id] = page.cursor_params();
 
         let groups = d1_query_all::<DisplayGroup>(
             &ctx.data.db,
@@ -158,16 +208,26 @@ pub async fn get_display_groups(
              FROM display_groups g
              LEFT JOIN display_group_members m ON g.id = m.group_id
              WHERE g.organization_id = ?1 AND g.user_id = ?2
+               AND (?3 IS NULL OR (g.created_at, g.id) > (?3, ?4))
              GROUP BY g.id
-             ORDER BY g.name ASC",
+             ORDER BY g.created_at ASC, g.id ASC
+             LIMIT ?5",
             &[
                 D1Param::Integer(claims.organization_id),
                 D1Param::Integer(claims.user_id),
+                cursor_created_at,
+                cursor_id,
+                D1Param::Integer(page.fetch_limit()),
             ],
         )
         .await?;
 
-        json_with_status(&groups, 200)
+        json_with_status(
+            &Paginated::from_rows(groups, &page, |group| {
+                PageCursor::new(group.created_at.clone(), group.id)
+            }),
+            200,
+        )
     }
     .await;
 
@@ -186,16 +246,24 @@ pub async fn create_display_group(
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
 
-        d1_execute(
-            &ctx.data.db,
-            "INSERT INTO display_groups (organization_id, user_id, name) VALUES (?1, ?2, ?3)",
-            &[
+        let creation_key = uuid::Uuid::new_v4().to_string();
+        let mut statements = vec![D1Statement::new(
+            "INSERT INTO display_groups (organization_id, user_id, name, creation_key)
+             VALUES (?1, ?2, ?3, ?4)",
+            vec![
                 D1Param::Integer(claims.organization_id),
                 D1Param::Integer(claims.user_id),
                 D1Param::Text(input.name.clone()),
+                D1Param::Text(creation_key.clone()),
             ],
-        )
-        .await?;
+        )];
+        statements.extend(member_insert_statements(
+            &GroupRef::InsertedInBatch { creation_key },
+            &input.member_ids,
+        ));
+
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        let group_id = results[0].inserted_id()?;
 
         let group = d1_query_one::<DisplayGroup>(
             &ctx.data.db,
@@ -203,27 +271,13 @@ pub async fn create_display_group(
                     '' AS member_ids,
                     created_at
              FROM display_groups
-             WHERE organization_id = ?1 AND user_id = ?2 AND name = ?3
-             ORDER BY id DESC
+             WHERE id = ?1
              LIMIT 1",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
-                D1Param::Text(input.name.clone()),
-            ],
+            &[D1Param::Integer(group_id)],
         )
         .await?
         .ok_or_else(|| ApiError::internal("Failed to resolve created group"))?;
 
-        for member_id in &input.member_ids {
-            d1_execute(
-                &ctx.data.db,
-                "INSERT INTO display_group_members (group_id, member_id) VALUES (?1, ?2)",
-                &[D1Param::Integer(group.id), D1Param::Integer(*member_id)],
-            )
-            .await?;
-        }
-
         let result = DisplayGroup {
             member_ids: input.member_ids,
             ..group
@@ -253,7 +307,7 @@ pub async fn update_display_group(
             .parse::<i64>()
             .map_err(|_| ApiError::new(400, "Invalid group id"))?;
 
-        let exists = d1_query_one::<GroupExistsRow>(
+        let exists = d1_query_one::<IdRow>(
             &ctx.data.db,
             "SELECT id
              FROM display_groups
@@ -271,34 +325,31 @@ pub async fn update_display_group(
             return Err(ApiError::new(404, "Group not found"));
         }
 
-        d1_execute(
-            &ctx.data.db,
-            "UPDATE display_groups
-             SET name = ?1
-             WHERE id = ?2 AND organization_id = ?3 AND user_id = ?4",
-            &[
-                D1Param::Text(input.name.clone()),
-                D1Param::Integer(id),
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
-            ],
-        )
-        .await?;
-
-        d1_execute(
-            &ctx.data.db,
-            "DELETE FROM display_group_members WHERE group_id = ?1",
-            &[D1Param::Integer(id)],
-        )
-        .await?;
-
-        for member_id in &input.member_ids {
-            d1_execute(
-                &ctx.data.db,
-                "INSERT INTO display_group_members (group_id, member_id) VALUES (?1, ?2)",
-                &[D1Param::Integer(id), D1Param::Integer(*member_id)],
-            )
-            .await?;
+        let mut statements = vec![
+            D1Statement::new(
+                "UPDATE display_groups
+                 SET name = ?1
+                 WHERE id = ?2 AND organization_id = ?3 AND user_id = ?4",
+                vec![
+                    D1Param::Text(input.name.clone()),
+                    D1Param::Integer(id),
+                    D1Param::Integer(claims.organization_id),
+                    D1Param::Integer(claims.user_id),
+                ],
+            ),
+            D1Statement::new(
+                "DELETE FROM display_group_members WHERE group_id = ?1",
+                vec![D1Param::Integer(id)],
+            ),
+        ];
+        statements.extend(member_insert_statements(
+            &GroupRef::Id(id),
+            &input.member_ids,
+        ));
+
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(404, "Group not found"));
         }
 
         let mut group = d1_query_one::<DisplayGroup>(
@@ -340,7 +391,7 @@ pub async fn delete_display_group(
             .parse::<i64>()
             .map_err(|_| ApiError::new(400, "Invalid group id"))?;
 
-        let exists = d1_query_one::<GroupExistsRow>(
+        let exists = d1_query_one::<IdRow>(
             &ctx.data.db,
             "SELECT id
              FROM display_groups
@@ -358,23 +409,27 @@ pub async fn delete_display_group(
             return Err(ApiError::new(404, "Group not found"));
         }
 
-        d1_execute(
+        let results = d1_batch(
             &ctx.data.db,
-            "DELETE FROM display_group_members WHERE group_id = ?1",
-            &[D1Param::Integer(id)],
-        )
-        .await?;
-
-        d1_execute(
-            &ctx.data.db,
-            "DELETE FROM display_groups WHERE id = ?1 AND organization_id = ?2 AND user_id = ?3",
             &[
-                D1Param::Integer(id),
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
+                D1Statement::new(
+                    "DELETE FROM display_group_members WHERE group_id = ?1",
+                    vec![D1Param::Integer(id)],
+                ),
+                D1Statement::new(
+                    "DELETE FROM display_groups WHERE id = ?1 AND organization_id = ?2 AND user_id = ?3",
+                    vec![
+                        D1Param::Integer(id),
+                        D1Param::Integer(claims.organization_id),
+                        D1Param::Integer(claims.user_id),
+                    ],
+                ),
             ],
         )
         .await?;
+        if results[1].changes == 0 {
+            return Err(ApiError::new(404, "Group not found"));
+        }
 
         Ok(Response::empty()?.with_status(204))
     }
@@ -1,12 +1,18 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    Claims, CreateInvitationInput, D1Param, D1Row, Invitation, ModelError, d1_execute, d1_query_one,
+    Claims, CreateInvitationInput, D1Param, Invitation, InvitationSummary, ModelError, RoleRow,
+    d1_execute, d1_query_all, d1_query_one,
 };
+use crate::permissions::{self, Permission};
+use crate::routing::{Request, Response, RouteContext};
+use crate::settings::{self, MAX_INVITATION_EXPIRATION_DAYS};
+use crate::throttle::AttemptKeys;
+use crate::utils::is_valid_email;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
 use serde::Serialize;
-use serde_json::Value;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use serde_json::json;
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -17,6 +23,7 @@ struct ErrorBody {
 struct ApiError {
     status: u16,
     message: String,
+    retry_after: Option<i64>,
 }
 
 impl ApiError {
@@ -24,6 +31,7 @@ impl ApiError {
         Self {
             status,
             message: message.into(),
+            retry_after: None,
         }
     }
 
@@ -31,11 +39,24 @@ impl ApiError {
         Self::new(500, message)
     }
 
+    fn too_many_attempts(retry_after: i64) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(429, "Too many attempts. Please try again later.")
+        }
+    }
+
     fn into_response(self) -> WorkerResult<Response> {
-        Response::from_json(&ErrorBody {
+        let mut response = Response::from_json(&ErrorBody {
             error: self.message,
-        })
-        .map(|response| response.with_status(self.status))
+        })?
+        .with_status(self.status);
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .set("Retry-After", &seconds.to_string())?;
+        }
+        Ok(response)
     }
 }
 
@@ -51,22 +72,6 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -103,21 +108,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -127,6 +149,75 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
+async fn require_permission(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+    permission: Permission,
+) -> Result<Claims, ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    permissions::authorize(&ctx.data.db, &claims, permission)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok(claims)
+}
+
+const MAX_USES: i64 = 500;
+
+async fn log_activity_d1(
+    state: &AppState,
+    claims: &Claims,
+    action: &str,
+    invitation_id: i64,
+    details: Option<String>,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, 'invitation', ?4, ?5)",
+        &[
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Integer(invitation_id),
+            details.map(D1Param::Text).unwrap_or(D1Param::Null),
+        ],
+    )
+    .await;
+}
+
+pub async fn get_invitations(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageInvitations).await?;
+
+        let invitations = d1_query_all::<InvitationSummary>(
+            &ctx.data.db,
+            "SELECT i.id, i.token, i.role, i.email, i.max_uses, i.created_by, u.name AS created_by_name,
+                    i.expires_at, i.revoked_at, i.created_at,
+                    COUNT(r.id) AS use_count,
+                    json_group_array(r.user_id) FILTER (WHERE r.user_id IS NOT NULL) AS redeemed_by,
+                    CASE
+                        WHEN i.revoked_at IS NOT NULL THEN 'revoked'
+                        WHEN COUNT(r.id) >= i.max_uses THEN 'used'
+                        WHEN datetime(i.expires_at) <= datetime('now') THEN 'expired'
+                        ELSE 'active'
+                    END AS status
+             FROM invitations i
+             LEFT JOIN invitation_redemptions r ON r.invitation_id = i.id
+             LEFT JOIN users u ON u.id = i.created_by
+             WHERE i.organization_id = ?1
+             GROUP BY i.id
+             ORDER BY i.created_at DESC, i.id DESC",
+            &[D1Param::Integer(claims.organization_id)],
+        )
+        .await?;
+
+        json_with_status(&invitations, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
 pub async fn create_invitation(
     mut req: Request,
     ctx: RouteContext<AppState>,
@@ -137,42 +228,84 @@ pub async fn create_invitation(
     };
 
     let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Only admins can create invitations"));
+        let claims = require_permission(&req, &ctx, Permission::ManageInvitations).await?;
+        permissions::authorize_grant(&ctx.data.db, &claims, &input.role)
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+
+        let email = input
+            .email
+            .as_deref()
+            .map(|email| email.trim().to_lowercase())
+            .filter(|email| !email.is_empty());
+        if email.as_deref().is_some_and(|email| !is_valid_email(email)) {
+            return Err(ApiError::new(400, "Invalid email address"));
+        }
+        let expires_in_days = match input.expires_in_days {
+            Some(days) => days,
+            None => {
+                settings::load(&ctx.data.db, claims.organization_id)
+                    .await?
+                    .ok_or_else(|| ApiError::new(404, "Organization not found"))?
+                    .invitation_expiration_days
+            }
+        };
+        if !(1..=MAX_INVITATION_EXPIRATION_DAYS).contains(&expires_in_days) {
+            return Err(ApiError::new(
+                400,
+                format!("expires_in_days must be between 1 and {MAX_INVITATION_EXPIRATION_DAYS}"),
+            ));
+        }
+        let max_uses = input.max_uses.unwrap_or(1);
+        if !(1..=MAX_USES).contains(&max_uses) {
+            return Err(ApiError::new(
+                400,
+                format!("max_uses must be between 1 and {MAX_USES}"),
+            ));
+        }
+        if email.is_some() && max_uses > 1 {
+            return Err(ApiError::new(
+                400,
+                "An invitation bound to an email address can only be used once",
+            ));
         }
 
         let token = uuid::Uuid::new_v4().to_string();
-        let expires_at = (Utc::now() + Duration::days(7))
+        let expires_at = (Utc::now() + Duration::days(expires_in_days))
             .format("%Y-%m-%d %H:%M:%S")
             .to_string();
 
-        d1_execute(
+        let invitation_id = d1_execute(
             &ctx.data.db,
-            "INSERT INTO invitations (organization_id, token, role, expires_at)
-             VALUES (?1, ?2, ?3, ?4)",
+            "INSERT INTO invitations (organization_id, token, role, expires_at, email, max_uses, created_by)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             &[
                 D1Param::Integer(claims.organization_id),
                 D1Param::Text(token.clone()),
                 D1Param::Text(input.role.clone()),
                 D1Param::Text(expires_at),
+                email.clone().map(D1Param::Text).unwrap_or(D1Param::Null),
+                D1Param::Integer(max_uses),
+                D1Param::Integer(claims.user_id),
             ],
         )
-        .await?;
+        .await?
+        .inserted_id()?;
 
         let invitation = d1_query_one::<Invitation>(
             &ctx.data.db,
-            "SELECT i.id, i.organization_id, o.name AS org_name, i.token, i.role, i.expires_at, i.created_at
+            "SELECT i.id, i.organization_id, o.name AS org_name, o.slug AS org_slug, i.token, i.role,
+                    i.email, i.max_uses, 0 AS use_count, i.expires_at, i.created_at
              FROM invitations i
              JOIN organizations o ON i.organization_id = o.id
-             WHERE i.token = ?1
+             WHERE i.id = ?1
              LIMIT 1",
-            &[D1Param::Text(token.clone())],
+            &[D1Param::Integer(invitation_id)],
         )
         .await?
         .ok_or_else(|| ApiError::internal("Failed to resolve created invitation"))?;
 
-        if let Some(email) = &input.email {
+        if let Some(email) = &email {
             ctx.data
                 .email_service
                 .send_invitation_email(
@@ -187,6 +320,19 @@ pub async fn create_invitation(
                 .map_err(ApiError::internal)?;
         }
 
+        log_activity_d1(
+            &ctx.data,
+            &claims,
+            "invitation_created",
+            invitation.id,
+            Some(format!(
+                "role={}, max_uses={max_uses}, expires_in_days={expires_in_days}{}",
+                invitation.role,
+                if email.is_some() { ", email-bound" } else { "" }
+            )),
+        )
+        .await;
+
         json_with_status(&invitation, 201)
     }
     .await;
@@ -194,25 +340,78 @@ pub async fn create_invitation(
     result.or_else(db_error_to_response)
 }
 
-pub async fn get_invitation(_req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+pub async fn revoke_invitation(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageInvitations).await?;
+        let id = ctx
+            .param("id")
+            .ok_or_else(|| ApiError::new(400, "Missing invitation id"))?
+            .parse::<i64>()
+            .map_err(|_| ApiError::new(400, "Invalid invitation id"))?;
+
+        let revoked = d1_execute(
+            &ctx.data.db,
+            "UPDATE invitations SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND organization_id = ?2 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+        if revoked.changes == 0 {
+            return Err(ApiError::new(404, "Invitation not found"));
+        }
+
+        log_activity_d1(&ctx.data, &claims, "invitation_revoked", id, None).await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn get_invitation(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
     let result = async {
         let token = ctx
             .param("token")
             .ok_or_else(|| ApiError::new(400, "Missing invitation token"))?
             .to_string();
 
-        let invitation = d1_query_one::<Invitation>(
+        // Guessing tokens is only limited per client IP; there is no account yet.
+        let now = Utc::now().timestamp();
+        let client_ip = req.headers().get("CF-Connecting-IP").ok().flatten();
+        let attempt_keys = AttemptKeys::new("invitation", None, client_ip.as_deref());
+        if let Some(seconds) = attempt_keys.retry_after(&ctx.data.attempts, now).await? {
+            return Err(ApiError::too_many_attempts(seconds));
+        }
+
+        let Some(invitation) = d1_query_one::<Invitation>(
             &ctx.data.db,
-            "SELECT i.id, i.organization_id, o.name AS org_name, i.token, i.role, i.expires_at, i.created_at
+            "SELECT i.id, i.organization_id, o.name AS org_name, o.slug AS org_slug, i.token, i.role,
+                    i.email, i.max_uses, COUNT(r.id) AS use_count, i.expires_at, i.created_at
              FROM invitations i
              JOIN organizations o ON i.organization_id = o.id
+             LEFT JOIN invitation_redemptions r ON r.invitation_id = i.id
              WHERE i.token = ?1
+               AND i.revoked_at IS NULL
                AND datetime(i.expires_at) > datetime('now')
+             GROUP BY i.id
+             HAVING COUNT(r.id) < i.max_uses
              LIMIT 1",
             &[D1Param::Text(token)],
         )
         .await?
-        .ok_or_else(|| ApiError::new(404, "Invalid or expired invitation token"))?;
+        else {
+            attempt_keys
+                .record_failure(&ctx.data.attempts, now)
+                .await?;
+            return Err(ApiError::new(404, "Invalid or expired invitation token"));
+        };
 
         json_with_status(&invitation, 200)
     }
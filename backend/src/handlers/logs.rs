@@ -1,13 +1,15 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    ActivityLog, Claims, D1Param, D1Row, LogQuery, ModelError, PaginatedLogs, d1_query_all,
-    d1_query_one,
+    ActivityLog, Claims, D1Param, LogQuery, ModelError, PageCursor, PageQuery, Paginated, RoleRow,
+    d1_query_all, d1_query_one,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::routing::{Request, Response, RouteContext};
+use crate::timezone;
+use chrono::Duration;
 use serde::Serialize;
-use serde_json::Value;
 use std::collections::HashMap;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -52,37 +54,6 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct CountRow {
-    count: i64,
-}
-
-impl crate::models::FromD1Row for CountRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let count = row
-            .get("count")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("count"))?;
-        Ok(Self { count })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -115,21 +86,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -143,7 +131,7 @@ fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ApiError> {
     let url = req
         .url()
         .map_err(|e| ApiError::new(400, format!("invalid url: {e}")))?;
-    
+
     let mut pairs = HashMap::new();
     for (k, v) in url.query_pairs() {
         pairs.insert(k.into_owned(), v.into_owned());
@@ -162,11 +150,18 @@ fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i
     }
 }
 
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
+    let pairs = query_pairs(req)?;
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
 fn parse_log_query(req: &Request) -> Result<LogQuery, ApiError> {
     let pairs = query_pairs(req)?;
     Ok(LogQuery {
-        page: parse_i64_opt(pairs.get("page"), "page")?,
-        per_page: parse_i64_opt(pairs.get("per_page"), "per_page")?,
         user_id: parse_i64_opt(pairs.get("user_id"), "user_id")?,
         start_date: pairs
             .get("start_date")
@@ -183,8 +178,30 @@ fn parse_log_query(req: &Request) -> Result<LogQuery, ApiError> {
     })
 }
 
-fn validate_date_range(query: &LogQuery) -> Result<(), ApiError> {
-    if let (Some(start), Some(end)) = (&query.start_date, &query.end_date)
+/// The UTC bounds of the requested local days: `start` is inclusive, `end`
+/// exclusive.
+struct CreatedAtRange {
+    start: Option<String>,
+    end: Option<String>,
+}
+
+async fn created_at_range(
+    ctx: &RouteContext<AppState>,
+    claims: &Claims,
+    query: &LogQuery,
+) -> Result<CreatedAtRange, ApiError> {
+    let parse = |value: &Option<String>, field: &str| {
+        value
+            .as_deref()
+            .map(|v| {
+                timezone::parse_date(v)
+                    .ok_or_else(|| ApiError::new(400, format!("invalid {field}")))
+            })
+            .transpose()
+    };
+    let start_date = parse(&query.start_date, "start_date")?;
+    let end_date = parse(&query.end_date, "end_date")?;
+    if let (Some(start), Some(end)) = (start_date, end_date)
         && start > end
     {
         return Err(ApiError::new(
@@ -192,10 +209,21 @@ fn validate_date_range(query: &LogQuery) -> Result<(), ApiError> {
             "start_date must be before or equal to end_date",
         ));
     }
-    Ok(())
+
+    let tz = timezone::for_member(&ctx.data.db, claims.organization_id, claims.user_id).await?;
+    Ok(CreatedAtRange {
+        start: start_date.map(|date| timezone::day_start(tz, date)),
+        end: end_date.map(|date| timezone::day_start(tz, date + Duration::days(1))),
+    })
 }
 
-fn append_log_filters(sql: &mut String, params: &mut Vec<D1Param>, query: &LogQuery, org_id: i64) {
+fn append_log_filters(
+    sql: &mut String,
+    params: &mut Vec<D1Param>,
+    query: &LogQuery,
+    range: &CreatedAtRange,
+    org_id: i64,
+) {
     sql.push_str(" WHERE l.organization_id = ?");
     params.push(D1Param::Integer(org_id));
 
@@ -204,14 +232,14 @@ fn append_log_filters(sql: &mut String, params: &mut Vec<D1Param>, query: &LogQu
         params.push(D1Param::Integer(user_id));
     }
 
-    if let Some(start_date) = &query.start_date {
-        sql.push_str(" AND date(l.created_at) >= ?");
-        params.push(D1Param::Text(start_date.clone()));
+    if let Some(start) = &range.start {
+        sql.push_str(" AND datetime(l.created_at) >= ?");
+        params.push(D1Param::Text(start.clone()));
     }
 
-    if let Some(end_date) = &query.end_date {
-        sql.push_str(" AND date(l.created_at) <= ?");
-        params.push(D1Param::Text(end_date.clone()));
+    if let Some(end) = &range.end {
+        sql.push_str(" AND datetime(l.created_at) < ?");
+        params.push(D1Param::Text(end.clone()));
     }
 
     if let Some(action) = &query.action {
@@ -255,11 +283,9 @@ pub async fn get_logs(req: Request, ctx: RouteContext<AppState>) -> WorkerResult
     let result: Result<Response, ApiError> = async {
         let claims = extract_claims(&req, &ctx).await?;
         let query = parse_log_query(&req)?;
-        validate_date_range(&query)?;
+        let range = created_at_range(&ctx, &claims, &query).await?;
 
-        let page = query.page.unwrap_or(1).max(1);
-        let per_page = query.per_page.unwrap_or(20).clamp(1, 100);
-        let offset = (page - 1) * per_page;
+        let page = parse_page_query(&req)?;
 
         let mut sql = String::from(
             "SELECT l.id, l.organization_id, l.user_id, u.name AS user_name,
@@ -268,40 +294,26 @@ pub async fn get_logs(req: Request, ctx: RouteContext<AppState>) -> WorkerResult
              JOIN users u ON l.user_id = u.id",
         );
         let mut params = Vec::new();
-        append_log_filters(&mut sql, &mut params, &query, claims.organization_id);
-        sql.push_str(" ORDER BY l.created_at DESC LIMIT ? OFFSET ?");
-        params.push(D1Param::Integer(per_page));
-        params.push(D1Param::Integer(offset));
-
-        let items = d1_query_all::<ActivityLog>(&ctx.data.db, &sql, &params).await?;
-
-        let mut total_sql = String::from("SELECT COUNT(*) AS count FROM activity_logs l");
-        let mut total_params = Vec::new();
         append_log_filters(
-            &mut total_sql,
-            &mut total_params,
+            &mut sql,
+            &mut params,
             &query,
+            &range,
             claims.organization_id,
         );
+        if page.cursor.is_some() {
+            sql.push_str(" AND (l.created_at, l.id) < (?, ?)");
+            params.extend(page.cursor_params());
+        }
+        sql.push_str(" ORDER BY l.created_at DESC, l.id DESC LIMIT ?");
+        params.push(D1Param::Integer(page.fetch_limit()));
 
-        let total = d1_query_one::<CountRow>(&ctx.data.db, &total_sql, &total_params)
-            .await?
-            .ok_or_else(|| ApiError::internal("failed to count activity logs"))?
-            .count;
-
-        let total_pages = if total == 0 {
-            0
-        } else {
-            (total + per_page - 1) / per_page
-        };
+        let rows = d1_query_all::<ActivityLog>(&ctx.data.db, &sql, &params).await?;
 
         json_with_status(
-            &PaginatedLogs {
-                items,
-                total,
-                page,
-                total_pages,
-            },
+            &Paginated::from_rows(rows, &page, |log| {
+                PageCursor::new(log.created_at.clone(), log.id)
+            }),
             200,
         )
     }
@@ -314,7 +326,7 @@ pub async fn export_logs(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
     let result: Result<Response, ApiError> = async {
         let claims = extract_claims(&req, &ctx).await?;
         let query = parse_log_query(&req)?;
-        validate_date_range(&query)?;
+        let range = created_at_range(&ctx, &claims, &query).await?;
 
         let mut sql = String::from(
             "SELECT l.id, l.organization_id, l.user_id, u.name AS user_name,
@@ -323,8 +335,14 @@ pub async fn export_logs(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
              JOIN users u ON l.user_id = u.id",
         );
         let mut params = Vec::new();
-        append_log_filters(&mut sql, &mut params, &query, claims.organization_id);
-        sql.push_str(" ORDER BY l.created_at DESC");
+        append_log_filters(
+            &mut sql,
+            &mut params,
+            &query,
+            &range,
+            claims.organization_id,
+        );
+        sql.push_str(" ORDER BY l.created_at DESC, l.id DESC");
 
         let items = d1_query_all::<ActivityLog>(&ctx.data.db, &sql, &params).await?;
         let csv = logs_to_csv(&items);
@@ -1,13 +1,14 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    Claims, D1Param, D1Row, ModelError, Notification, NotificationQuery, PaginatedNotifications,
+    Claims, D1Param, ModelError, Notification, PageCursor, PageQuery, Paginated, RoleRow,
     d1_execute, d1_query_all, d1_query_one,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::routing::{Request, Response, RouteContext};
 use serde::Serialize;
-use serde_json::{Value, json};
+use serde_json::json;
 use std::collections::HashMap;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -52,37 +53,6 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct CountRow {
-    count: i64,
-}
-
-impl crate::models::FromD1Row for CountRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let count = row
-            .get("count")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("count"))?;
-        Ok(Self { count })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -119,21 +89,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -176,12 +163,13 @@ fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i
     }
 }
 
-fn parse_notification_query(req: &Request) -> Result<NotificationQuery, ApiError> {
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
     let pairs = query_pairs(req)?;
-    Ok(NotificationQuery {
-        page: parse_i64_opt(pairs.get("page"), "page")?,
-        per_page: parse_i64_opt(pairs.get("per_page"), "per_page")?,
-    })
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
 }
 
 pub async fn get_notifications(
@@ -190,59 +178,33 @@ pub async fn get_notifications(
 ) -> WorkerResult<Response> {
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
-        let query = parse_notification_query(&req)?;
-
-        let page = query.page.unwrap_or(1).max(1);
-        let per_page = query.per_page.unwrap_or(20).clamp(1, 100);
-        let offset = (page - 1) * per_page;
+        let page = parse_page_query(&req)?;
+        let [cursor_created_at, cursor_id] = page.cursor_params();
 
-        let items = d1_query_all::<Notification>(
+        let rows = d1_query_all::<Notification>(
             &ctx.data.db,
             "SELECT id, organization_id, user_id, title, body, category, target_type, target_id, is_read, created_at
              FROM notifications
              WHERE organization_id = ?1
                AND user_id = ?2
                AND (is_read = 0 OR datetime(created_at) >= datetime('now', '-30 days'))
-             ORDER BY is_read ASC, created_at DESC
-             LIMIT ?3 OFFSET ?4",
+               AND (?3 IS NULL OR (created_at, id) < (?3, ?4))
+             ORDER BY created_at DESC, id DESC
+             LIMIT ?5",
             &[
                 D1Param::Integer(claims.organization_id),
                 D1Param::Integer(claims.user_id),
-                D1Param::Integer(per_page),
-                D1Param::Integer(offset),
+                cursor_created_at,
+                cursor_id,
+                D1Param::Integer(page.fetch_limit()),
             ],
         )
         .await?;
 
-        let total = d1_query_one::<CountRow>(
-            &ctx.data.db,
-            "SELECT COUNT(*) AS count
-             FROM notifications
-             WHERE organization_id = ?1
-               AND user_id = ?2
-               AND (is_read = 0 OR datetime(created_at) >= datetime('now', '-30 days'))",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
-            ],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("failed to count notifications"))?
-        .count;
-
-        let total_pages = if total == 0 {
-            0
-        } else {
-            (total + per_page - 1) / per_page
-        };
-
         json_with_status(
-            &PaginatedNotifications {
-                items,
-                total,
-                page,
-                total_pages,
-            },
+            &Paginated::from_rows(rows, &page, |n| {
+                PageCursor::new(n.created_at.clone(), n.id)
+            }),
             200,
         )
     }
@@ -260,25 +222,7 @@ pub async fn mark_as_read(req: Request, ctx: RouteContext<AppState>) -> WorkerRe
             .parse::<i64>()
             .map_err(|_| ApiError::new(400, "Invalid notification id"))?;
 
-        let existing = d1_query_one::<Notification>(
-            &ctx.data.db,
-            "SELECT id, organization_id, user_id, title, body, category, target_type, target_id, is_read, created_at
-             FROM notifications
-             WHERE id = ?1 AND organization_id = ?2 AND user_id = ?3
-             LIMIT 1",
-            &[
-                D1Param::Integer(id),
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
-            ],
-        )
-        .await?;
-
-        if existing.is_none() {
-            return Err(ApiError::new(404, "Notification not found"));
-        }
-
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE notifications
              SET is_read = 1
@@ -290,6 +234,9 @@ pub async fn mark_as_read(req: Request, ctx: RouteContext<AppState>) -> WorkerRe
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "Notification not found"));
+        }
 
         let notification = d1_query_one::<Notification>(
             &ctx.data.db,
@@ -317,21 +264,7 @@ pub async fn mark_all_as_read(req: Request, ctx: RouteContext<AppState>) -> Work
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
 
-        let unread = d1_query_one::<CountRow>(
-            &ctx.data.db,
-            "SELECT COUNT(*) AS count
-             FROM notifications
-             WHERE organization_id = ?1 AND user_id = ?2 AND is_read = 0",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(claims.user_id),
-            ],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("failed to count unread notifications"))?
-        .count;
-
-        d1_execute(
+        let unread = d1_execute(
             &ctx.data.db,
             "UPDATE notifications
              SET is_read = 1
@@ -341,7 +274,8 @@ pub async fn mark_all_as_read(req: Request, ctx: RouteContext<AppState>) -> Work
                 D1Param::Integer(claims.user_id),
             ],
         )
-        .await?;
+        .await?
+        .changes;
 
         json_with_status(&json!({ "updated": unread }), 200)
     }
@@ -0,0 +1,716 @@
+use crate::AppState;
+use crate::api_tokens;
+use crate::models::{
+    Claims, CountRow, CreateScimTokenInput, CreatedScimTokenResponse, D1Param, D1Statement,
+    ModelError, OrganizationSettings, RoleRow, ScimToken, SecuritySettings, SsoSettings,
+    UpdateOrganizationSettingsInput, UpdateSecuritySettingsInput, UpdateSsoSettingsInput, d1_batch,
+    d1_execute, d1_query_all, d1_query_one,
+};
+use crate::oidc;
+use crate::permissions::{self, Permission};
+use crate::routing::{Request, Response, RouteContext};
+use crate::scim;
+use crate::settings::{
+    self, MAX_INVITATION_EXPIRATION_DAYS, MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH,
+};
+use crate::utils::{is_valid_display_name, is_valid_slug, is_valid_timezone};
+use serde::Serialize;
+use serde_json::json;
+use worker::Result as WorkerResult;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+
+    fn into_response(self) -> WorkerResult<Response> {
+        Response::from_json(&ErrorBody {
+            error: self.message,
+        })
+        .map(|response| response.with_status(self.status))
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(value: ModelError) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<worker::Error> for ApiError {
+    fn from(value: worker::Error) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
+    Response::from_json(value)
+        .map(|response| response.with_status(status))
+        .map_err(ApiError::from)
+}
+
+fn db_error_to_response(err: ApiError) -> WorkerResult<Response> {
+    err.into_response()
+}
+
+fn extract_bearer_token(req: &Request) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.to_string()));
+
+    if header_token.is_some() {
+        return header_token;
+    }
+
+    req.url().ok().and_then(|url| {
+        url.query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(k, v)| (k == "token" && !v.is_empty()).then_some(v.to_string()))
+        })
+    })
+}
+
+async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<Claims, ApiError> {
+    let token = extract_bearer_token(req)
+        .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
+
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
+
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
+    let latest_role = d1_query_one::<RoleRow>(
+        &ctx.data.db,
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+    claims.role = latest_role.role;
+    Ok(claims)
+}
+
+async fn require_permission(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+    permission: Permission,
+) -> Result<Claims, ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    permissions::authorize(&ctx.data.db, &claims, permission)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok(claims)
+}
+
+async fn load_security_settings(
+    state: &AppState,
+    organization_id: i64,
+) -> Result<SecuritySettings, ApiError> {
+    d1_query_one::<SecuritySettings>(
+        &state.db,
+        "SELECT require_admin_2fa FROM organizations WHERE id = ?1 LIMIT 1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(404, "Organization not found"))
+}
+
+pub async fn get_security_settings(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let settings = load_security_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn update_security_settings(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: UpdateSecuritySettingsInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+
+        // Requiring 2FA without having it would demote the caller on their
+        // next request and leave nobody able to undo the change.
+        if input.require_admin_2fa {
+            let enrolled = d1_query_one::<CountRow>(
+                &ctx.data.db,
+                "SELECT totp_enabled AS count FROM users WHERE id = ?1 LIMIT 1",
+                &[D1Param::Integer(claims.user_id)],
+            )
+            .await?
+            .is_some_and(|row| row.count == 1);
+            if !enrolled {
+                return Err(ApiError::new(
+                    409,
+                    "Enable two-factor authentication on your own account first",
+                ));
+            }
+        }
+
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE organizations SET require_admin_2fa = ?1 WHERE id = ?2",
+            &[
+                D1Param::Integer(input.require_admin_2fa as i64),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+
+        let _ = d1_execute(
+            &ctx.data.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+             VALUES (?1, ?2, 'security_settings_updated', 'organization', ?1, ?3)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Text(format!("require_admin_2fa={}", input.require_admin_2fa)),
+            ],
+        )
+        .await;
+
+        let settings = load_security_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+async fn load_settings(
+    state: &AppState,
+    organization_id: i64,
+) -> Result<OrganizationSettings, ApiError> {
+    settings::load(&state.db, organization_id)
+        .await?
+        .ok_or_else(|| ApiError::new(404, "Organization not found"))
+}
+
+/// Validates a settings update into the columns it changes.
+fn settings_changes(
+    input: UpdateOrganizationSettingsInput,
+) -> Result<Vec<(&'static str, D1Param)>, ApiError> {
+    let mut changes = Vec::new();
+    if let Some(name) = input.name {
+        let name = name.trim().to_string();
+        if !is_valid_display_name(&name) {
+            return Err(ApiError::new(400, "name must be 1-50 characters"));
+        }
+        changes.push(("name", D1Param::Text(name)));
+    }
+    if let Some(slug) = input.slug {
+        let slug = slug.trim().to_string();
+        if !is_valid_slug(&slug) {
+            return Err(ApiError::new(
+                400,
+                "slug must be 3-40 lowercase letters, digits or hyphens",
+            ));
+        }
+        changes.push(("slug", D1Param::Text(slug)));
+    }
+    if let Some(timezone) = input.timezone {
+        let timezone = timezone.trim().to_string();
+        if !is_valid_timezone(&timezone) {
+            return Err(ApiError::new(
+                400,
+                "timezone must be an IANA timezone such as Asia/Tokyo",
+            ));
+        }
+        changes.push(("timezone", D1Param::Text(timezone)));
+    }
+    if let Some(day) = input.week_start_day {
+        let day = day.trim().to_lowercase();
+        if settings::parse_weekday(&day).is_none() {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "week_start_day must be one of {}",
+                    settings::WEEKDAYS.join(", ")
+                ),
+            ));
+        }
+        changes.push(("week_start_day", D1Param::Text(day)));
+    }
+    let invalid_status = || {
+        ApiError::new(
+            400,
+            format!(
+                "Task statuses must be one of {}",
+                settings::TASK_STATUSES.join(", ")
+            ),
+        )
+    };
+    if let Some(status) = input.default_task_status {
+        let status = status.trim().to_string();
+        if !settings::is_task_status(&status) {
+            return Err(invalid_status());
+        }
+        changes.push(("default_task_status", D1Param::Text(status)));
+    }
+    for (column, statuses) in [
+        ("report_statuses", input.report_statuses),
+        ("closed_task_statuses", input.closed_task_statuses),
+    ] {
+        let Some(statuses) = statuses else {
+            continue;
+        };
+        let mut normalized: Vec<String> = Vec::new();
+        for status in statuses {
+            let status = status.trim().to_string();
+            if !settings::is_task_status(&status) {
+                return Err(invalid_status());
+            }
+            if !normalized.contains(&status) {
+                normalized.push(status);
+            }
+        }
+        if normalized.is_empty() {
+            return Err(ApiError::new(400, format!("{column} must not be empty")));
+        }
+        let encoded =
+            serde_json::to_string(&normalized).map_err(|e| ApiError::internal(e.to_string()))?;
+        changes.push((column, D1Param::Text(encoded)));
+    }
+    if let Some(days) = input.invitation_expiration_days {
+        if !(1..=MAX_INVITATION_EXPIRATION_DAYS).contains(&days) {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "invitation_expiration_days must be between 1 and {MAX_INVITATION_EXPIRATION_DAYS}"
+                ),
+            ));
+        }
+        changes.push(("invitation_expiration_days", D1Param::Integer(days)));
+    }
+    if let Some(policy) = input.password_policy {
+        if let Some(min_length) = policy.min_length {
+            if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&min_length) {
+                return Err(ApiError::new(
+                    400,
+                    format!(
+                        "password_policy.min_length must be between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH}"
+                    ),
+                ));
+            }
+            changes.push(("password_min_length", D1Param::Integer(min_length)));
+        }
+        for (column, required) in [
+            ("password_require_uppercase", policy.require_uppercase),
+            ("password_require_lowercase", policy.require_lowercase),
+            ("password_require_digit", policy.require_digit),
+            ("password_require_symbol", policy.require_symbol),
+        ] {
+            if let Some(required) = required {
+                changes.push((column, D1Param::Integer(required as i64)));
+            }
+        }
+    }
+    if changes.is_empty() {
+        return Err(ApiError::new(400, "No organization settings to update"));
+    }
+    Ok(changes)
+}
+
+pub async fn get_organization(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let settings = load_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn update_organization(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: UpdateOrganizationSettingsInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let changes = settings_changes(input)?;
+
+        if let Some((_, D1Param::Text(slug))) = changes.iter().find(|(column, _)| *column == "slug")
+        {
+            let taken = d1_query_one::<CountRow>(
+                &ctx.data.db,
+                "SELECT COUNT(*) AS count FROM organizations WHERE slug = ?1 AND id != ?2",
+                &[
+                    D1Param::Text(slug.clone()),
+                    D1Param::Integer(claims.organization_id),
+                ],
+            )
+            .await?
+            .is_some_and(|row| row.count > 0);
+            if taken {
+                return Err(ApiError::new(409, "This slug is already in use"));
+            }
+        }
+
+        let assignments = changes
+            .iter()
+            .enumerate()
+            .map(|(i, (column, _))| format!("{column} = ?{}", i + 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut params = vec![D1Param::Integer(claims.organization_id)];
+        params.extend(changes.iter().map(|(_, value)| value.clone()));
+        d1_execute(
+            &ctx.data.db,
+            &format!("UPDATE organizations SET {assignments} WHERE id = ?1"),
+            &params,
+        )
+        .await?;
+
+        let changed = changes
+            .iter()
+            .map(|(column, _)| *column)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = d1_execute(
+            &ctx.data.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+             VALUES (?1, ?2, 'organization_settings_updated', 'organization', ?1, ?3)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Text(changed),
+            ],
+        )
+        .await;
+
+        let settings = load_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+async fn load_sso_settings(
+    state: &AppState,
+    organization_id: i64,
+) -> Result<SsoSettings, ApiError> {
+    d1_query_one::<SsoSettings>(
+        &state.db,
+        "SELECT p.issuer, p.client_id, (p.client_secret IS NOT NULL AND p.client_secret != '') AS has_client_secret,
+                COALESCE(p.allowed_domains, '[]') AS allowed_domains, COALESCE(p.enabled, 0) AS enabled,
+                o.password_login_disabled
+         FROM organizations o
+         LEFT JOIN sso_providers p ON p.organization_id = o.id
+         WHERE o.id = ?1
+         LIMIT 1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(404, "Organization not found"))
+}
+
+pub async fn get_sso_settings(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let settings = load_sso_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn update_sso_settings(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: UpdateSsoSettingsInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let current = load_sso_settings(&ctx.data, claims.organization_id).await?;
+
+        let issuer = oidc::validate_url("issuer", &input.issuer)
+            .map_err(|e| ApiError::new(400, e.to_string()))?
+            .trim_end_matches('/')
+            .to_string();
+        let client_id = input.client_id.trim().to_string();
+        if client_id.is_empty() {
+            return Err(ApiError::new(400, "client_id is required"));
+        }
+        let client_secret = input
+            .client_secret
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string);
+        if client_secret.is_none() && current.has_client_secret == 0 {
+            return Err(ApiError::new(400, "client_secret is required"));
+        }
+        let allowed_domains = oidc::normalize_domains(&input.allowed_domains)
+            .map_err(|e| ApiError::new(400, e.to_string()))?;
+        if input.password_login_disabled && !input.enabled {
+            return Err(ApiError::new(
+                400,
+                "Password login can only be disabled while single sign-on is enabled",
+            ));
+        }
+
+        // Catch a mistyped issuer now rather than on the first sign-in.
+        if input.enabled {
+            oidc::discover(&*ctx.data.oidc_http, &issuer)
+                .await
+                .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+        }
+
+        d1_batch(
+            &ctx.data.db,
+            &[
+                D1Statement::new(
+                    "INSERT INTO sso_providers (organization_id, issuer, client_id, client_secret, allowed_domains, enabled)
+                     VALUES (?1, ?2, ?3, COALESCE(?4, ''), ?5, ?6)
+                     ON CONFLICT (organization_id) DO UPDATE SET
+                         issuer = excluded.issuer,
+                         client_id = excluded.client_id,
+                         client_secret = COALESCE(?4, sso_providers.client_secret),
+                         allowed_domains = excluded.allowed_domains,
+                         enabled = excluded.enabled,
+                         updated_at = CURRENT_TIMESTAMP",
+                    vec![
+                        D1Param::Integer(claims.organization_id),
+                        D1Param::Text(issuer),
+                        D1Param::Text(client_id),
+                        client_secret.map(D1Param::Text).unwrap_or(D1Param::Null),
+                        D1Param::Text(serde_json::to_string(&allowed_domains).map_err(ModelError::from)?),
+                        D1Param::Integer(input.enabled as i64),
+                    ],
+                ),
+                D1Statement::new(
+                    "UPDATE organizations SET password_login_disabled = ?1 WHERE id = ?2",
+                    vec![
+                        D1Param::Integer(input.password_login_disabled as i64),
+                        D1Param::Integer(claims.organization_id),
+                    ],
+                ),
+            ],
+        )
+        .await?;
+
+        let _ = d1_execute(
+            &ctx.data.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+             VALUES (?1, ?2, 'sso_settings_updated', 'organization', ?1, ?3)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Text(format!(
+                    "enabled={}, password_login_disabled={}",
+                    input.enabled, input.password_login_disabled
+                )),
+            ],
+        )
+        .await;
+
+        let settings = load_sso_settings(&ctx.data, claims.organization_id).await?;
+        json_with_status(&settings, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+const SCIM_TOKEN_COLUMNS: &str =
+    "t.id, t.name, u.name AS created_by_name, t.last_used_at, t.created_at";
+
+pub async fn get_scim_tokens(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+
+        let tokens = d1_query_all::<ScimToken>(
+            &ctx.data.db,
+            &format!(
+                "SELECT {SCIM_TOKEN_COLUMNS}
+                 FROM scim_tokens t
+                 LEFT JOIN users u ON u.id = t.created_by
+                 WHERE t.organization_id = ?1 AND t.revoked_at IS NULL
+                 ORDER BY t.created_at DESC, t.id DESC"
+            ),
+            &[D1Param::Integer(claims.organization_id)],
+        )
+        .await?;
+
+        json_with_status(&tokens, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// The directory acts as the admin who creates the token.
+pub async fn create_scim_token(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: CreateScimTokenInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > scim::MAX_TOKEN_NAME_LENGTH {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "Token name must be 1 to {} characters",
+                    scim::MAX_TOKEN_NAME_LENGTH
+                ),
+            ));
+        }
+
+        let (token_id, secret) =
+            scim::create_token(&ctx.data.db, claims.organization_id, claims.user_id, name).await?;
+        let token = d1_query_one::<ScimToken>(
+            &ctx.data.db,
+            &format!(
+                "SELECT {SCIM_TOKEN_COLUMNS}
+                 FROM scim_tokens t
+                 LEFT JOIN users u ON u.id = t.created_by
+                 WHERE t.id = ?1
+                 LIMIT 1"
+            ),
+            &[D1Param::Integer(token_id)],
+        )
+        .await?
+        .ok_or_else(|| ApiError::internal("Failed to load created token"))?;
+
+        let _ = d1_execute(
+            &ctx.data.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+             VALUES (?1, ?2, 'scim_token_created', 'scim_token', ?3, ?4)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(token.id),
+                D1Param::Text(token.name.clone()),
+            ],
+        )
+        .await;
+
+        json_with_status(&CreatedScimTokenResponse { token, secret }, 201)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn revoke_scim_token(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let token_id = ctx
+            .param("id")
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| ApiError::new(400, "Invalid token id"))?;
+
+        let revoked = d1_execute(
+            &ctx.data.db,
+            "UPDATE scim_tokens SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND organization_id = ?2 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(token_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+        if revoked.changes == 0 {
+            return Err(ApiError::new(404, "Token not found"));
+        }
+
+        let _ = d1_execute(
+            &ctx.data.db,
+            "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id)
+             VALUES (?1, ?2, 'scim_token_revoked', 'scim_token', ?3)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(token_id),
+            ],
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
@@ -1,13 +1,15 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    Claims, CreateReportInput, D1Param, D1Row, DailyReport, ModelError, ReportQuery,
-    UpdateReportInput, d1_execute, d1_query_all, d1_query_one,
+    Claims, CreateReportInput, D1Param, DailyReport, ModelError, PageCursor, PageQuery, Paginated,
+    ReportQuery, RoleRow, UpdateReportInput, d1_execute, d1_query_all, d1_query_one,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::permissions::{self, Permission};
+use crate::routing::{Request, Response, RouteContext};
 use serde::Serialize;
-use serde_json::{Value, json};
+use serde_json::json;
 use std::collections::HashMap;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -52,22 +54,6 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -104,21 +90,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -161,6 +164,15 @@ fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i
     }
 }
 
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
+    let pairs = query_pairs(req)?;
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
 fn parse_report_query(req: &Request) -> Result<ReportQuery, ApiError> {
     let pairs = query_pairs(req)?;
     Ok(ReportQuery {
@@ -198,6 +210,8 @@ pub async fn get_reports(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
         let params = parse_report_query(&req)?;
+        let page = parse_page_query(&req)?;
+        let [cursor_created_at, cursor_id] = page.cursor_params();
 
         let reports = d1_query_all::<DailyReport>(
             &ctx.data.db,
@@ -206,7 +220,9 @@ pub async fn get_reports(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
              WHERE organization_id = ?1
                AND (?2 IS NULL OR report_date = ?2)
                AND (?3 IS NULL OR user_id = ?3)
-             ORDER BY report_date DESC, created_at DESC",
+               AND (?4 IS NULL OR (created_at, id) < (?4, ?5))
+             ORDER BY created_at DESC, id DESC
+             LIMIT ?6",
             &[
                 D1Param::Integer(claims.organization_id),
                 params.date.map(D1Param::Text).unwrap_or(D1Param::Null),
@@ -214,11 +230,19 @@ pub async fn get_reports(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
                     .user_id
                     .map(D1Param::Integer)
                     .unwrap_or(D1Param::Null),
+                cursor_created_at,
+                cursor_id,
+                D1Param::Integer(page.fetch_limit()),
             ],
         )
         .await?;
 
-        json_with_status(&reports, 200)
+        json_with_status(
+            &Paginated::from_rows(reports, &page, |report| {
+                PageCursor::new(report.created_at.clone(), report.id)
+            }),
+            200,
+        )
     }
     .await;
 
@@ -346,11 +370,14 @@ pub async fn update_report(
         .await?
         .ok_or_else(|| ApiError::new(404, "Report not found"))?;
 
-        if report.user_id != claims.user_id && claims.role != "admin" {
+        if report.user_id != claims.user_id
+            && !permissions::has_permission(&ctx.data.db, &claims, Permission::EditAllReports)
+                .await?
+        {
             return Err(ApiError::new(403, "You can only edit your own reports"));
         }
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE daily_reports
              SET content = ?1
@@ -362,6 +389,9 @@ pub async fn update_report(
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "Report not found"));
+        }
 
         let updated_report = d1_query_one::<DailyReport>(
             &ctx.data.db,
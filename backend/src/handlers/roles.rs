@@ -0,0 +1,380 @@
+use crate::AppState;
+use crate::api_tokens;
+use crate::models::{
+    Claims, CountRow, CreateRoleInput, D1Param, ModelError, Role, RoleRow, UpdateRoleInput,
+    d1_execute, d1_query_one,
+};
+use crate::permissions::{self, Permission};
+use crate::routing::{Request, Response, RouteContext};
+use serde::Serialize;
+use serde_json::json;
+use worker::Result as WorkerResult;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+
+    fn into_response(self) -> WorkerResult<Response> {
+        Response::from_json(&ErrorBody {
+            error: self.message,
+        })
+        .map(|response| response.with_status(self.status))
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(value: ModelError) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<worker::Error> for ApiError {
+    fn from(value: worker::Error) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
+    Response::from_json(value)
+        .map(|response| response.with_status(status))
+        .map_err(ApiError::from)
+}
+
+fn db_error_to_response(err: ApiError) -> WorkerResult<Response> {
+    err.into_response()
+}
+
+fn extract_bearer_token(req: &Request) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.to_string()));
+
+    if header_token.is_some() {
+        return header_token;
+    }
+
+    req.url().ok().and_then(|url| {
+        url.query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(k, v)| (k == "token" && !v.is_empty()).then_some(v.to_string()))
+        })
+    })
+}
+
+async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<Claims, ApiError> {
+    let token = extract_bearer_token(req)
+        .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
+
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
+
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
+    let latest_role = d1_query_one::<RoleRow>(
+        &ctx.data.db,
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+    claims.role = latest_role.role;
+    Ok(claims)
+}
+
+async fn require_permission(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+    permission: Permission,
+) -> Result<Claims, ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    permissions::authorize(&ctx.data.db, &claims, permission)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok(claims)
+}
+
+async fn log_activity_d1(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    action: &str,
+    details: String,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, 'role', NULL, ?4)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Text(details),
+        ],
+    )
+    .await;
+}
+
+fn role_name_param(ctx: &RouteContext<AppState>) -> Result<String, ApiError> {
+    let name = ctx
+        .param("name")
+        .ok_or_else(|| ApiError::new(400, "Missing role name"))?;
+    if permissions::is_builtin_role(name) {
+        return Err(ApiError::new(400, "Built-in roles cannot be changed"));
+    }
+    Ok(name.to_string())
+}
+
+/// Parses requested permissions and checks the caller holds all of them.
+async fn grantable_permissions(
+    ctx: &RouteContext<AppState>,
+    claims: &Claims,
+    values: &[String],
+) -> Result<Vec<Permission>, ApiError> {
+    let requested =
+        permissions::parse_permissions(values).map_err(|e| ApiError::new(400, e.to_string()))?;
+    let held = permissions::permissions_of(&ctx.data.db, claims).await?;
+    if !permissions::can_grant(&held, &requested) {
+        return Err(ApiError::new(
+            403,
+            "You cannot grant permissions you do not have",
+        ));
+    }
+    Ok(requested)
+}
+
+fn permission_names(permissions: &[Permission]) -> Vec<&'static str> {
+    permissions.iter().map(|p| p.as_str()).collect()
+}
+
+pub async fn get_roles(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let roles = permissions::list_roles(&ctx.data.db, claims.organization_id).await?;
+        json_with_status(&roles, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn create_role(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: CreateRoleInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+
+        let name = input.name.trim().to_string();
+        if !permissions::is_valid_role_name(&name) {
+            return Err(ApiError::new(
+                400,
+                "Role names must be 3-30 characters of lowercase letters, digits, '_' or '-'",
+            ));
+        }
+        if permissions::role_exists(&ctx.data.db, claims.organization_id, &name).await? {
+            return Err(ApiError::new(409, "Role already exists"));
+        }
+        let granted = grantable_permissions(&ctx, &claims, &input.permissions).await?;
+
+        d1_execute(
+            &ctx.data.db,
+            "INSERT INTO roles (organization_id, name, permissions) VALUES (?1, ?2, ?3)",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Text(name.clone()),
+                D1Param::Text(
+                    serde_json::to_string(&permission_names(&granted)).map_err(ModelError::from)?,
+                ),
+            ],
+        )
+        .await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "role_created",
+            format!("{name}: {}", permission_names(&granted).join(",")),
+        )
+        .await;
+
+        json_with_status(
+            &Role {
+                name,
+                permissions: granted,
+                builtin: false,
+            },
+            201,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn update_role(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: UpdateRoleInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let name = role_name_param(&ctx)?;
+
+        let previous = permissions::role_permissions(&ctx.data.db, claims.organization_id, &name)
+            .await?
+            .ok_or_else(|| ApiError::new(404, "Role not found"))?;
+        // Taking a permission away is as privileged as granting it.
+        let held = permissions::permissions_of(&ctx.data.db, &claims).await?;
+        if !permissions::can_grant(&held, &previous) {
+            return Err(ApiError::new(
+                403,
+                "You cannot change a role with permissions you do not have",
+            ));
+        }
+        let granted = grantable_permissions(&ctx, &claims, &input.permissions).await?;
+
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE roles SET permissions = ?1, updated_at = CURRENT_TIMESTAMP
+             WHERE organization_id = ?2 AND name = ?3",
+            &[
+                D1Param::Text(
+                    serde_json::to_string(&permission_names(&granted)).map_err(ModelError::from)?,
+                ),
+                D1Param::Integer(claims.organization_id),
+                D1Param::Text(name.clone()),
+            ],
+        )
+        .await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "role_updated",
+            format!("{name}: {}", permission_names(&granted).join(",")),
+        )
+        .await;
+
+        json_with_status(
+            &Role {
+                name,
+                permissions: granted,
+                builtin: false,
+            },
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn delete_role(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageOrganization).await?;
+        let name = role_name_param(&ctx)?;
+
+        let in_use = d1_query_one::<CountRow>(
+            &ctx.data.db,
+            "SELECT
+                 (SELECT COUNT(*) FROM memberships WHERE organization_id = ?1 AND role = ?2)
+               + (SELECT COUNT(*) FROM invitations
+                  WHERE organization_id = ?1 AND role = ?2 AND revoked_at IS NULL
+                    AND datetime(expires_at) > datetime('now')) AS count",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Text(name.clone()),
+            ],
+        )
+        .await?
+        .map(|row| row.count)
+        .unwrap_or(0);
+        if in_use > 0 {
+            return Err(ApiError::new(
+                409,
+                "Role is still assigned to members or pending invitations",
+            ));
+        }
+
+        let deleted = d1_execute(
+            &ctx.data.db,
+            "DELETE FROM roles WHERE organization_id = ?1 AND name = ?2",
+            &[
+                D1Param::Integer(claims.organization_id),
+                D1Param::Text(name.clone()),
+            ],
+        )
+        .await?;
+        if deleted.changes == 0 {
+            return Err(ApiError::new(404, "Role not found"));
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "role_deleted",
+            name,
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
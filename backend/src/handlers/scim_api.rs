@@ -0,0 +1,406 @@
+use crate::AppState;
+use crate::models::{D1Param, d1_execute};
+use crate::provisioning;
+use crate::routing::{Request, Response, RouteContext};
+use crate::scim::{self, ScimClient, ScimError, UserAttributes};
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::Result as WorkerResult;
+
+fn scim_response(value: &Value, status: u16) -> WorkerResult<Response> {
+    let mut response = Response::from_json(value)?.with_status(status);
+    response
+        .headers_mut()
+        .set("Content-Type", scim::CONTENT_TYPE)?;
+    Ok(response)
+}
+
+fn scim_error_to_response(err: ScimError) -> WorkerResult<Response> {
+    let mut response = scim_response(&err.to_body(), err.status())?;
+    if matches!(err, ScimError::Unauthorized) {
+        response
+            .headers_mut()
+            .set("WWW-Authenticate", "Bearer realm=\"scim\"")?;
+    }
+    Ok(response)
+}
+
+/// Directories send the token in the header only; query strings end up in
+/// their logs.
+async fn authenticate(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+) -> Result<ScimClient, ScimError> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.trim().to_string()))
+        .ok_or(ScimError::Unauthorized)?;
+    scim::authenticate(&ctx.data.db, &token).await
+}
+
+fn base_url(req: &Request) -> Result<String, ScimError> {
+    req.url()
+        .map(|url| url.origin().ascii_serialization())
+        .map_err(|e| ScimError::InvalidSyntax(format!("invalid url: {e}")))
+}
+
+fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ScimError> {
+    let url = req
+        .url()
+        .map_err(|e| ScimError::InvalidSyntax(format!("invalid url: {e}")))?;
+
+    let mut pairs = HashMap::new();
+    for (k, v) in url.query_pairs() {
+        pairs.insert(k.into_owned(), v.into_owned());
+    }
+    Ok(pairs)
+}
+
+async fn json_body(req: &mut Request) -> Result<Value, ScimError> {
+    let body = req
+        .text()
+        .await
+        .map_err(|e| ScimError::InvalidSyntax(e.to_string()))?;
+    serde_json::from_str(&body).map_err(|e| ScimError::InvalidSyntax(e.to_string()))
+}
+
+fn user_id_param(ctx: &RouteContext<AppState>) -> Result<i64, ScimError> {
+    ctx.param("id")
+        .and_then(|id| id.parse::<i64>().ok())
+        .ok_or_else(|| ScimError::NotFound("User".to_string()))
+}
+
+fn group_param(ctx: &RouteContext<AppState>) -> Result<String, ScimError> {
+    ctx.param("id")
+        .cloned()
+        .ok_or_else(|| ScimError::NotFound("Group".to_string()))
+}
+
+async fn log_activity_d1(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    action: &str,
+    target_type: &str,
+    target_id: Option<i64>,
+    details: Option<String>,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Text(target_type.to_string()),
+            target_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
+            details.map(D1Param::Text).unwrap_or(D1Param::Null),
+        ],
+    )
+    .await;
+}
+
+/// Logs a user change made through SCIM, including any (de)activation.
+async fn log_user_update(
+    state: &AppState,
+    client: &ScimClient,
+    user_id: i64,
+    activation: Option<bool>,
+) {
+    let action = match activation {
+        Some(true) => "scim_user_reactivated",
+        Some(false) => "scim_user_deactivated",
+        None => "scim_user_updated",
+    };
+    log_activity_d1(
+        state,
+        client.organization_id,
+        client.acting_user_id,
+        action,
+        "user",
+        Some(user_id),
+        None,
+    )
+    .await;
+}
+
+pub async fn service_provider_config(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        authenticate(&req, &ctx).await?;
+        Ok(scim::service_provider_config())
+    }
+    .await;
+
+    match result {
+        Ok(config) => scim_response(&config, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn list_users(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let pairs = query_pairs(&req)?;
+        let filter = pairs
+            .get("filter")
+            .map(|f| scim::parse_filter(f))
+            .transpose()?;
+        let (start_index, count) = scim::page_params(
+            pairs.get("startIndex").map(String::as_str),
+            pairs.get("count").map(String::as_str),
+        );
+
+        let (total, users) = scim::list_users(
+            &ctx.data.db,
+            client.organization_id,
+            filter.as_ref(),
+            start_index,
+            count,
+        )
+        .await?;
+        let base_url = base_url(&req)?;
+        Ok(scim::list_response(
+            users.iter().map(|u| u.to_resource(&base_url)).collect(),
+            total,
+            start_index,
+        ))
+    }
+    .await;
+
+    match result {
+        Ok(list) => scim_response(&list, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn get_user(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let user =
+            scim::get_user(&ctx.data.db, client.organization_id, user_id_param(&ctx)?).await?;
+        Ok(user.to_resource(&base_url(&req)?))
+    }
+    .await;
+
+    match result {
+        Ok(user) => scim_response(&user, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn create_user(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let attributes = UserAttributes::from_resource(&json_body(&mut req).await?)?;
+        let (user, provisioned) =
+            scim::create_user(&ctx.data.db, client.organization_id, attributes).await?;
+
+        // Directories that sign members in through SSO never need the link;
+        // send_welcome_emails skips organizations without password login.
+        if user.deactivated_at.is_none() {
+            provisioning::send_welcome_emails(
+                &ctx.data.db,
+                ctx.data.email_service.as_ref(),
+                client.organization_id,
+                &[provisioned],
+            )
+            .await?;
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            client.organization_id,
+            client.acting_user_id,
+            "scim_user_created",
+            "user",
+            Some(user.id),
+            None,
+        )
+        .await;
+
+        Ok(user.to_resource(&base_url(&req)?))
+    }
+    .await;
+
+    match result {
+        Ok(user) => scim_response(&user, 201),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn replace_user(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let id = user_id_param(&ctx)?;
+        let attributes = UserAttributes::from_resource(&json_body(&mut req).await?)?;
+        let (user, activation) =
+            scim::update_user(&ctx.data.db, client.organization_id, id, attributes).await?;
+        log_user_update(&ctx.data, &client, id, activation).await;
+        Ok(user.to_resource(&base_url(&req)?))
+    }
+    .await;
+
+    match result {
+        Ok(user) => scim_response(&user, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn patch_user(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let id = user_id_param(&ctx)?;
+        let patch = json_body(&mut req).await?;
+        let (user, activation) =
+            scim::patch_user(&ctx.data.db, client.organization_id, id, &patch).await?;
+        log_user_update(&ctx.data, &client, id, activation).await;
+        Ok(user.to_resource(&base_url(&req)?))
+    }
+    .await;
+
+    match result {
+        Ok(user) => scim_response(&user, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+/// Deactivates rather than deletes, so the member's tasks and reports stay.
+pub async fn delete_user(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let id = user_id_param(&ctx)?;
+        let user = scim::get_user(&ctx.data.db, client.organization_id, id).await?;
+        if user.deactivated_at.is_none() {
+            scim::set_active(&ctx.data.db, client.organization_id, id, false).await?;
+            log_user_update(&ctx.data, &client, id, Some(false)).await;
+        }
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+async fn group_resource(
+    ctx: &RouteContext<AppState>,
+    client: &ScimClient,
+    role: &str,
+    base_url: &str,
+) -> Result<Value, ScimError> {
+    let members = scim::group_members(&ctx.data.db, client.organization_id, role).await?;
+    Ok(scim::group_resource(role, &members, base_url))
+}
+
+pub async fn list_groups(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        let pairs = query_pairs(&req)?;
+        let filter = pairs
+            .get("filter")
+            .map(|f| scim::parse_filter(f))
+            .transpose()?;
+        let roles =
+            scim::list_groups(&ctx.data.db, client.organization_id, filter.as_ref()).await?;
+        let base_url = base_url(&req)?;
+
+        let mut groups = Vec::with_capacity(roles.len());
+        for role in &roles {
+            groups.push(group_resource(&ctx, &client, role, &base_url).await?);
+        }
+        Ok(scim::list_response(groups, roles.len() as i64, 1))
+    }
+    .await;
+
+    match result {
+        Ok(list) => scim_response(&list, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn get_group(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let client = authenticate(&req, &ctx).await?;
+        group_resource(&ctx, &client, &group_param(&ctx)?, &base_url(&req)?).await
+    }
+    .await;
+
+    match result {
+        Ok(group) => scim_response(&group, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+async fn update_group(
+    req: &mut Request,
+    ctx: &RouteContext<AppState>,
+    replace: bool,
+) -> Result<Value, ScimError> {
+    let client = authenticate(req, ctx).await?;
+    let role = group_param(ctx)?;
+    let body = json_body(req).await?;
+    let members = if replace {
+        scim::group_members_from_resource(&role, &body)?
+    } else {
+        let mut members =
+            scim::current_group_member_ids(&ctx.data.db, client.organization_id, &role).await?;
+        scim::patch_members(&role, &mut members, &body)?;
+        members
+    };
+
+    let changed =
+        scim::set_group_members(&ctx.data.db, client.organization_id, &role, &members).await?;
+    if changed > 0 {
+        log_activity_d1(
+            &ctx.data,
+            client.organization_id,
+            client.acting_user_id,
+            "scim_group_updated",
+            "role",
+            None,
+            Some(format!("{role}: {changed} member(s) changed")),
+        )
+        .await;
+    }
+    group_resource(ctx, &client, &role, &base_url(req)?).await
+}
+
+pub async fn replace_group(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    match update_group(&mut req, &ctx, true).await {
+        Ok(group) => scim_response(&group, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+pub async fn patch_group(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    match update_group(&mut req, &ctx, false).await {
+        Ok(group) => scim_response(&group, 200),
+        Err(err) => scim_error_to_response(err),
+    }
+}
+
+/// Roles are created and deleted in the app.
+pub async fn unsupported_group_change(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let err = match authenticate(&req, &ctx).await {
+        Ok(_) => ScimError::NotImplemented(
+            "Groups are the organization's roles; create and delete them in the app".to_string(),
+        ),
+        Err(err) => err,
+    };
+    scim_error_to_response(err)
+}
@@ -1,15 +1,20 @@
 use crate::AppState;
+use crate::api_tokens;
 use crate::models::{
-    AddTimeLogInput, Claims, CreateTaskInput, D1Param, D1Row, GetTasksQuery, ModelError, Task,
-    TaskReportQuery, TaskReportRow, TaskTimeLog, UpdateTaskInput, UpdateTimeLogInput, d1_execute,
-    d1_query_all, d1_query_one,
+    AddTimeLogInput, Claims, CountRow, CreateTaskInput, D1Param, D1Statement, GetTasksQuery,
+    ModelError, OrganizationSettings, PageCursor, PageQuery, Paginated, RoleRow, Task,
+    TaskReportQuery, TaskReportRow, TaskTimeLog, UpdateTaskInput, UpdateTimeLogInput, d1_batch,
+    d1_execute, d1_query_all, d1_query_one,
 };
-use chrono::{DateTime, Duration, FixedOffset, Utc};
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::permissions::{self, Permission, Scope};
+use crate::routing::{Request, Response, RouteContext};
+use crate::settings;
+use crate::timezone;
+use chrono::{DateTime, Duration, FixedOffset};
 use serde::Serialize;
-use serde_json::{Value, json};
+use serde_json::json;
 use std::collections::HashMap;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -54,139 +59,6 @@ impl From<worker::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct CountRow {
-    count: i64,
-}
-
-impl crate::models::FromD1Row for CountRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let count = row
-            .get("count")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("count"))?;
-        Ok(Self { count })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct IdRow {
-    id: i64,
-}
-
-impl crate::models::FromD1Row for IdRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let id = row
-            .get("id")
-            .and_then(Value::as_i64)
-            .ok_or(ModelError::MissingField("id"))?;
-        Ok(Self { id })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
-struct ReportFlatRow {
-    id: i64,
-    organization_id: i64,
-    member_id: i64,
-    title: String,
-    description: Option<String>,
-    status: String,
-    progress_rate: i64,
-    tags: Option<Vec<String>>,
-    created_at: String,
-    updated_at: Option<String>,
-    total_duration_minutes: i64,
-    user_name: String,
-    start_at: Option<String>,
-    end_at: Option<String>,
-}
-
-impl crate::models::FromD1Row for ReportFlatRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let tags = match row.get("tags") {
-            None | Some(Value::Null) => None,
-            Some(Value::String(raw)) if raw.trim().is_empty() => Some(vec![]),
-            Some(Value::String(raw)) => {
-                Some(raw.split(',').map(|v| v.trim().to_string()).collect())
-            }
-            Some(Value::Array(items)) => {
-                let mut parsed = Vec::with_capacity(items.len());
-                for item in items {
-                    let text = item.as_str().ok_or(ModelError::InvalidType {
-                        field: "tags",
-                        expected: "array<string>",
-                    })?;
-                    parsed.push(text.to_string());
-                }
-                Some(parsed)
-            }
-            Some(_) => {
-                return Err(ModelError::InvalidType {
-                    field: "tags",
-                    expected: "string|array<string>|null",
-                });
-            }
-        };
-
-        let required_i64 = |field: &'static str| {
-            row.get(field)
-                .and_then(Value::as_i64)
-                .ok_or(ModelError::MissingField(field))
-        };
-        let required_text = |field: &'static str| {
-            row.get(field)
-                .and_then(Value::as_str)
-                .map(|v| v.to_string())
-                .ok_or(ModelError::MissingField(field))
-        };
-        let optional_text = |field: &'static str| match row.get(field) {
-            None | Some(Value::Null) => Ok(None),
-            Some(Value::String(v)) => Ok(Some(v.clone())),
-            _ => Err(ModelError::InvalidType {
-                field,
-                expected: "text|null",
-            }),
-        };
-
-        Ok(Self {
-            id: required_i64("id")?,
-            organization_id: required_i64("organization_id")?,
-            member_id: required_i64("member_id")?,
-            title: required_text("title")?,
-            description: optional_text("description")?,
-            status: required_text("status")?,
-            progress_rate: required_i64("progress_rate")?,
-            tags,
-            created_at: required_text("created_at")?,
-            updated_at: optional_text("updated_at")?,
-            total_duration_minutes: row
-                .get("total_duration_minutes")
-                .and_then(Value::as_i64)
-                .unwrap_or(0),
-            user_name: required_text("user_name")?,
-            start_at: optional_text("start_at")?,
-            end_at: optional_text("end_at")?,
-        })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -197,7 +69,7 @@ fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ApiError> {
     let url = req
         .url()
         .map_err(|e| ApiError::new(400, format!("invalid url: {e}")))?;
-    
+
     let mut pairs = HashMap::new();
     for (k, v) in url.query_pairs() {
         pairs.insert(k.into_owned(), v.into_owned());
@@ -216,6 +88,15 @@ fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i
     }
 }
 
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
+    let pairs = query_pairs(req)?;
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
 fn parse_get_tasks_query(req: &Request) -> Result<GetTasksQuery, ApiError> {
     let pairs = query_pairs(req)?;
     Ok(GetTasksQuery {
@@ -224,6 +105,7 @@ fn parse_get_tasks_query(req: &Request) -> Result<GetTasksQuery, ApiError> {
         q: pairs.get("q").cloned(),
         date: pairs.get("date").cloned(),
         status: pairs.get("status").cloned(),
+        due: pairs.get("due").cloned(),
     })
 }
 
@@ -264,21 +146,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -288,14 +187,39 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
-async fn user_in_organization(
+/// Claims of a caller who may read the task report, and how much of it: a
+/// manager sees their team only.
+async fn require_report_scope(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+) -> Result<(Claims, Scope), ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    let scope = permissions::authorize_scope(&ctx.data.db, &claims, Permission::ViewTaskReport)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok((claims, scope))
+}
+
+async fn load_settings(
+    state: &AppState,
+    organization_id: i64,
+) -> Result<OrganizationSettings, ApiError> {
+    settings::load(&state.db, organization_id)
+        .await?
+        .ok_or_else(|| ApiError::new(404, "Organization not found"))
+}
+
+/// Whether new work can be assigned to or logged for the user; deactivated
+/// members only keep their history.
+async fn active_user_in_organization(
     state: &AppState,
     organization_id: i64,
     user_id: i64,
 ) -> Result<bool, ApiError> {
     let row = d1_query_one::<CountRow>(
         &state.db,
-        "SELECT COUNT(*) AS count FROM users WHERE id = ?1 AND organization_id = ?2",
+        "SELECT COUNT(*) AS count FROM memberships
+         WHERE user_id = ?1 AND organization_id = ?2 AND deactivated_at IS NULL",
         &[D1Param::Integer(user_id), D1Param::Integer(organization_id)],
     )
     .await?
@@ -321,6 +245,35 @@ fn parse_iso_datetime(input: &str, field: &'static str) -> Result<DateTime<Fixed
         .map_err(|_| ApiError::new(400, format!("{field} must be RFC3339 datetime")))
 }
 
+/// Normalizes a `due_date` input; an empty string stays empty, which clears
+/// the due date on update.
+fn parse_due_date(input: Option<&String>) -> Result<Option<String>, ApiError> {
+    input
+        .map(|value| {
+            if value.trim().is_empty() {
+                return Ok(String::new());
+            }
+            timezone::parse_date(value)
+                .map(|date| date.to_string())
+                .ok_or_else(|| ApiError::new(400, "due_date must be a YYYY-MM-DD date"))
+        })
+        .transpose()
+}
+
+/// A full year in minutes; anything larger is a typo.
+const MAX_ESTIMATED_MINUTES: i64 = 525_600;
+
+/// `0` is allowed and clears the estimate on update.
+fn validate_estimated_minutes(input: Option<i64>) -> Result<Option<i64>, ApiError> {
+    match input {
+        Some(minutes) if !(0..=MAX_ESTIMATED_MINUTES).contains(&minutes) => Err(ApiError::new(
+            400,
+            format!("estimated_minutes must be between 0 and {MAX_ESTIMATED_MINUTES}"),
+        )),
+        _ => Ok(input),
+    }
+}
+
 fn csv_escape(value: &str) -> String {
     if value.contains([',', '"', '\n', '\r']) {
         format!("\"{}\"", value.replace('"', "\"\""))
@@ -331,8 +284,13 @@ fn csv_escape(value: &str) -> String {
 
 fn task_report_to_csv(rows: &[TaskReportRow]) -> String {
     let mut csv = String::from(
-        "担当者,タスク名,ステータス,進捗率,タグ,開始日時,終了日時,合計時間(時間)\n",
+        "担当者,タスク名,ステータス,進捗率,タグ,開始日時,終了日時,合計時間(時間),期限,見積(時間),見積差(時間)\n",
     );
+    let hours = |minutes: Option<i64>| {
+        minutes
+            .map(|minutes| format!("{:.2}", minutes as f64 / 60.0))
+            .unwrap_or_default()
+    };
 
     for row in rows {
         let tags = row
@@ -341,9 +299,10 @@ fn task_report_to_csv(rows: &[TaskReportRow]) -> String {
             .as_ref()
             .map(|v| v.join("|"))
             .unwrap_or_default();
+        let total_hours = format!("{:.2}", row.task.total_duration_minutes as f64 / 60.0);
 
         csv.push_str(&format!(
-            "{},{},{},{},{},{},{},{}\n",
+            "{},{},{},{},{},{},{},{},{},{},{}\n",
             csv_escape(&row.user_name),
             csv_escape(&row.task.title),
             csv_escape(&row.task.status),
@@ -351,7 +310,10 @@ fn task_report_to_csv(rows: &[TaskReportRow]) -> String {
             csv_escape(&tags),
             csv_escape(row.start_at.as_deref().unwrap_or("")),
             csv_escape(row.end_at.as_deref().unwrap_or("")),
-            format!("{:.2}", row.task.total_duration_minutes as f64 / 60.0),
+            total_hours,
+            row.task.due_date.as_deref().unwrap_or(""),
+            hours(row.task.estimated_minutes),
+            hours(row.estimate_variance_minutes),
         ));
     }
 
@@ -383,6 +345,7 @@ async fn log_activity_d1(
     .await;
 }
 
+/// `target` is the `(target_type, target_id)` the notification links to.
 async fn notify_user_d1(
     state: &AppState,
     organization_id: i64,
@@ -390,8 +353,7 @@ async fn notify_user_d1(
     title: &str,
     body: Option<&str>,
     category: &str,
-    target_type: Option<&str>,
-    target_id: Option<i64>,
+    target: Option<(&str, i64)>,
 ) {
     let _ = d1_execute(
         &state.db,
@@ -404,17 +366,78 @@ async fn notify_user_d1(
             body.map(|v| D1Param::Text(v.to_string()))
                 .unwrap_or(D1Param::Null),
             D1Param::Text(category.to_string()),
-            target_type
-                .map(|v| D1Param::Text(v.to_string()))
+            target
+                .map(|(target_type, _)| D1Param::Text(target_type.to_string()))
+                .unwrap_or(D1Param::Null),
+            target
+                .map(|(_, target_id)| D1Param::Integer(target_id))
                 .unwrap_or(D1Param::Null),
-            target_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
         ],
     )
     .await;
 }
 
+/// Tells the assignee once the time logged on the task passes its estimate.
+/// The mark is cleared when the total drops back to the estimate or below, so
+/// passing it again sends another notification.
+async fn notify_if_over_estimate(state: &AppState, organization_id: i64, task_id: i64) {
+    let total = "(SELECT COALESCE(SUM(l.duration_minutes), 0)
+                  FROM task_time_logs l
+                  WHERE l.task_id = tasks.id AND l.organization_id = tasks.organization_id)";
+    let params = vec![D1Param::Integer(task_id), D1Param::Integer(organization_id)];
+    let Ok(results) = d1_batch(
+        &state.db,
+        &[
+            D1Statement::new(
+                format!(
+                    "UPDATE tasks SET estimate_exceeded_at = NULL
+                     WHERE id = ?1 AND organization_id = ?2 AND estimate_exceeded_at IS NOT NULL
+                       AND (estimated_minutes IS NULL OR {total} <= estimated_minutes)"
+                ),
+                params.clone(),
+            ),
+            D1Statement::new(
+                format!(
+                    "UPDATE tasks SET estimate_exceeded_at = CURRENT_TIMESTAMP
+                     WHERE id = ?1 AND organization_id = ?2 AND estimate_exceeded_at IS NULL
+                       AND {total} > estimated_minutes"
+                ),
+                params,
+            ),
+        ],
+    )
+    .await
+    else {
+        return;
+    };
+    if results[1].changes == 0 {
+        return;
+    }
+    let Ok(Some(task)) = fetch_task_by_id(state, organization_id, task_id).await else {
+        return;
+    };
+
+    let body = format!(
+        "{} has {} minutes logged against an estimate of {} minutes",
+        task.title,
+        task.total_duration_minutes,
+        task.estimated_minutes.unwrap_or_default()
+    );
+    notify_user_d1(
+        state,
+        organization_id,
+        task.member_id,
+        "Task estimate exceeded",
+        Some(&body),
+        "task_estimate_exceeded",
+        Some(("task", task.id)),
+    )
+    .await;
+}
+
 fn task_select_sql() -> &'static str {
     "SELECT t.id, t.organization_id, t.member_id, t.title, t.description, t.status, t.progress_rate,
+            t.due_date, t.estimated_minutes,
             NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS tags,
             t.created_at, t.updated_at,
             COALESCE((
@@ -445,43 +468,74 @@ async fn fetch_task_by_id(
     .map_err(ApiError::from)
 }
 
-async fn upsert_tag_and_link(
-    state: &AppState,
-    organization_id: i64,
-    task_id: i64,
-    tag_name: &str,
-) -> Result<(), ApiError> {
-    d1_execute(
-        &state.db,
-        "INSERT INTO tags (organization_id, name)
-         VALUES (?1, ?2)
-         ON CONFLICT (organization_id, name) DO UPDATE SET name = excluded.name",
-        &[
-            D1Param::Integer(organization_id),
-            D1Param::Text(tag_name.to_string()),
-        ],
-    )
-    .await?;
+fn normalized_tags(tags: Option<&Vec<String>>) -> Vec<String> {
+    tags.map(|tags| {
+        tags.iter()
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(ToString::to_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
 
-    let tag = d1_query_one::<IdRow>(
-        &state.db,
-        "SELECT id FROM tags WHERE organization_id = ?1 AND name = ?2 LIMIT 1",
-        &[
-            D1Param::Integer(organization_id),
-            D1Param::Text(tag_name.to_string()),
-        ],
-    )
-    .await?
-    .ok_or_else(|| ApiError::internal("failed to resolve tag id"))?;
+/// Identifies the task that tag links and time logs should point at.
+enum TaskRef {
+    Id(i64),
+    /// The task inserted with this `creation_key` by an earlier statement of
+    /// the same batch.
+    InsertedInBatch {
+        creation_key: String,
+    },
+}
 
-    d1_execute(
-        &state.db,
-        "INSERT OR IGNORE INTO task_tags (task_id, tag_id) VALUES (?1, ?2)",
-        &[D1Param::Integer(task_id), D1Param::Integer(tag.id)],
-    )
-    .await?;
+impl TaskRef {
+    /// The SQL for the task's id, reading it from parameter `?{index}`, and
+    /// that parameter.
+    fn bind(&self, index: usize) -> (String, D1Param) {
+        match self {
+            Self::Id(id) => (format!("?{index}"), D1Param::Integer(*id)),
+            Self::InsertedInBatch { creation_key } => (
+                format!("(SELECT id FROM tasks WHERE creation_key = ?{index})"),
+                D1Param::Text(creation_key.clone()),
+            ),
+        }
+    }
+}
 
-    Ok(())
+fn tag_link_statements(
+    organization_id: i64,
+    task_ref: &TaskRef,
+    tags: &[String],
+) -> Vec<D1Statement> {
+    let mut statements = Vec::with_capacity(tags.len() * 2);
+    let (task_id, task_param) = task_ref.bind(3);
+
+    for tag_name in tags {
+        statements.push(D1Statement::new(
+            "INSERT INTO tags (organization_id, name)
+             VALUES (?1, ?2)
+             ON CONFLICT (organization_id, name) DO UPDATE SET name = excluded.name",
+            vec![
+                D1Param::Integer(organization_id),
+                D1Param::Text(tag_name.clone()),
+            ],
+        ));
+
+        statements.push(D1Statement::new(
+            format!(
+                "INSERT OR IGNORE INTO task_tags (task_id, tag_id)
+                 SELECT {task_id}, id FROM tags WHERE organization_id = ?1 AND name = ?2"
+            ),
+            vec![
+                D1Param::Integer(organization_id),
+                D1Param::Text(tag_name.clone()),
+                task_param.clone(),
+            ],
+        ));
+    }
+
+    statements
 }
 
 async fn fetch_time_log_with_task(
@@ -499,6 +553,8 @@ async fn fetch_time_log_with_task(
                 t.status AS task_status,
                 t.progress_rate AS task_progress_rate,
                 NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS task_tags,
+                t.due_date AS task_due_date,
+                t.estimated_minutes AS task_estimated_minutes,
                 COALESCE(sums.total, 0) AS total_duration_minutes
          FROM task_time_logs l
          JOIN tasks t ON t.id = l.task_id AND t.organization_id = l.organization_id
@@ -543,11 +599,11 @@ pub async fn add_time_log(mut req: Request, ctx: RouteContext<AppState>) -> Work
             return Err(ApiError::new(400, "end_at must be after start_at"));
         }
 
-        if !user_in_organization(&ctx.data, claims.organization_id, input.user_id).await? {
+        if !active_user_in_organization(&ctx.data, claims.organization_id, input.user_id).await? {
             return Err(ApiError::new(400, "Invalid user_id"));
         }
 
-        let task_id = if let Some(task_id) = input.task_id {
+        let (task_id, new_task_title) = if let Some(task_id) = input.task_id {
             let task = fetch_task_by_id(&ctx.data, claims.organization_id, task_id)
                 .await?
                 .ok_or_else(|| ApiError::new(404, "Task not found"))?;
@@ -558,7 +614,7 @@ pub async fn add_time_log(mut req: Request, ctx: RouteContext<AppState>) -> Work
                     "Selected task does not belong to user_id",
                 ));
             }
-            task.id
+            (Some(task.id), None)
         } else {
             let title = input
                 .title
@@ -571,7 +627,8 @@ pub async fn add_time_log(mut req: Request, ctx: RouteContext<AppState>) -> Work
             let existing = d1_query_one::<Task>(
                 &ctx.data.db,
                 &format!(
-                    "{} WHERE t.organization_id = ?1 AND t.member_id = ?2 AND t.title = ?3 AND t.status != 'done'
+                    "{} WHERE t.organization_id = ?1 AND t.member_id = ?2 AND t.title = ?3
+                       AND t.status NOT IN (SELECT value FROM json_each(?4))
                      GROUP BY t.id
                      ORDER BY t.created_at DESC
                      LIMIT 1",
@@ -581,95 +638,85 @@ pub async fn add_time_log(mut req: Request, ctx: RouteContext<AppState>) -> Work
                     D1Param::Integer(claims.organization_id),
                     D1Param::Integer(input.user_id),
                     D1Param::Text(title.clone()),
+                    load_settings(&ctx.data, claims.organization_id)
+                        .await?
+                        .closed_statuses_param(),
                 ],
             )
             .await?;
 
-            if let Some(task) = existing {
-                task.id
-            } else {
-                d1_execute(
-                    &ctx.data.db,
-                    "INSERT INTO tasks (organization_id, member_id, title, description, status)
-                     VALUES (?1, ?2, ?3, ?4, ?5)",
-                    &[
+            match existing {
+                Some(task) => (Some(task.id), None),
+                None => (None, Some(title)),
+            }
+        };
+
+        let time_log = |task_ref: &TaskRef| {
+            let (task_id, task_param) = task_ref.bind(3);
+            D1Statement::new(
+                format!(
+                    "INSERT INTO task_time_logs (organization_id, user_id, task_id, start_at, end_at)
+                     VALUES (?1, ?2, {task_id}, ?4, ?5)"
+                ),
+                vec![
+                    D1Param::Integer(claims.organization_id),
+                    D1Param::Integer(input.user_id),
+                    task_param,
+                    D1Param::Text(input.start_at.clone()),
+                    D1Param::Text(input.end_at.clone()),
+                ],
+            )
+        };
+        // A new task, its tags and the time log are created in one batch.
+        let statements = match (task_id, new_task_title) {
+            (Some(task_id), _) => vec![time_log(&TaskRef::Id(task_id))],
+            (None, Some(title)) => {
+                let status = match input.status.clone() {
+                    Some(status) => status,
+                    None => {
+                        load_settings(&ctx.data, claims.organization_id)
+                            .await?
+                            .default_task_status
+                    }
+                };
+                let creation_key = uuid::Uuid::new_v4().to_string();
+                let task_ref = TaskRef::InsertedInBatch {
+                    creation_key: creation_key.clone(),
+                };
+                let mut statements = vec![D1Statement::new(
+                    "INSERT INTO tasks (organization_id, member_id, title, description, status, creation_key)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    vec![
                         D1Param::Integer(claims.organization_id),
                         D1Param::Integer(input.user_id),
-                        D1Param::Text(title.clone()),
+                        D1Param::Text(title),
                         input
                             .description
                             .clone()
                             .map(D1Param::Text)
                             .unwrap_or(D1Param::Null),
-                        input
-                            .status
-                            .clone()
-                            .map(D1Param::Text)
-                            .unwrap_or_else(|| D1Param::Text("todo".to_string())),
-                    ],
-                )
-                .await?;
-
-                let created = d1_query_one::<IdRow>(
-                    &ctx.data.db,
-                    "SELECT id FROM tasks
-                     WHERE organization_id = ?1 AND member_id = ?2 AND title = ?3
-                     ORDER BY id DESC LIMIT 1",
-                    &[
-                        D1Param::Integer(claims.organization_id),
-                        D1Param::Integer(input.user_id),
-                        D1Param::Text(title),
+                        D1Param::Text(status),
+                        D1Param::Text(creation_key),
                     ],
-                )
-                .await?
-                .ok_or_else(|| ApiError::internal("failed to resolve created task id"))?;
-
-                if let Some(tags) = &input.tags {
-                    for tag_name in tags {
-                        let normalized = tag_name.trim();
-                        if normalized.is_empty() {
-                            continue;
-                        }
-                        upsert_tag_and_link(&ctx.data, claims.organization_id, created.id, normalized)
-                            .await?;
-                    }
-                }
-
-                created.id
+                )];
+                statements.extend(tag_link_statements(
+                    claims.organization_id,
+                    &task_ref,
+                    &normalized_tags(input.tags.as_ref()),
+                ));
+                statements.push(time_log(&task_ref));
+                statements
             }
+            (None, None) => return Err(ApiError::internal("time log has no task")),
         };
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        let time_log_id = results
+            .last()
+            .ok_or_else(|| ApiError::internal("failed to insert time log"))?
+            .inserted_id()?;
 
-        d1_execute(
-            &ctx.data.db,
-            "INSERT INTO task_time_logs (organization_id, user_id, task_id, start_at, end_at)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(input.user_id),
-                D1Param::Integer(task_id),
-                D1Param::Text(input.start_at.clone()),
-                D1Param::Text(input.end_at.clone()),
-            ],
-        )
-        .await?;
-
-        let inserted_log = d1_query_one::<IdRow>(
-            &ctx.data.db,
-            "SELECT id FROM task_time_logs
-             WHERE organization_id = ?1 AND user_id = ?2 AND task_id = ?3 AND start_at = ?4 AND end_at = ?5
-             ORDER BY id DESC LIMIT 1",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(input.user_id),
-                D1Param::Integer(task_id),
-                D1Param::Text(input.start_at),
-                D1Param::Text(input.end_at),
-            ],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("failed to resolve created time log id"))?;
-
-        let time_log = fetch_time_log_with_task(&ctx.data, claims.organization_id, inserted_log.id).await?;
+        let time_log = fetch_time_log_with_task(&ctx.data, claims.organization_id, time_log_id).await?;
+        notify_if_over_estimate(&ctx.data, claims.organization_id, time_log.task_id).await;
 
         log_activity_d1(
             &ctx.data,
@@ -726,7 +773,7 @@ pub async fn update_time_log(
             return Err(ApiError::new(400, "end_at must be after start_at"));
         }
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE task_time_logs
              SET start_at = COALESCE(?1, start_at),
@@ -740,8 +787,12 @@ pub async fn update_time_log(
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "Time log not found"));
+        }
 
         let updated = fetch_time_log_with_task(&ctx.data, claims.organization_id, id).await?;
+        notify_if_over_estimate(&ctx.data, claims.organization_id, updated.task_id).await;
 
         log_activity_d1(
             &ctx.data,
@@ -796,41 +847,37 @@ pub async fn delete_time_log(req: Request, ctx: RouteContext<AppState>) -> Worke
 
         let task_id = log_entry.task_id;
 
-        d1_execute(
+        let results = d1_batch(
             &ctx.data.db,
-            "DELETE FROM task_time_logs WHERE id = ?1 AND organization_id = ?2",
             &[
-                D1Param::Integer(id),
-                D1Param::Integer(claims.organization_id),
-            ],
-        )
-        .await?;
-
-        // Check if there are any other logs for this task
-        let other_logs = d1_query_one::<CountRow>(
-            &ctx.data.db,
-            "SELECT COUNT(*) as count FROM task_time_logs WHERE task_id = ?1 AND organization_id = ?2",
-            &[
-                D1Param::Integer(task_id),
-                D1Param::Integer(claims.organization_id),
-            ],
-        )
-        .await?;
-
-        if let Some(row) = other_logs {
-            if row.count == 0 {
-                // Orphaned task, delete it
-                let _ = d1_execute(
-                    &ctx.data.db,
-                    "DELETE FROM tasks WHERE id = ?1 AND organization_id = ?2",
-                    &[
+                D1Statement::new(
+                    "DELETE FROM task_time_logs WHERE id = ?1 AND organization_id = ?2",
+                    vec![
+                        D1Param::Integer(id),
+                        D1Param::Integer(claims.organization_id),
+                    ],
+                ),
+                // Remove the task as well once its last time log is gone.
+                D1Statement::new(
+                    "DELETE FROM tasks
+                     WHERE id = ?1 AND organization_id = ?2
+                       AND NOT EXISTS (
+                           SELECT 1 FROM task_time_logs
+                           WHERE task_id = ?1 AND organization_id = ?2
+                       )",
+                    vec![
                         D1Param::Integer(task_id),
                         D1Param::Integer(claims.organization_id),
                     ],
-                )
-                .await;
-            }
+                ),
+            ],
+        )
+        .await?;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(404, "Time log not found"));
         }
+        // Back under the estimate, the next time it is passed notifies again.
+        notify_if_over_estimate(&ctx.data, claims.organization_id, task_id).await;
 
         log_activity_d1(
             &ctx.data,
@@ -854,17 +901,51 @@ pub async fn get_tasks(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
         let query = parse_get_tasks_query(&req)?;
+        let page = parse_page_query(&req)?;
+
+        // A log counts towards every local day it overlaps.
+        let day_range = match query.date.as_deref() {
+            Some(date) => {
+                let date = timezone::parse_date(date)
+                    .ok_or_else(|| ApiError::new(400, "invalid date"))?;
+                let tz =
+                    timezone::for_member(&ctx.data.db, claims.organization_id, claims.user_id)
+                        .await?;
+                Some(timezone::day_range(tz, date))
+            }
+            None => None,
+        };
+        // Due dates are local calendar dates, compared as `YYYY-MM-DD` text.
+        let due_range = match query.due.as_deref() {
+            Some(due) => {
+                let settings = load_settings(&ctx.data, claims.organization_id).await?;
+                let tz =
+                    timezone::for_member(&ctx.data.db, claims.organization_id, claims.user_id)
+                        .await?;
+                let today = timezone::today(tz);
+                let (first, last) = match due {
+                    "overdue" => (None, today - Duration::days(1)),
+                    "this_week" => {
+                        let first = timezone::week_start(today, settings.week_start());
+                        (Some(first), first + Duration::days(6))
+                    }
+                    _ => return Err(ApiError::new(400, "due must be overdue or this_week")),
+                };
+                Some((first, last, settings.closed_task_statuses))
+            }
+            None => None,
+        };
 
         let mut params = Vec::new();
-        let duration_subquery = if let Some(date) = query.date.as_ref() {
-            params.push(D1Param::Text(date.clone()));
-            params.push(D1Param::Text(date.clone()));
+        let duration_subquery = if let Some((day_start, day_end)) = &day_range {
+            params.push(D1Param::Text(day_end.clone()));
+            params.push(D1Param::Text(day_start.clone()));
             "(SELECT COALESCE(SUM(l_dur.duration_minutes), 0)
               FROM task_time_logs l_dur
               WHERE l_dur.task_id = t.id
                 AND l_dur.organization_id = t.organization_id
-                AND date(datetime(l_dur.start_at, '+9 hours')) <= ?
-                AND date(datetime(l_dur.end_at, '+9 hours')) >= ?)"
+                AND datetime(l_dur.start_at) < ?
+                AND datetime(l_dur.end_at) >= ?)"
         } else {
             "(SELECT COALESCE(SUM(l_dur.duration_minutes), 0)
               FROM task_time_logs l_dur
@@ -874,6 +955,7 @@ pub async fn get_tasks(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
 
         let mut sql = format!(
             "SELECT t.id, t.organization_id, t.member_id, t.title, t.description, t.status, t.progress_rate,
+                    t.due_date, t.estimated_minutes,
                     NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS tags,
                     t.created_at, t.updated_at,
                     {} AS total_duration_minutes
@@ -923,8 +1005,9 @@ pub async fn get_tasks(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
                     OR EXISTS (
                         SELECT 1
                         FROM users u_q
+                        JOIN memberships m_q ON m_q.user_id = u_q.id
                         WHERE u_q.id = t.member_id
-                          AND u_q.organization_id = ?
+                          AND m_q.organization_id = ?
                           AND LOWER(COALESCE(u_q.username, '')) LIKE LOWER(?)
                     )
                 )",
@@ -936,19 +1019,19 @@ pub async fn get_tasks(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
             params.push(D1Param::Text(like_pattern));
         }
 
-        if let Some(date) = query.date {
+        if let Some((day_start, day_end)) = day_range {
             sql.push_str(
                 " AND EXISTS (
                     SELECT 1
                     FROM task_time_logs l_filter
                     WHERE l_filter.task_id = t.id
                       AND l_filter.organization_id = t.organization_id
-                      AND date(datetime(l_filter.start_at, '+9 hours')) <= ?
-                      AND date(datetime(l_filter.end_at, '+9 hours')) >= ?
+                      AND datetime(l_filter.start_at) < ?
+                      AND datetime(l_filter.end_at) >= ?
                 )",
             );
-            params.push(D1Param::Text(date.clone()));
-            params.push(D1Param::Text(date));
+            params.push(D1Param::Text(day_end));
+            params.push(D1Param::Text(day_start));
         }
 
         if let Some(status) = query.status {
@@ -962,10 +1045,37 @@ pub async fn get_tasks(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
             }
         }
 
-        sql.push_str(" GROUP BY t.id ORDER BY t.created_at DESC");
+        if let Some((first, last, closed)) = due_range {
+            // Finished tasks, in the organization's closed statuses, are
+            // neither overdue nor still due.
+            if !closed.is_empty() {
+                let placeholders = vec!["?"; closed.len()].join(", ");
+                sql.push_str(&format!(" AND t.status NOT IN ({placeholders})"));
+                params.extend(closed.into_iter().map(D1Param::Text));
+            }
+            sql.push_str(" AND t.due_date <= ?");
+            params.push(D1Param::Text(last.to_string()));
+            if let Some(first) = first {
+                sql.push_str(" AND t.due_date >= ?");
+                params.push(D1Param::Text(first.to_string()));
+            }
+        }
+
+        if page.cursor.is_some() {
+            sql.push_str(" AND (t.created_at, t.id) < (?, ?)");
+            params.extend(page.cursor_params());
+        }
+
+        sql.push_str(" GROUP BY t.id ORDER BY t.created_at DESC, t.id DESC LIMIT ?");
+        params.push(D1Param::Integer(page.fetch_limit()));
 
         let tasks = d1_query_all::<Task>(&ctx.data.db, &sql, &params).await?;
-        json_with_status(&tasks, 200)
+        json_with_status(
+            &Paginated::from_rows(tasks, &page, |task| {
+                PageCursor::new(task.created_at.clone(), task.id)
+            }),
+            200,
+        )
     }
     .await;
 
@@ -980,15 +1090,21 @@ pub async fn create_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
             .await
             .map_err(|e| ApiError::new(400, e.to_string()))?;
 
-        if !user_in_organization(&ctx.data, claims.organization_id, input.member_id).await? {
+        if !active_user_in_organization(&ctx.data, claims.organization_id, input.member_id).await? {
             return Err(ApiError::new(400, "Invalid member_id"));
         }
-
-        d1_execute(
-            &ctx.data.db,
-            "INSERT INTO tasks (organization_id, member_id, title, description)
-             VALUES (?1, ?2, ?3, ?4)",
-            &[
+        let due_date = parse_due_date(input.due_date.as_ref())?.filter(|date| !date.is_empty());
+        let estimated_minutes =
+            validate_estimated_minutes(input.estimated_minutes)?.filter(|minutes| *minutes > 0);
+
+        let settings = load_settings(&ctx.data, claims.organization_id).await?;
+
+        let creation_key = uuid::Uuid::new_v4().to_string();
+        let mut statements = vec![D1Statement::new(
+            "INSERT INTO tasks (organization_id, member_id, title, description, status,
+                                due_date, estimated_minutes, creation_key)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            vec![
                 D1Param::Integer(claims.organization_id),
                 D1Param::Integer(input.member_id),
                 D1Param::Text(input.title.clone()),
@@ -997,36 +1113,23 @@ pub async fn create_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
                     .clone()
                     .map(D1Param::Text)
                     .unwrap_or(D1Param::Null),
+                D1Param::Text(settings.default_task_status),
+                due_date.map(D1Param::Text).unwrap_or(D1Param::Null),
+                estimated_minutes
+                    .map(D1Param::Integer)
+                    .unwrap_or(D1Param::Null),
+                D1Param::Text(creation_key.clone()),
             ],
-        )
-        .await?;
-
-        let created = d1_query_one::<IdRow>(
-            &ctx.data.db,
-            "SELECT id FROM tasks
-             WHERE organization_id = ?1 AND member_id = ?2 AND title = ?3
-             ORDER BY id DESC LIMIT 1",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Integer(input.member_id),
-                D1Param::Text(input.title.clone()),
-            ],
-        )
-        .await?
-        .ok_or_else(|| ApiError::internal("failed to resolve created task id"))?;
-
-        if let Some(tags) = &input.tags {
-            for tag_name in tags {
-                let normalized = tag_name.trim();
-                if normalized.is_empty() {
-                    continue;
-                }
-                upsert_tag_and_link(&ctx.data, claims.organization_id, created.id, normalized)
-                    .await?;
-            }
-        }
+        )];
+        statements.extend(tag_link_statements(
+            claims.organization_id,
+            &TaskRef::InsertedInBatch { creation_key },
+            &normalized_tags(input.tags.as_ref()),
+        ));
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        let task_id = results[0].inserted_id()?;
 
-        let task = fetch_task_by_id(&ctx.data, claims.organization_id, created.id)
+        let task = fetch_task_by_id(&ctx.data, claims.organization_id, task_id)
             .await?
             .ok_or_else(|| ApiError::internal("failed to load created task"))?;
 
@@ -1050,8 +1153,7 @@ pub async fn create_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
                 "New task assignment",
                 Some(&body),
                 "task_assigned",
-                Some("task"),
-                Some(task.id),
+                Some(("task", task.id)),
             )
             .await;
         }
@@ -1078,24 +1180,27 @@ pub async fn update_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
         let current_task = fetch_task_by_id(&ctx.data, claims.organization_id, id)
             .await?
             .ok_or_else(|| ApiError::new(404, "Task not found"))?;
+        let due_date = parse_due_date(input.due_date.as_ref())?;
+        let estimated_minutes = validate_estimated_minutes(input.estimated_minutes)?;
 
         if let Some(new_member_id) = input.member_id
-            && !user_in_organization(&ctx.data, claims.organization_id, new_member_id).await?
+            && !active_user_in_organization(&ctx.data, claims.organization_id, new_member_id).await?
         {
             return Err(ApiError::new(400, "Invalid member_id"));
         }
 
-        d1_execute(
-            &ctx.data.db,
+        let mut statements = vec![D1Statement::new(
             "UPDATE tasks
              SET member_id = COALESCE(?1, member_id),
                  title = COALESCE(?2, title),
                  description = COALESCE(?3, description),
                  status = COALESCE(?4, status),
                  progress_rate = COALESCE(?5, progress_rate),
+                 due_date = CASE WHEN ?8 IS NULL THEN due_date ELSE NULLIF(?8, '') END,
+                 estimated_minutes = CASE WHEN ?9 IS NULL THEN estimated_minutes ELSE NULLIF(?9, 0) END,
                  updated_at = CURRENT_TIMESTAMP
              WHERE id = ?6 AND organization_id = ?7",
-            &[
+            vec![
                 input.member_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
                 input
                     .title
@@ -1118,25 +1223,28 @@ pub async fn update_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
                     .unwrap_or(D1Param::Null),
                 D1Param::Integer(id),
                 D1Param::Integer(claims.organization_id),
+                due_date.map(D1Param::Text).unwrap_or(D1Param::Null),
+                estimated_minutes
+                    .map(D1Param::Integer)
+                    .unwrap_or(D1Param::Null),
             ],
-        )
-        .await?;
+        )];
 
-        if let Some(tags) = &input.tags {
-            d1_execute(
-                &ctx.data.db,
+        if input.tags.is_some() {
+            statements.push(D1Statement::new(
                 "DELETE FROM task_tags WHERE task_id = ?1",
-                &[D1Param::Integer(id)],
-            )
-            .await?;
+                vec![D1Param::Integer(id)],
+            ));
+            statements.extend(tag_link_statements(
+                claims.organization_id,
+                &TaskRef::Id(id),
+                &normalized_tags(input.tags.as_ref()),
+            ));
+        }
 
-            for tag_name in tags {
-                let normalized = tag_name.trim();
-                if normalized.is_empty() {
-                    continue;
-                }
-                upsert_tag_and_link(&ctx.data, claims.organization_id, id, normalized).await?;
-            }
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(404, "Task not found"));
         }
 
         let task = fetch_task_by_id(&ctx.data, claims.organization_id, id)
@@ -1153,6 +1261,13 @@ pub async fn update_task(mut req: Request, ctx: RouteContext<AppState>) -> Worke
         if current_task.progress_rate != task.progress_rate {
             changes.push(json!({ "field": "progress_rate", "old": current_task.progress_rate, "new": task.progress_rate }));
         }
+        if current_task.due_date != task.due_date {
+            changes.push(json!({ "field": "due_date", "old": current_task.due_date, "new": task.due_date }));
+        }
+        if current_task.estimated_minutes != task.estimated_minutes {
+            changes.push(json!({ "field": "estimated_minutes", "old": current_task.estimated_minutes, "new": task.estimated_minutes }));
+            notify_if_over_estimate(&ctx.data, claims.organization_id, task.id).await;
+        }
 
         log_activity_d1(
             &ctx.data,
@@ -1180,12 +1295,7 @@ pub async fn delete_task(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
             .and_then(|v| v.parse::<i64>().ok())
             .ok_or_else(|| ApiError::new(400, "invalid id"))?;
 
-        let exists = fetch_task_by_id(&ctx.data, claims.organization_id, id).await?;
-        if exists.is_none() {
-            return Err(ApiError::new(404, "Task not found"));
-        }
-
-        d1_execute(
+        let deleted = d1_execute(
             &ctx.data.db,
             "DELETE FROM tasks WHERE id = ?1 AND organization_id = ?2",
             &[
@@ -1194,6 +1304,9 @@ pub async fn delete_task(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
             ],
         )
         .await?;
+        if deleted.changes == 0 {
+            return Err(ApiError::new(404, "Task not found"));
+        }
 
         log_activity_d1(
             &ctx.data,
@@ -1213,32 +1326,47 @@ pub async fn delete_task(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
     result.or_else(|e| e.into_response())
 }
 
+/// Fetches task report rows; `page` limits the result to one keyset page,
+/// while `None` returns every matching row (used by the CSV export).
 async fn fetch_task_report_rows(
     state: &AppState,
-    organization_id: i64,
+    claims: &Claims,
+    scope: Scope,
     query: &TaskReportQuery,
+    page: Option<&PageQuery>,
 ) -> Result<Vec<TaskReportRow>, ApiError> {
-    let start_date = query.start_date.clone();
-    let end_date = query.end_date.clone();
-
-    // Aggregation range
-    let (effective_start, effective_end) = if start_date.is_none() && end_date.is_none() {
-        let now = Utc::now();
-        let three_months_ago = now - Duration::days(90);
-        (
-            Some(three_months_ago.format("%Y-%m-%d").to_string()),
-            Some(now.format("%Y-%m-%d").to_string()),
-        )
+    let organization_id = claims.organization_id;
+    let parse_date = |value: &Option<String>, field: &str| {
+        value
+            .as_deref()
+            .map(|v| {
+                timezone::parse_date(v)
+                    .ok_or_else(|| ApiError::new(400, format!("invalid {field}")))
+            })
+            .transpose()
+    };
+    let start_date = parse_date(&query.start_date, "start_date")?;
+    let end_date = parse_date(&query.end_date, "end_date")?;
+    let tz = timezone::for_member(&state.db, organization_id, claims.user_id).await?;
+
+    // Aggregation range, as UTC bounds of the local days
+    let (start_date, end_date) = if start_date.is_none() && end_date.is_none() {
+        let today = timezone::today(tz);
+        (Some(today - Duration::days(90)), Some(today))
     } else {
         (start_date, end_date)
     };
+    let effective_start = start_date.map(|date| timezone::day_start(tz, date));
+    let effective_end = end_date.map(|date| timezone::day_start(tz, date + Duration::days(1)));
 
     let mut sql = String::from(
         "SELECT t.id, t.organization_id, t.member_id, t.title, t.description, t.status, t.progress_rate,
+                t.due_date, t.estimated_minutes,
                 (SELECT GROUP_CONCAT(tg.name) FROM task_tags tt JOIN tags tg ON tg.id = tt.tag_id WHERE tt.task_id = t.id) AS tags,
                 t.created_at, t.updated_at,
                 u.name AS user_name,
                 COALESCE(SUM(l.duration_minutes), 0) AS total_duration_minutes,
+                COALESCE(SUM(l.duration_minutes), 0) - t.estimated_minutes AS estimate_variance_minutes,
                 MIN(l.start_at) AS start_at,
                 MAX(l.end_at) AS end_at
          FROM tasks t
@@ -1250,11 +1378,11 @@ async fn fetch_task_report_rows(
 
     // 1. Logs join condition (filters what is summed)
     if let Some(s) = &effective_start {
-        sql.push_str(" AND date(datetime(l.start_at, '+9 hours')) >= ?");
+        sql.push_str(" AND datetime(l.start_at) >= ?");
         params.push(D1Param::Text(s.clone()));
     }
     if let Some(e) = &effective_end {
-        sql.push_str(" AND date(datetime(l.end_at, '+9 hours')) <= ?");
+        sql.push_str(" AND datetime(l.end_at) < ?");
         params.push(D1Param::Text(e.clone()));
     }
 
@@ -1267,7 +1395,20 @@ async fn fetch_task_report_rows(
         params.push(D1Param::Integer(member_id));
     }
 
-    if let Some(q) = query.q.as_ref().map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) {
+    if scope == Scope::Team {
+        sql.push_str(
+            " AND (t.member_id = ? OR t.member_id IN (SELECT user_id FROM memberships WHERE organization_id = t.organization_id AND manager_id = ?))",
+        );
+        params.push(D1Param::Integer(claims.user_id));
+        params.push(D1Param::Integer(claims.user_id));
+    }
+
+    if let Some(q) = query
+        .q
+        .as_ref()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+    {
         let pattern = format!("%{q}%");
         sql.push_str(" AND (LOWER(t.title) LIKE LOWER(?) OR LOWER(u.name) LIKE LOWER(?) OR EXISTS (SELECT 1 FROM task_tags tt_q JOIN tags tg_q ON tg_q.id = tt_q.tag_id WHERE tt_q.task_id = t.id AND LOWER(tg_q.name) LIKE LOWER(?)))");
         params.push(D1Param::Text(pattern.clone()));
@@ -1275,66 +1416,62 @@ async fn fetch_task_report_rows(
         params.push(D1Param::Text(pattern));
     }
 
-    if let Some(raw) = &query.statuses {
-        let st = split_csv_values(raw);
-        if !st.is_empty() {
-            let marks = vec!["?"; st.len()].join(", ");
-            sql.push_str(&format!(" AND t.status IN ({})", marks));
-            for s in st {
-                params.push(D1Param::Text(s));
-            }
+    // Without a status filter the organization's report statuses apply.
+    let settings = load_settings(state, organization_id).await?;
+    let st = match &query.statuses {
+        Some(raw) => split_csv_values(raw),
+        None => settings.report_statuses.clone(),
+    };
+    if !st.is_empty() {
+        let marks = vec!["?"; st.len()].join(", ");
+        sql.push_str(&format!(" AND t.status IN ({})", marks));
+        for s in st {
+            params.push(D1Param::Text(s));
         }
     }
 
     // 3. Visibility logic (ANDed with status/member/keyword)
-    // If it's a 'done' task, only show if it has activity or was created in the period.
-    // If it's 'todo' or 'doing', always show it if it matches the other filters.
+    // A task in one of the closed statuses only shows if it has activity or was
+    // created in the period; open tasks always show if they match the other filters.
     if let Some(s) = &effective_start {
-        sql.push_str(" AND (t.status != 'done' OR l.id IS NOT NULL OR date(datetime(t.created_at, '+9 hours')) >= ?)");
+        sql.push_str(
+            " AND (t.status NOT IN (SELECT value FROM json_each(?)) OR l.id IS NOT NULL OR datetime(t.created_at) >= ?)",
+        );
+        params.push(settings.closed_statuses_param());
         params.push(D1Param::Text(s.clone()));
     }
 
-    sql.push_str(" GROUP BY t.id, u.name ORDER BY t.created_at DESC, t.id ASC");
-
-    let flat_rows = d1_query_all::<ReportFlatRow>(&state.db, &sql, &params).await?;
-
-    let rows = flat_rows
-        .into_iter()
-        .map(|row| TaskReportRow {
-            user_name: row.user_name,
-            start_at: row.start_at,
-            end_at: row.end_at,
-            task: Task {
-                id: row.id,
-                organization_id: row.organization_id,
-                member_id: row.member_id,
-                title: row.title,
-                description: row.description,
-                status: row.status,
-                progress_rate: row.progress_rate,
-                tags: row.tags,
-                created_at: row.created_at,
-                updated_at: row.updated_at,
-                total_duration_minutes: row.total_duration_minutes,
-            },
-        })
-        .collect();
+    if let Some(page) = page.filter(|page| page.cursor.is_some()) {
+        sql.push_str(" AND (t.created_at, t.id) < (?, ?)");
+        params.extend(page.cursor_params());
+    }
+
+    sql.push_str(" GROUP BY t.id, u.name ORDER BY t.created_at DESC, t.id DESC");
+    if let Some(page) = page {
+        sql.push_str(" LIMIT ?");
+        params.push(D1Param::Integer(page.fetch_limit()));
+    }
+
+    let rows = d1_query_all::<TaskReportRow>(&state.db, &sql, &params).await?;
 
     Ok(rows)
 }
 
 pub async fn get_task_report(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
     let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Admin access required"));
-        }
+        let (claims, scope) = require_report_scope(&req, &ctx).await?;
 
         let query = parse_task_report_query(&req)?;
         validate_report_date_range(&query)?;
+        let page = parse_page_query(&req)?;
 
-        let rows = fetch_task_report_rows(&ctx.data, claims.organization_id, &query).await?;
-        json_with_status(&rows, 200)
+        let rows = fetch_task_report_rows(&ctx.data, &claims, scope, &query, Some(&page)).await?;
+        json_with_status(
+            &Paginated::from_rows(rows, &page, |row| {
+                PageCursor::new(row.task.created_at.clone(), row.task.id)
+            }),
+            200,
+        )
     }
     .await;
 
@@ -1345,16 +1482,13 @@ pub async fn export_task_report(
     req: Request,
     ctx: RouteContext<AppState>,
 ) -> WorkerResult<Response> {
-    let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Admin access required"));
-        }
+    let result: Result<Response, ApiError> = async {
+        let (claims, scope) = require_report_scope(&req, &ctx).await?;
 
         let query = parse_task_report_query(&req)?;
         validate_report_date_range(&query)?;
 
-        let rows = fetch_task_report_rows(&ctx.data, claims.organization_id, &query).await?;
+        let rows = fetch_task_report_rows(&ctx.data, &claims, scope, &query, None).await?;
         let csv = task_report_to_csv(&rows);
 
         let mut response = Response::from_bytes(csv.into_bytes())?.with_status(200);
@@ -0,0 +1,295 @@
+use crate::AppState;
+use crate::api_tokens;
+use crate::models::{
+    ApiToken, Claims, CreateApiTokenInput, CreatedApiTokenResponse, D1Param, ModelError, RoleRow,
+    d1_execute, d1_query_all, d1_query_one,
+};
+use crate::routing::{Request, Response, RouteContext};
+use serde::Serialize;
+use serde_json::json;
+use worker::Result as WorkerResult;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+
+    fn into_response(self) -> WorkerResult<Response> {
+        Response::from_json(&ErrorBody {
+            error: self.message,
+        })
+        .map(|response| response.with_status(self.status))
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(value: ModelError) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<worker::Error> for ApiError {
+    fn from(value: worker::Error) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
+    Response::from_json(value)
+        .map(|response| response.with_status(status))
+        .map_err(ApiError::from)
+}
+
+fn db_error_to_response(err: ApiError) -> WorkerResult<Response> {
+    err.into_response()
+}
+
+fn extract_bearer_token(req: &Request) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.to_string()));
+
+    if header_token.is_some() {
+        return header_token;
+    }
+
+    req.url().ok().and_then(|url| {
+        url.query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(k, v)| (k == "token" && !v.is_empty()).then_some(v.to_string()))
+        })
+    })
+}
+
+async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<Claims, ApiError> {
+    let token = extract_bearer_token(req)
+        .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
+
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
+
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
+    let latest_role = d1_query_one::<RoleRow>(
+        &ctx.data.db,
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+    claims.role = latest_role.role;
+    Ok(claims)
+}
+
+async fn log_activity_d1(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    action: &str,
+    target_type: &str,
+    target_id: Option<i64>,
+    details: Option<String>,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Text(target_type.to_string()),
+            target_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
+            details.map(D1Param::Text).unwrap_or(D1Param::Null),
+        ],
+    )
+    .await;
+}
+
+pub async fn get_tokens(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let tokens = d1_query_all::<ApiToken>(
+            &ctx.data.db,
+            "SELECT id, name, scopes, expires_at, last_used_at, created_at
+             FROM api_tokens
+             WHERE user_id = ?1 AND organization_id = ?2
+               AND revoked_at IS NULL
+               AND datetime(expires_at) > datetime('now')
+             ORDER BY created_at DESC, id DESC",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+
+        json_with_status(&tokens, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn create_token(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: CreateApiTokenInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > api_tokens::MAX_NAME_LENGTH {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "Token name must be 1 to {} characters",
+                    api_tokens::MAX_NAME_LENGTH
+                ),
+            ));
+        }
+        let scopes = api_tokens::normalize_scopes(&input.scopes)
+            .map_err(|e| ApiError::new(400, e.to_string()))?;
+        let expires_in_days = input
+            .expires_in_days
+            .unwrap_or(api_tokens::DEFAULT_EXPIRATION_DAYS);
+        if !(1..=api_tokens::MAX_EXPIRATION_DAYS).contains(&expires_in_days) {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "expires_in_days must be between 1 and {}",
+                    api_tokens::MAX_EXPIRATION_DAYS
+                ),
+            ));
+        }
+
+        let (token_id, secret) = api_tokens::create(
+            &ctx.data.db,
+            claims.organization_id,
+            claims.user_id,
+            name,
+            &scopes,
+            expires_in_days,
+        )
+        .await?;
+
+        let token = d1_query_one::<ApiToken>(
+            &ctx.data.db,
+            "SELECT id, name, scopes, expires_at, last_used_at, created_at
+             FROM api_tokens
+             WHERE id = ?1
+             LIMIT 1",
+            &[D1Param::Integer(token_id)],
+        )
+        .await?
+        .ok_or_else(|| ApiError::internal("Failed to load created token"))?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "api_token_created",
+            "api_token",
+            Some(token.id),
+            Some(format!("{} ({})", token.name, token.scopes.join(", "))),
+        )
+        .await;
+
+        json_with_status(&CreatedApiTokenResponse { token, secret }, 201)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn revoke_token(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let token_id = ctx
+            .param("id")
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| ApiError::new(400, "Invalid token id"))?;
+
+        let revoked = d1_execute(
+            &ctx.data.db,
+            "UPDATE api_tokens SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND user_id = ?2 AND organization_id = ?3 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(token_id),
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+        if revoked.changes == 0 {
+            return Err(ApiError::new(404, "Token not found"));
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "api_token_revoked",
+            "api_token",
+            Some(token_id),
+            None,
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
@@ -0,0 +1,437 @@
+use crate::AppState;
+use crate::api_tokens;
+use crate::models::{
+    Claims, D1Param, D1Statement, DisableTwoFactorInput, FromD1Row, ModelError,
+    RecoveryCodesResponse, RoleRow, TwoFactorCodeInput, TwoFactorSetupResponse, TwoFactorStatus,
+    d1_batch, d1_execute, d1_query_one,
+};
+use crate::routing::{Request, Response, RouteContext};
+use crate::totp;
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordVerifier},
+};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+use worker::Result as WorkerResult;
+
+const INVALID_CODE_MESSAGE: &str = "認証コードが正しくありません";
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+
+    fn into_response(self) -> WorkerResult<Response> {
+        Response::from_json(&ErrorBody {
+            error: self.message,
+        })
+        .map(|response| response.with_status(self.status))
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(value: ModelError) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+impl From<worker::Error> for ApiError {
+    fn from(value: worker::Error) -> Self {
+        Self::internal(value.to_string())
+    }
+}
+
+fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
+    Response::from_json(value)
+        .map(|response| response.with_status(status))
+        .map_err(ApiError::from)
+}
+
+fn db_error_to_response(err: ApiError) -> WorkerResult<Response> {
+    err.into_response()
+}
+
+fn extract_bearer_token(req: &Request) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix("Bearer ").map(|s| s.to_string()));
+
+    if header_token.is_some() {
+        return header_token;
+    }
+
+    req.url().ok().and_then(|url| {
+        url.query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(k, v)| (k == "token" && !v.is_empty()).then_some(v.to_string()))
+        })
+    })
+}
+
+async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<Claims, ApiError> {
+    let token = extract_bearer_token(req)
+        .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
+
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
+
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
+
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
+    let latest_role = d1_query_one::<RoleRow>(
+        &ctx.data.db,
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(401, "Unauthorized"))?;
+
+    claims.role = latest_role.role;
+    Ok(claims)
+}
+
+#[derive(Clone, Debug, FromD1Row)]
+struct EnrollmentRow {
+    username: Option<String>,
+    email: Option<String>,
+    #[d1(bool_int)]
+    totp_enabled: i64,
+}
+
+#[derive(Clone, Debug, FromD1Row)]
+struct PasswordRow {
+    password_hash: String,
+}
+
+async fn log_activity_d1(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    action: &str,
+    target_type: &str,
+    target_id: Option<i64>,
+    details: Option<String>,
+) {
+    let _ = d1_execute(
+        &state.db,
+        "INSERT INTO activity_logs (organization_id, user_id, action, target_type, target_id, details)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(action.to_string()),
+            D1Param::Text(target_type.to_string()),
+            target_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
+            details.map(D1Param::Text).unwrap_or(D1Param::Null),
+        ],
+    )
+    .await;
+}
+
+async fn load_enrollment(state: &AppState, claims: &Claims) -> Result<EnrollmentRow, ApiError> {
+    d1_query_one::<EnrollmentRow>(
+        &state.db,
+        "SELECT username, email, totp_enabled
+         FROM users
+         WHERE id = ?1 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)
+         LIMIT 1",
+        &[
+            D1Param::Integer(claims.user_id),
+            D1Param::Integer(claims.organization_id),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(404, "User not found"))
+}
+
+pub async fn get_status(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let status = d1_query_one::<TwoFactorStatus>(
+            &ctx.data.db,
+            "SELECT u.totp_enabled AS enabled,
+                    (SELECT COUNT(*) FROM recovery_codes rc
+                     WHERE rc.user_id = u.id AND rc.used_at IS NULL) AS recovery_codes_remaining,
+                    (m.role = 'admin' AND o.require_admin_2fa = 1) AS required
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             JOIN organizations o ON o.id = m.organization_id
+             WHERE u.id = ?1 AND m.organization_id = ?2
+             LIMIT 1",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(404, "User not found"))?;
+
+        json_with_status(&status, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Starts enrollment with a fresh secret; 2FA stays off until `enable`
+/// confirms a code generated from it.
+pub async fn setup(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let enrollment = load_enrollment(&ctx.data, &claims).await?;
+        if enrollment.totp_enabled == 1 {
+            return Err(ApiError::new(
+                409,
+                "Two-factor authentication is already enabled",
+            ));
+        }
+
+        let secret = totp::generate_secret();
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE users SET totp_secret = ?1, totp_last_used_step = NULL
+             WHERE id = ?2 AND totp_enabled = 0",
+            &[
+                D1Param::Text(secret.clone()),
+                D1Param::Integer(claims.user_id),
+            ],
+        )
+        .await?;
+
+        let account = enrollment
+            .email
+            .or(enrollment.username)
+            .unwrap_or_else(|| claims.sub.clone());
+        json_with_status(
+            &TwoFactorSetupResponse {
+                otpauth_uri: totp::otpauth_uri(&secret, &account),
+                secret,
+            },
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn enable(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: TwoFactorCodeInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let code = input
+            .code
+            .as_deref()
+            .ok_or_else(|| ApiError::new(400, "code is required"))?;
+
+        if !totp::consume_code(
+            &ctx.data.db,
+            claims.user_id,
+            code,
+            false,
+            Utc::now().timestamp(),
+        )
+        .await?
+        {
+            return Err(ApiError::new(400, INVALID_CODE_MESSAGE));
+        }
+
+        let recovery_codes = totp::generate_recovery_codes();
+        let mut statements = vec![D1Statement::new(
+            "UPDATE users SET totp_enabled = 1 WHERE id = ?1",
+            vec![D1Param::Integer(claims.user_id)],
+        )];
+        statements.extend(totp::replace_recovery_codes(
+            claims.user_id,
+            &recovery_codes,
+        ));
+        d1_batch(&ctx.data.db, &statements).await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "two_factor_enabled",
+            "user",
+            Some(claims.user_id),
+            None,
+        )
+        .await;
+
+        json_with_status(&RecoveryCodesResponse { recovery_codes }, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Turns 2FA off; needs the password and a current second factor so a
+/// stolen access token alone cannot remove it.
+pub async fn disable(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: DisableTwoFactorInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let stored_hash = d1_query_one::<PasswordRow>(
+            &ctx.data.db,
+            "SELECT password_hash
+             FROM users
+             WHERE id = ?1 AND totp_enabled = 1
+               AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)
+             LIMIT 1",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(409, "Two-factor authentication is not enabled"))?;
+
+        let parsed_hash = PasswordHash::new(&stored_hash.password_hash)
+            .map_err(|_| ApiError::internal("Invalid password hash in DB"))?;
+        Argon2::default()
+            .verify_password(input.password.as_bytes(), &parsed_hash)
+            .map_err(|_| ApiError::new(401, "Password is incorrect"))?;
+
+        if !totp::consume_second_factor(
+            &ctx.data.db,
+            claims.user_id,
+            input.code.as_deref(),
+            input.recovery_code.as_deref(),
+            Utc::now().timestamp(),
+        )
+        .await?
+        {
+            return Err(ApiError::new(401, INVALID_CODE_MESSAGE));
+        }
+
+        d1_batch(
+            &ctx.data.db,
+            &[
+                D1Statement::new(
+                    "UPDATE users
+                     SET totp_enabled = 0, totp_secret = NULL, totp_last_used_step = NULL
+                     WHERE id = ?1",
+                    vec![D1Param::Integer(claims.user_id)],
+                ),
+                D1Statement::new(
+                    "DELETE FROM recovery_codes WHERE user_id = ?1",
+                    vec![D1Param::Integer(claims.user_id)],
+                ),
+            ],
+        )
+        .await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "two_factor_disabled",
+            "user",
+            Some(claims.user_id),
+            None,
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Replaces all recovery codes; the old ones stop working immediately.
+pub async fn regenerate_recovery_codes(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: TwoFactorCodeInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        if !totp::consume_second_factor(
+            &ctx.data.db,
+            claims.user_id,
+            input.code.as_deref(),
+            input.recovery_code.as_deref(),
+            Utc::now().timestamp(),
+        )
+        .await?
+        {
+            return Err(ApiError::new(401, INVALID_CODE_MESSAGE));
+        }
+
+        let recovery_codes = totp::generate_recovery_codes();
+        d1_batch(
+            &ctx.data.db,
+            &totp::replace_recovery_codes(claims.user_id, &recovery_codes),
+        )
+        .await?;
+
+        json_with_status(&RecoveryCodesResponse { recovery_codes }, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
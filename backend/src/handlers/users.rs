@@ -1,20 +1,33 @@
 use crate::AppState;
+use crate::account;
+use crate::api_tokens;
+use crate::avatars;
+use crate::memberships;
 use crate::models::{
-    Claims, CreateUserInput, D1Param, D1Row, GetUsersQuery, ModelError, TaskTimeLog,
-    UpdateEmailInput, UpdatePasswordInput, UpdateUserRoleInput, User, UserWithTimeLogs, d1_execute,
+    Claims, CountRow, CreateUserInput, D1Param, D1Statement, DeactivateUserInput,
+    DeleteAccountInput, FromD1Row, GetUsersQuery, ModelError, PageCursor, PageQuery, Paginated,
+    RoleRow, TaskTimeLog, UpdateEmailInput, UpdatePasswordInput, UpdateProfileInput,
+    UpdateUserManagerInput, UpdateUserRoleInput, User, UserWithTimeLogs, d1_batch, d1_execute,
     d1_query_all, d1_query_one,
 };
-use crate::utils::{is_secure_password, is_valid_username};
+use crate::permissions::{self, Permission};
+use crate::provisioning::{self, ImportReport};
+use crate::routing::{Request, Response, RouteContext};
+use crate::settings;
+use crate::timezone;
+use crate::utils::{
+    is_valid_avatar_url, is_valid_display_name, is_valid_locale, is_valid_timezone,
+    is_valid_username,
+};
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
 };
 use chrono::Utc;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
 use serde::Serialize;
 use serde_json::{Value, json};
 use std::collections::HashMap;
-use worker::{Request, Response, Result as WorkerResult, RouteContext};
+use worker::Result as WorkerResult;
 
 #[derive(Serialize)]
 struct ErrorBody {
@@ -65,38 +78,11 @@ impl From<serde_json::Error> for ApiError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct RoleRow {
-    role: String,
-}
-
-impl crate::models::FromD1Row for RoleRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let role = row
-            .get("role")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("role"))?
-            .to_string();
-        Ok(Self { role })
-    }
-}
-
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, FromD1Row)]
 struct PasswordRow {
     password_hash: String,
 }
 
-impl crate::models::FromD1Row for PasswordRow {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let password_hash = row
-            .get("password_hash")
-            .and_then(Value::as_str)
-            .ok_or(ModelError::MissingField("password_hash"))?
-            .to_string();
-        Ok(Self { password_hash })
-    }
-}
-
 fn json_with_status<T: Serialize>(value: &T, status: u16) -> Result<Response, ApiError> {
     Response::from_json(value)
         .map(|response| response.with_status(status))
@@ -133,21 +119,38 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     let token = extract_bearer_token(req)
         .ok_or_else(|| ApiError::new(401, "Missing authorization token"))?;
 
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(ctx.data.jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| ApiError::new(401, "Invalid token"))?;
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate(&ctx.data.db, &token, req.method().as_ref(), &req.path())
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()));
+    }
 
-    let mut claims = token_data.claims;
+    let mut claims: Claims = ctx
+        .data
+        .jwt_keys
+        .verify(&token)
+        .map_err(|_| ApiError::new(401, "Invalid token"))?;
 
+    // The session check makes logout and revocation take effect immediately.
+    // Admins who have not enrolled in a required 2FA act as regular users.
     let latest_role = d1_query_one::<RoleRow>(
         &ctx.data.db,
-        "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+        "SELECT CASE
+                    WHEN m.role = 'admin' AND o.require_admin_2fa = 1 AND u.totp_enabled = 0 THEN 'user'
+                    ELSE m.role
+                END AS role
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id AND m.organization_id = ?2
+         JOIN organizations o ON o.id = m.organization_id
+         JOIN sessions s ON s.user_id = u.id
+         WHERE u.id = ?1
+           AND s.id = ?3 AND s.revoked_at IS NULL
+           AND datetime(s.expires_at) > datetime('now')
+         LIMIT 1",
         &[
             D1Param::Integer(claims.user_id),
             D1Param::Integer(claims.organization_id),
+            D1Param::Integer(claims.sid),
         ],
     )
     .await?
@@ -157,11 +160,23 @@ async fn extract_claims(req: &Request, ctx: &RouteContext<AppState>) -> Result<C
     Ok(claims)
 }
 
+async fn require_permission(
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+    permission: Permission,
+) -> Result<Claims, ApiError> {
+    let claims = extract_claims(req, ctx).await?;
+    permissions::authorize(&ctx.data.db, &claims, permission)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+    Ok(claims)
+}
+
 fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ApiError> {
     let url = req
         .url()
         .map_err(|e| ApiError::new(400, format!("invalid url: {e}")))?;
-    
+
     let mut pairs = HashMap::new();
     for (k, v) in url.query_pairs() {
         pairs.insert(k.into_owned(), v.into_owned());
@@ -169,22 +184,34 @@ fn query_pairs(req: &Request) -> Result<HashMap<String, String>, ApiError> {
     Ok(pairs)
 }
 
+fn parse_i64_opt(value: Option<&String>, field: &'static str) -> Result<Option<i64>, ApiError> {
+    match value {
+        None => Ok(None),
+        Some(v) if v.trim().is_empty() => Ok(None),
+        Some(v) => v
+            .parse::<i64>()
+            .map(Some)
+            .map_err(|_| ApiError::new(400, format!("invalid {field}"))),
+    }
+}
+
+fn parse_page_query(req: &Request) -> Result<PageQuery, ApiError> {
+    let pairs = query_pairs(req)?;
+    PageQuery::parse(
+        pairs.get("cursor").map(String::as_str),
+        parse_i64_opt(pairs.get("limit"), "limit")?,
+    )
+    .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
 fn parse_get_users_query(req: &Request) -> Result<GetUsersQuery, ApiError> {
     let pairs = query_pairs(req)?;
     Ok(GetUsersQuery {
         date: pairs.get("date").cloned(),
+        status: pairs.get("status").cloned(),
     })
 }
 
-fn today_jst_date() -> String {
-    let offset = chrono::FixedOffset::east_opt(9 * 3600).expect("valid fixed offset");
-    Utc::now()
-        .with_timezone(&offset)
-        .date_naive()
-        .format("%Y-%m-%d")
-        .to_string()
-}
-
 async fn log_activity_d1(
     state: &AppState,
     organization_id: i64,
@@ -214,21 +241,53 @@ pub async fn get_users(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
         let params = parse_get_users_query(&req)?;
-        let date = params.date.unwrap_or_else(today_jst_date);
+        let tz = timezone::for_member(&ctx.data.db, claims.organization_id, claims.user_id).await?;
+        let date = match params.date.as_deref() {
+            Some(date) => {
+                timezone::parse_date(date).ok_or_else(|| ApiError::new(400, "invalid date"))?
+            }
+            None => timezone::today(tz),
+        };
+        let (day_start, day_end) = timezone::day_range(tz, date);
+        let page = parse_page_query(&req)?;
+        let [cursor_created_at, cursor_id] = page.cursor_params();
+
+        // Deactivated members are left out of the timeline and pickers unless asked for.
+        let status_filter = match params.status.as_deref().unwrap_or("active") {
+            "active" => "m.deactivated_at IS NULL",
+            "inactive" => "m.deactivated_at IS NOT NULL",
+            "all" => "1 = 1",
+            _ => return Err(ApiError::new(400, "status must be active, inactive or all")),
+        };
 
         let users = d1_query_all::<User>(
             &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE organization_id = ?1
-             ORDER BY id",
-            &[D1Param::Integer(claims.organization_id)],
+            &format!(
+                "SELECT {}
+                 FROM users u
+                 JOIN memberships m ON m.user_id = u.id
+                 WHERE m.organization_id = ?1
+                   AND {status_filter}
+                   AND (?2 IS NULL OR (u.created_at, u.id) > (?2, ?3))
+                 ORDER BY u.created_at ASC, u.id ASC
+                 LIMIT ?4",
+                memberships::MEMBER_COLUMNS
+            ),
+            &[
+                D1Param::Integer(claims.organization_id),
+                cursor_created_at,
+                cursor_id,
+                D1Param::Integer(page.fetch_limit()),
+            ],
         )
         .await?;
+        let users = Paginated::from_rows(users, &page, |user| {
+            PageCursor::new(user.created_at.clone().unwrap_or_default(), user.id)
+        });
 
-        let mut result = Vec::with_capacity(users.len());
+        let mut result = Vec::with_capacity(users.items.len());
 
-        for user in users {
+        for user in users.items {
             let time_logs = d1_query_all::<TaskTimeLog>(
                 &ctx.data.db,
                 "SELECT l.id, l.organization_id, l.user_id, l.task_id, l.start_at, l.end_at, l.duration_minutes,
@@ -236,6 +295,8 @@ pub async fn get_users(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
                         t.title AS task_title, t.description AS task_description, t.status AS task_status,
                         t.progress_rate AS task_progress_rate,
                         NULLIF(GROUP_CONCAT(DISTINCT tg.name), '') AS task_tags,
+                        t.due_date AS task_due_date,
+                        t.estimated_minutes AS task_estimated_minutes,
                         COALESCE(sums.total, 0) AS total_duration_minutes
                  FROM task_time_logs l
                  JOIN tasks t ON t.id = l.task_id AND t.organization_id = l.organization_id
@@ -249,13 +310,14 @@ pub async fn get_users(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
                  ) sums ON sums.task_id = l.task_id
                  WHERE l.organization_id = ?1
                    AND l.user_id = ?2
-                   AND date(datetime(l.start_at, '+9 hours')) = ?3
+                   AND datetime(l.start_at) >= ?3 AND datetime(l.start_at) < ?4
                  GROUP BY l.id
                  ORDER BY l.start_at ASC, l.id ASC",
                 &[
                     D1Param::Integer(claims.organization_id),
                     D1Param::Integer(user.id),
-                    D1Param::Text(date.clone()),
+                    D1Param::Text(day_start.clone()),
+                    D1Param::Text(day_end.clone()),
                 ],
             )
             .await?;
@@ -274,7 +336,14 @@ pub async fn get_users(req: Request, ctx: RouteContext<AppState>) -> WorkerResul
             }
             final_result.push(val);
         }
-        json_with_status(&final_result, 200)
+        json_with_status(
+            &Paginated {
+                items: final_result,
+                next_cursor: users.next_cursor,
+                limit: users.limit,
+            },
+            200,
+        )
     }
     .await;
 
@@ -293,18 +362,16 @@ pub async fn update_password(
     let result = async {
         let claims = extract_claims(&req, &ctx).await?;
 
-        if !is_secure_password(&input.new_password) {
-            return Err(ApiError::new(
-                400,
-                "Password must be at least 8 characters and include uppercase, lowercase, number, and symbol",
-            ));
+        let policy = settings::password_policy(&ctx.data.db, claims.organization_id).await?;
+        if !policy.allows(&input.new_password) {
+            return Err(ApiError::new(400, policy.describe()));
         }
 
         let stored_hash = d1_query_one::<PasswordRow>(
             &ctx.data.db,
             "SELECT password_hash
              FROM users
-             WHERE id = ?1 AND organization_id = ?2
+             WHERE id = ?1 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)
              LIMIT 1",
             &[
                 D1Param::Integer(claims.user_id),
@@ -328,11 +395,11 @@ pub async fn update_password(
             .map_err(|e| ApiError::internal(e.to_string()))?
             .to_string();
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE users
              SET password_hash = ?1
-             WHERE id = ?2 AND organization_id = ?3",
+             WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?3)",
             &[
                 D1Param::Text(new_password_hash),
                 D1Param::Integer(claims.user_id),
@@ -340,6 +407,21 @@ pub async fn update_password(
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
+
+        // Keep the current device signed in; every other session must log in again.
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND id != ?2 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.sid),
+            ],
+        )
+        .await?;
 
         log_activity_d1(
             &ctx.data,
@@ -366,11 +448,14 @@ pub async fn create_user(mut req: Request, ctx: RouteContext<AppState>) -> Worke
     };
 
     let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
-
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Only admins can create users"));
-        }
+        let claims = require_permission(&req, &ctx, Permission::ManageUsers).await?;
+        let role = input
+            .role
+            .clone()
+            .unwrap_or_else(|| permissions::USER_ROLE.to_string());
+        permissions::authorize_grant(&ctx.data.db, &claims, &role)
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
 
         if !is_valid_username(&input.username) {
             return Err(ApiError::new(
@@ -378,11 +463,25 @@ pub async fn create_user(mut req: Request, ctx: RouteContext<AppState>) -> Worke
                 "Username must contain only alphanumeric characters, underscores, or hyphens",
             ));
         }
-        if !is_secure_password(&input.password) {
-            return Err(ApiError::new(
-                400,
-                "Password must be at least 8 characters and include uppercase, lowercase, number, and symbol",
-            ));
+        let policy = settings::password_policy(&ctx.data.db, claims.organization_id).await?;
+        if !policy.allows(&input.password) {
+            return Err(ApiError::new(400, policy.describe()));
+        }
+        if !is_valid_display_name(input.name.trim()) {
+            return Err(ApiError::new(400, "Name must be 1-50 characters"));
+        }
+        if input
+            .avatar_url
+            .as_deref()
+            .is_some_and(|url| !is_valid_avatar_url(url))
+        {
+            return Err(ApiError::new(400, "Avatar URL must be an https URL"));
+        }
+
+        if memberships::username_taken(&ctx.data.db, claims.organization_id, &input.username)
+            .await?
+        {
+            return Err(ApiError::new(409, "Username is already taken"));
         }
 
         let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
@@ -392,64 +491,311 @@ pub async fn create_user(mut req: Request, ctx: RouteContext<AppState>) -> Worke
             .map_err(|e| ApiError::internal(e.to_string()))?
             .to_string();
 
-        d1_execute(
+        let results = d1_batch(
             &ctx.data.db,
-            "INSERT INTO users (organization_id, name, username, password_hash, avatar_url, role, email_verified)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1)",
             &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Text(input.name.clone()),
-                D1Param::Text(input.username.clone()),
-                D1Param::Text(password_hash),
-                input
-                    .avatar_url
-                    .clone()
-                    .map(D1Param::Text)
-                    .unwrap_or(D1Param::Null),
-                D1Param::Text(input.role.clone().unwrap_or_else(|| "user".to_string())),
+                D1Statement::new(
+                    "INSERT INTO users (organization_id, name, username, password_hash, avatar_url, email_verified)
+                     VALUES (?1, ?2, ?3, ?4, ?5, 1)",
+                    vec![
+                        D1Param::Integer(claims.organization_id),
+                        D1Param::Text(input.name.trim().to_string()),
+                        D1Param::Text(input.username.clone()),
+                        D1Param::Text(password_hash),
+                        input
+                            .avatar_url
+                            .clone()
+                            .map(D1Param::Text)
+                            .unwrap_or(D1Param::Null),
+                    ],
+                ),
+                D1Statement::new(
+                    "INSERT INTO memberships (user_id, organization_id, role)
+                     SELECT id, ?1, ?3 FROM users WHERE organization_id = ?1 AND username = ?2",
+                    vec![
+                        D1Param::Integer(claims.organization_id),
+                        D1Param::Text(input.username.clone()),
+                        D1Param::Text(role),
+                    ],
+                ),
             ],
         )
         .await?;
+        let user_id = results[0].inserted_id()?;
+
+        let user = memberships::load_member(&ctx.data.db, claims.organization_id, user_id)
+            .await?
+            .ok_or_else(|| ApiError::internal("Failed to load created user"))?;
+
+        json_with_status(&user, 201)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Creates members from a CSV file (`name,username,email[,role]` with a
+/// header row) and emails each a link to set their password. With
+/// `?dry_run=true` the file is only validated. Nothing is created unless
+/// every row is valid.
+pub async fn import_users(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let body = match req.text().await {
+        Ok(body) => body,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageUsers).await?;
+        let dry_run = query_pairs(&req)?
+            .get("dry_run")
+            .is_some_and(|v| v == "true" || v == "1");
+        let rows = provisioning::parse_csv(&body).map_err(|e| ApiError::new(400, e.to_string()))?;
+
+        let held = permissions::permissions_of(&ctx.data.db, &claims).await?;
+        let assignable_roles: Vec<String> =
+            permissions::list_roles(&ctx.data.db, claims.organization_id)
+                .await?
+                .into_iter()
+                .filter(|role| permissions::can_grant(&held, &role.permissions))
+                .map(|role| role.name)
+                .collect();
+        let errors = provisioning::validate_import(
+            &ctx.data.db,
+            claims.organization_id,
+            &rows,
+            &assignable_roles,
+        )
+        .await?;
+
+        let mut report = ImportReport {
+            dry_run,
+            rows,
+            errors,
+            created: 0,
+            emailed: 0,
+        };
+        if dry_run {
+            return json_with_status(&report, 200);
+        }
+        if !report.errors.is_empty() {
+            return json_with_status(&report, 422);
+        }
 
-        let user = d1_query_one::<User>(
+        let members: Vec<_> = report.rows.iter().map(|row| row.member.clone()).collect();
+        let provisioned =
+            provisioning::create_members(&ctx.data.db, claims.organization_id, &members).await?;
+        report.created = provisioned.len();
+        report.emailed = provisioning::send_welcome_emails(
             &ctx.data.db,
-            "SELECT id, organization_id, name, username, email, pending_email, avatar_url, role, email_verified, created_at
-             FROM users
-             WHERE organization_id = ?1 AND username = ?2
-             ORDER BY id DESC
-             LIMIT 1",
-            &[
-                D1Param::Integer(claims.organization_id),
-                D1Param::Text(input.username.clone()),
-            ],
+            ctx.data.email_service.as_ref(),
+            claims.organization_id,
+            &provisioned,
         )
-        .await?
-        .ok_or_else(|| ApiError::internal("Failed to load created user"))?;
+        .await?;
 
-        json_with_status(&user, 201)
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "users_imported",
+            "user",
+            None,
+            Some(format!(
+                "{} members created, {} welcome emails sent",
+                report.created, report.emailed
+            )),
+        )
+        .await;
+
+        json_with_status(&report, 201)
     }
     .await;
 
     result.or_else(db_error_to_response)
 }
 
-pub async fn delete_user(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+#[derive(Clone, Debug, FromD1Row)]
+struct MemberStateRow {
+    role: String,
+    deactivated_at: Option<String>,
+    deleted_at: Option<String>,
+}
+
+fn user_id_param(ctx: &RouteContext<AppState>) -> Result<i64, ApiError> {
+    ctx.param("id")
+        .ok_or_else(|| ApiError::new(400, "Missing user id"))?
+        .parse::<i64>()
+        .map_err(|_| ApiError::new(400, "Invalid user id"))
+}
+
+/// Loads a member the caller is about to manage; acting on a member with
+/// permissions the caller lacks is refused like a role change.
+async fn member_state_for_update(
+    ctx: &RouteContext<AppState>,
+    claims: &Claims,
+    user_id: i64,
+) -> Result<MemberStateRow, ApiError> {
+    let member = d1_query_one::<MemberStateRow>(
+        &ctx.data.db,
+        "SELECT m.role, m.deactivated_at, u.deleted_at
+         FROM memberships m
+         JOIN users u ON u.id = m.user_id
+         WHERE m.user_id = ?1 AND m.organization_id = ?2
+         LIMIT 1",
+        &[
+            D1Param::Integer(user_id),
+            D1Param::Integer(claims.organization_id),
+        ],
+    )
+    .await?
+    .ok_or_else(|| ApiError::new(404, "User not found"))?;
+
+    let member_permissions =
+        permissions::role_permissions(&ctx.data.db, claims.organization_id, &member.role)
+            .await?
+            .unwrap_or_default();
+    let held = permissions::permissions_of(&ctx.data.db, claims).await?;
+    if !permissions::can_grant(&held, &member_permissions) {
+        return Err(ApiError::new(
+            403,
+            "You cannot manage a member with permissions you do not have",
+        ));
+    }
+    Ok(member)
+}
+
+pub async fn deactivate_user(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    // The body is optional; without one the member's tasks stay with them.
+    let input: DeactivateUserInput = match req.text().await {
+        Ok(body) if body.trim().is_empty() => DeactivateUserInput { reassign_to: None },
+        Ok(body) => match serde_json::from_str(&body) {
+            Ok(v) => v,
+            Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+        },
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
     let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
+        let claims = require_permission(&req, &ctx, Permission::DeleteUsers).await?;
+        let id = user_id_param(&ctx)?;
+        if claims.user_id == id {
+            return Err(ApiError::new(403, "You cannot deactivate your own account"));
+        }
 
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Only admins can delete users"));
+        let member = member_state_for_update(&ctx, &claims, id).await?;
+        if member.deactivated_at.is_some() {
+            return Err(ApiError::new(409, "User is already deactivated"));
         }
 
-        let id = ctx
-            .param("id")
-            .ok_or_else(|| ApiError::new(400, "Missing user id"))?
-            .parse::<i64>()
-            .map_err(|_| ApiError::new(400, "Invalid user id"))?;
+        if let Some(assignee) = input.reassign_to {
+            let active = d1_query_one::<CountRow>(
+                &ctx.data.db,
+                "SELECT COUNT(*) AS count FROM memberships
+                 WHERE user_id = ?1 AND organization_id = ?2 AND deactivated_at IS NULL",
+                &[
+                    D1Param::Integer(assignee),
+                    D1Param::Integer(claims.organization_id),
+                ],
+            )
+            .await?
+            .map(|row| row.count)
+            .unwrap_or(0);
+            if assignee == id || active == 0 {
+                return Err(ApiError::new(
+                    400,
+                    "reassign_to must be another active member",
+                ));
+            }
+        }
+
+        let mut statements = vec![
+            D1Statement::new(
+                "UPDATE memberships SET deactivated_at = CURRENT_TIMESTAMP
+                 WHERE user_id = ?1 AND organization_id = ?2 AND deactivated_at IS NULL",
+                vec![
+                    D1Param::Integer(id),
+                    D1Param::Integer(claims.organization_id),
+                ],
+            ),
+            // Deactivated members are signed out of this organization; their
+            // sessions in other organizations are not ours to end.
+            D1Statement::new(
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+                 WHERE user_id = ?1 AND organization_id = ?2 AND revoked_at IS NULL",
+                vec![
+                    D1Param::Integer(id),
+                    D1Param::Integer(claims.organization_id),
+                ],
+            ),
+        ];
+        if let Some(assignee) = input.reassign_to {
+            // Tasks in the organization's closed statuses stay with their owner.
+            let settings = settings::load(&ctx.data.db, claims.organization_id)
+                .await?
+                .ok_or_else(|| ApiError::new(404, "Organization not found"))?;
+            statements.push(D1Statement::new(
+                "UPDATE tasks SET member_id = ?3, updated_at = CURRENT_TIMESTAMP
+                 WHERE organization_id = ?1 AND member_id = ?2
+                   AND status NOT IN (SELECT value FROM json_each(?4))",
+                vec![
+                    D1Param::Integer(claims.organization_id),
+                    D1Param::Integer(id),
+                    D1Param::Integer(assignee),
+                    settings.closed_statuses_param(),
+                ],
+            ));
+        }
+        let results = d1_batch(&ctx.data.db, &statements).await?;
+        if results[0].changes == 0 {
+            return Err(ApiError::new(409, "User is already deactivated"));
+        }
+        let reassigned_tasks = results.get(2).map(|r| r.changes).unwrap_or(0);
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "user_deactivated",
+            "user",
+            Some(id),
+            input.reassign_to.map(|assignee| {
+                format!("reassigned {reassigned_tasks} open tasks to user {assignee}")
+            }),
+        )
+        .await;
+
+        json_with_status(
+            &json!({ "status": "ok", "reassigned_tasks": reassigned_tasks }),
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn reactivate_user(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::DeleteUsers).await?;
+        let id = user_id_param(&ctx)?;
+
+        let member = member_state_for_update(&ctx, &claims, id).await?;
+        if member.deactivated_at.is_none() {
+            return Err(ApiError::new(409, "User is not deactivated"));
+        }
+        if member.deleted_at.is_some() {
+            return Err(ApiError::new(
+                409,
+                "This account was deleted and cannot be reactivated",
+            ));
+        }
 
         d1_execute(
             &ctx.data.db,
-            "DELETE FROM users WHERE id = ?1 AND organization_id = ?2",
+            "UPDATE memberships SET deactivated_at = NULL WHERE user_id = ?1 AND organization_id = ?2",
             &[
                 D1Param::Integer(id),
                 D1Param::Integer(claims.organization_id),
@@ -457,7 +803,189 @@ pub async fn delete_user(req: Request, ctx: RouteContext<AppState>) -> WorkerRes
         )
         .await?;
 
-        Ok(Response::empty()?.with_status(204))
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "user_reactivated",
+            "user",
+            Some(id),
+            None,
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Validates a profile update into `(column, value)` pairs.
+fn profile_changes(input: UpdateProfileInput) -> Result<Vec<(&'static str, D1Param)>, ApiError> {
+    // An empty string clears an optional field.
+    fn optional(value: String) -> D1Param {
+        if value.is_empty() {
+            D1Param::Null
+        } else {
+            D1Param::Text(value)
+        }
+    }
+
+    let mut changes = Vec::new();
+    if let Some(name) = input.name {
+        let name = name.trim().to_string();
+        if !is_valid_display_name(&name) {
+            return Err(ApiError::new(400, "Name must be 1-50 characters"));
+        }
+        changes.push(("name", D1Param::Text(name)));
+    }
+    if let Some(avatar_url) = input.avatar_url {
+        let avatar_url = avatar_url.trim().to_string();
+        if !avatar_url.is_empty() && !is_valid_avatar_url(&avatar_url) {
+            return Err(ApiError::new(400, "Avatar URL must be an https URL"));
+        }
+        changes.push(("avatar_url", optional(avatar_url)));
+    }
+    if let Some(locale) = input.locale {
+        let locale = locale.trim().to_string();
+        if !locale.is_empty() && !is_valid_locale(&locale) {
+            return Err(ApiError::new(
+                400,
+                "Locale must be a language tag such as ja or en-US",
+            ));
+        }
+        changes.push(("locale", optional(locale)));
+    }
+    if let Some(timezone) = input.timezone {
+        let timezone = timezone.trim().to_string();
+        if !timezone.is_empty() && !is_valid_timezone(&timezone) {
+            return Err(ApiError::new(
+                400,
+                "Timezone must be an IANA time zone such as Asia/Tokyo",
+            ));
+        }
+        changes.push(("timezone", optional(timezone)));
+    }
+    if changes.is_empty() {
+        return Err(ApiError::new(400, "No profile fields to update"));
+    }
+    Ok(changes)
+}
+
+/// Applies a validated profile update and returns the updated user.
+async fn update_profile(
+    state: &AppState,
+    organization_id: i64,
+    user_id: i64,
+    changes: &[(&'static str, D1Param)],
+) -> Result<User, ApiError> {
+    // Setting the URL by hand replaces an uploaded avatar.
+    let replaced_upload = match changes.iter().find(|(column, _)| *column == "avatar_url") {
+        Some((_, url)) => avatars::superseded_key(&state.db, organization_id, user_id, url).await?,
+        None => None,
+    };
+    let mut changes = changes.to_vec();
+    if replaced_upload.is_some() {
+        changes.push(("avatar_key", D1Param::Null));
+    }
+
+    let assignments = changes
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{column} = ?{}", i + 3))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut params = vec![D1Param::Integer(user_id), D1Param::Integer(organization_id)];
+    params.extend(changes.iter().map(|(_, value)| value.clone()));
+    let update = d1_execute(
+        &state.db,
+        &format!(
+            "UPDATE users SET {assignments}
+             WHERE id = ?1 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)"
+        ),
+        &params,
+    )
+    .await?;
+    if update.changes == 0 {
+        return Err(ApiError::new(404, "User not found"));
+    }
+    if let Some(key) = replaced_upload {
+        state.avatars.delete(&key).await?;
+    }
+
+    memberships::load_member(&state.db, organization_id, user_id)
+        .await?
+        .ok_or_else(|| ApiError::new(404, "User not found"))
+}
+
+fn changed_fields(changes: &[(&'static str, D1Param)]) -> String {
+    changes
+        .iter()
+        .map(|(column, _)| *column)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub async fn update_me(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let input: UpdateProfileInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let changes = profile_changes(input)?;
+        let user =
+            update_profile(&ctx.data, claims.organization_id, claims.user_id, &changes).await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "profile_updated",
+            "user",
+            Some(claims.user_id),
+            Some(changed_fields(&changes)),
+        )
+        .await;
+
+        json_with_status(&user, 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn update_user_profile(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: UpdateProfileInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageUsers).await?;
+        let id = user_id_param(&ctx)?;
+        member_state_for_update(&ctx, &claims, id).await?;
+
+        let changes = profile_changes(input)?;
+        let user = update_profile(&ctx.data, claims.organization_id, id, &changes).await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "user_profile_updated",
+            "user",
+            Some(id),
+            Some(changed_fields(&changes)),
+        )
+        .await;
+
+        json_with_status(&user, 200)
     }
     .await;
 
@@ -474,7 +1002,7 @@ pub async fn update_user_role(
     };
 
     let result = async {
-        let claims = extract_claims(&req, &ctx).await?;
+        let claims = require_permission(&req, &ctx, Permission::ManageUsers).await?;
 
         let target_user_id = ctx
             .param("id")
@@ -482,17 +1010,13 @@ pub async fn update_user_role(
             .parse::<i64>()
             .map_err(|_| ApiError::new(400, "Invalid user id"))?;
 
-        if claims.role != "admin" {
-            return Err(ApiError::new(403, "Only admins can update user roles"));
-        }
-
         if claims.user_id == target_user_id {
             return Err(ApiError::new(403, "You cannot update your own role"));
         }
 
         let previous_role = d1_query_one::<RoleRow>(
             &ctx.data.db,
-            "SELECT role FROM users WHERE id = ?1 AND organization_id = ?2 LIMIT 1",
+            "SELECT role FROM memberships WHERE user_id = ?1 AND organization_id = ?2 LIMIT 1",
             &[
                 D1Param::Integer(target_user_id),
                 D1Param::Integer(claims.organization_id),
@@ -501,9 +1025,28 @@ pub async fn update_user_role(
         .await?
         .ok_or_else(|| ApiError::new(404, "User not found"))?;
 
-        d1_execute(
+        permissions::authorize_grant(&ctx.data.db, &claims, &input.role)
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+        // Changing a role also takes the current one away, so the same limit applies.
+        let previous_permissions = permissions::role_permissions(
+            &ctx.data.db,
+            claims.organization_id,
+            &previous_role.role,
+        )
+        .await?
+        .unwrap_or_default();
+        let held = permissions::permissions_of(&ctx.data.db, &claims).await?;
+        if !permissions::can_grant(&held, &previous_permissions) {
+            return Err(ApiError::new(
+                403,
+                "You cannot change the role of a member with permissions you do not have",
+            ));
+        }
+
+        let update = d1_execute(
             &ctx.data.db,
-            "UPDATE users SET role = ?1 WHERE id = ?2 AND organization_id = ?3",
+            "UPDATE memberships SET role = ?1 WHERE user_id = ?2 AND organization_id = ?3",
             &[
                 D1Param::Text(input.role.clone()),
                 D1Param::Integer(target_user_id),
@@ -511,6 +1054,9 @@ pub async fn update_user_role(
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
 
         log_activity_d1(
             &ctx.data,
@@ -530,6 +1076,79 @@ pub async fn update_user_role(
     result.or_else(db_error_to_response)
 }
 
+/// Puts a member on a manager's team, or takes them off it with a `null`
+/// `manager_id`.
+pub async fn update_user_manager(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: UpdateUserManagerInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = require_permission(&req, &ctx, Permission::ManageUsers).await?;
+
+        let target_user_id = ctx
+            .param("id")
+            .ok_or_else(|| ApiError::new(400, "Missing user id"))?
+            .parse::<i64>()
+            .map_err(|_| ApiError::new(400, "Invalid user id"))?;
+
+        if let Some(manager_id) = input.manager_id {
+            let active = d1_query_one::<CountRow>(
+                &ctx.data.db,
+                "SELECT COUNT(*) AS count FROM memberships
+                 WHERE user_id = ?1 AND organization_id = ?2 AND deactivated_at IS NULL",
+                &[
+                    D1Param::Integer(manager_id),
+                    D1Param::Integer(claims.organization_id),
+                ],
+            )
+            .await?
+            .map(|row| row.count)
+            .unwrap_or(0);
+            if manager_id == target_user_id || active == 0 {
+                return Err(ApiError::new(
+                    400,
+                    "manager_id must be another active member",
+                ));
+            }
+        }
+
+        let update = d1_execute(
+            &ctx.data.db,
+            "UPDATE memberships SET manager_id = ?1 WHERE user_id = ?2 AND organization_id = ?3",
+            &[
+                input.manager_id.map_or(D1Param::Null, D1Param::Integer),
+                D1Param::Integer(target_user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "user_manager_updated",
+            "user",
+            Some(target_user_id),
+            input.manager_id.map(|id| format!("manager_id: {id}")),
+        )
+        .await;
+
+        json_with_status(&json!({ "status": "ok" }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
 pub async fn update_email(mut req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
     let input: UpdateEmailInput = match req.json().await {
         Ok(v) => v,
@@ -545,11 +1164,11 @@ pub async fn update_email(mut req: Request, ctx: RouteContext<AppState>) -> Work
 
         let token = uuid::Uuid::new_v4().to_string();
 
-        d1_execute(
+        let update = d1_execute(
             &ctx.data.db,
             "UPDATE users
              SET pending_email = ?1, email_verification_token = ?2
-             WHERE id = ?3 AND organization_id = ?4",
+             WHERE id = ?3 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?4)",
             &[
                 D1Param::Text(input.email.clone()),
                 D1Param::Text(token.clone()),
@@ -558,6 +1177,9 @@ pub async fn update_email(mut req: Request, ctx: RouteContext<AppState>) -> Work
             ],
         )
         .await?;
+        if update.changes == 0 {
+            return Err(ApiError::new(404, "User not found"));
+        }
 
         ctx.data
             .email_service
@@ -582,3 +1204,323 @@ pub async fn update_email(mut req: Request, ctx: RouteContext<AppState>) -> Work
 
     result.or_else(db_error_to_response)
 }
+
+pub async fn export_account(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let format = query_pairs(&req)?
+            .get("format")
+            .cloned()
+            .unwrap_or_else(|| "json".to_string());
+
+        let export = account::collect_export(&ctx.data.db, claims.organization_id, claims.user_id)
+            .await?
+            .ok_or_else(|| ApiError::new(404, "User not found"))?;
+        let date = Utc::now().format("%Y%m%d");
+
+        let mut response = match format.as_str() {
+            "json" => {
+                let mut response = Response::from_json(&export)?;
+                response.headers_mut().set(
+                    "Content-Disposition",
+                    &format!("attachment; filename=\"account-export-{date}.json\""),
+                )?;
+                response
+            }
+            "zip" => {
+                let mut response = Response::from_bytes(export.to_zip()?)?;
+                let headers = response.headers_mut();
+                headers.set("Content-Type", "application/zip")?;
+                headers.set(
+                    "Content-Disposition",
+                    &format!("attachment; filename=\"account-export-{date}.zip\""),
+                )?;
+                response
+            }
+            _ => return Err(ApiError::new(400, "format must be json or zip")),
+        };
+        response.headers_mut().set("Cache-Control", "no-store")?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "account_exported",
+            "user",
+            Some(claims.user_id),
+            Some(format),
+        )
+        .await;
+
+        Ok(response)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn get_account_deletion(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let scheduled_at =
+            account::deletion_scheduled_at(&ctx.data.db, claims.organization_id, claims.user_id)
+                .await?;
+        let organizations = account::organizations_of(&ctx.data.db, claims.user_id).await?;
+
+        json_with_status(
+            &json!({ "scheduled_at": scheduled_at, "organizations": organizations }),
+            200,
+        )
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn request_account_deletion(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let input: DeleteAccountInput = match req.json().await {
+        Ok(v) => v,
+        Err(e) => return ApiError::new(400, e.to_string()).into_response(),
+    };
+
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let organizations = account::organizations_of(&ctx.data.db, claims.user_id).await?;
+        if !input.all_organizations {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "Deleting your account removes it from every organization it belongs to ({}); confirm with all_organizations",
+                    organizations.join(", ")
+                ),
+            ));
+        }
+
+        let stored_hash = d1_query_one::<PasswordRow>(
+            &ctx.data.db,
+            "SELECT password_hash
+             FROM users
+             WHERE id = ?1 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?2)
+             LIMIT 1",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.organization_id),
+            ],
+        )
+        .await?
+        .ok_or_else(|| ApiError::new(404, "User not found"))?;
+        let parsed_hash = PasswordHash::new(&stored_hash.password_hash)
+            .map_err(|_| ApiError::internal("Invalid password hash in DB"))?;
+        Argon2::default()
+            .verify_password(input.password.as_bytes(), &parsed_hash)
+            .map_err(|_| ApiError::new(401, "Password is incorrect"))?;
+
+        // Deleting the account ends every membership, so each organization it
+        // administers must keep an admin who is not leaving as well.
+        let sole_admin_of = d1_query_one::<CountRow>(
+            &ctx.data.db,
+            "SELECT COUNT(*) AS count
+             FROM memberships mine
+             WHERE mine.user_id = ?1 AND mine.role = ?2 AND mine.deactivated_at IS NULL
+               AND NOT EXISTS (
+                   SELECT 1
+                   FROM memberships other
+                   JOIN users u ON u.id = other.user_id
+                   WHERE other.organization_id = mine.organization_id
+                     AND other.user_id != ?1 AND other.role = ?2
+                     AND other.deactivated_at IS NULL AND u.deletion_scheduled_at IS NULL
+               )",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Text(permissions::ADMIN_ROLE.to_string()),
+            ],
+        )
+        .await?
+        .map(|row| row.count)
+        .unwrap_or(0);
+        if sole_admin_of > 0 {
+            return Err(ApiError::new(
+                409,
+                "Make another member an admin before deleting your account",
+            ));
+        }
+
+        let scheduled_at =
+            account::schedule_deletion(&ctx.data.db, claims.organization_id, claims.user_id)
+                .await?;
+
+        // Other devices are signed out; this one stays so the owner can cancel.
+        d1_execute(
+            &ctx.data.db,
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND id != ?2 AND revoked_at IS NULL",
+            &[
+                D1Param::Integer(claims.user_id),
+                D1Param::Integer(claims.sid),
+            ],
+        )
+        .await?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "account_deletion_scheduled",
+            "user",
+            Some(claims.user_id),
+            Some(format!(
+                "purge after {scheduled_at}; organizations: {}",
+                organizations.join(", ")
+            )),
+        )
+        .await;
+
+        json_with_status(&json!({ "scheduled_at": scheduled_at }), 202)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn cancel_account_deletion(
+    req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        if !account::cancel_deletion(&ctx.data.db, claims.organization_id, claims.user_id).await? {
+            return Err(ApiError::new(404, "No account deletion is scheduled"));
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "account_deletion_cancelled",
+            "user",
+            Some(claims.user_id),
+            None,
+        )
+        .await;
+
+        json_with_status(&json!({ "scheduled_at": null }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn upload_avatar(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+        let bytes = req.bytes().await?;
+        let url = req.url()?;
+        let base_url = url.origin().ascii_serialization();
+
+        let avatar_url = avatars::replace(
+            &ctx.data.db,
+            &ctx.data.avatars,
+            claims.organization_id,
+            claims.user_id,
+            bytes,
+            &base_url,
+        )
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "avatar_uploaded",
+            "user",
+            Some(claims.user_id),
+            None,
+        )
+        .await;
+
+        json_with_status(&json!({ "avatar_url": avatar_url }), 200)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+pub async fn delete_avatar(req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let claims = extract_claims(&req, &ctx).await?;
+
+        let removed = avatars::remove(
+            &ctx.data.db,
+            &ctx.data.avatars,
+            claims.organization_id,
+            claims.user_id,
+        )
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.to_string()))?;
+        if !removed {
+            return Err(ApiError::new(404, "No avatar is set"));
+        }
+
+        log_activity_d1(
+            &ctx.data,
+            claims.organization_id,
+            claims.user_id,
+            "avatar_removed",
+            "user",
+            Some(claims.user_id),
+            None,
+        )
+        .await;
+
+        Ok(Response::empty()?.with_status(204))
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
+
+/// Serves an uploaded avatar. Public so `<img>` tags can load it; the URL
+/// carries a random id and changes with every upload.
+pub async fn get_avatar(_req: Request, ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+    let result = async {
+        let id_param = |name: &str| {
+            ctx.param(name)
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or_else(|| ApiError::new(404, "Avatar not found"))
+        };
+        let organization_id = id_param("org")?;
+        let user_id = id_param("user")?;
+        let file_name = ctx.param("file").map(String::as_str).unwrap_or_default();
+
+        let object = avatars::load(&ctx.data.avatars, organization_id, user_id, file_name)
+            .await?
+            .ok_or_else(|| ApiError::new(404, "Avatar not found"))?;
+
+        let mut response = Response::from_bytes(object.bytes)?;
+        let headers = response.headers_mut();
+        headers.set(
+            "Content-Type",
+            object
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream"),
+        )?;
+        headers.set("Cache-Control", "public, max-age=31536000, immutable")?;
+        headers.set("X-Content-Type-Options", "nosniff")?;
+        Ok(response)
+    }
+    .await;
+
+    result.or_else(db_error_to_response)
+}
@@ -1,7 +1,8 @@
-use crate::AppState;
-use worker::{Request, Response, Result as WorkerResult, RouteContext, WebSocketPair};
+use worker::{Request, Response, Result as WorkerResult, WebSocketPair};
 
-pub async fn ws_handler(req: Request, _ctx: RouteContext<AppState>) -> WorkerResult<Response> {
+/// Called from `fetch` directly rather than through [`crate::router`]:
+/// websocket upgrades only exist in the Workers runtime.
+pub async fn ws_handler(req: Request) -> WorkerResult<Response> {
     let upgrade = req
         .headers()
         .get("Upgrade")?
@@ -1,7 +1,26 @@
+pub mod account;
+pub mod api_tokens;
+pub mod archive;
+pub mod avatars;
+pub mod db;
 pub mod email;
+pub mod jwt;
+pub mod memberships;
+pub mod migrations;
 pub mod models;
+pub mod oidc;
+pub mod permissions;
+pub mod provisioning;
+pub mod routing;
+pub mod scim;
+pub mod settings;
+pub mod throttle;
+pub mod timezone;
+pub mod totp;
 mod utils;
 
+#[path = "handlers/admin.rs"]
+mod admin;
 #[path = "handlers/analytics.rs"]
 mod analytics;
 #[path = "handlers/auth.rs"]
@@ -14,10 +33,20 @@ mod invitations;
 mod logs;
 #[path = "handlers/notifications.rs"]
 mod notifications;
+#[path = "handlers/organization.rs"]
+mod organization;
 #[path = "handlers/reports.rs"]
 mod reports;
+#[path = "handlers/roles.rs"]
+mod roles;
+#[path = "handlers/scim_api.rs"]
+mod scim_api;
 #[path = "handlers/tasks.rs"]
 mod tasks;
+#[path = "handlers/tokens.rs"]
+mod tokens;
+#[path = "handlers/two_factor.rs"]
+mod two_factor;
 #[path = "handlers/users.rs"]
 mod users;
 #[path = "handlers/ws.rs"]
@@ -78,21 +107,240 @@ fn with_cors(mut response: Response, env: &Env, request_origin: Option<&str>) ->
         "Access-Control-Allow-Methods",
         "GET, POST, PUT, PATCH, DELETE, OPTIONS",
     )?;
-    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization")?;
+    headers.set(
+        "Access-Control-Allow-Headers",
+        "Content-Type, Authorization",
+    )?;
+    headers.set("Access-Control-Expose-Headers", "Retry-After")?;
     headers.set("Access-Control-Max-Age", "86400")?;
     Ok(response)
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Arc<D1Database>,
-    pub jwt_secret: String,
+    pub db: Arc<dyn db::Database>,
+    pub jwt_keys: Arc<jwt::KeyRing>,
     pub email_service: Arc<dyn email::EmailService>,
+    pub attempts: Arc<dyn throttle::AttemptStore>,
+    pub oidc_http: Arc<dyn oidc::HttpClient>,
+    pub avatars: Arc<dyn avatars::ObjectStore>,
+    /// SHA-256 of the `OPERATOR_TOKEN` secret. Schema migrations touch every
+    /// organization, so they answer to this rather than to any tenant role;
+    /// `None` turns them off.
+    pub operator_token_hash: Option<String>,
+}
+
+impl AppState {
+    /// Lockouts are kept in `db`; SSO discovery and token calls go through
+    /// `fetch`.
+    pub fn new(
+        db: Arc<dyn db::Database>,
+        avatars: Arc<dyn avatars::ObjectStore>,
+        jwt_keys: Arc<jwt::KeyRing>,
+        email_service: Arc<dyn email::EmailService>,
+    ) -> Self {
+        Self {
+            attempts: Arc::new(throttle::D1AttemptStore::new(db.clone())),
+            db,
+            jwt_keys,
+            email_service,
+            oidc_http: Arc::new(oidc::FetchHttpClient),
+            avatars,
+            operator_token_hash: None,
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct HealthResponse {
     status: &'static str,
+    schema_version: Option<i64>,
+    expected_schema_version: i64,
+}
+
+/// Every API route. `ws` is served by `fetch` alone, since websocket
+/// upgrades only exist in the Workers runtime.
+pub fn router(state: AppState) -> routing::Router<AppState> {
+    routing::Router::with_data(state)
+        .get_async("/", |_req, _ctx| async move {
+            routing::Response::ok("GlanceFlow API is running")
+        })
+        .get_async("/ping", |_req, _ctx| async move {
+            routing::Response::ok("Pong")
+        })
+        .get_async("/.well-known/jwks.json", |_req, ctx| async move {
+            let mut response = routing::Response::from_json(&ctx.data.jwt_keys.jwks())?;
+            response
+                .headers_mut()
+                .set("Cache-Control", "public, max-age=300")?;
+            Ok(response)
+        })
+        .get_async("/health", |_req, ctx| async move {
+            let schema_version = migrations::current_version(&ctx.data.db)
+                .await
+                .unwrap_or_else(|err| {
+                    console_error!("failed to read schema version: {}", err);
+                    None
+                });
+            let expected_schema_version = migrations::latest_version();
+            let status = if schema_version == Some(expected_schema_version) {
+                "ok"
+            } else {
+                "schema_mismatch"
+            };
+            routing::Response::from_json(&HealthResponse {
+                status,
+                schema_version,
+                expected_schema_version,
+            })
+        })
+        .get_async("/api/admin/migrations", admin::get_migrations)
+        .post_async("/api/admin/migrations/apply", admin::apply_migrations)
+        .get_async("/api/admin/archive", admin::export_archive)
+        .post_async("/api/admin/archive", admin::import_archive)
+        .delete_async("/api/admin/archive", admin::clear_partial_restore)
+        .post_async("/api/auth/login", auth::login)
+        .post_async("/api/auth/register", auth::register)
+        .post_async("/api/auth/join", auth::join)
+        .post_async("/api/auth/forgot-password", auth::forgot_password)
+        .post_async("/api/auth/reset-password", auth::reset_password)
+        .post_async("/api/auth/verify-email", auth::verify_email)
+        .post_async("/api/auth/resend-verification", auth::resend_verification)
+        .post_async("/api/auth/refresh", auth::refresh)
+        .post_async("/api/auth/logout", auth::logout)
+        .get_async("/api/auth/sessions", auth::get_sessions)
+        .delete_async("/api/auth/sessions", auth::revoke_all_sessions)
+        .delete_async("/api/auth/sessions/:id", auth::revoke_session)
+        .get_async("/api/auth/organizations", auth::get_organizations)
+        .post_async("/api/auth/switch-organization", auth::switch_organization)
+        .post_async("/api/auth/2fa/verify", auth::verify_two_factor)
+        .post_async("/api/auth/sso/start", auth::sso_start)
+        .post_async("/api/auth/sso/callback", auth::sso_callback)
+        .get_async("/api/auth/2fa", two_factor::get_status)
+        .post_async("/api/auth/2fa/setup", two_factor::setup)
+        .post_async("/api/auth/2fa/enable", two_factor::enable)
+        .post_async("/api/auth/2fa/disable", two_factor::disable)
+        .post_async(
+            "/api/auth/2fa/recovery-codes",
+            two_factor::regenerate_recovery_codes,
+        )
+        .get_async("/api/organization", organization::get_organization)
+        .patch_async("/api/organization", organization::update_organization)
+        .get_async(
+            "/api/organization/security",
+            organization::get_security_settings,
+        )
+        .patch_async(
+            "/api/organization/security",
+            organization::update_security_settings,
+        )
+        .get_async("/api/organization/sso", organization::get_sso_settings)
+        .put_async("/api/organization/sso", organization::update_sso_settings)
+        .get_async(
+            "/api/organization/scim-tokens",
+            organization::get_scim_tokens,
+        )
+        .post_async(
+            "/api/organization/scim-tokens",
+            organization::create_scim_token,
+        )
+        .delete_async(
+            "/api/organization/scim-tokens/:id",
+            organization::revoke_scim_token,
+        )
+        .get_async(
+            "/scim/v2/ServiceProviderConfig",
+            scim_api::service_provider_config,
+        )
+        .get_async("/scim/v2/Users", scim_api::list_users)
+        .post_async("/scim/v2/Users", scim_api::create_user)
+        .get_async("/scim/v2/Users/:id", scim_api::get_user)
+        .put_async("/scim/v2/Users/:id", scim_api::replace_user)
+        .patch_async("/scim/v2/Users/:id", scim_api::patch_user)
+        .delete_async("/scim/v2/Users/:id", scim_api::delete_user)
+        .get_async("/scim/v2/Groups", scim_api::list_groups)
+        .post_async("/scim/v2/Groups", scim_api::unsupported_group_change)
+        .get_async("/scim/v2/Groups/:id", scim_api::get_group)
+        .put_async("/scim/v2/Groups/:id", scim_api::replace_group)
+        .patch_async("/scim/v2/Groups/:id", scim_api::patch_group)
+        .delete_async("/scim/v2/Groups/:id", scim_api::unsupported_group_change)
+        .get_async("/api/roles", roles::get_roles)
+        .post_async("/api/roles", roles::create_role)
+        .put_async("/api/roles/:name", roles::update_role)
+        .delete_async("/api/roles/:name", roles::delete_role)
+        .get_async("/api/invitations", invitations::get_invitations)
+        .post_async("/api/invitations", invitations::create_invitation)
+        .delete_async("/api/invitations/:id", invitations::revoke_invitation)
+        .get_async("/api/invitations/:token", invitations::get_invitation)
+        .get_async("/api/tasks", tasks::get_tasks)
+        .post_async("/api/tasks", tasks::create_task)
+        .post_async("/api/tasks/time-logs", tasks::add_time_log)
+        .patch_async("/api/tasks/time-logs/:id", tasks::update_time_log)
+        .delete_async("/api/tasks/time-logs/:id", tasks::delete_time_log)
+        .get_async("/api/tasks/report", tasks::get_task_report)
+        .get_async("/api/tasks/report/export", tasks::export_task_report)
+        .patch_async("/api/tasks/:id", tasks::update_task)
+        .delete_async("/api/tasks/:id", tasks::delete_task)
+        .get_async("/api/reports", reports::get_reports)
+        .post_async("/api/reports", reports::create_report)
+        .get_async("/api/reports/:id", reports::get_report)
+        .patch_async("/api/reports/:id", reports::update_report)
+        .get_async("/api/logs", logs::get_logs)
+        .get_async("/api/logs/export", logs::export_logs)
+        .get_async("/api/notifications", notifications::get_notifications)
+        .patch_async(
+            "/api/notifications/read-all",
+            notifications::mark_all_as_read,
+        )
+        .patch_async("/api/notifications/:id/read", notifications::mark_as_read)
+        .get_async("/api/analytics/personal", analytics::get_personal_analytics)
+        .get_async("/api/analytics/users/:id", analytics::get_user_analytics)
+        .get_async("/api/avatars/:org/:user/:file", users::get_avatar)
+        .get_async("/api/users", users::get_users)
+        .post_async("/api/users", users::create_user)
+        .post_async("/api/users/import", users::import_users)
+        .patch_async("/api/users/me", users::update_me)
+        .put_async("/api/users/me/avatar", users::upload_avatar)
+        .delete_async("/api/users/me/avatar", users::delete_avatar)
+        .patch_async("/api/users/me/password", users::update_password)
+        .patch_async("/api/users/me/email", users::update_email)
+        .get_async("/api/users/me/export", users::export_account)
+        .get_async("/api/users/me/deletion", users::get_account_deletion)
+        .post_async("/api/users/me/deletion", users::request_account_deletion)
+        .delete_async("/api/users/me/deletion", users::cancel_account_deletion)
+        .get_async("/api/users/me/tokens", tokens::get_tokens)
+        .post_async("/api/users/me/tokens", tokens::create_token)
+        .delete_async("/api/users/me/tokens/:id", tokens::revoke_token)
+        .patch_async("/api/users/:id", users::update_user_profile)
+        .put_async("/api/users/:id/role", users::update_user_role)
+        .put_async("/api/users/:id/manager", users::update_user_manager)
+        .post_async("/api/users/:id/deactivate", users::deactivate_user)
+        .post_async("/api/users/:id/reactivate", users::reactivate_user)
+        .get_async("/api/display-groups", groups::get_display_groups)
+        .post_async("/api/display-groups", groups::create_display_group)
+        .patch_async("/api/display-groups/:id", groups::update_display_group)
+        .delete_async("/api/display-groups/:id", groups::delete_display_group)
+}
+
+async fn into_request(mut req: Request) -> Result<routing::Request> {
+    let mut request = routing::Request::new(req.url()?.as_str(), req.method())?;
+    for (name, value) in req.headers().entries() {
+        request.headers_mut().set(&name, &value)?;
+    }
+    Ok(request.with_body(req.bytes().await?))
+}
+
+fn into_worker_response(response: routing::Response) -> Result<Response> {
+    let (status, headers, body) = response.into_parts();
+    let mut response = match body {
+        routing::ResponseBody::Empty => Response::empty()?,
+        routing::ResponseBody::Body(bytes) => Response::from_bytes(bytes)?,
+        routing::ResponseBody::Stream(stream) => Response::from_stream(stream)?,
+    };
+    for (name, value) in headers.entries() {
+        response.headers_mut().set(name, value)?;
+    }
+    Ok(response.with_status(status))
 }
 
 #[event(fetch)]
@@ -107,39 +355,17 @@ pub async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     }
 
     let result: Result<Response> = async {
-        let db = Arc::new(env.d1("DB")?);
-        let jwt_secret = match env.secret("JWT_SECRET") {
-            Ok(secret) => secret.to_string(),
+        let db: Arc<dyn db::Database> = Arc::new(env.d1("DB")?);
+        let jwt_keys = match jwt::KeyRing::from_config(
+            env.secret("JWT_KEYS").ok().map(|v| v.to_string()).as_deref(),
+            read_optional_env(&env, "JWT_ACTIVE_KID").as_deref(),
+            env.secret("JWT_SECRET").ok().map(|v| v.to_string()).as_deref(),
+        ) {
+            Ok(keys) => Arc::new(keys),
             Err(err) => {
-                let environment = read_optional_env(&env, "ENVIRONMENT")
-                    .or_else(|| read_optional_env(&env, "NODE_ENV"))
-                    .unwrap_or_else(|| "development".to_string());
-
-                if environment.eq_ignore_ascii_case("production") {
-                    console_error!("JWT_SECRET is missing in production: {}", err);
-                    return Response::from_json(
-                        &json!({"error": "Server misconfiguration: JWT_SECRET is missing"}),
-                    )
-                    .map(|response| response.with_status(500));
-                }
-
-                #[cfg(debug_assertions)]
-                {
-                    console_error!(
-                        "JWT_SECRET is missing (environment={}); using insecure default secret in debug build",
-                        environment
-                    );
-                    "insecure-default-secret".to_string()
-                }
-
-                #[cfg(not(debug_assertions))]
-                console_log!(
-                    "JWT_SECRET is missing (environment={}); refusing startup in non-debug build",
-                    environment
-                );
-                #[cfg(not(debug_assertions))]
+                console_error!("{}", err);
                 return Response::from_json(
-                    &json!({"error": "Server misconfiguration: JWT_SECRET is missing"}),
+                    &json!({"error": "Server misconfiguration: JWT signing keys are missing or invalid"}),
                 )
                 .map(|response| response.with_status(500));
             }
@@ -164,65 +390,21 @@ pub async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             Arc::new(email::StdoutEmailProvider::new(frontend_url))
         };
 
+        let avatars: Arc<dyn avatars::ObjectStore> =
+            Arc::new(avatars::R2ObjectStore::new(env.bucket("AVATARS")?));
         let state = AppState {
-            db,
-            jwt_secret,
-            email_service,
+            operator_token_hash: env
+                .secret("OPERATOR_TOKEN")
+                .ok()
+                .map(|v| utils::hash_token(&v.to_string())),
+            ..AppState::new(db, avatars, jwt_keys, email_service)
         };
 
-        Router::with_data(state)
-            .get_async("/", |_req, _ctx| async move {
-                Response::ok("GlanceFlow API is running")
-            })
-            .get_async("/ping", |_req, _ctx| async move { Response::ok("Pong") })
-            .get_async("/health", |_req, _ctx| async move {
-                Response::from_json(&HealthResponse { status: "ok" })
-            })
-            .post_async("/api/auth/login", auth::login)
-            .post_async("/api/auth/register", auth::register)
-            .post_async("/api/auth/join", auth::join)
-            .post_async("/api/auth/forgot-password", auth::forgot_password)
-            .post_async("/api/auth/reset-password", auth::reset_password)
-            .post_async("/api/auth/verify-email", auth::verify_email)
-            .post_async("/api/auth/resend-verification", auth::resend_verification)
-            .post_async("/api/invitations", invitations::create_invitation)
-            .get_async("/api/invitations/:token", invitations::get_invitation)
-            .get_async("/api/tasks", tasks::get_tasks)
-            .post_async("/api/tasks", tasks::create_task)
-            .post_async("/api/tasks/time-logs", tasks::add_time_log)
-            .patch_async("/api/tasks/time-logs/:id", tasks::update_time_log)
-            .delete_async("/api/tasks/time-logs/:id", tasks::delete_time_log)
-            .get_async("/api/tasks/report", tasks::get_task_report)
-            .get_async("/api/tasks/report/export", tasks::export_task_report)
-            .patch_async("/api/tasks/:id", tasks::update_task)
-            .delete_async("/api/tasks/:id", tasks::delete_task)
-            .get_async("/api/reports", reports::get_reports)
-            .post_async("/api/reports", reports::create_report)
-            .get_async("/api/reports/:id", reports::get_report)
-            .patch_async("/api/reports/:id", reports::update_report)
-            .get_async("/api/logs", logs::get_logs)
-            .get_async("/api/logs/export", logs::export_logs)
-            .get_async("/api/notifications", notifications::get_notifications)
-            .patch_async(
-                "/api/notifications/read-all",
-                notifications::mark_all_as_read,
-            )
-            .patch_async("/api/notifications/:id/read", notifications::mark_as_read)
-            .get_async("/api/analytics/personal", analytics::get_personal_analytics)
-            .get_async("/api/analytics/users/:id", analytics::get_user_analytics)
-            .get_async("/api/users", users::get_users)
-            .post_async("/api/users", users::create_user)
-            .patch_async("/api/users/me/password", users::update_password)
-            .patch_async("/api/users/me/email", users::update_email)
-            .put_async("/api/users/:id/role", users::update_user_role)
-            .delete_async("/api/users/:id", users::delete_user)
-            .get_async("/api/display-groups", groups::get_display_groups)
-            .post_async("/api/display-groups", groups::create_display_group)
-            .patch_async("/api/display-groups/:id", groups::update_display_group)
-            .delete_async("/api/display-groups/:id", groups::delete_display_group)
-            .get_async("/ws", ws::ws_handler)
-            .run(req, env.clone())
-            .await
+        if req.method() == Method::Get && req.path() == "/ws" {
+            return ws::ws_handler(req).await;
+        }
+        let response = router(state).run(into_request(req).await?).await?;
+        into_worker_response(response)
     }
     .await;
 
@@ -236,3 +418,28 @@ pub async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         }
     }
 }
+
+/// Hourly cron: purges accounts whose deletion grace period has passed.
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+    let db: Arc<dyn db::Database> = match env.d1("DB") {
+        Ok(db) => Arc::new(db),
+        Err(err) => {
+            console_error!("scheduled: D1 binding unavailable: {:?}", err);
+            return;
+        }
+    };
+    let avatars = match env.bucket("AVATARS") {
+        Ok(bucket) => avatars::R2ObjectStore::new(bucket),
+        Err(err) => {
+            console_error!("scheduled: R2 binding unavailable: {:?}", err);
+            return;
+        }
+    };
+    match account::purge_due_deletions(&db, &avatars).await {
+        Ok(0) => {}
+        Ok(purged) => console_log!("purged {} deleted account(s)", purged),
+        Err(err) => console_error!("failed to purge deleted accounts: {}", err),
+    }
+}
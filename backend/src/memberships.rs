@@ -0,0 +1,84 @@
+//! Accounts that belong to several organizations.
+//!
+//! An account (`users`) joins an organization through a row in
+//! `memberships`, which holds its role and deactivation there. Tokens are
+//! scoped to one membership at a time; `users.organization_id` only records
+//! where the account was created.
+
+use crate::db::Database;
+use crate::models::{
+    CountRow, D1Param, MembershipSummary, ModelError, User, d1_query_all, d1_query_one,
+};
+
+/// [`User`] columns for `FROM users u JOIN memberships m`.
+pub const MEMBER_COLUMNS: &str =
+    "u.id, m.organization_id, u.name, u.username, u.email, u.pending_email,
+     u.avatar_url, u.locale, u.timezone, m.role, u.email_verified, u.created_at,
+     m.deactivated_at, m.manager_id";
+
+/// The account as a member of `organization_id`, including deactivated
+/// members.
+pub async fn load_member(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Option<User>, ModelError> {
+    d1_query_one::<User>(
+        db,
+        &format!(
+            "SELECT {MEMBER_COLUMNS}
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             WHERE m.organization_id = ?1 AND u.id = ?2
+             LIMIT 1"
+        ),
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await
+}
+
+/// The organizations the account is an active member of, oldest first.
+pub async fn list(
+    db: &dyn Database,
+    user_id: i64,
+    current_organization_id: i64,
+) -> Result<Vec<MembershipSummary>, ModelError> {
+    d1_query_all::<MembershipSummary>(
+        db,
+        "SELECT m.organization_id, o.slug, o.name, m.role,
+                (m.organization_id = ?2) AS current
+         FROM memberships m
+         JOIN organizations o ON o.id = m.organization_id
+         WHERE m.user_id = ?1 AND m.deactivated_at IS NULL
+         ORDER BY m.created_at ASC, m.id ASC",
+        &[
+            D1Param::Integer(user_id),
+            D1Param::Integer(current_organization_id),
+        ],
+    )
+    .await
+}
+
+/// Whether a member of `organization_id`, deactivated or not, already has
+/// `username`. The `users` table only keeps usernames unique within the
+/// organization an account was created in, so accounts that joined from
+/// elsewhere are checked through their memberships.
+pub async fn username_taken(
+    db: &dyn Database,
+    organization_id: i64,
+    username: &str,
+) -> Result<bool, ModelError> {
+    Ok(d1_query_one::<CountRow>(
+        db,
+        "SELECT COUNT(*) AS count
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND u.username = ?2",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(username.to_string()),
+        ],
+    )
+    .await?
+    .is_some_and(|row| row.count > 0))
+}
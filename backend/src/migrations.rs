@@ -0,0 +1,306 @@
+use crate::db::Database;
+use crate::models::{
+    D1Param, D1Statement, FromD1Row, ModelError, d1_batch, d1_execute, d1_query_all, d1_query_one,
+};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// A schema migration embedded from `backend/migrations`.
+#[derive(Clone, Copy, Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// The migration script split into individual statements.
+    pub fn statements(&self) -> Vec<String> {
+        split_statements(self.sql)
+    }
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!(
+                "../migrations/",
+                stringify!($version),
+                "_",
+                $name,
+                ".sql"
+            )),
+        }
+    };
+}
+
+/// All migrations, in the order they must be applied.
+///
+/// Every migration listed here must also be reflected in `d1_schema.sql`,
+/// including its row in the `schema_migrations` seed at the end of that file.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(20260215000000, "init"),
+    migration!(20260222000000, "add_description_to_tasks"),
+    migration!(20260223000000, "email_verification"),
+    migration!(20260224000000, "add_pending_email"),
+    migration!(20260225000000, "fix_duration_rounding"),
+    migration!(20260226000000, "add_password_resets"),
+    migration!(20260301000000, "add_organization_slug"),
+    migration!(20260302000000, "add_sessions"),
+    migration!(20260303000000, "add_two_factor"),
+    migration!(20260304000000, "add_auth_throttle"),
+    migration!(20260305000000, "add_api_tokens"),
+    migration!(20260306000000, "add_sso"),
+    migration!(20260307000000, "add_roles"),
+    migration!(20260308000000, "manageable_invitations"),
+    migration!(20260309000000, "account_deletion"),
+    migration!(20260310000000, "user_deactivation"),
+    migration!(20260311000000, "user_preferences"),
+    migration!(20260312000000, "avatar_uploads"),
+    migration!(20260313000000, "scim_provisioning"),
+    migration!(20260314000000, "organization_timezone"),
+    migration!(20260315000000, "organization_settings"),
+    migration!(20260316000000, "memberships"),
+    migration!(20260323000000, "task_due_dates"),
+    migration!(20260324000000, "closed_task_statuses"),
+    migration!(20260325000000, "organization_restores"),
+    migration!(20260326000000, "creation_keys"),
+    migration!(20260327000000, "anonymized_accounts"),
+    migration!(20260328000000, "team_managers"),
+    migration!(20260329000000, "join_verifications"),
+];
+
+/// Databases created before `schema_migrations` existed were set up from
+/// `d1_schema.sql` and already contain every migration up to this version.
+pub const BASELINE_VERSION: i64 = 20260226000000;
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+#[derive(Clone, Debug, Serialize, FromD1Row)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingMigration {
+    pub version: i64,
+    pub name: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaStatus {
+    pub current_version: Option<i64>,
+    pub latest_version: i64,
+    pub applied: Vec<AppliedMigration>,
+    pub pending: Vec<PendingMigration>,
+}
+
+#[derive(FromD1Row)]
+struct VersionRow {
+    version: Option<i64>,
+}
+
+/// Returns the highest applied migration version without touching the schema.
+///
+/// Yields `None` when `schema_migrations` does not exist yet.
+pub async fn current_version(db: &dyn Database) -> Result<Option<i64>, ModelError> {
+    let exists = d1_query_one::<VersionRow>(
+        db,
+        "SELECT COUNT(*) AS version FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+        &[],
+    )
+    .await?
+    .and_then(|row| row.version)
+    .unwrap_or(0);
+    if exists == 0 {
+        return Ok(None);
+    }
+
+    Ok(d1_query_one::<VersionRow>(
+        db,
+        "SELECT MAX(version) AS version FROM schema_migrations",
+        &[],
+    )
+    .await?
+    .and_then(|row| row.version))
+}
+
+/// Creates `schema_migrations` if needed and records the baseline for
+/// databases that predate it.
+async fn ensure_migrations_table(db: &dyn Database) -> Result<(), ModelError> {
+    d1_execute(
+        db,
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+             version INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+         )",
+        &[],
+    )
+    .await?;
+
+    // A single statement, so the emptiness check is evaluated once for all rows.
+    let baseline: Vec<&Migration> = MIGRATIONS
+        .iter()
+        .filter(|m| m.version <= BASELINE_VERSION)
+        .collect();
+    let values = vec!["(?, ?)"; baseline.len()].join(", ");
+    let params: Vec<D1Param> = baseline
+        .iter()
+        .flat_map(|m| {
+            [
+                D1Param::Integer(m.version),
+                D1Param::Text(m.name.to_string()),
+            ]
+        })
+        .collect();
+    d1_execute(
+        db,
+        &format!(
+            "INSERT INTO schema_migrations (version, name)
+             SELECT column1, column2 FROM (VALUES {values})
+             WHERE NOT EXISTS (SELECT 1 FROM schema_migrations)
+               AND EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'organizations')"
+        ),
+        &params,
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn status(db: &dyn Database) -> Result<SchemaStatus, ModelError> {
+    ensure_migrations_table(db).await?;
+
+    let applied = d1_query_all::<AppliedMigration>(
+        db,
+        "SELECT version, name, applied_at FROM schema_migrations ORDER BY version ASC",
+        &[],
+    )
+    .await?;
+    let applied_versions: HashSet<i64> = applied.iter().map(|m| m.version).collect();
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|m| !applied_versions.contains(&m.version))
+        .map(|m| PendingMigration {
+            version: m.version,
+            name: m.name,
+        })
+        .collect();
+
+    Ok(SchemaStatus {
+        current_version: applied.iter().map(|m| m.version).max(),
+        latest_version: latest_version(),
+        applied,
+        pending,
+    })
+}
+
+/// Applies every pending migration in version order.
+///
+/// Each migration runs as one batch together with its `schema_migrations`
+/// row, so a failing migration leaves no partial changes behind. Migrations
+/// applied before the failure stay applied.
+pub async fn apply_pending(db: &dyn Database) -> Result<Vec<PendingMigration>, ModelError> {
+    let pending = status(db).await?.pending;
+
+    for migration in &pending {
+        let sql = MIGRATIONS
+            .iter()
+            .find(|m| m.version == migration.version)
+            .map(|m| m.sql)
+            .unwrap_or_default();
+
+        let mut statements: Vec<D1Statement> = split_statements(sql)
+            .into_iter()
+            .map(|stmt| D1Statement::new(stmt, vec![]))
+            .collect();
+        statements.push(D1Statement::new(
+            "INSERT INTO schema_migrations (version, name) VALUES (?1, ?2)",
+            vec![
+                D1Param::Integer(migration.version),
+                D1Param::Text(migration.name.to_string()),
+            ],
+        ));
+
+        d1_batch(db, &statements).await.map_err(|e| {
+            ModelError::Database(format!(
+                "migration {}_{} failed: {e}",
+                migration.version, migration.name
+            ))
+        })?;
+    }
+
+    Ok(pending)
+}
+
+/// Splits a migration script into individual statements.
+///
+/// Handles `--` comments and semicolons inside single-quoted strings; the
+/// migrations do not use triggers, so `BEGIN ... END` blocks are not supported.
+fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut in_string = false;
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if !in_string && c == '-' && chars.peek() == Some(&'-') {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    current.push('\n');
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if c == '\'' {
+            in_string = !in_string;
+        }
+
+        if c == ';' && !in_string {
+            let statement = current.trim();
+            if !statement.is_empty() {
+                statements.push(statement.to_string());
+            }
+            current.clear();
+            continue;
+        }
+
+        current.push(c);
+    }
+
+    let statement = current.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MIGRATIONS, split_statements};
+
+    #[test]
+    fn splits_statements_and_strips_comments() {
+        let statements = split_statements(
+            "-- header; ignored\nCREATE TABLE a (id INTEGER, -- trailing; comment\n name TEXT DEFAULT 'x;y');\n\nINSERT INTO a VALUES (1, 'it''s');",
+        );
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].contains("'x;y'"));
+        assert!(!statements[0].contains("trailing"));
+        assert_eq!(statements[1], "INSERT INTO a VALUES (1, 'it''s')");
+    }
+
+    #[test]
+    fn migrations_are_strictly_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+    }
+}
@@ -1,7 +1,11 @@
+use crate::db::Database;
+use crate::permissions::Permission;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+pub use d1_derive::{FromD1Row, ToD1Params};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use std::fmt;
-use worker::{D1Database, D1PreparedStatement, D1Result, wasm_bindgen::JsValue};
 
 pub type D1Row = Map<String, Value>;
 
@@ -18,6 +22,7 @@ pub enum ModelError {
     },
     Worker(worker::Error),
     Serde(serde_json::Error),
+    Database(String),
 }
 
 impl fmt::Display for ModelError {
@@ -32,6 +37,7 @@ impl fmt::Display for ModelError {
             }
             Self::Worker(err) => write!(f, "d1 worker error: {err}"),
             Self::Serde(err) => write!(f, "serialization error: {err}"),
+            Self::Database(message) => write!(f, "database error: {message}"),
         }
     }
 }
@@ -50,6 +56,13 @@ impl From<serde_json::Error> for ModelError {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+impl From<rusqlite::Error> for ModelError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Database(value.to_string())
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum D1Param {
@@ -59,17 +72,6 @@ pub enum D1Param {
     Text(String),
 }
 
-impl D1Param {
-    fn as_js_value(&self) -> JsValue {
-        match self {
-            Self::Null => JsValue::NULL,
-            Self::Integer(v) => JsValue::from_f64(*v as f64),
-            Self::Real(v) => JsValue::from_f64(*v),
-            Self::Text(v) => JsValue::from_str(v),
-        }
-    }
-}
-
 pub trait FromD1Row: Sized {
     fn from_d1_row(row: &D1Row) -> Result<Self, ModelError>;
 }
@@ -78,32 +80,53 @@ pub trait ToD1Params {
     fn to_d1_params(&self) -> Vec<D1Param>;
 }
 
+/// A single SQL statement with its bound parameters, queued for [`d1_batch`].
+#[derive(Clone, Debug)]
+pub struct D1Statement {
+    pub sql: String,
+    pub params: Vec<D1Param>,
+}
+
+impl D1Statement {
+    pub fn new(sql: impl Into<String>, params: Vec<D1Param>) -> Self {
+        Self {
+            sql: sql.into(),
+            params,
+        }
+    }
+}
+
+/// Outcome of a write statement, taken from the backend's result metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct D1ExecResult {
+    /// Number of rows inserted, updated or deleted.
+    pub changes: u64,
+    /// Rowid of the last inserted row, if the statement inserted one.
+    pub last_row_id: Option<i64>,
+}
+
+impl D1ExecResult {
+    /// Returns the inserted rowid, failing if the statement did not insert a row.
+    pub fn inserted_id(&self) -> Result<i64, ModelError> {
+        self.last_row_id
+            .ok_or(ModelError::MissingField("last_row_id"))
+    }
+}
+
 pub async fn d1_query_all<T: FromD1Row>(
-    db: &D1Database,
+    db: &dyn Database,
     sql: &str,
     params: &[D1Param],
 ) -> Result<Vec<T>, ModelError> {
-    let mut stmt: D1PreparedStatement = db.prepare(sql);
-    if !params.is_empty() {
-        let js_params: Vec<JsValue> = params.iter().map(D1Param::as_js_value).collect();
-        stmt = stmt.bind(&js_params)?;
-    }
-
-    let raw: D1Result = stmt.all().await?;
-    let rows: Vec<Value> = raw.results::<Value>()?;
-    rows.into_iter()
-        .map(|value| match value {
-            Value::Object(map) => T::from_d1_row(&map),
-            _ => Err(ModelError::InvalidType {
-                field: "row",
-                expected: "object",
-            }),
-        })
+    db.query_all(sql, params)
+        .await?
+        .iter()
+        .map(T::from_d1_row)
         .collect()
 }
 
 pub async fn d1_query_one<T: FromD1Row>(
-    db: &D1Database,
+    db: &dyn Database,
     sql: &str,
     params: &[D1Param],
 ) -> Result<Option<T>, ModelError> {
@@ -111,131 +134,246 @@ pub async fn d1_query_one<T: FromD1Row>(
     Ok(rows.into_iter().next())
 }
 
-pub async fn d1_execute(db: &D1Database, sql: &str, params: &[D1Param]) -> Result<u64, ModelError> {
-    let mut stmt: D1PreparedStatement = db.prepare(sql);
-    if !params.is_empty() {
-        let js_params: Vec<JsValue> = params.iter().map(D1Param::as_js_value).collect();
-        stmt = stmt.bind(&js_params)?;
-    }
+pub async fn d1_execute(
+    db: &dyn Database,
+    sql: &str,
+    params: &[D1Param],
+) -> Result<D1ExecResult, ModelError> {
+    db.execute(sql, params).await
+}
+
+/// Executes `statements` as one batch.
+///
+/// The batch runs inside a single transaction: statements execute in order,
+/// and if any of them fails the whole batch is rolled back. A statement that
+/// refers to a row inserted earlier in the batch looks it up by a unique key,
+/// such as a username or a `creation_key`, and the new id is read back with
+/// [`D1ExecResult::inserted_id`].
+///
+/// Returns one [`D1ExecResult`] per statement, in the order given.
+pub async fn d1_batch(
+    db: &dyn Database,
+    statements: &[D1Statement],
+) -> Result<Vec<D1ExecResult>, ModelError> {
+    db.batch(statements).await
+}
 
-    let _ = stmt.run().await?;
-    Ok(0)
+// =============================
+// Column Codecs
+// =============================
+
+/// Decodes a single column value; used by `#[derive(FromD1Row)]`.
+pub trait FromD1Value: Sized {
+    fn from_d1_value(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError>;
 }
 
-fn required_i64(row: &D1Row, field: &'static str) -> Result<i64, ModelError> {
-    let value = row.get(field).ok_or(ModelError::MissingField(field))?;
-    match value {
-        Value::Number(n) => n.as_i64().ok_or(ModelError::InvalidType {
-            field,
-            expected: "integer",
-        }),
-        Value::String(s) => s.parse::<i64>().map_err(|_| ModelError::InvalidType {
-            field,
-            expected: "integer",
-        }),
-        _ => Err(ModelError::InvalidType {
+impl FromD1Value for i64 {
+    fn from_d1_value(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        let invalid = || ModelError::InvalidType {
             field,
             expected: "integer",
-        }),
+        };
+        match value.ok_or(ModelError::MissingField(field))? {
+            Value::Number(n) => n.as_i64().ok_or_else(invalid),
+            Value::String(s) => s.parse::<i64>().map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
     }
 }
 
-fn optional_i64(row: &D1Row, field: &'static str) -> Result<Option<i64>, ModelError> {
-    match row.get(field) {
-        None | Some(Value::Null) => Ok(None),
-        Some(Value::Number(n)) => n.as_i64().map(Some).ok_or(ModelError::InvalidType {
+impl FromD1Value for f64 {
+    fn from_d1_value(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        let invalid = || ModelError::InvalidType {
             field,
-            expected: "integer",
-        }),
-        Some(Value::String(s)) => s
-            .parse::<i64>()
-            .map(Some)
-            .map_err(|_| ModelError::InvalidType {
+            expected: "real",
+        };
+        match value.ok_or(ModelError::MissingField(field))? {
+            Value::Number(n) => n.as_f64().ok_or_else(invalid),
+            Value::String(s) => s.parse::<f64>().map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FromD1Value for String {
+    fn from_d1_value(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        value
+            .ok_or(ModelError::MissingField(field))?
+            .as_str()
+            .map(ToOwned::to_owned)
+            .ok_or(ModelError::InvalidType {
                 field,
-                expected: "integer",
-            }),
-        Some(_) => Err(ModelError::InvalidType {
-            field,
-            expected: "integer",
-        }),
+                expected: "text",
+            })
+    }
+}
+
+impl<T: FromD1Value> FromD1Value for Option<T> {
+    fn from_d1_value(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        match value {
+            None | Some(Value::Null) => Ok(None),
+            Some(_) => T::from_d1_value(value, field).map(Some),
+        }
     }
 }
 
-fn required_text(row: &D1Row, field: &'static str) -> Result<String, ModelError> {
-    row.get(field)
-        .ok_or(ModelError::MissingField(field))?
-        .as_str()
-        .map(ToOwned::to_owned)
-        .ok_or(ModelError::InvalidType {
+/// Decodes an `INTEGER` column constrained to `0`/`1` (`#[d1(bool_int)]`).
+pub trait FromD1BoolInt: Sized {
+    fn from_d1_bool_int(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError>;
+}
+
+fn check_bool_int(value: i64, field: &'static str) -> Result<i64, ModelError> {
+    if value == 0 || value == 1 {
+        Ok(value)
+    } else {
+        Err(ModelError::InvalidValue {
             field,
-            expected: "text",
+            message: "expected 0 or 1".to_string(),
         })
+    }
 }
 
-fn optional_text(row: &D1Row, field: &'static str) -> Result<Option<String>, ModelError> {
-    match row.get(field) {
-        None | Some(Value::Null) => Ok(None),
-        Some(Value::String(v)) => Ok(Some(v.clone())),
-        Some(_) => Err(ModelError::InvalidType {
-            field,
-            expected: "text",
-        }),
+impl FromD1BoolInt for i64 {
+    fn from_d1_bool_int(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        check_bool_int(i64::from_d1_value(value, field)?, field)
+    }
+}
+
+impl FromD1BoolInt for Option<i64> {
+    fn from_d1_bool_int(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        Option::<i64>::from_d1_value(value, field)?
+            .map(|v| check_bool_int(v, field))
+            .transpose()
     }
 }
 
-fn optional_text_vec(row: &D1Row, field: &'static str) -> Result<Option<Vec<String>>, ModelError> {
-    match row.get(field) {
-        None | Some(Value::Null) => Ok(None),
-        Some(Value::Array(items)) => items
-            .iter()
-            .map(|item| {
-                item.as_str()
-                    .map(ToOwned::to_owned)
-                    .ok_or(ModelError::InvalidType {
+/// Element type of a list column.
+pub trait D1ListItem: Sized + Serialize {
+    const EXPECTED: &'static str;
+
+    fn from_json(value: &Value) -> Option<Self>;
+    fn from_csv(raw: &str) -> Option<Self>;
+}
+
+impl D1ListItem for String {
+    const EXPECTED: &'static str = "array<string>";
+
+    fn from_json(value: &Value) -> Option<Self> {
+        value.as_str().map(ToOwned::to_owned)
+    }
+
+    fn from_csv(raw: &str) -> Option<Self> {
+        Some(raw.trim().to_string())
+    }
+}
+
+impl D1ListItem for i64 {
+    const EXPECTED: &'static str = "array<integer>";
+
+    fn from_json(value: &Value) -> Option<Self> {
+        value.as_i64()
+    }
+
+    fn from_csv(raw: &str) -> Option<Self> {
+        raw.trim().parse().ok()
+    }
+}
+
+/// List column (`#[d1(list)]`) stored as a JSON array, a JSON-encoded string
+/// or a comma-separated string such as `GROUP_CONCAT` output.
+pub trait FromD1List: Sized {
+    fn from_d1_list(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError>;
+    fn to_d1_list_param(&self) -> D1Param;
+}
+
+impl<T: D1ListItem> FromD1List for Vec<T> {
+    fn from_d1_list(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        match value {
+            None | Some(Value::Null) => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| {
+                    T::from_json(item).ok_or(ModelError::InvalidType {
                         field,
-                        expected: "array<string>",
+                        expected: T::EXPECTED,
                     })
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .map(Some),
-        Some(Value::String(raw)) => {
-            if raw.trim().is_empty() {
-                return Ok(Some(vec![]));
-            }
+                })
+                .collect(),
+            Some(Value::String(raw)) => {
+                if raw.trim().is_empty() {
+                    return Ok(Vec::new());
+                }
+
+                if let Ok(items) = serde_json::from_str::<Vec<Value>>(raw)
+                    && let Some(parsed) = items.iter().map(T::from_json).collect::<Option<Vec<_>>>()
+                {
+                    return Ok(parsed);
+                }
 
-            match serde_json::from_str::<Vec<String>>(raw) {
-                Ok(parsed) => Ok(Some(parsed)),
-                Err(_) => Ok(Some(raw.split(',').map(|s| s.trim().to_string()).collect())),
+                raw.split(',')
+                    .map(|item| {
+                        T::from_csv(item).ok_or(ModelError::InvalidType {
+                            field,
+                            expected: "json-array|csv",
+                        })
+                    })
+                    .collect()
             }
+            Some(_) => Err(ModelError::InvalidType {
+                field,
+                expected: "array|json-string|csv-string",
+            }),
         }
-        Some(_) => Err(ModelError::InvalidType {
-            field,
-            expected: "array<string>|json-string|csv-string",
-        }),
+    }
+
+    fn to_d1_list_param(&self) -> D1Param {
+        D1Param::Text(serde_json::to_string(self).unwrap_or_else(|_| "[]".to_string()))
     }
 }
 
-fn required_bool_int(row: &D1Row, field: &'static str) -> Result<i64, ModelError> {
-    let value = required_i64(row, field)?;
-    if value == 0 || value == 1 {
-        Ok(value)
-    } else {
-        Err(ModelError::InvalidValue {
-            field,
-            message: "expected 0 or 1".to_string(),
-        })
+impl<T: D1ListItem> FromD1List for Option<Vec<T>> {
+    fn from_d1_list(value: Option<&Value>, field: &'static str) -> Result<Self, ModelError> {
+        match value {
+            None | Some(Value::Null) => Ok(None),
+            Some(_) => Vec::<T>::from_d1_list(value, field).map(Some),
+        }
+    }
+
+    fn to_d1_list_param(&self) -> D1Param {
+        self.as_ref()
+            .map(FromD1List::to_d1_list_param)
+            .unwrap_or(D1Param::Null)
     }
 }
 
-fn optional_bool_int(row: &D1Row, field: &'static str) -> Result<Option<i64>, ModelError> {
-    let value = optional_i64(row, field)?;
-    match value {
-        None => Ok(None),
-        Some(v) if v == 0 || v == 1 => Ok(Some(v)),
-        Some(_) => Err(ModelError::InvalidValue {
-            field,
-            message: "expected 0 or 1".to_string(),
-        }),
+/// Encodes a field as a bound parameter; used by `#[derive(ToD1Params)]`.
+pub trait ToD1Param {
+    fn to_d1_param(&self) -> D1Param;
+}
+
+impl ToD1Param for i64 {
+    fn to_d1_param(&self) -> D1Param {
+        D1Param::Integer(*self)
+    }
+}
+
+impl ToD1Param for f64 {
+    fn to_d1_param(&self) -> D1Param {
+        D1Param::Real(*self)
+    }
+}
+
+impl ToD1Param for String {
+    fn to_d1_param(&self) -> D1Param {
+        D1Param::Text(self.clone())
+    }
+}
+
+impl<T: ToD1Param> ToD1Param for Option<T> {
+    fn to_d1_param(&self) -> D1Param {
+        self.as_ref()
+            .map(ToD1Param::to_d1_param)
+            .unwrap_or(D1Param::Null)
     }
 }
 
@@ -243,8 +381,12 @@ fn optional_bool_int(row: &D1Row, field: &'static str) -> Result<Option<i64>, Mo
 // Database Entities
 // =============================
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// An account as a member of one organization. `organization_id`, `role`,
+/// `deactivated_at` and `manager_id` come from the membership, so the same account reads
+/// differently in each organization it belongs to.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct User {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub name: String,
@@ -252,57 +394,32 @@ pub struct User {
     pub email: Option<String>,
     pub pending_email: Option<String>,
     pub avatar_url: Option<String>,
+    /// Preferred BCP 47 language tag, e.g. `ja` or `en-US`.
+    #[serde(default)]
+    #[d1(default)]
+    pub locale: Option<String>,
+    /// Preferred IANA time zone, e.g. `Asia/Tokyo`.
+    #[serde(default)]
+    #[d1(default)]
+    pub timezone: Option<String>,
     pub role: String,
+    #[d1(bool_int)]
     pub email_verified: i64,
+    #[d1(readonly)]
     pub created_at: Option<String>,
+    /// Set while the member is deactivated; `None` for active members.
+    #[serde(default)]
+    #[d1(default)]
+    pub deactivated_at: Option<String>,
+    /// The member this one reports to, whose team they are on.
+    #[serde(default)]
+    #[d1(default)]
+    pub manager_id: Option<i64>,
 }
 
-impl FromD1Row for User {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            name: required_text(row, "name")?,
-            username: optional_text(row, "username")?,
-            email: optional_text(row, "email")?,
-            pending_email: optional_text(row, "pending_email")?,
-            avatar_url: optional_text(row, "avatar_url")?,
-            role: required_text(row, "role")?,
-            email_verified: required_bool_int(row, "email_verified")?,
-            created_at: optional_text(row, "created_at")?,
-        })
-    }
-}
-
-impl ToD1Params for User {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Text(self.name.clone()),
-            self.username
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            self.email
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            self.pending_email
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            self.avatar_url
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            D1Param::Text(self.role.clone()),
-            D1Param::Integer(self.email_verified),
-        ]
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct Task {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub member_id: i64,
@@ -310,295 +427,168 @@ pub struct Task {
     pub description: Option<String>,
     pub status: String,
     pub progress_rate: i64,
+    /// Local calendar date, `YYYY-MM-DD`.
+    #[serde(default)]
+    #[d1(default)]
+    pub due_date: Option<String>,
+    #[serde(default)]
+    #[d1(default)]
+    pub estimated_minutes: Option<i64>,
+    #[d1(list, readonly)]
     pub tags: Option<Vec<String>>,
+    #[d1(readonly)]
     pub created_at: String,
+    #[d1(readonly)]
     pub updated_at: Option<String>,
+    #[d1(default, readonly)]
     pub total_duration_minutes: i64,
 }
 
-impl FromD1Row for Task {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            member_id: required_i64(row, "member_id")?,
-            title: required_text(row, "title")?,
-            description: optional_text(row, "description")?,
-            status: required_text(row, "status")?,
-            progress_rate: required_i64(row, "progress_rate")?,
-            tags: optional_text_vec(row, "tags")?,
-            created_at: required_text(row, "created_at")?,
-            updated_at: optional_text(row, "updated_at")?,
-            total_duration_minutes: optional_i64(row, "total_duration_minutes")?.unwrap_or(0),
-        })
-    }
-}
-
-impl ToD1Params for Task {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.member_id),
-            D1Param::Text(self.title.clone()),
-            self.description
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            D1Param::Text(self.status.clone()),
-            D1Param::Integer(self.progress_rate),
-        ]
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct DisplayGroup {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub user_id: i64,
     pub name: String,
+    #[d1(list, readonly)]
     pub member_ids: Vec<i64>,
+    #[d1(readonly)]
     pub created_at: String,
 }
 
-impl FromD1Row for DisplayGroup {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        let member_ids = match row.get("member_ids") {
-            None | Some(Value::Null) => Vec::new(),
-            Some(Value::Array(values)) => values
-                .iter()
-                .map(|v| {
-                    v.as_i64().ok_or(ModelError::InvalidType {
-                        field: "member_ids",
-                        expected: "array<integer>",
-                    })
-                })
-                .collect::<Result<Vec<_>, _>>()?,
-            Some(Value::String(raw)) => {
-                if raw.trim().is_empty() {
-                    vec![]
-                } else if let Ok(parsed) = serde_json::from_str::<Vec<i64>>(raw) {
-                    parsed
-                } else {
-                    raw.split(',')
-                        .map(|v| {
-                            v.trim()
-                                .parse::<i64>()
-                                .map_err(|_| ModelError::InvalidType {
-                                    field: "member_ids",
-                                    expected: "json-array|csv",
-                                })
-                        })
-                        .collect::<Result<Vec<_>, _>>()?
-                }
-            }
-            Some(_) => {
-                return Err(ModelError::InvalidType {
-                    field: "member_ids",
-                    expected: "array<integer>|json-string|csv-string",
-                });
-            }
-        };
-
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            user_id: required_i64(row, "user_id")?,
-            name: required_text(row, "name")?,
-            member_ids,
-            created_at: required_text(row, "created_at")?,
-        })
-    }
-}
-
-impl ToD1Params for DisplayGroup {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.user_id),
-            D1Param::Text(self.name.clone()),
-        ]
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct TaskTimeLog {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub user_id: i64,
     pub task_id: i64,
     pub start_at: String,
     pub end_at: String,
+    #[d1(default, readonly)]
     pub duration_minutes: i64,
+    #[d1(readonly)]
     pub created_at: Option<String>,
+    #[d1(readonly)]
     pub task_title: Option<String>,
+    #[d1(readonly)]
     pub task_description: Option<String>,
+    #[d1(readonly)]
     pub task_status: Option<String>,
+    #[d1(readonly)]
     pub task_progress_rate: Option<i64>,
+    #[d1(list, readonly)]
     pub task_tags: Option<Vec<String>>,
+    #[serde(default)]
+    #[d1(default, readonly)]
+    pub task_due_date: Option<String>,
+    #[serde(default)]
+    #[d1(default, readonly)]
+    pub task_estimated_minutes: Option<i64>,
+    #[d1(default, readonly)]
     pub total_duration_minutes: i64,
 }
 
-impl FromD1Row for TaskTimeLog {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            user_id: required_i64(row, "user_id")?,
-            task_id: required_i64(row, "task_id")?,
-            start_at: required_text(row, "start_at")?,
-            end_at: required_text(row, "end_at")?,
-            duration_minutes: optional_i64(row, "duration_minutes")?.unwrap_or(0),
-            created_at: optional_text(row, "created_at")?,
-            task_title: optional_text(row, "task_title")?,
-            task_description: optional_text(row, "task_description")?,
-            task_status: optional_text(row, "task_status")?,
-            task_progress_rate: optional_i64(row, "task_progress_rate")?,
-            task_tags: optional_text_vec(row, "task_tags")?,
-            total_duration_minutes: optional_i64(row, "total_duration_minutes")?.unwrap_or(0),
-        })
-    }
-}
-
-impl ToD1Params for TaskTimeLog {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.user_id),
-            D1Param::Integer(self.task_id),
-            D1Param::Text(self.start_at.clone()),
-            D1Param::Text(self.end_at.clone()),
-        ]
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
 pub struct TaskReportRow {
     #[serde(flatten)]
+    #[d1(flatten)]
     pub task: Task,
     pub user_name: String,
     pub start_at: Option<String>,
     pub end_at: Option<String>,
+    /// `total_duration_minutes` minus the estimate: positive once the time
+    /// logged in the report's range passes it, `None` without an estimate.
+    #[d1(default)]
+    pub estimate_variance_minutes: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct ActivityLog {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub user_id: i64,
+    #[d1(readonly)]
     pub user_name: String,
     pub action: String,
     pub target_type: String,
     pub target_id: Option<i64>,
     pub details: Option<String>,
+    #[d1(readonly)]
     pub created_at: String,
 }
 
-impl FromD1Row for ActivityLog {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            user_id: required_i64(row, "user_id")?,
-            user_name: required_text(row, "user_name")?,
-            action: required_text(row, "action")?,
-            target_type: required_text(row, "target_type")?,
-            target_id: optional_i64(row, "target_id")?,
-            details: optional_text(row, "details")?,
-            created_at: required_text(row, "created_at")?,
-        })
-    }
-}
-
-impl ToD1Params for ActivityLog {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.user_id),
-            D1Param::Text(self.action.clone()),
-            D1Param::Text(self.target_type.clone()),
-            self.target_id
-                .map(D1Param::Integer)
-                .unwrap_or(D1Param::Null),
-            self.details
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-        ]
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct DailyReport {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub user_id: i64,
     pub report_date: String,
     pub content: String,
+    #[d1(readonly)]
     pub created_at: String,
 }
 
-impl FromD1Row for DailyReport {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            user_id: required_i64(row, "user_id")?,
-            report_date: required_text(row, "report_date")?,
-            content: required_text(row, "content")?,
-            created_at: required_text(row, "created_at")?,
-        })
-    }
-}
-
-impl ToD1Params for DailyReport {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.user_id),
-            D1Param::Text(self.report_date.clone()),
-            D1Param::Text(self.content.clone()),
-        ]
-    }
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
+pub struct Tag {
+    #[d1(readonly)]
+    pub id: i64,
+    pub organization_id: i64,
+    pub name: String,
+    #[d1(readonly)]
+    pub created_at: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct Invitation {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
+    #[d1(readonly)]
     pub org_name: Option<String>,
+    #[d1(readonly, default)]
+    pub org_slug: Option<String>,
     pub token: String,
     pub role: String,
+    /// Only this address can join with the invitation.
+    #[d1(default)]
+    pub email: Option<String>,
+    pub max_uses: i64,
+    #[d1(readonly, default)]
+    pub use_count: i64,
     pub expires_at: String,
+    #[d1(readonly)]
     pub created_at: String,
 }
 
-impl FromD1Row for Invitation {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            org_name: optional_text(row, "org_name")?,
-            token: required_text(row, "token")?,
-            role: required_text(row, "role")?,
-            expires_at: required_text(row, "expires_at")?,
-            created_at: required_text(row, "created_at")?,
-        })
-    }
-}
-
-impl ToD1Params for Invitation {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Text(self.token.clone()),
-            D1Param::Text(self.role.clone()),
-            D1Param::Text(self.expires_at.clone()),
-        ]
-    }
+/// An invitation as listed to members who manage invitations.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct InvitationSummary {
+    pub id: i64,
+    pub token: String,
+    pub role: String,
+    pub email: Option<String>,
+    pub max_uses: i64,
+    pub use_count: i64,
+    /// `active`, `used`, `expired` or `revoked`.
+    pub status: String,
+    pub created_by: Option<i64>,
+    pub created_by_name: Option<String>,
+    /// Members who joined with the invitation; deleted members are omitted
+    /// but still count towards `use_count`.
+    #[d1(list)]
+    pub redeemed_by: Vec<i64>,
+    pub expires_at: String,
+    pub revoked_at: Option<String>,
+    pub created_at: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row, ToD1Params)]
 pub struct Notification {
+    #[d1(readonly)]
     pub id: i64,
     pub organization_id: i64,
     pub user_id: i64,
@@ -607,48 +597,75 @@ pub struct Notification {
     pub category: String,
     pub target_type: Option<String>,
     pub target_id: Option<i64>,
+    #[d1(bool_int)]
     pub is_read: i64,
+    #[d1(readonly)]
     pub created_at: String,
 }
 
-impl FromD1Row for Notification {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            id: required_i64(row, "id")?,
-            organization_id: required_i64(row, "organization_id")?,
-            user_id: required_i64(row, "user_id")?,
-            title: required_text(row, "title")?,
-            body: optional_text(row, "body")?,
-            category: required_text(row, "category")?,
-            target_type: optional_text(row, "target_type")?,
-            target_id: optional_i64(row, "target_id")?,
-            is_read: required_bool_int(row, "is_read")?,
-            created_at: required_text(row, "created_at")?,
-        })
-    }
+/// A signed-in device, as listed by `GET /api/auth/sessions`.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub expires_at: String,
+    /// Whether this is the session of the requesting access token.
+    #[d1(bool_int, default)]
+    pub current: i64,
 }
 
-impl ToD1Params for Notification {
-    fn to_d1_params(&self) -> Vec<D1Param> {
-        vec![
-            D1Param::Integer(self.organization_id),
-            D1Param::Integer(self.user_id),
-            D1Param::Text(self.title.clone()),
-            self.body
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            D1Param::Text(self.category.clone()),
-            self.target_type
-                .as_ref()
-                .map(|v| D1Param::Text(v.clone()))
-                .unwrap_or(D1Param::Null),
-            self.target_id
-                .map(D1Param::Integer)
-                .unwrap_or(D1Param::Null),
-            D1Param::Integer(self.is_read),
-        ]
-    }
+/// A personal access token as listed by `GET /api/users/me/tokens`; the
+/// secret itself is only returned once, on creation.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    #[d1(list)]
+    pub scopes: Vec<String>,
+    pub expires_at: String,
+    pub last_used_at: Option<String>,
+    pub created_at: String,
+}
+
+/// An organization's SCIM token as listed by
+/// `GET /api/organization/scim-tokens`; the secret is only returned once.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct ScimToken {
+    pub id: i64,
+    pub name: String,
+    pub created_by_name: Option<String>,
+    pub last_used_at: Option<String>,
+    pub created_at: String,
+}
+
+/// A role as listed by `GET /api/roles`; built-in roles cannot be changed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+    pub builtin: bool,
+}
+
+// =============================
+// Shared Query Rows
+// =============================
+
+#[derive(Clone, Debug, FromD1Row)]
+pub struct IdRow {
+    pub id: i64,
+}
+
+#[derive(Clone, Debug, FromD1Row)]
+pub struct CountRow {
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, FromD1Row)]
+pub struct RoleRow {
+    pub role: String,
 }
 
 // =============================
@@ -661,20 +678,38 @@ pub struct UserWithTimeLogs {
     pub time_logs: Vec<TaskTimeLog>,
 }
 
+/// Envelope returned by every list endpoint.
+///
+/// Pass `next_cursor` back as the `cursor` query parameter to fetch the next
+/// page; it is `None` on the last page.
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct PaginatedLogs {
-    pub items: Vec<ActivityLog>,
-    pub total: i64,
-    pub page: i64,
-    pub total_pages: i64,
-}
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub limit: i64,
+}
+
+impl<T> Paginated<T> {
+    /// Builds a page from rows fetched with [`PageQuery::fetch_limit`].
+    pub fn from_rows(
+        mut rows: Vec<T>,
+        page: &PageQuery,
+        cursor_of: impl Fn(&T) -> PageCursor,
+    ) -> Self {
+        let has_more = rows.len() as i64 > page.limit;
+        rows.truncate(page.limit as usize);
+        let next_cursor = if has_more {
+            rows.last().map(|row| cursor_of(row).encode())
+        } else {
+            None
+        };
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct PaginatedNotifications {
-    pub items: Vec<Notification>,
-    pub total: i64,
-    pub page: i64,
-    pub total_pages: i64,
+        Self {
+            items: rows,
+            next_cursor,
+            limit: page.limit,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -693,54 +728,200 @@ pub struct TaskStats {
     pub by_status: Vec<StatusCount>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
 pub struct StatusCount {
     pub status: String,
     pub count: i64,
 }
 
-impl FromD1Row for StatusCount {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            status: required_text(row, "status")?,
-            count: required_i64(row, "count")?,
-        })
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ReportStats {
     pub total_submitted: i64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
 pub struct HeatmapDay {
     pub date: String,
     pub count: i64,
 }
 
-impl FromD1Row for HeatmapDay {
-    fn from_d1_row(row: &D1Row) -> Result<Self, ModelError> {
-        Ok(Self {
-            date: required_text(row, "date")?,
-            count: required_i64(row, "count")?,
-        })
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
     pub user_id: i64,
+    /// The active organization. Tokens for another of the account's
+    /// organizations come from `POST /api/auth/switch-organization`.
     pub organization_id: i64,
     pub role: String,
     pub exp: usize,
+    /// Session the token was issued for; revoking it invalidates the token.
+    pub sid: i64,
+    /// Scopes of a personal access token; `None` for session JWTs, which
+    /// carry the user's full access.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: User,
+    pub organization_slug: Option<String>,
+    /// Set for admins of organizations that require 2FA who have not enrolled
+    /// yet; they keep user-level access until they do.
+    #[serde(default)]
+    pub two_factor_setup_required: bool,
+}
+
+/// Returned with `202` by `login` when the account has 2FA enabled; the client
+/// completes the login at `POST /api/auth/2fa/verify`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactorChallengeResponse {
+    pub challenge_token: String,
+    pub methods: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct TwoFactorStatus {
+    #[d1(bool_int)]
+    pub enabled: i64,
+    pub recovery_codes_remaining: i64,
+    /// Whether the organization requires 2FA for this user's role.
+    #[d1(bool_int)]
+    pub required: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct SecuritySettings {
+    #[d1(bool_int)]
+    pub require_admin_2fa: i64,
+}
+
+/// Requirements for passwords set by the organization's members.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, FromD1Row)]
+pub struct PasswordPolicy {
+    /// In characters.
+    pub min_length: i64,
+    #[d1(bool_int)]
+    pub require_uppercase: i64,
+    #[d1(bool_int)]
+    pub require_lowercase: i64,
+    #[d1(bool_int)]
+    pub require_digit: i64,
+    #[d1(bool_int)]
+    pub require_symbol: i64,
+}
+
+/// `GET /api/organization`.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct OrganizationSettings {
+    pub name: String,
+    pub slug: Option<String>,
+    /// IANA timezone that days and weeks are counted in; members can override
+    /// it with their own `timezone`.
+    pub timezone: String,
+    /// Lowercase English weekday name, e.g. `monday`.
+    pub week_start_day: String,
+    /// Status of tasks created without one.
+    pub default_task_status: String,
+    /// Statuses the task report shows when the request names none.
+    #[d1(list)]
+    pub report_statuses: Vec<String>,
+    /// Statuses of finished tasks, which are never overdue. Archives written
+    /// before the setting existed restore with `done`.
+    #[d1(list)]
+    #[serde(default = "default_closed_task_statuses")]
+    pub closed_task_statuses: Vec<String>,
+    /// Used for invitations created without `expires_in_days`.
+    pub invitation_expiration_days: i64,
+    #[d1(flatten)]
+    pub password_policy: PasswordPolicy,
+}
+
+fn default_closed_task_statuses() -> Vec<String> {
+    vec!["done".to_string()]
+}
+
+/// Single sign-on configuration as shown to admins; the client secret is
+/// write-only.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct SsoSettings {
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    #[d1(bool_int)]
+    pub has_client_secret: i64,
+    #[d1(list)]
+    pub allowed_domains: Vec<String>,
+    #[d1(bool_int)]
+    pub enabled: i64,
+    /// Members must sign in through the provider; admins keep password login.
+    #[d1(bool_int)]
+    pub password_login_disabled: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SsoStartResponse {
+    pub authorization_url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactorSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreatedApiTokenResponse {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// The token to send as `Authorization: Bearer ...`; shown only once.
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreatedScimTokenResponse {
+    #[serde(flatten)]
+    pub token: ScimToken,
+    /// The token to configure in the identity provider; shown only once.
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct OrganizationSummary {
+    pub slug: String,
+    pub name: String,
+}
+
+/// One of the organizations the signed-in account belongs to.
+#[derive(Serialize, Deserialize, Clone, Debug, FromD1Row)]
+pub struct MembershipSummary {
+    pub organization_id: i64,
+    pub slug: Option<String>,
+    pub name: String,
+    pub role: String,
+    /// Whether this is the organization the current token is scoped to.
+    #[d1(bool_int)]
+    pub current: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RefreshResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+/// Returned with `409` when the credentials match accounts in several
+/// organizations; the client retries `login` with one of the slugs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrganizationSelectionResponse {
+    pub error: String,
+    pub organizations: Vec<OrganizationSummary>,
 }
 
 // =============================
@@ -757,6 +938,8 @@ pub struct CreateDisplayGroupInput {
 pub struct LoginInput {
     pub username: String,
     pub password: String,
+    /// Organization slug; required when the username exists in several organizations.
+    pub organization: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -774,6 +957,10 @@ pub struct CreateTaskInput {
     pub title: String,
     pub description: Option<String>,
     pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub due_date: Option<String>,
+    #[serde(default)]
+    pub estimated_minutes: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -784,6 +971,12 @@ pub struct UpdateTaskInput {
     pub status: Option<String>,
     pub progress_rate: Option<i64>,
     pub tags: Option<Vec<String>>,
+    /// An empty string clears the due date.
+    #[serde(default)]
+    pub due_date: Option<String>,
+    /// `0` clears the estimate.
+    #[serde(default)]
+    pub estimated_minutes: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -813,6 +1006,8 @@ pub struct CreateReportInput {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RegisterInput {
     pub organization_name: String,
+    /// Derived from `organization_name` when omitted.
+    pub organization_slug: Option<String>,
     pub admin_name: String,
     pub username: String,
     pub email: String,
@@ -821,17 +1016,40 @@ pub struct RegisterInput {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CreateInvitationInput {
+    /// Sends the invitation to this address and binds it to it.
     pub email: Option<String>,
     pub role: String,
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+    #[serde(default)]
+    pub max_uses: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct JoinInput {
     pub token: String,
+    /// Only used when creating a new account.
+    #[serde(default)]
     pub name: String,
     pub username: String,
+    /// Only used when creating a new account.
+    #[serde(default)]
     pub email: String,
     pub password: String,
+    /// Slug of an organization the caller already belongs to. When set, the
+    /// invitation is redeemed by signing in to that existing account instead
+    /// of creating one.
+    #[serde(default)]
+    pub existing_organization: Option<String>,
+    /// For a new account on an invitation bound to an email address: the
+    /// token a first join request emailed there.
+    #[serde(default)]
+    pub email_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SwitchOrganizationInput {
+    pub organization_id: i64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -845,17 +1063,172 @@ pub struct ResetPasswordInput {
     pub new_password: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RefreshTokenInput {
+    pub refresh_token: String,
+}
+
+/// A TOTP code, or for endpoints that accept it, a recovery code instead.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactorCodeInput {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerifyTwoFactorInput {
+    pub challenge_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DisableTwoFactorInput {
+    pub password: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateSecuritySettingsInput {
+    pub require_admin_2fa: bool,
+}
+
+/// `PATCH /api/organization`. Omitted fields are left unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UpdateOrganizationSettingsInput {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub week_start_day: Option<String>,
+    #[serde(default)]
+    pub default_task_status: Option<String>,
+    #[serde(default)]
+    pub report_statuses: Option<Vec<String>>,
+    #[serde(default)]
+    pub closed_task_statuses: Option<Vec<String>>,
+    #[serde(default)]
+    pub invitation_expiration_days: Option<i64>,
+    #[serde(default)]
+    pub password_policy: Option<UpdatePasswordPolicyInput>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UpdatePasswordPolicyInput {
+    #[serde(default)]
+    pub min_length: Option<i64>,
+    #[serde(default)]
+    pub require_uppercase: Option<bool>,
+    #[serde(default)]
+    pub require_lowercase: Option<bool>,
+    #[serde(default)]
+    pub require_digit: Option<bool>,
+    #[serde(default)]
+    pub require_symbol: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateSsoSettingsInput {
+    pub issuer: String,
+    pub client_id: String,
+    /// Keeps the stored secret when omitted.
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    pub enabled: bool,
+    #[serde(default)]
+    pub password_login_disabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SsoStartInput {
+    /// Organization slug.
+    pub organization: String,
+    /// Where the provider sends the browser back to; must be registered with it.
+    pub redirect_uri: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SsoCallbackInput {
+    pub state: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateApiTokenInput {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Defaults to 90 days; at most 365.
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateScimTokenInput {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteAccountInput {
+    pub password: String,
+    /// Acknowledges that the account leaves every organization it belongs
+    /// to, not only the current one.
+    #[serde(default)]
+    pub all_organizations: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdatePasswordInput {
     pub current_password: String,
     pub new_password: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateRoleInput {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateRoleInput {
+    pub permissions: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateUserRoleInput {
     pub role: String,
 }
 
+/// `PUT /api/users/:id/manager`; `None` takes the member off any team.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateUserManagerInput {
+    pub manager_id: Option<i64>,
+}
+
+/// `PATCH /api/users/me` and `PATCH /api/users/:id`. Omitted fields are left
+/// unchanged; an empty string clears `avatar_url`, `locale` or `timezone`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UpdateProfileInput {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeactivateUserInput {
+    /// Member who takes over the deactivated member's unfinished tasks.
+    #[serde(default)]
+    pub reassign_to: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateEmailInput {
     pub email: String,
@@ -884,6 +1257,8 @@ pub struct ReportQuery {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GetUsersQuery {
     pub date: Option<String>,
+    /// `active` (default), `inactive` or `all`.
+    pub status: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -897,8 +1272,6 @@ pub struct TaskReportQuery {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LogQuery {
-    pub page: Option<i64>,
-    pub per_page: Option<i64>,
     pub user_id: Option<i64>,
     pub start_date: Option<String>,
     pub end_date: Option<String>,
@@ -913,18 +1286,194 @@ pub struct GetTasksQuery {
     pub q: Option<String>,
     pub date: Option<String>,
     pub status: Option<String>,
+    /// `overdue` or `this_week`, in the requesting member's timezone.
+    pub due: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct NotificationQuery {
-    pub page: Option<i64>,
-    pub per_page: Option<i64>,
+pub const DEFAULT_PAGE_LIMIT: i64 = 50;
+pub const MAX_PAGE_LIMIT: i64 = 200;
+
+/// Keyset position of the last row on a page, ordered by `(created_at, id)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageCursor {
+    pub created_at: String,
+    pub id: i64,
+}
+
+impl PageCursor {
+    pub fn new(created_at: impl Into<String>, id: i64) -> Self {
+        Self {
+            created_at: created_at.into(),
+            id,
+        }
+    }
+
+    /// Encodes the cursor as an opaque, URL-safe token.
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.id, self.created_at))
+    }
+
+    pub fn decode(raw: &str) -> Result<Self, ModelError> {
+        let invalid = || ModelError::InvalidValue {
+            field: "cursor",
+            message: "malformed cursor".to_string(),
+        };
+        let bytes = URL_SAFE_NO_PAD.decode(raw).map_err(|_| invalid())?;
+        let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+        let (id, created_at) = text.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            created_at: created_at.to_string(),
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// `cursor` and `limit` query parameters shared by every list endpoint.
+#[derive(Clone, Debug)]
+pub struct PageQuery {
+    pub cursor: Option<PageCursor>,
+    pub limit: i64,
+}
+
+impl PageQuery {
+    pub fn parse(cursor: Option<&str>, limit: Option<i64>) -> Result<Self, ModelError> {
+        let cursor = cursor
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(PageCursor::decode)
+            .transpose()?;
+        Ok(Self {
+            cursor,
+            limit: limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+        })
+    }
+
+    /// Rows to fetch: one more than `limit`, to tell whether another page exists.
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// `created_at` and `id` bound for the keyset condition; `NULL`s on the first page.
+    pub fn cursor_params(&self) -> [D1Param; 2] {
+        match &self.cursor {
+            Some(cursor) => [
+                D1Param::Text(cursor.created_at.clone()),
+                D1Param::Integer(cursor.id),
+            ],
+            None => [D1Param::Null, D1Param::Null],
+        }
+    }
 }
 
-#[allow(dead_code)]
-fn _validate_boolean_helpers(row: &D1Row) -> Result<(Option<i64>, i64), ModelError> {
-    Ok((
-        optional_bool_int(row, "flag")?,
-        required_bool_int(row, "is_read")?,
-    ))
+#[cfg(test)]
+mod tests {
+    use super::{
+        D1Param, D1Row, DisplayGroup, FromD1Row, MAX_PAGE_LIMIT, Notification, PageCursor,
+        PageQuery, Paginated, Task, ToD1Params,
+    };
+    use serde_json::json;
+
+    fn row(value: serde_json::Value) -> D1Row {
+        value.as_object().cloned().expect("object")
+    }
+
+    fn task_row() -> serde_json::Value {
+        json!({
+            "id": 1,
+            "organization_id": 2,
+            "member_id": 3,
+            "title": "Write docs",
+            "description": null,
+            "status": "todo",
+            "progress_rate": "40",
+            "created_at": "2024-01-01 00:00:00",
+        })
+    }
+
+    #[test]
+    fn decodes_csv_and_json_list_columns() {
+        let mut csv = task_row();
+        csv["tags"] = json!("rust, d1");
+        let task = Task::from_d1_row(&row(csv)).expect("csv tags");
+        assert_eq!(task.tags, Some(vec!["rust".to_string(), "d1".to_string()]));
+        assert_eq!(task.progress_rate, 40);
+
+        let mut group = json!({
+            "id": 1,
+            "organization_id": 2,
+            "user_id": 3,
+            "name": "Team",
+            "member_ids": "[4,5]",
+            "created_at": "2024-01-01 00:00:00",
+        });
+        let parsed = DisplayGroup::from_d1_row(&row(group.clone())).expect("json member_ids");
+        assert_eq!(parsed.member_ids, vec![4, 5]);
+
+        group["member_ids"] = json!(null);
+        let parsed = DisplayGroup::from_d1_row(&row(group)).expect("null member_ids");
+        assert!(parsed.member_ids.is_empty());
+    }
+
+    #[test]
+    fn defaults_missing_columns_only_when_marked() {
+        let task = Task::from_d1_row(&row(task_row())).expect("task");
+        assert_eq!(task.total_duration_minutes, 0);
+        assert_eq!(task.tags, None);
+
+        let mut missing_title = task_row();
+        missing_title.as_object_mut().unwrap().remove("title");
+        assert!(Task::from_d1_row(&row(missing_title)).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_bool_int() {
+        let notification = json!({
+            "id": 1,
+            "organization_id": 2,
+            "user_id": 3,
+            "title": "Hi",
+            "category": "task",
+            "is_read": 2,
+            "created_at": "2024-01-01 00:00:00",
+        });
+        assert!(Notification::from_d1_row(&row(notification)).is_err());
+    }
+
+    #[test]
+    fn to_d1_params_skips_readonly_fields() {
+        let task = Task::from_d1_row(&row(task_row())).expect("task");
+        let params = task.to_d1_params();
+        assert_eq!(params.len(), 8);
+        assert!(matches!(params[0], D1Param::Integer(2)));
+        assert!(matches!(params[3], D1Param::Null));
+        assert!(matches!(params[6], D1Param::Null));
+    }
+
+    #[test]
+    fn page_cursor_round_trips() {
+        let cursor = PageCursor::new("2024-01-01 09:00:00", 42);
+        assert_eq!(
+            PageCursor::decode(&cursor.encode()).expect("decode"),
+            cursor
+        );
+        assert!(PageCursor::decode("not a cursor").is_err());
+    }
+
+    #[test]
+    fn paginated_sets_next_cursor_only_when_more_rows_exist() {
+        let page = PageQuery::parse(None, Some(2)).expect("page query");
+        let cursor_of = |id: &i64| PageCursor::new("2024-01-01 00:00:00", *id);
+
+        let full = Paginated::from_rows(vec![3, 2, 1], &page, cursor_of);
+        assert_eq!(full.items, vec![3, 2]);
+        let next = PageCursor::decode(full.next_cursor.as_deref().expect("next cursor"));
+        assert_eq!(next.expect("decode").id, 2);
+
+        let last = Paginated::from_rows(vec![1], &page, cursor_of);
+        assert!(last.next_cursor.is_none());
+
+        let clamped = PageQuery::parse(Some(""), Some(10_000)).expect("page query");
+        assert_eq!(clamped.limit, MAX_PAGE_LIMIT);
+        assert!(clamped.cursor.is_none());
+    }
 }
@@ -0,0 +1,698 @@
+//! OpenID Connect single sign-on: the authorization-code flow with PKCE
+//! against an identity provider configured per organization.
+//!
+//! Calls to the provider go through [`HttpClient`], so native tests can run
+//! the whole flow against a mock provider that serves discovery and JWKS.
+
+use crate::db::Database;
+use crate::models::{
+    D1Param, D1Statement, FromD1Row, ModelError, d1_batch, d1_execute, d1_query_one,
+};
+use crate::provisioning::{available_username, unusable_password_hash};
+use crate::utils::{generate_token, hash_token};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{Duration, Utc};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use worker::Url;
+#[cfg(target_arch = "wasm32")]
+use worker::{Fetch, Headers, Method, Request, RequestInit};
+
+pub const LOGIN_STATE_EXPIRATION_MINUTES: i64 = 10;
+const SCOPE: &str = "openid email profile";
+
+#[async_trait(?Send)]
+pub trait HttpClient: Send + Sync {
+    async fn get_json(&self, url: &str) -> Result<Value, String>;
+
+    /// Posts `form` as `application/x-www-form-urlencoded` and parses the JSON reply.
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<Value, String>;
+}
+
+#[async_trait(?Send)]
+impl<T: HttpClient + ?Sized> HttpClient for std::sync::Arc<T> {
+    async fn get_json(&self, url: &str) -> Result<Value, String> {
+        (**self).get_json(url).await
+    }
+
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<Value, String> {
+        (**self).post_form(url, form).await
+    }
+}
+
+/// [`HttpClient`] backed by the Workers `fetch` API.
+#[derive(Debug, Clone, Default)]
+pub struct FetchHttpClient;
+
+impl FetchHttpClient {
+    #[cfg(target_arch = "wasm32")]
+    async fn send(&self, url: &str, init: &RequestInit) -> Result<Value, String> {
+        let req = Request::new_with_init(url, init).map_err(|e| e.to_string())?;
+        let mut res = Fetch::Request(req)
+            .send()
+            .await
+            .map_err(|e| format!("fetch {url} failed: {e}"))?;
+
+        if !(200..300).contains(&res.status_code()) {
+            let status = res.status_code();
+            let body = res
+                .text()
+                .await
+                .unwrap_or_else(|_| "<empty body>".to_string());
+            return Err(format!("{url} returned status={status} body={body}"));
+        }
+
+        res.json::<Value>().await.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl HttpClient for FetchHttpClient {
+    async fn get_json(&self, url: &str) -> Result<Value, String> {
+        let headers = Headers::new();
+        headers
+            .set("Accept", "application/json")
+            .map_err(|e| e.to_string())?;
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Get);
+        init.with_headers(headers);
+        self.send(url, &init).await
+    }
+
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<Value, String> {
+        let body = form
+            .iter()
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let headers = Headers::new();
+        headers
+            .set("Content-Type", "application/x-www-form-urlencoded")
+            .map_err(|e| e.to_string())?;
+        headers
+            .set("Accept", "application/json")
+            .map_err(|e| e.to_string())?;
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        init.with_headers(headers);
+        init.with_body(Some(body.into()));
+        self.send(url, &init).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait(?Send)]
+impl HttpClient for FetchHttpClient {
+    async fn get_json(&self, _url: &str) -> Result<Value, String> {
+        Err("FetchHttpClient is currently intended for Cloudflare Workers (wasm32) only".into())
+    }
+
+    async fn post_form(&self, _url: &str, _form: &[(&str, &str)]) -> Result<Value, String> {
+        Err("FetchHttpClient is currently intended for Cloudflare Workers (wasm32) only".into())
+    }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The organization has no enabled provider.
+    NotConfigured,
+    /// Unknown, expired or already redeemed `state`.
+    InvalidState,
+    /// The provider could not be reached or answered unexpectedly.
+    Provider(String),
+    InvalidIdToken(String),
+    /// The ID token carries no verified email, or one outside the allowed domains.
+    EmailNotAllowed,
+    /// The matching member has been deactivated.
+    AccountDeactivated,
+    Invalid(ModelError),
+    Database(ModelError),
+}
+
+impl OidcError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::NotConfigured => 404,
+            Self::InvalidState | Self::Invalid(_) => 400,
+            Self::Provider(_) => 502,
+            Self::InvalidIdToken(_) => 401,
+            Self::EmailNotAllowed | Self::AccountDeactivated => 403,
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "Single sign-on is not configured"),
+            Self::InvalidState => write!(f, "Sign-in request expired; please start again"),
+            Self::Provider(message) => write!(f, "Identity provider error: {message}"),
+            Self::InvalidIdToken(message) => write!(f, "Invalid ID token: {message}"),
+            Self::EmailNotAllowed => write!(
+                f,
+                "Your account's email address is not allowed to sign in to this organization"
+            ),
+            Self::AccountDeactivated => write!(f, "This account has been deactivated"),
+            Self::Invalid(err) | Self::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ModelError> for OidcError {
+    fn from(value: ModelError) -> Self {
+        match value {
+            ModelError::InvalidValue { .. } => Self::Invalid(value),
+            other => Self::Database(other),
+        }
+    }
+}
+
+/// The subset of the discovery document the login flow needs.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Claims read from a verified ID token.
+#[derive(Clone, Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub nonce: Option<String>,
+}
+
+/// Checks that `value` is an absolute http(s) URL and returns it trimmed.
+/// Plain http is only accepted for localhost.
+pub fn validate_url(field: &'static str, value: &str) -> Result<String, ModelError> {
+    let invalid = |message: &str| ModelError::InvalidValue {
+        field,
+        message: message.to_string(),
+    };
+    let url = Url::parse(value.trim()).map_err(|_| invalid("must be an absolute URL"))?;
+    let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1"));
+    match url.scheme() {
+        "https" => {}
+        "http" if local => {}
+        _ => return Err(invalid("must use https")),
+    }
+    if url.fragment().is_some() {
+        return Err(invalid("must not contain a fragment"));
+    }
+    Ok(value.trim().to_string())
+}
+
+/// Normalizes allowed email domains: lowercased, without a leading `@`,
+/// sorted and de-duplicated.
+pub fn normalize_domains(domains: &[String]) -> Result<Vec<String>, ModelError> {
+    let mut normalized = Vec::with_capacity(domains.len());
+    for domain in domains {
+        let domain = domain.trim().trim_start_matches('@').to_ascii_lowercase();
+        let valid = domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid {
+            return Err(ModelError::InvalidValue {
+                field: "allowed_domains",
+                message: format!("invalid domain: {domain}"),
+            });
+        }
+        normalized.push(domain);
+    }
+    normalized.sort();
+    normalized.dedup();
+    Ok(normalized)
+}
+
+/// An empty list allows every domain.
+pub fn email_domain_allowed(email: &str, allowed_domains: &[String]) -> bool {
+    let Some((_, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    allowed_domains.is_empty()
+        || allowed_domains
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+}
+
+/// S256 PKCE challenge for `verifier` (RFC 7636).
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+pub fn authorization_url(
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    redirect_uri: &str,
+    state: &str,
+    nonce: &str,
+    verifier: &str,
+) -> String {
+    let params = [
+        ("response_type", "code"),
+        ("client_id", client_id),
+        ("redirect_uri", redirect_uri),
+        ("scope", SCOPE),
+        ("state", state),
+        ("nonce", nonce),
+        ("code_challenge", &code_challenge(verifier)),
+        ("code_challenge_method", "S256"),
+    ]
+    .iter()
+    .map(|(k, v)| format!("{k}={}", urlencoding::encode(v)))
+    .collect::<Vec<_>>()
+    .join("&");
+    let separator = if metadata.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!("{}{separator}{params}", metadata.authorization_endpoint)
+}
+
+/// Fetches the discovery document of `issuer` and checks that it describes
+/// that issuer.
+pub async fn discover(http: &dyn HttpClient, issuer: &str) -> Result<ProviderMetadata, OidcError> {
+    let issuer = issuer.trim_end_matches('/');
+    let document = http
+        .get_json(&format!("{issuer}/.well-known/openid-configuration"))
+        .await
+        .map_err(OidcError::Provider)?;
+    let metadata: ProviderMetadata = serde_json::from_value(document)
+        .map_err(|e| OidcError::Provider(format!("invalid discovery document: {e}")))?;
+    if metadata.issuer.trim_end_matches('/') != issuer {
+        return Err(OidcError::Provider(format!(
+            "discovery document is for issuer {}",
+            metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+/// Verifies an RS256 ID token against the provider's JWKS and the expected
+/// audience and nonce.
+pub async fn verify_id_token(
+    http: &dyn HttpClient,
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(id_token).map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+    if header.alg != Algorithm::RS256 {
+        return Err(OidcError::InvalidIdToken(format!(
+            "unsupported algorithm {:?}",
+            header.alg
+        )));
+    }
+
+    let jwks: JwkSet = serde_json::from_value(
+        http.get_json(&metadata.jwks_uri)
+            .await
+            .map_err(OidcError::Provider)?,
+    )
+    .map_err(|e| OidcError::Provider(format!("invalid JWKS: {e}")))?;
+    let jwk = match header.kid.as_deref() {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| OidcError::InvalidIdToken("unknown signing key".to_string()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| OidcError::Provider(e.to_string()))?;
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidIdToken("nonce mismatch".to_string()));
+    }
+    Ok(claims)
+}
+
+#[derive(FromD1Row)]
+struct ProviderRow {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    #[d1(list)]
+    allowed_domains: Vec<String>,
+}
+
+async fn load_provider(db: &dyn Database, organization_id: i64) -> Result<ProviderRow, OidcError> {
+    d1_query_one::<ProviderRow>(
+        db,
+        "SELECT issuer, client_id, client_secret, allowed_domains
+         FROM sso_providers
+         WHERE organization_id = ?1 AND enabled = 1
+         LIMIT 1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    .ok_or(OidcError::NotConfigured)
+}
+
+/// Starts a sign-in to `organization_id` and returns the provider URL to send
+/// the browser to. The provider redirects back to `redirect_uri` with the
+/// `code` and `state` that [`complete`] expects.
+pub async fn begin(
+    db: &dyn Database,
+    http: &dyn HttpClient,
+    organization_id: i64,
+    redirect_uri: &str,
+) -> Result<String, OidcError> {
+    let redirect_uri = validate_url("redirect_uri", redirect_uri)?;
+    let provider = load_provider(db, organization_id).await?;
+    let metadata = discover(http, &provider.issuer).await?;
+
+    let state = generate_token();
+    let nonce = generate_token();
+    let verifier = generate_token();
+    let expires_at = (Utc::now() + Duration::minutes(LOGIN_STATE_EXPIRATION_MINUTES))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    d1_batch(
+        db,
+        &[
+            D1Statement::new(
+                "DELETE FROM sso_login_states WHERE datetime(expires_at) <= datetime('now')",
+                vec![],
+            ),
+            D1Statement::new(
+                "INSERT INTO sso_login_states (organization_id, state_hash, nonce, code_verifier, redirect_uri, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                vec![
+                    D1Param::Integer(organization_id),
+                    D1Param::Text(hash_token(&state)),
+                    D1Param::Text(nonce.clone()),
+                    D1Param::Text(verifier.clone()),
+                    D1Param::Text(redirect_uri.clone()),
+                    D1Param::Text(expires_at),
+                ],
+            ),
+        ],
+    )
+    .await?;
+
+    Ok(authorization_url(
+        &metadata,
+        &provider.client_id,
+        &redirect_uri,
+        &state,
+        &nonce,
+        &verifier,
+    ))
+}
+
+/// The account a completed sign-in resolved to.
+#[derive(Clone, Debug)]
+pub struct SsoLogin {
+    pub organization_id: i64,
+    pub user_id: i64,
+    /// Set when the account was created by this sign-in.
+    pub provisioned: bool,
+}
+
+#[derive(FromD1Row)]
+struct LoginStateRow {
+    organization_id: i64,
+    nonce: String,
+    code_verifier: String,
+    redirect_uri: String,
+}
+
+/// Redeems the provider's callback: exchanges `code` with the stored PKCE
+/// verifier, verifies the ID token and finds or provisions the user.
+pub async fn complete(
+    db: &dyn Database,
+    http: &dyn HttpClient,
+    state: &str,
+    code: &str,
+) -> Result<SsoLogin, OidcError> {
+    // Deleting while reading makes each state redeemable exactly once.
+    let login = d1_query_one::<LoginStateRow>(
+        db,
+        "DELETE FROM sso_login_states
+         WHERE state_hash = ?1 AND datetime(expires_at) > datetime('now')
+         RETURNING organization_id, nonce, code_verifier, redirect_uri",
+        &[D1Param::Text(hash_token(state))],
+    )
+    .await?
+    .ok_or(OidcError::InvalidState)?;
+
+    let provider = load_provider(db, login.organization_id).await?;
+    let metadata = discover(http, &provider.issuer).await?;
+
+    let response = http
+        .post_form(
+            &metadata.token_endpoint,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &login.redirect_uri),
+                ("client_id", &provider.client_id),
+                ("client_secret", &provider.client_secret),
+                ("code_verifier", &login.code_verifier),
+            ],
+        )
+        .await
+        .map_err(OidcError::Provider)?;
+    let id_token = response
+        .get("id_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| OidcError::Provider("token response has no id_token".to_string()))?;
+
+    let claims =
+        verify_id_token(http, &metadata, &provider.client_id, id_token, &login.nonce).await?;
+    resolve_user(
+        db,
+        login.organization_id,
+        &provider.issuer,
+        &provider.allowed_domains,
+        &claims,
+    )
+    .await
+}
+
+#[derive(FromD1Row)]
+struct MemberRow {
+    id: i64,
+    deactivated_at: Option<String>,
+}
+
+/// Finds the user linked to the token's subject, links an existing user with
+/// the same email, or provisions a new member.
+async fn resolve_user(
+    db: &dyn Database,
+    organization_id: i64,
+    issuer: &str,
+    allowed_domains: &[String],
+    claims: &IdTokenClaims,
+) -> Result<SsoLogin, OidcError> {
+    let email = claims
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| !email.is_empty());
+    // Narrowing the allowed domains also shuts out accounts linked earlier.
+    if email.is_some_and(|email| !email_domain_allowed(email, allowed_domains)) {
+        return Err(OidcError::EmailNotAllowed);
+    }
+
+    let linked = d1_query_one::<MemberRow>(
+        db,
+        "SELECT m.user_id AS id, m.deactivated_at
+         FROM user_identities i
+         JOIN memberships m ON m.user_id = i.user_id AND m.organization_id = i.organization_id
+         WHERE i.organization_id = ?1 AND i.issuer = ?2 AND i.subject = ?3
+         LIMIT 1",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(issuer.to_string()),
+            D1Param::Text(claims.sub.clone()),
+        ],
+    )
+    .await?;
+    if let Some(user) = linked {
+        if user.deactivated_at.is_some() {
+            return Err(OidcError::AccountDeactivated);
+        }
+        return Ok(SsoLogin {
+            organization_id,
+            user_id: user.id,
+            provisioned: false,
+        });
+    }
+
+    // Linking or provisioning by email trusts the provider's word for it; a
+    // missing `email_verified` is no such word.
+    let Some(email) = email.filter(|_| claims.email_verified == Some(true)) else {
+        return Err(OidcError::EmailNotAllowed);
+    };
+    let existing = d1_query_one::<MemberRow>(
+        db,
+        "SELECT u.id, m.deactivated_at
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND lower(u.email) = lower(?2)
+         LIMIT 1",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(email.to_string()),
+        ],
+    )
+    .await?;
+    if let Some(user) = existing {
+        if user.deactivated_at.is_some() {
+            return Err(OidcError::AccountDeactivated);
+        }
+        d1_execute(
+            db,
+            "INSERT INTO user_identities (organization_id, user_id, issuer, subject)
+             VALUES (?1, ?2, ?3, ?4)",
+            &[
+                D1Param::Integer(organization_id),
+                D1Param::Integer(user.id),
+                D1Param::Text(issuer.to_string()),
+                D1Param::Text(claims.sub.clone()),
+            ],
+        )
+        .await?;
+        return Ok(SsoLogin {
+            organization_id,
+            user_id: user.id,
+            provisioned: false,
+        });
+    }
+
+    let username = available_username(db, organization_id, email).await?;
+    let name = claims
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&username)
+        .to_string();
+    let results = d1_batch(
+        db,
+        &[
+            D1Statement::new(
+                "INSERT INTO users (organization_id, name, username, email, email_verified, password_hash)
+                 VALUES (?1, ?2, ?3, ?4, 1, ?5)",
+                vec![
+                    D1Param::Integer(organization_id),
+                    D1Param::Text(name),
+                    D1Param::Text(username.clone()),
+                    D1Param::Text(email.to_string()),
+                    D1Param::Text(unusable_password_hash()?),
+                ],
+            ),
+            D1Statement::new(
+                "INSERT INTO memberships (user_id, organization_id, role)
+                 SELECT id, ?1, 'user' FROM users WHERE organization_id = ?1 AND username = ?2",
+                vec![
+                    D1Param::Integer(organization_id),
+                    D1Param::Text(username.clone()),
+                ],
+            ),
+            D1Statement::new(
+                "INSERT INTO user_identities (organization_id, user_id, issuer, subject)
+                 SELECT ?1, id, ?3, ?4 FROM users WHERE organization_id = ?1 AND username = ?2",
+                vec![
+                    D1Param::Integer(organization_id),
+                    D1Param::Text(username.clone()),
+                    D1Param::Text(issuer.to_string()),
+                    D1Param::Text(claims.sub.clone()),
+                ],
+            ),
+        ],
+    )
+    .await?;
+
+    Ok(SsoLogin {
+        organization_id,
+        user_id: results[0].inserted_id()?,
+        provisioned: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ProviderMetadata, authorization_url, code_challenge, email_domain_allowed,
+        normalize_domains, validate_url,
+    };
+
+    #[test]
+    fn builds_pkce_authorization_requests() {
+        assert_eq!(
+            code_challenge("glanceflow-pkce-verifier"),
+            "2K5BAD92GfvvFN8E1qtwR-XONaP9Nd6r1C3CTDBnIDU"
+        );
+
+        let metadata = ProviderMetadata {
+            issuer: "https://idp.example".into(),
+            authorization_endpoint: "https://idp.example/authorize".into(),
+            token_endpoint: "https://idp.example/token".into(),
+            jwks_uri: "https://idp.example/jwks".into(),
+        };
+        let url = authorization_url(
+            &metadata,
+            "client",
+            "https://app.example/sso/callback",
+            "st",
+            "nc",
+            "glanceflow-pkce-verifier",
+        );
+        assert!(
+            url.starts_with("https://idp.example/authorize?response_type=code&client_id=client")
+        );
+        assert!(url.contains("redirect_uri=https%3A%2F%2Fapp.example%2Fsso%2Fcallback"));
+        assert!(url.contains("scope=openid%20email%20profile"));
+        assert!(url.contains("code_challenge=2K5BAD92GfvvFN8E1qtwR-XONaP9Nd6r1C3CTDBnIDU"));
+        assert!(url.ends_with("code_challenge_method=S256"));
+    }
+
+    #[test]
+    fn validates_urls_and_domains() {
+        assert_eq!(
+            validate_url("issuer", " https://idp.example/realms/acme ").expect("valid"),
+            "https://idp.example/realms/acme"
+        );
+        assert!(validate_url("issuer", "http://localhost:8080").is_ok());
+        assert!(validate_url("issuer", "http://idp.example").is_err());
+        assert!(validate_url("issuer", "idp.example").is_err());
+
+        let domains =
+            normalize_domains(&["@Acme.example".into(), "acme.example".into()]).expect("valid");
+        assert_eq!(domains, vec!["acme.example".to_string()]);
+        assert!(normalize_domains(&["localhost".into()]).is_err());
+
+        assert!(email_domain_allowed("a@ACME.example", &domains));
+        assert!(!email_domain_allowed("a@evil.example", &domains));
+        assert!(!email_domain_allowed("a@sub.acme.example", &domains));
+        assert!(email_domain_allowed("a@anything.example", &[]));
+    }
+}
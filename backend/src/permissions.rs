@@ -0,0 +1,418 @@
+//! Role-based authorization.
+//!
+//! A user's role maps to a set of [`Permission`]s. The built-in `admin`,
+//! `manager` and `user` roles are defined here; organizations can add custom
+//! roles in the `roles` table. Handlers check permissions through
+//! [`authorize`] rather than comparing role names; reports that a manager may
+//! read for their team only go through [`authorize_scope`].
+
+use crate::db::Database;
+use crate::models::{
+    Claims, CountRow, D1Param, FromD1Row, ModelError, Role, d1_query_all, d1_query_one,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const ADMIN_ROLE: &str = "admin";
+/// Reads the task report and analytics of their team: the members whose
+/// `memberships.manager_id` points at them.
+pub const MANAGER_ROLE: &str = "manager";
+pub const USER_ROLE: &str = "user";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Create members and change their roles.
+    ManageUsers,
+    /// Deactivate and reactivate members.
+    DeleteUsers,
+    ManageInvitations,
+    /// Organization settings, security, SSO and roles.
+    ManageOrganization,
+    /// The organization-wide task report and its CSV export.
+    ViewTaskReport,
+    /// Analytics of any other member of the organization.
+    ViewMemberAnalytics,
+    /// The task report and its CSV export, limited to the caller's team.
+    ViewTeamTaskReport,
+    /// Analytics of the members the caller manages.
+    ViewTeamAnalytics,
+    /// Edit daily reports written by other members.
+    EditAllReports,
+}
+
+impl Permission {
+    pub const ALL: &'static [Permission] = &[
+        Permission::ManageUsers,
+        Permission::DeleteUsers,
+        Permission::ManageInvitations,
+        Permission::ManageOrganization,
+        Permission::ViewTaskReport,
+        Permission::ViewMemberAnalytics,
+        Permission::ViewTeamTaskReport,
+        Permission::ViewTeamAnalytics,
+        Permission::EditAllReports,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ManageUsers => "manage_users",
+            Permission::DeleteUsers => "delete_users",
+            Permission::ManageInvitations => "manage_invitations",
+            Permission::ManageOrganization => "manage_organization",
+            Permission::ViewTaskReport => "view_task_report",
+            Permission::ViewMemberAnalytics => "view_member_analytics",
+            Permission::ViewTeamTaskReport => "view_team_task_report",
+            Permission::ViewTeamAnalytics => "view_team_analytics",
+            Permission::EditAllReports => "edit_all_reports",
+        }
+    }
+
+    /// The team-limited counterpart of an organization-wide permission.
+    pub fn team_scoped(self) -> Option<Permission> {
+        match self {
+            Permission::ViewTaskReport => Some(Permission::ViewTeamTaskReport),
+            Permission::ViewMemberAnalytics => Some(Permission::ViewTeamAnalytics),
+            _ => None,
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Permission> {
+        Permission::ALL
+            .iter()
+            .copied()
+            .find(|permission| permission.as_str() == value)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Permissions of a built-in role, or `None` for any other name.
+pub fn builtin_permissions(role: &str) -> Option<Vec<Permission>> {
+    match role {
+        ADMIN_ROLE => Some(Permission::ALL.to_vec()),
+        MANAGER_ROLE => Some(vec![
+            Permission::ViewTeamTaskReport,
+            Permission::ViewTeamAnalytics,
+        ]),
+        USER_ROLE => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+pub fn is_builtin_role(role: &str) -> bool {
+    builtin_permissions(role).is_some()
+}
+
+/// Custom role names: 3-30 lowercase ASCII letters, digits, `_` and `-`.
+pub fn is_valid_role_name(name: &str) -> bool {
+    (3..=30).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Parses permission names, sorted and de-duplicated.
+pub fn parse_permissions(values: &[String]) -> Result<Vec<Permission>, ModelError> {
+    let mut permissions = values
+        .iter()
+        .map(|value| {
+            Permission::parse(value.trim()).ok_or_else(|| ModelError::InvalidValue {
+                field: "permissions",
+                message: format!("unknown permission: {value}"),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    permissions.sort();
+    permissions.dedup();
+    Ok(permissions)
+}
+
+#[derive(FromD1Row)]
+struct RolePermissionsRow {
+    #[d1(list)]
+    permissions: Vec<String>,
+}
+
+/// Permissions of `role` in the organization, or `None` if no such role exists.
+pub async fn role_permissions(
+    db: &dyn Database,
+    organization_id: i64,
+    role: &str,
+) -> Result<Option<Vec<Permission>>, ModelError> {
+    if let Some(permissions) = builtin_permissions(role) {
+        return Ok(Some(permissions));
+    }
+    let Some(row) = d1_query_one::<RolePermissionsRow>(
+        db,
+        "SELECT permissions FROM roles WHERE organization_id = ?1 AND name = ?2 LIMIT 1",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(role.to_string()),
+        ],
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+    // Names dropped from the enum since the role was saved are ignored.
+    let mut permissions: Vec<Permission> = row
+        .permissions
+        .iter()
+        .filter_map(|value| Permission::parse(value))
+        .collect();
+    permissions.sort();
+    Ok(Some(permissions))
+}
+
+pub async fn role_exists(
+    db: &dyn Database,
+    organization_id: i64,
+    role: &str,
+) -> Result<bool, ModelError> {
+    Ok(role_permissions(db, organization_id, role).await?.is_some())
+}
+
+/// Permissions held by the caller; a role that no longer exists grants none.
+pub async fn permissions_of(
+    db: &dyn Database,
+    claims: &Claims,
+) -> Result<Vec<Permission>, ModelError> {
+    Ok(role_permissions(db, claims.organization_id, &claims.role)
+        .await?
+        .unwrap_or_default())
+}
+
+pub async fn has_permission(
+    db: &dyn Database,
+    claims: &Claims,
+    permission: Permission,
+) -> Result<bool, ModelError> {
+    Ok(permissions_of(db, claims).await?.contains(&permission))
+}
+
+#[derive(Debug)]
+pub enum AuthzError {
+    Forbidden(Permission),
+    UnknownRole(String),
+    /// The role grants permissions the caller does not hold.
+    CannotGrant(String),
+    Database(ModelError),
+}
+
+impl AuthzError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Forbidden(_) | Self::CannotGrant(_) => 403,
+            Self::UnknownRole(_) => 400,
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for AuthzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forbidden(permission) => write!(f, "Missing permission: {permission}"),
+            Self::UnknownRole(role) => write!(f, "Unknown role: {role}"),
+            Self::CannotGrant(role) => write!(
+                f,
+                "You cannot grant the {role} role because it has permissions you do not have"
+            ),
+            Self::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ModelError> for AuthzError {
+    fn from(value: ModelError) -> Self {
+        Self::Database(value)
+    }
+}
+
+/// The authorization check every handler goes through.
+pub async fn authorize(
+    db: &dyn Database,
+    claims: &Claims,
+    permission: Permission,
+) -> Result<(), AuthzError> {
+    if has_permission(db, claims, permission).await? {
+        Ok(())
+    } else {
+        Err(AuthzError::Forbidden(permission))
+    }
+}
+
+/// How much of the organization an [`authorize_scope`] check covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Organization,
+    /// The caller and the members whose manager they are.
+    Team,
+}
+
+/// Like [`authorize`], but also accepts the team-scoped counterpart of
+/// `permission` and says which of the two the caller holds.
+pub async fn authorize_scope(
+    db: &dyn Database,
+    claims: &Claims,
+    permission: Permission,
+) -> Result<Scope, AuthzError> {
+    let held = permissions_of(db, claims).await?;
+    if held.contains(&permission) {
+        Ok(Scope::Organization)
+    } else if permission
+        .team_scoped()
+        .is_some_and(|team| held.contains(&team))
+    {
+        Ok(Scope::Team)
+    } else {
+        Err(AuthzError::Forbidden(permission))
+    }
+}
+
+/// Whether `manager_id` manages `member_id` in the organization.
+pub async fn manages(
+    db: &dyn Database,
+    organization_id: i64,
+    manager_id: i64,
+    member_id: i64,
+) -> Result<bool, ModelError> {
+    let row = d1_query_one::<CountRow>(
+        db,
+        "SELECT COUNT(*) AS count FROM memberships
+         WHERE organization_id = ?1 AND user_id = ?2 AND manager_id = ?3",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(member_id),
+            D1Param::Integer(manager_id),
+        ],
+    )
+    .await?;
+    Ok(row.is_some_and(|row| row.count > 0))
+}
+
+/// Whether someone holding `granter` may hand out a role with `granted`;
+/// nobody can grant more than they have.
+pub fn can_grant(granter: &[Permission], granted: &[Permission]) -> bool {
+    granted
+        .iter()
+        .all(|permission| granter.contains(permission))
+}
+
+/// Checks that `role` exists in the caller's organization and that the caller
+/// may assign it, either directly or through an invitation.
+pub async fn authorize_grant(
+    db: &dyn Database,
+    claims: &Claims,
+    role: &str,
+) -> Result<(), AuthzError> {
+    let granted = role_permissions(db, claims.organization_id, role)
+        .await?
+        .ok_or_else(|| AuthzError::UnknownRole(role.to_string()))?;
+    if can_grant(&permissions_of(db, claims).await?, &granted) {
+        Ok(())
+    } else {
+        Err(AuthzError::CannotGrant(role.to_string()))
+    }
+}
+
+#[derive(FromD1Row)]
+struct CustomRoleRow {
+    name: String,
+    #[d1(list)]
+    permissions: Vec<String>,
+}
+
+/// Every role available in the organization: built-ins first, then custom
+/// roles by name.
+pub async fn list_roles(db: &dyn Database, organization_id: i64) -> Result<Vec<Role>, ModelError> {
+    let mut roles: Vec<Role> = [ADMIN_ROLE, MANAGER_ROLE, USER_ROLE]
+        .iter()
+        .map(|role| Role {
+            name: role.to_string(),
+            permissions: builtin_permissions(role).unwrap_or_default(),
+            builtin: true,
+        })
+        .collect();
+    let custom = d1_query_all::<CustomRoleRow>(
+        db,
+        "SELECT name, permissions FROM roles WHERE organization_id = ?1 ORDER BY name ASC",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?;
+    roles.extend(custom.into_iter().map(|row| {
+        let mut permissions: Vec<Permission> = row
+            .permissions
+            .iter()
+            .filter_map(|value| Permission::parse(value))
+            .collect();
+        permissions.sort();
+        Role {
+            name: row.name,
+            permissions,
+            builtin: false,
+        }
+    }));
+    Ok(roles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Permission, builtin_permissions, can_grant, is_valid_role_name, parse_permissions,
+    };
+
+    #[test]
+    fn builtin_roles_map_to_permissions() {
+        assert_eq!(
+            builtin_permissions("admin").map(|p| p.len()),
+            Some(Permission::ALL.len())
+        );
+        let manager = builtin_permissions("manager").expect("manager");
+        assert!(manager.contains(&Permission::ViewTeamTaskReport));
+        assert!(manager.contains(&Permission::ViewTeamAnalytics));
+        assert!(!manager.contains(&Permission::ViewTaskReport));
+        assert!(!manager.contains(&Permission::DeleteUsers));
+        assert_eq!(builtin_permissions("user"), Some(vec![]));
+        assert_eq!(builtin_permissions("owner"), None);
+    }
+
+    #[test]
+    fn parses_permissions_and_role_names() {
+        assert_eq!(
+            parse_permissions(&[
+                "view_task_report".into(),
+                "manage_users".into(),
+                "manage_users".into()
+            ])
+            .expect("valid"),
+            vec![Permission::ManageUsers, Permission::ViewTaskReport]
+        );
+        assert!(parse_permissions(&["superuser".into()]).is_err());
+        for permission in Permission::ALL {
+            assert_eq!(Permission::parse(permission.as_str()), Some(*permission));
+            assert_eq!(
+                serde_json::to_string(permission).expect("serialize"),
+                format!("\"{permission}\"")
+            );
+        }
+
+        assert!(is_valid_role_name("team-lead"));
+        assert!(!is_valid_role_name("Team Lead"));
+        assert!(!is_valid_role_name("ab"));
+    }
+
+    #[test]
+    fn cannot_grant_more_than_held() {
+        let manager = builtin_permissions("manager").expect("manager");
+        assert!(can_grant(&manager, &[Permission::ViewTeamTaskReport]));
+        assert!(!can_grant(&manager, &[Permission::ViewTaskReport]));
+        assert!(!can_grant(&manager, &[Permission::DeleteUsers]));
+        assert!(can_grant(&manager, &[]));
+    }
+}
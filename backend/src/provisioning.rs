@@ -0,0 +1,493 @@
+//! Creating members in bulk, for the CSV import and the SCIM API.
+//!
+//! Provisioned members start without a usable password. Each one gets a link
+//! to choose a password, valid for [`WELCOME_LINK_EXPIRATION_DAYS`]. The link
+//! is emailed unless the organization only allows single sign-on.
+
+use crate::db::Database;
+use crate::email::EmailService;
+use crate::models::{
+    CountRow, D1Param, D1Statement, FromD1Row, ModelError, d1_batch, d1_query_all, d1_query_one,
+};
+use crate::permissions::USER_ROLE;
+use crate::utils::{generate_token, is_valid_display_name, is_valid_email, is_valid_username};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHasher, SaltString},
+};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+pub const WELCOME_LINK_EXPIRATION_DAYS: i64 = 7;
+/// Keeps one import within a single D1 batch of two statements per row.
+pub const MAX_IMPORT_ROWS: usize = 200;
+
+/// A member to create.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NewMember {
+    pub name: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub role: String,
+    /// The directory's id for the member; only set through SCIM.
+    #[serde(skip)]
+    pub external_id: Option<String>,
+}
+
+/// A CSV data row, numbered by the line it starts on (the header is line 1).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ImportRow {
+    pub row: usize,
+    #[serde(flatten)]
+    pub member: NewMember,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// The result of `POST /api/users/import`. A dry run, or an import with
+/// errors, creates nobody.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub rows: Vec<ImportRow>,
+    pub errors: Vec<RowError>,
+    pub created: usize,
+    pub emailed: usize,
+}
+
+#[derive(Debug)]
+pub struct CsvError(String);
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid CSV: {}", self.0)
+    }
+}
+
+/// Splits RFC 4180 CSV into records, each with the line it starts on. Blank
+/// lines are skipped and a leading byte order mark is ignored.
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<String>)>, CsvError> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+
+    let mut finish_record = |record: &mut Vec<String>, record_line: usize| {
+        let fields = std::mem::take(record);
+        if !(fields.len() == 1 && fields[0].trim().is_empty()) {
+            records.push((record_line, fields));
+        }
+    };
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                finish_record(&mut record, record_line);
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(CsvError(format!(
+            "unterminated quoted field on line {record_line}"
+        )));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        finish_record(&mut record, record_line);
+    }
+    Ok(records)
+}
+
+/// Parses an import file with a `name,username,email,role` header. Columns
+/// may come in any order, `role` is optional (default `user`) and other
+/// columns are ignored.
+pub fn parse_csv(text: &str) -> Result<Vec<ImportRow>, CsvError> {
+    let mut records = csv_records(text)?.into_iter();
+    let Some((_, header)) = records.next() else {
+        return Err(CsvError("the file is empty".to_string()));
+    };
+    let header: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+    let column = |name: &str| header.iter().position(|h| h == name);
+    let required =
+        |name: &str| column(name).ok_or_else(|| CsvError(format!("missing the {name} column")));
+    let (name, username, email) = (required("name")?, required("username")?, required("email")?);
+    let role = column("role");
+
+    let rows: Vec<(usize, Vec<String>)> = records.collect();
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(CsvError(format!(
+            "at most {MAX_IMPORT_ROWS} members can be imported at once"
+        )));
+    }
+    Ok(rows
+        .into_iter()
+        .map(|(row, fields)| {
+            let value = |index: usize| {
+                fields
+                    .get(index)
+                    .map(|v| v.trim().to_string())
+                    .unwrap_or_default()
+            };
+            let email = value(email);
+            let role = role.map(value).filter(|role| !role.is_empty());
+            ImportRow {
+                row,
+                member: NewMember {
+                    name: value(name),
+                    username: value(username),
+                    email: (!email.is_empty()).then_some(email),
+                    role: role.unwrap_or_else(|| USER_ROLE.to_string()),
+                    external_id: None,
+                },
+            }
+        })
+        .collect())
+}
+
+#[derive(FromD1Row)]
+struct ExistingMemberRow {
+    username: String,
+    email: Option<String>,
+}
+
+/// Checks every import row against the field rules, the organization's
+/// existing members and the other rows. `assignable_roles` are the roles the
+/// importing admin may grant.
+pub async fn validate_import(
+    db: &dyn Database,
+    organization_id: i64,
+    rows: &[ImportRow],
+    assignable_roles: &[String],
+) -> Result<Vec<RowError>, ModelError> {
+    let existing = d1_query_all::<ExistingMemberRow>(
+        db,
+        "SELECT u.username, u.email
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?;
+    let taken_usernames: HashSet<String> = existing.iter().map(|m| m.username.clone()).collect();
+    let taken_emails: HashSet<String> = existing
+        .iter()
+        .filter_map(|m| m.email.as_deref().map(str::to_lowercase))
+        .collect();
+
+    let mut errors = Vec::new();
+    let mut seen_usernames = HashSet::new();
+    let mut seen_emails = HashSet::new();
+    for ImportRow { row, member } in rows {
+        let mut error = |field: &'static str, message: String| {
+            errors.push(RowError {
+                row: *row,
+                field,
+                message,
+            })
+        };
+
+        if !is_valid_display_name(&member.name) {
+            error("name", "Name must be 1-50 characters".to_string());
+        }
+        if !is_valid_username(&member.username) {
+            error(
+                "username",
+                "Username must be 3-30 alphanumeric characters, underscores, or hyphens"
+                    .to_string(),
+            );
+        } else if taken_usernames.contains(&member.username) {
+            error("username", "Username is already taken".to_string());
+        } else if !seen_usernames.insert(member.username.clone()) {
+            error(
+                "username",
+                "Username appears more than once in the file".to_string(),
+            );
+        }
+        match member.email.as_deref() {
+            None => error("email", "Email is required".to_string()),
+            Some(email) if !is_valid_email(email) => {
+                error("email", "Invalid email address".to_string())
+            }
+            Some(email) if taken_emails.contains(&email.to_lowercase()) => error(
+                "email",
+                "Email is already used by another member".to_string(),
+            ),
+            Some(email) if !seen_emails.insert(email.to_lowercase()) => error(
+                "email",
+                "Email appears more than once in the file".to_string(),
+            ),
+            Some(_) => {}
+        }
+        if !assignable_roles.contains(&member.role) {
+            error(
+                "role",
+                format!("Unknown role, or one you cannot grant: {}", member.role),
+            );
+        }
+    }
+    Ok(errors)
+}
+
+/// A created member and the token of their set-password link.
+#[derive(Clone, Debug)]
+pub struct ProvisionedMember {
+    pub user_id: i64,
+    pub email: Option<String>,
+    pub welcome_token: String,
+}
+
+/// Creates validated members in one batch, so either all or none are added.
+pub async fn create_members(
+    db: &dyn Database,
+    organization_id: i64,
+    members: &[NewMember],
+) -> Result<Vec<ProvisionedMember>, ModelError> {
+    // One hash for the whole batch: nobody knows the secret behind it.
+    let password_hash = unusable_password_hash()?;
+    let expires_at = (Utc::now() + Duration::days(WELCOME_LINK_EXPIRATION_DAYS)).to_rfc3339();
+    let tokens: Vec<String> = members.iter().map(|_| generate_token()).collect();
+
+    let statements: Vec<D1Statement> = members
+        .iter()
+        .zip(&tokens)
+        .flat_map(|(member, token)| {
+            [
+                D1Statement::new(
+                    "INSERT INTO users (organization_id, name, username, email, email_verified, password_hash, external_id)
+                     VALUES (?1, ?2, ?3, ?4, 1, ?5, ?6)",
+                    vec![
+                        D1Param::Integer(organization_id),
+                        D1Param::Text(member.name.clone()),
+                        D1Param::Text(member.username.clone()),
+                        member.email.clone().map(D1Param::Text).unwrap_or(D1Param::Null),
+                        D1Param::Text(password_hash.clone()),
+                        member
+                            .external_id
+                            .clone()
+                            .map(D1Param::Text)
+                            .unwrap_or(D1Param::Null),
+                    ],
+                ),
+                D1Statement::new(
+                    "INSERT INTO memberships (user_id, organization_id, role)
+                     SELECT id, ?1, ?3 FROM users WHERE organization_id = ?1 AND username = ?2",
+                    vec![
+                        D1Param::Integer(organization_id),
+                        D1Param::Text(member.username.clone()),
+                        D1Param::Text(member.role.clone()),
+                    ],
+                ),
+                D1Statement::new(
+                    "INSERT INTO password_resets (user_id, token, expires_at)
+                     SELECT id, ?3, ?4 FROM users WHERE organization_id = ?1 AND username = ?2",
+                    vec![
+                        D1Param::Integer(organization_id),
+                        D1Param::Text(member.username.clone()),
+                        D1Param::Text(token.clone()),
+                        D1Param::Text(expires_at.clone()),
+                    ],
+                ),
+            ]
+        })
+        .collect();
+    if statements.is_empty() {
+        return Ok(Vec::new());
+    }
+    let results = d1_batch(db, &statements).await?;
+
+    members
+        .iter()
+        .zip(tokens)
+        .enumerate()
+        .map(|(i, (member, welcome_token))| {
+            Ok(ProvisionedMember {
+                user_id: results[3 * i].inserted_id()?,
+                email: member.email.clone(),
+                welcome_token,
+            })
+        })
+        .collect()
+}
+
+#[derive(FromD1Row)]
+struct WelcomeSettingsRow {
+    name: String,
+    password_login_disabled: i64,
+}
+
+/// Emails the set-password links; returns how many were sent. Organizations
+/// that only allow single sign-on get no email, since their members never
+/// use a password.
+pub async fn send_welcome_emails(
+    db: &dyn Database,
+    email_service: &dyn EmailService,
+    organization_id: i64,
+    members: &[ProvisionedMember],
+) -> Result<usize, ModelError> {
+    let Some(organization) = d1_query_one::<WelcomeSettingsRow>(
+        db,
+        "SELECT name, password_login_disabled FROM organizations WHERE id = ?1 LIMIT 1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    else {
+        return Ok(0);
+    };
+    if organization.password_login_disabled == 1 {
+        return Ok(0);
+    }
+
+    let mut sent = 0;
+    for member in members {
+        let Some(email) = member.email.as_deref() else {
+            continue;
+        };
+        if email_service
+            .send_welcome_email(email, &member.welcome_token, &organization.name)
+            .await
+            .is_ok()
+        {
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+/// Derives a username from the local part of `email`.
+pub fn username_from_email(email: &str) -> String {
+    let local = email.split('@').next().unwrap_or_default();
+    let mut username: String = local
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .take(30)
+        .collect();
+    while username.len() < 3 {
+        username.push('_');
+    }
+    username
+}
+
+/// A free username derived from `email`, with a random suffix if the plain
+/// one is taken.
+pub async fn available_username(
+    db: &dyn Database,
+    organization_id: i64,
+    email: &str,
+) -> Result<String, ModelError> {
+    let base = username_from_email(email);
+    let taken = d1_query_one::<CountRow>(
+        db,
+        "SELECT COUNT(*) AS count
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND u.username = ?2",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(base.clone()),
+        ],
+    )
+    .await?
+    .is_some_and(|row| row.count > 0);
+    if !taken {
+        return Ok(base);
+    }
+
+    let suffix = &uuid::Uuid::new_v4().simple().to_string()[..6];
+    let username = format!("{}-{suffix}", &base[..base.len().min(23)]);
+    Ok(username)
+}
+
+/// Provisioned members sign in through a provider or a set-password link, so
+/// their password hash is of a random secret nobody knows.
+pub fn unusable_password_hash() -> Result<String, ModelError> {
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+        .map_err(|e| ModelError::Database(e.to_string()))?;
+    Argon2::default()
+        .hash_password(generate_token().as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ModelError::Database(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_IMPORT_ROWS, csv_records, parse_csv, username_from_email};
+
+    #[test]
+    fn splits_quoted_csv_records() {
+        let records = csv_records(
+            "\u{feff}name,username\r\n\"Doe, Jane\",jane\r\n\r\n\"Multi\nline \"\"quoted\"\"\",x\n",
+        )
+        .expect("valid");
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1], (2, vec!["Doe, Jane".into(), "jane".into()]));
+        assert_eq!(records[2].0, 4);
+        assert_eq!(records[2].1[0], "Multi\nline \"quoted\"");
+        assert!(csv_records("a,\"b\n").is_err());
+    }
+
+    #[test]
+    fn maps_import_columns_by_header() {
+        let rows =
+            parse_csv("Email,Name,Username,Extra\nrita@example.com,Rita,rita,x\n,Sam,sam,\n")
+                .expect("valid");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].row, 2);
+        assert_eq!(rows[0].member.email.as_deref(), Some("rita@example.com"));
+        assert_eq!(rows[0].member.role, "user");
+        assert_eq!(rows[1].member.email, None);
+
+        assert!(parse_csv("").is_err());
+        assert!(parse_csv("name,username\nRita,rita\n").is_err());
+        let too_many = format!(
+            "name,username,email\n{}",
+            "A,a,a@example.com\n".repeat(MAX_IMPORT_ROWS + 1)
+        );
+        assert!(parse_csv(&too_many).is_err());
+    }
+
+    #[test]
+    fn derives_usernames_from_email() {
+        assert_eq!(username_from_email("Jane.Doe@acme.example"), "jane_doe");
+        assert_eq!(username_from_email("x@acme.example"), "x__");
+    }
+}
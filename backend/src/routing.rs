@@ -0,0 +1,330 @@
+//! Requests, responses and routing that do not depend on the Workers runtime.
+//!
+//! `worker::Request` and `worker::Response` wrap JavaScript objects and panic
+//! when built natively. Handlers use the plain types here instead, with the
+//! same method names, and `fetch` converts at the edge. That keeps
+//! [`crate::router`] callable from `cargo test` against the SQLite backend.
+
+use futures::future::LocalBoxFuture;
+use futures::stream::{LocalBoxStream, StreamExt, TryStream, TryStreamExt};
+use matchit::Router as MatchItRouter;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::future::Future;
+use worker::{Error, Method, Result, Url};
+
+/// Header map with case-insensitive names, mirroring `worker::Headers`.
+#[derive(Clone, Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone()))
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let name = name.to_ascii_lowercase();
+        match self.0.iter_mut().find(|(key, _)| *key == name) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => self.0.push((name, value.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+pub struct Request {
+    method: Method,
+    url: Url,
+    headers: Headers,
+    body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(uri: &str, method: Method) -> Result<Self> {
+        Ok(Self {
+            method,
+            url: Url::parse(uri).map_err(|e| Error::RustError(e.to_string()))?,
+            headers: Headers::new(),
+            body: Some(Vec::new()),
+        })
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sets a JSON body and its `Content-Type`.
+    pub fn with_json<T: Serialize>(mut self, value: &T) -> Result<Self> {
+        self.headers.set("Content-Type", "application/json")?;
+        Ok(self.with_body(serde_json::to_vec(value)?))
+    }
+
+    pub fn method(&self) -> Method {
+        self.method.clone()
+    }
+
+    pub fn path(&self) -> String {
+        self.url.path().to_string()
+    }
+
+    pub fn url(&self) -> Result<Url> {
+        Ok(self.url.clone())
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub async fn bytes(&mut self) -> Result<Vec<u8>> {
+        self.body.take().ok_or(Error::BodyUsed)
+    }
+
+    pub async fn text(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes().await?).map_err(|_| Error::BadEncoding)
+    }
+
+    pub async fn json<T: DeserializeOwned>(&mut self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.bytes().await?)?)
+    }
+}
+
+pub enum ResponseBody {
+    Empty,
+    Body(Vec<u8>),
+    Stream(LocalBoxStream<'static, Result<Vec<u8>>>),
+}
+
+pub struct Response {
+    status: u16,
+    headers: Headers,
+    body: ResponseBody,
+}
+
+impl Response {
+    fn with_content_type(body: ResponseBody, content_type: &str) -> Result<Self> {
+        let mut headers = Headers::new();
+        headers.set("Content-Type", content_type)?;
+        Ok(Self {
+            status: 200,
+            headers,
+            body,
+        })
+    }
+
+    pub fn empty() -> Result<Self> {
+        Ok(Self {
+            status: 200,
+            headers: Headers::new(),
+            body: ResponseBody::Empty,
+        })
+    }
+
+    pub fn ok(body: impl Into<String>) -> Result<Self> {
+        Self::with_content_type(
+            ResponseBody::Body(body.into().into_bytes()),
+            "text/plain; charset=utf-8",
+        )
+    }
+
+    pub fn error(message: impl Into<String>, status: u16) -> Result<Self> {
+        Ok(Self::ok(message)?.with_status(status))
+    }
+
+    pub fn from_json<T: Serialize>(value: &T) -> Result<Self> {
+        Self::with_content_type(
+            ResponseBody::Body(serde_json::to_vec(value)?),
+            "application/json",
+        )
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::with_content_type(ResponseBody::Body(bytes), "application/octet-stream")
+    }
+
+    pub fn from_stream<S>(stream: S) -> Result<Self>
+    where
+        S: TryStream + 'static,
+        S::Ok: Into<Vec<u8>>,
+        S::Error: Into<Error>,
+    {
+        let stream = stream.map_ok(Into::into).map_err(Into::into).boxed_local();
+        Ok(Self {
+            status: 200,
+            headers: Headers::new(),
+            body: ResponseBody::Stream(stream),
+        })
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn into_parts(self) -> (u16, Headers, ResponseBody) {
+        (self.status, self.headers, self.body)
+    }
+
+    /// Reads the whole body, draining a streamed one.
+    pub async fn bytes(self) -> Result<Vec<u8>> {
+        match self.body {
+            ResponseBody::Empty => Ok(Vec::new()),
+            ResponseBody::Body(bytes) => Ok(bytes),
+            ResponseBody::Stream(stream) => Ok(stream.try_concat().await?),
+        }
+    }
+
+    pub async fn text(self) -> Result<String> {
+        String::from_utf8(self.bytes().await?).map_err(|_| Error::BadEncoding)
+    }
+
+    pub async fn json<T: DeserializeOwned>(self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.bytes().await?)?)
+    }
+}
+
+pub struct RouteContext<D> {
+    pub data: D,
+    params: HashMap<String, String>,
+}
+
+impl<D> RouteContext<D> {
+    pub fn param(&self, key: &str) -> Option<&String> {
+        self.params.get(key)
+    }
+}
+
+type Handler<D> =
+    Box<dyn Fn(Request, RouteContext<D>) -> LocalBoxFuture<'static, Result<Response>>>;
+
+/// Matches routes the way `worker::Router` does: per method, with `:name`
+/// parameters, static segments winning over parameters, and 405 for a path
+/// that only exists under another method.
+pub struct Router<D> {
+    data: D,
+    handlers: HashMap<Method, MatchItRouter<Handler<D>>>,
+}
+
+impl<D: Clone + 'static> Router<D> {
+    pub fn with_data(data: D) -> Self {
+        Self {
+            data,
+            handlers: HashMap::new(),
+        }
+    }
+
+    fn add<T>(
+        mut self,
+        method: Method,
+        pattern: &str,
+        func: fn(Request, RouteContext<D>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        let handler: Handler<D> = Box::new(move |req, ctx| Box::pin(func(req, ctx)));
+        if let Err(err) = self
+            .handlers
+            .entry(method)
+            .or_default()
+            .insert(pattern, handler)
+        {
+            panic!("invalid route {pattern}: {err}");
+        }
+        self
+    }
+
+    pub fn get_async<T>(self, pattern: &str, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        self.add(Method::Get, pattern, func)
+    }
+
+    pub fn post_async<T>(self, pattern: &str, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        self.add(Method::Post, pattern, func)
+    }
+
+    pub fn put_async<T>(self, pattern: &str, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        self.add(Method::Put, pattern, func)
+    }
+
+    pub fn patch_async<T>(self, pattern: &str, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        self.add(Method::Patch, pattern, func)
+    }
+
+    pub fn delete_async<T>(self, pattern: &str, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        self.add(Method::Delete, pattern, func)
+    }
+
+    pub async fn run(&self, req: Request) -> Result<Response> {
+        let path = req.path();
+        if let Some(found) = self
+            .handlers
+            .get(&req.method())
+            .and_then(|routes| routes.at(&path).ok())
+        {
+            let ctx = RouteContext {
+                data: self.data.clone(),
+                params: found
+                    .params
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            };
+            return (found.value)(req, ctx).await;
+        }
+
+        if self
+            .handlers
+            .values()
+            .any(|routes| routes.at(&path).is_ok())
+        {
+            return Response::error("Method Not Allowed", 405);
+        }
+        Response::error("Not Found", 404)
+    }
+}
@@ -0,0 +1,1247 @@
+//! SCIM 2.0 (RFC 7643 / 7644) provisioning for identity directories.
+//!
+//! Directories authenticate with an organization token (`gfs_...`) that an
+//! admin creates; changes made with it are logged as that admin.
+//!
+//! - Users are members. `active` follows deactivation, and `DELETE`
+//!   deactivates instead of deleting so the member's history is kept.
+//!   Directories that use email addresses as user names get a username
+//!   derived from the address and are matched on email.
+//! - Groups are the organization's roles, identified by name. A member holds
+//!   exactly one role, so joining a group moves them out of their previous
+//!   one and leaving a group returns them to `user`. Roles themselves are
+//!   managed in the app.
+
+use crate::db::Database;
+use crate::models::{
+    D1Param, D1Statement, FromD1Row, ModelError, d1_batch, d1_execute, d1_query_all, d1_query_one,
+};
+use crate::permissions::{self, ADMIN_ROLE, USER_ROLE};
+use crate::provisioning::{self, NewMember, ProvisionedMember};
+use crate::utils::{
+    generate_token, hash_token, is_valid_display_name, is_valid_email, is_valid_username,
+};
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Distinguishes SCIM tokens from access tokens and JWTs.
+pub const TOKEN_PREFIX: &str = "gfs_";
+pub const MAX_TOKEN_NAME_LENGTH: usize = 100;
+pub const CONTENT_TYPE: &str = "application/scim+json";
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+pub const SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug)]
+pub enum ScimError {
+    Unauthorized,
+    NotFound(String),
+    /// Malformed JSON or a request the protocol does not allow.
+    InvalidSyntax(String),
+    InvalidFilter(String),
+    InvalidValue(String),
+    /// Another member already has the user name, email or external id.
+    Uniqueness(String),
+    /// The change would leave the organization without an active admin.
+    Conflict(String),
+    Mutability(String),
+    NotImplemented(String),
+    Database(ModelError),
+}
+
+impl ScimError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Unauthorized => 401,
+            Self::NotFound(_) => 404,
+            Self::InvalidSyntax(_)
+            | Self::InvalidFilter(_)
+            | Self::InvalidValue(_)
+            | Self::Mutability(_) => 400,
+            Self::Uniqueness(_) | Self::Conflict(_) => 409,
+            Self::NotImplemented(_) => 501,
+            Self::Database(_) => 500,
+        }
+    }
+
+    fn scim_type(&self) -> Option<&'static str> {
+        match self {
+            Self::InvalidSyntax(_) => Some("invalidSyntax"),
+            Self::InvalidFilter(_) => Some("invalidFilter"),
+            Self::InvalidValue(_) => Some("invalidValue"),
+            Self::Uniqueness(_) => Some("uniqueness"),
+            Self::Mutability(_) => Some("mutability"),
+            _ => None,
+        }
+    }
+
+    /// The RFC 7644 error response body.
+    pub fn to_body(&self) -> Value {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status().to_string(),
+            "detail": self.to_string(),
+        });
+        if let Some(scim_type) = self.scim_type() {
+            body["scimType"] = json!(scim_type);
+        }
+        body
+    }
+}
+
+impl fmt::Display for ScimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Invalid SCIM token"),
+            Self::NotFound(what) => write!(f, "{what} not found"),
+            Self::InvalidSyntax(message)
+            | Self::InvalidFilter(message)
+            | Self::InvalidValue(message)
+            | Self::Uniqueness(message)
+            | Self::Conflict(message)
+            | Self::Mutability(message)
+            | Self::NotImplemented(message) => write!(f, "{message}"),
+            Self::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ModelError> for ScimError {
+    fn from(value: ModelError) -> Self {
+        Self::Database(value)
+    }
+}
+
+// =============================
+// Tokens
+// =============================
+
+pub fn is_scim_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Stores a new organization token and returns its id and secret; only the
+/// hash of the secret is kept.
+pub async fn create_token(
+    db: &dyn Database,
+    organization_id: i64,
+    created_by: i64,
+    name: &str,
+) -> Result<(i64, String), ModelError> {
+    let secret = format!("{TOKEN_PREFIX}{}", generate_token());
+    let id = d1_execute(
+        db,
+        "INSERT INTO scim_tokens (organization_id, name, token_hash, created_by)
+         VALUES (?1, ?2, ?3, ?4)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(name.to_string()),
+            D1Param::Text(hash_token(&secret)),
+            D1Param::Integer(created_by),
+        ],
+    )
+    .await?
+    .inserted_id()?;
+    Ok((id, secret))
+}
+
+/// The organization a SCIM request acts on, and the admin it is logged as.
+/// Tokens stop working when that admin is deactivated.
+#[derive(Clone, Debug, FromD1Row)]
+pub struct ScimClient {
+    pub token_id: i64,
+    pub organization_id: i64,
+    pub acting_user_id: i64,
+}
+
+pub async fn authenticate(db: &dyn Database, token: &str) -> Result<ScimClient, ScimError> {
+    if !is_scim_token(token) {
+        return Err(ScimError::Unauthorized);
+    }
+    let client = d1_query_one::<ScimClient>(
+        db,
+        "SELECT t.id AS token_id, t.organization_id, t.created_by AS acting_user_id
+         FROM scim_tokens t
+         JOIN memberships m ON m.user_id = t.created_by AND m.organization_id = t.organization_id
+         WHERE t.token_hash = ?1 AND t.revoked_at IS NULL AND m.deactivated_at IS NULL
+         LIMIT 1",
+        &[D1Param::Text(hash_token(token))],
+    )
+    .await?
+    .ok_or(ScimError::Unauthorized)?;
+
+    d1_execute(
+        db,
+        "UPDATE scim_tokens SET last_used_at = CURRENT_TIMESTAMP
+         WHERE id = ?1
+           AND (last_used_at IS NULL OR datetime(last_used_at) < datetime('now', '-1 minute'))",
+        &[D1Param::Integer(client.token_id)],
+    )
+    .await?;
+    Ok(client)
+}
+
+// =============================
+// Resources
+// =============================
+
+/// `2026-03-01 09:00:00` as `2026-03-01T09:00:00Z`.
+fn scim_datetime(value: &str) -> String {
+    format!("{}Z", value.replacen(' ', "T", 1))
+}
+
+const USER_COLUMNS: &str =
+    "u.id, u.name, u.username, u.email, u.external_id, m.role, m.deactivated_at, u.created_at";
+
+#[derive(Clone, Debug, FromD1Row)]
+pub struct ScimUserRow {
+    pub id: i64,
+    pub name: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub external_id: Option<String>,
+    pub role: String,
+    pub deactivated_at: Option<String>,
+    pub created_at: String,
+}
+
+impl ScimUserRow {
+    /// The member as a SCIM User; `base_url` is the worker's origin.
+    pub fn to_resource(&self, base_url: &str) -> Value {
+        let mut resource = json!({
+            "schemas": [USER_SCHEMA],
+            "id": self.id.to_string(),
+            "userName": self.username,
+            "displayName": self.name,
+            "name": { "formatted": self.name },
+            "active": self.deactivated_at.is_none(),
+            "groups": [{
+                "value": self.role,
+                "display": self.role,
+                "$ref": format!("{base_url}/scim/v2/Groups/{}", self.role),
+            }],
+            "meta": {
+                "resourceType": "User",
+                "created": scim_datetime(&self.created_at),
+                "location": format!("{base_url}/scim/v2/Users/{}", self.id),
+            },
+        });
+        if let Some(external_id) = &self.external_id {
+            resource["externalId"] = json!(external_id);
+        }
+        if let Some(email) = &self.email {
+            resource["emails"] = json!([{ "value": email, "type": "work", "primary": true }]);
+        }
+        resource
+    }
+}
+
+#[derive(Clone, Debug, FromD1Row)]
+pub struct GroupMemberRow {
+    pub id: i64,
+    pub name: String,
+}
+
+/// A role as a SCIM Group.
+pub fn group_resource(role: &str, members: &[GroupMemberRow], base_url: &str) -> Value {
+    json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": role,
+        "displayName": role,
+        "members": members
+            .iter()
+            .map(|member| json!({
+                "value": member.id.to_string(),
+                "display": member.name,
+                "$ref": format!("{base_url}/scim/v2/Users/{}", member.id),
+            }))
+            .collect::<Vec<_>>(),
+        "meta": {
+            "resourceType": "Group",
+            "location": format!("{base_url}/scim/v2/Groups/{role}"),
+        },
+    })
+}
+
+pub fn list_response(resources: Vec<Value>, total: i64, start_index: i64) -> Value {
+    json!({
+        "schemas": [LIST_RESPONSE_SCHEMA],
+        "totalResults": total,
+        "startIndex": start_index,
+        "itemsPerPage": resources.len(),
+        "Resources": resources,
+    })
+}
+
+pub fn service_provider_config() -> Value {
+    json!({
+        "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_PAGE_SIZE },
+        "changePassword": { "supported": false },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "Organization token",
+            "description": "A gfs_ token created by an organization admin",
+            "primary": true,
+        }],
+    })
+}
+
+// =============================
+// Filters and patches
+// =============================
+
+/// An `attribute eq "value"` filter, the only form directories need for
+/// matching accounts. The attribute name is lowercased.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub attribute: String,
+    pub value: String,
+}
+
+pub fn parse_filter(filter: &str) -> Result<Filter, ScimError> {
+    let invalid = || ScimError::InvalidFilter(format!("Unsupported filter: {filter}"));
+    let mut parts = filter.trim().splitn(3, char::is_whitespace);
+    let (Some(attribute), Some(operator), Some(value)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    if !operator.eq_ignore_ascii_case("eq") {
+        return Err(invalid());
+    }
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    Ok(Filter {
+        attribute: attribute.to_lowercase(),
+        value: value.replace("\\\"", "\"").replace("\\\\", "\\"),
+    })
+}
+
+fn operations(patch: &Value) -> Result<&Vec<Value>, ScimError> {
+    patch
+        .get("Operations")
+        .and_then(Value::as_array)
+        .ok_or_else(|| ScimError::InvalidSyntax("Operations are required".to_string()))
+}
+
+fn operation_kind(operation: &Value) -> Result<String, ScimError> {
+    let kind = operation
+        .get("op")
+        .and_then(Value::as_str)
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    match kind.as_str() {
+        "add" | "replace" | "remove" => Ok(kind),
+        _ => Err(ScimError::InvalidSyntax(format!(
+            "Unsupported patch operation: {kind}"
+        ))),
+    }
+}
+
+/// Some directories send booleans as strings.
+fn bool_value(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) if s.eq_ignore_ascii_case("true") => Some(true),
+        Value::String(s) if s.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+fn string_value(attribute: &str, value: &Value) -> Result<String, ScimError> {
+    value
+        .as_str()
+        .map(|s| s.trim().to_string())
+        .ok_or_else(|| ScimError::InvalidValue(format!("{attribute} must be a string")))
+}
+
+/// The primary (or else the first) address of an `emails` value.
+fn primary_email(value: &Value) -> Option<String> {
+    let emails = value.as_array()?;
+    emails
+        .iter()
+        .find(|email| email.get("primary").and_then(bool_value) == Some(true))
+        .or_else(|| emails.first())
+        .and_then(|email| email.get("value"))
+        .and_then(Value::as_str)
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty())
+}
+
+/// The member attributes a directory manages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserAttributes {
+    pub user_name: String,
+    pub display_name: String,
+    pub email: Option<String>,
+    pub external_id: Option<String>,
+    pub active: bool,
+}
+
+impl UserAttributes {
+    fn from_row(row: &ScimUserRow) -> Self {
+        Self {
+            user_name: row.username.clone(),
+            display_name: row.name.clone(),
+            email: row.email.clone(),
+            external_id: row.external_id.clone(),
+            active: row.deactivated_at.is_none(),
+        }
+    }
+
+    /// Reads a complete User resource, as sent to create or replace a
+    /// member. Attributes it leaves out are cleared; `active` defaults to true.
+    pub fn from_resource(resource: &Value) -> Result<Self, ScimError> {
+        let user_name = resource
+            .get("userName")
+            .map(|v| string_value("userName", v))
+            .transpose()?
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| ScimError::InvalidValue("userName is required".to_string()))?;
+        let name = resource.get("name");
+        let given_and_family = name.map(|name| {
+            ["givenName", "familyName"]
+                .iter()
+                .filter_map(|part| name.get(part).and_then(Value::as_str))
+                .map(str::trim)
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+        let display_name = [
+            resource.get("displayName").and_then(Value::as_str),
+            name.and_then(|name| name.get("formatted"))
+                .and_then(Value::as_str),
+            given_and_family.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|v| !v.is_empty())
+        .unwrap_or(&user_name)
+        .to_string();
+
+        Ok(Self {
+            display_name,
+            email: resource.get("emails").and_then(primary_email),
+            external_id: resource
+                .get("externalId")
+                .and_then(Value::as_str)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            active: resource
+                .get("active")
+                .map(|v| {
+                    bool_value(v)
+                        .ok_or_else(|| ScimError::InvalidValue("active must be a boolean".into()))
+                })
+                .transpose()?
+                .unwrap_or(true),
+            user_name,
+        })
+    }
+
+    fn set(&mut self, path: &str, value: Option<&Value>) -> Result<(), ScimError> {
+        let path = path.to_lowercase();
+        match (path.as_str(), value) {
+            ("active", Some(value)) => {
+                self.active = bool_value(value)
+                    .ok_or_else(|| ScimError::InvalidValue("active must be a boolean".into()))?;
+            }
+            ("username", Some(value)) => self.user_name = string_value("userName", value)?,
+            ("displayname" | "name.formatted", Some(value)) => {
+                self.display_name = string_value("displayName", value)?
+            }
+            ("name", Some(Value::Object(name))) => {
+                if let Some(formatted) = name.get("formatted") {
+                    self.display_name = string_value("name.formatted", formatted)?;
+                }
+            }
+            ("externalid", value) => {
+                self.external_id = value
+                    .filter(|v| !v.is_null())
+                    .map(|v| string_value("externalId", v))
+                    .transpose()?
+                    .filter(|v| !v.is_empty());
+            }
+            ("emails", value) => self.email = value.and_then(primary_email),
+            (path, value) if path.starts_with("emails[") || path == "emails.value" => {
+                self.email = value
+                    .filter(|v| !v.is_null())
+                    .map(|v| string_value("emails.value", v))
+                    .transpose()?
+                    .filter(|v| !v.is_empty());
+            }
+            ("active" | "username" | "displayname" | "name.formatted", None) => {
+                return Err(ScimError::Mutability(format!("{path} cannot be removed")));
+            }
+            // Attributes the app does not store (titles, phone numbers,
+            // extension schemas) are accepted and ignored.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Applies an RFC 7644 PatchOp request.
+    pub fn apply_patch(&mut self, patch: &Value) -> Result<(), ScimError> {
+        for operation in operations(patch)? {
+            let kind = operation_kind(operation)?;
+            let value = operation.get("value");
+            match (operation.get("path").and_then(Value::as_str), kind.as_str()) {
+                (Some(path), "remove") => self.set(path, None)?,
+                (Some(path), _) => {
+                    self.set(
+                        path,
+                        Some(value.ok_or_else(|| {
+                            ScimError::InvalidValue(format!("{path} needs a value"))
+                        })?),
+                    )?
+                }
+                (None, "remove") => {
+                    return Err(ScimError::InvalidSyntax(
+                        "remove operations need a path".to_string(),
+                    ));
+                }
+                (None, _) => {
+                    let Some(Value::Object(attributes)) = value else {
+                        return Err(ScimError::InvalidValue(
+                            "Operations without a path need an object value".to_string(),
+                        ));
+                    };
+                    for (attribute, value) in attributes {
+                        self.set(attribute, Some(value))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Member ids named in a `members` value.
+fn member_ids(value: Option<&Value>) -> Result<Vec<i64>, ScimError> {
+    let Some(members) = value.filter(|v| !v.is_null()) else {
+        return Ok(Vec::new());
+    };
+    let members = members
+        .as_array()
+        .ok_or_else(|| ScimError::InvalidValue("members must be an array".to_string()))?;
+    members
+        .iter()
+        .map(|member| {
+            member
+                .get("value")
+                .and_then(Value::as_str)
+                .and_then(|id| id.trim().parse::<i64>().ok())
+                .ok_or_else(|| ScimError::InvalidValue("Invalid member reference".to_string()))
+        })
+        .collect()
+}
+
+/// Applies a PatchOp request to a group's member ids.
+pub fn patch_members(
+    role: &str,
+    members: &mut BTreeSet<i64>,
+    patch: &Value,
+) -> Result<(), ScimError> {
+    for operation in operations(patch)? {
+        let kind = operation_kind(operation)?;
+        let value = operation.get("value");
+        let path = operation.get("path").and_then(Value::as_str);
+        let path_lower = path.map(str::to_lowercase);
+        match (path_lower.as_deref(), kind.as_str()) {
+            (Some("members"), "add") => members.extend(member_ids(value)?),
+            (Some("members"), "replace") => *members = member_ids(value)?.into_iter().collect(),
+            (Some("members"), "remove") => match value {
+                Some(_) => {
+                    for id in member_ids(value)? {
+                        members.remove(&id);
+                    }
+                }
+                None => members.clear(),
+            },
+            (Some(path), "remove") if path.starts_with("members[") => {
+                let filter = path
+                    .strip_prefix("members[")
+                    .and_then(|f| f.strip_suffix(']'))
+                    .map(parse_filter)
+                    .transpose()?
+                    .filter(|f| f.attribute == "value")
+                    .ok_or_else(|| {
+                        ScimError::InvalidFilter(format!("Unsupported path: {}", path))
+                    })?;
+                if let Ok(id) = filter.value.parse::<i64>() {
+                    members.remove(&id);
+                }
+            }
+            (None, "add" | "replace") => {
+                let Some(Value::Object(attributes)) = value else {
+                    return Err(ScimError::InvalidValue(
+                        "Operations without a path need an object value".to_string(),
+                    ));
+                };
+                for (attribute, value) in attributes {
+                    match attribute.to_lowercase().as_str() {
+                        "members" if kind == "add" => members.extend(member_ids(Some(value))?),
+                        "members" => *members = member_ids(Some(value))?.into_iter().collect(),
+                        "displayname" => check_display_name(role, value)?,
+                        _ => {}
+                    }
+                }
+            }
+            (Some("displayname"), "add" | "replace") => {
+                check_display_name(role, value.unwrap_or(&Value::Null))?
+            }
+            _ => {
+                return Err(ScimError::InvalidSyntax(format!(
+                    "Unsupported group operation: {kind} {}",
+                    path.unwrap_or_default()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Groups are roles, which cannot be renamed through SCIM.
+fn check_display_name(role: &str, value: &Value) -> Result<(), ScimError> {
+    if value.as_str() == Some(role) {
+        Ok(())
+    } else {
+        Err(ScimError::Mutability(
+            "Groups are roles and cannot be renamed".to_string(),
+        ))
+    }
+}
+
+/// The member ids of a complete Group resource, as sent to replace a group.
+pub fn group_members_from_resource(
+    role: &str,
+    resource: &Value,
+) -> Result<BTreeSet<i64>, ScimError> {
+    if let Some(display_name) = resource.get("displayName") {
+        check_display_name(role, display_name)?;
+    }
+    Ok(member_ids(resource.get("members"))?.into_iter().collect())
+}
+
+// =============================
+// Users
+// =============================
+
+fn user_filter_condition(filter: &Filter) -> Result<&'static str, ScimError> {
+    match filter.attribute.as_str() {
+        "username" => Ok("(username = ?2 OR lower(email) = lower(?2))"),
+        "externalid" => Ok("external_id = ?2"),
+        "emails" | "emails.value" => Ok("lower(email) = lower(?2)"),
+        _ => Err(ScimError::InvalidFilter(format!(
+            "Filtering users by {} is not supported",
+            filter.attribute
+        ))),
+    }
+}
+
+#[derive(FromD1Row)]
+struct TotalRow {
+    total: i64,
+}
+
+/// A page of members; `start_index` is 1-based.
+pub async fn list_users(
+    db: &dyn Database,
+    organization_id: i64,
+    filter: Option<&Filter>,
+    start_index: i64,
+    count: i64,
+) -> Result<(i64, Vec<ScimUserRow>), ScimError> {
+    let condition = filter
+        .map(user_filter_condition)
+        .transpose()?
+        .unwrap_or("?2 IS NULL");
+    let filter_value = filter
+        .map(|f| D1Param::Text(f.value.clone()))
+        .unwrap_or(D1Param::Null);
+
+    let total = d1_query_one::<TotalRow>(
+        db,
+        &format!(
+            "SELECT COUNT(*) AS total
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             WHERE m.organization_id = ?1 AND {condition}"
+        ),
+        &[D1Param::Integer(organization_id), filter_value.clone()],
+    )
+    .await?
+    .map(|row| row.total)
+    .unwrap_or(0);
+    let users = d1_query_all::<ScimUserRow>(
+        db,
+        &format!(
+            "SELECT {USER_COLUMNS}
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             WHERE m.organization_id = ?1 AND {condition}
+             ORDER BY u.id ASC
+             LIMIT ?3 OFFSET ?4"
+        ),
+        &[
+            D1Param::Integer(organization_id),
+            filter_value,
+            D1Param::Integer(count),
+            D1Param::Integer(start_index.max(1) - 1),
+        ],
+    )
+    .await?;
+    Ok((total, users))
+}
+
+pub async fn get_user(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<ScimUserRow, ScimError> {
+    d1_query_one::<ScimUserRow>(
+        db,
+        &format!(
+            "SELECT {USER_COLUMNS}
+             FROM users u
+             JOIN memberships m ON m.user_id = u.id
+             WHERE m.organization_id = ?1 AND u.id = ?2
+             LIMIT 1"
+        ),
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?
+    .ok_or_else(|| ScimError::NotFound("User".to_string()))
+}
+
+#[derive(FromD1Row)]
+struct ConflictRow {
+    field: String,
+}
+
+/// Fails if another member already uses the username, email or external id.
+async fn ensure_unique(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: Option<i64>,
+    username: &str,
+    attributes: &UserAttributes,
+) -> Result<(), ScimError> {
+    let conflict = d1_query_one::<ConflictRow>(
+        db,
+        "SELECT CASE
+                    WHEN username = ?3 THEN 'userName'
+                    WHEN ?5 IS NOT NULL AND external_id = ?5 THEN 'externalId'
+                    ELSE 'emails'
+                END AS field
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND (?2 IS NULL OR u.id != ?2)
+           AND (username = ?3
+                OR (?4 IS NOT NULL AND lower(email) = lower(?4))
+                OR (?5 IS NOT NULL AND external_id = ?5))
+         LIMIT 1",
+        &[
+            D1Param::Integer(organization_id),
+            user_id.map(D1Param::Integer).unwrap_or(D1Param::Null),
+            D1Param::Text(username.to_string()),
+            attributes
+                .email
+                .clone()
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+            attributes
+                .external_id
+                .clone()
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+        ],
+    )
+    .await?;
+    match conflict {
+        Some(row) => Err(ScimError::Uniqueness(format!(
+            "Another member already has this {}",
+            row.field
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Validates the attributes and fills in the email from an email-shaped
+/// user name.
+fn validate_attributes(attributes: &mut UserAttributes) -> Result<(), ScimError> {
+    let email_user_name = !is_valid_username(&attributes.user_name);
+    if email_user_name && !is_valid_email(&attributes.user_name) {
+        return Err(ScimError::InvalidValue(
+            "userName must be a username (3-30 alphanumeric characters, underscores or hyphens) or an email address"
+                .to_string(),
+        ));
+    }
+    if email_user_name && attributes.email.is_none() {
+        attributes.email = Some(attributes.user_name.clone());
+    }
+    attributes.display_name = attributes.display_name.trim().to_string();
+    if !is_valid_display_name(&attributes.display_name) {
+        return Err(ScimError::InvalidValue(
+            "displayName must be 1-50 characters".to_string(),
+        ));
+    }
+    if attributes
+        .email
+        .as_deref()
+        .is_some_and(|email| !is_valid_email(email))
+    {
+        return Err(ScimError::InvalidValue("Invalid email address".to_string()));
+    }
+    Ok(())
+}
+
+/// Creates a member with the `user` role. The returned set-password link is
+/// only worth sending when the member is active.
+pub async fn create_user(
+    db: &dyn Database,
+    organization_id: i64,
+    mut attributes: UserAttributes,
+) -> Result<(ScimUserRow, ProvisionedMember), ScimError> {
+    validate_attributes(&mut attributes)?;
+    let username = if is_valid_username(&attributes.user_name) {
+        attributes.user_name.clone()
+    } else {
+        provisioning::available_username(db, organization_id, &attributes.user_name).await?
+    };
+    ensure_unique(db, organization_id, None, &username, &attributes).await?;
+
+    let provisioned = provisioning::create_members(
+        db,
+        organization_id,
+        &[NewMember {
+            name: attributes.display_name.clone(),
+            username,
+            email: attributes.email.clone(),
+            role: USER_ROLE.to_string(),
+            external_id: attributes.external_id.clone(),
+        }],
+    )
+    .await?
+    .pop()
+    .ok_or_else(|| ModelError::Database("provisioned user missing".to_string()))?;
+    if !attributes.active {
+        set_active(db, organization_id, provisioned.user_id, false).await?;
+    }
+
+    let user = get_user(db, organization_id, provisioned.user_id).await?;
+    Ok((user, provisioned))
+}
+
+/// Replaces a member's attributes; returns the member and whether they were
+/// activated or deactivated.
+pub async fn update_user(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+    mut attributes: UserAttributes,
+) -> Result<(ScimUserRow, Option<bool>), ScimError> {
+    let current = get_user(db, organization_id, user_id).await?;
+    validate_attributes(&mut attributes)?;
+    // An email-shaped user name identifies the member by email instead.
+    let username = if is_valid_username(&attributes.user_name) {
+        attributes.user_name.clone()
+    } else {
+        current.username.clone()
+    };
+    ensure_unique(db, organization_id, Some(user_id), &username, &attributes).await?;
+
+    d1_execute(
+        db,
+        "UPDATE users SET name = ?3, username = ?4, email = ?5, external_id = ?6
+         WHERE id = ?2 AND id IN (SELECT user_id FROM memberships WHERE organization_id = ?1)",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Integer(user_id),
+            D1Param::Text(attributes.display_name.clone()),
+            D1Param::Text(username),
+            attributes
+                .email
+                .clone()
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+            attributes
+                .external_id
+                .clone()
+                .map(D1Param::Text)
+                .unwrap_or(D1Param::Null),
+        ],
+    )
+    .await?;
+
+    let was_active = current.deactivated_at.is_none();
+    let activation = (was_active != attributes.active).then_some(attributes.active);
+    if let Some(active) = activation {
+        set_active(db, organization_id, user_id, active).await?;
+    }
+    Ok((get_user(db, organization_id, user_id).await?, activation))
+}
+
+/// Applies a PatchOp request to a member.
+pub async fn patch_user(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+    patch: &Value,
+) -> Result<(ScimUserRow, Option<bool>), ScimError> {
+    let mut attributes = UserAttributes::from_row(&get_user(db, organization_id, user_id).await?);
+    attributes.apply_patch(patch)?;
+    update_user(db, organization_id, user_id, attributes).await
+}
+
+#[derive(FromD1Row)]
+struct AdminRow {
+    id: i64,
+}
+
+/// Fails if the members in `leaving` are all of the organization's active
+/// admins.
+async fn ensure_admin_remains(
+    db: &dyn Database,
+    organization_id: i64,
+    leaving: &BTreeSet<i64>,
+) -> Result<(), ScimError> {
+    let admins = d1_query_all::<AdminRow>(
+        db,
+        "SELECT user_id AS id FROM memberships
+         WHERE organization_id = ?1 AND role = ?2 AND deactivated_at IS NULL",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(ADMIN_ROLE.to_string()),
+        ],
+    )
+    .await?;
+    if !admins.is_empty() && admins.iter().all(|admin| leaving.contains(&admin.id)) {
+        return Err(ScimError::Conflict(
+            "The organization's last active admin cannot be removed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Deactivates (signing the member out everywhere) or reactivates a member.
+pub async fn set_active(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+    active: bool,
+) -> Result<(), ScimError> {
+    if active {
+        let reactivated = d1_execute(
+            db,
+            "UPDATE memberships SET deactivated_at = NULL
+             WHERE organization_id = ?1 AND user_id = ?2
+               AND user_id NOT IN (SELECT id FROM users WHERE deleted_at IS NOT NULL)",
+            &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+        )
+        .await?;
+        if reactivated.changes == 0 {
+            return Err(ScimError::Conflict(
+                "This account was deleted and cannot be reactivated".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    ensure_admin_remains(db, organization_id, &BTreeSet::from([user_id])).await?;
+    d1_batch(
+        db,
+        &[
+            D1Statement::new(
+                "UPDATE memberships SET deactivated_at = CURRENT_TIMESTAMP
+                 WHERE organization_id = ?1 AND user_id = ?2 AND deactivated_at IS NULL",
+                vec![D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+            ),
+            D1Statement::new(
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+                 WHERE organization_id = ?1 AND user_id = ?2 AND revoked_at IS NULL",
+                vec![D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+            ),
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+// =============================
+// Groups
+// =============================
+
+/// The organization's roles, optionally filtered by `displayName`.
+pub async fn list_groups(
+    db: &dyn Database,
+    organization_id: i64,
+    filter: Option<&Filter>,
+) -> Result<Vec<String>, ScimError> {
+    if let Some(filter) = filter
+        && filter.attribute != "displayname"
+    {
+        return Err(ScimError::InvalidFilter(format!(
+            "Filtering groups by {} is not supported",
+            filter.attribute
+        )));
+    }
+    Ok(permissions::list_roles(db, organization_id)
+        .await?
+        .into_iter()
+        .map(|role| role.name)
+        .filter(|name| filter.is_none_or(|f| *name == f.value))
+        .collect())
+}
+
+pub async fn group_members(
+    db: &dyn Database,
+    organization_id: i64,
+    role: &str,
+) -> Result<Vec<GroupMemberRow>, ScimError> {
+    if !permissions::role_exists(db, organization_id, role).await? {
+        return Err(ScimError::NotFound("Group".to_string()));
+    }
+    Ok(d1_query_all::<GroupMemberRow>(
+        db,
+        "SELECT u.id, u.name
+         FROM users u
+         JOIN memberships m ON m.user_id = u.id
+         WHERE m.organization_id = ?1 AND m.role = ?2
+         ORDER BY u.id ASC",
+        &[
+            D1Param::Integer(organization_id),
+            D1Param::Text(role.to_string()),
+        ],
+    )
+    .await?)
+}
+
+#[derive(FromD1Row)]
+struct MemberRoleRow {
+    id: i64,
+    role: String,
+}
+
+/// Moves members into or out of a role so that exactly `members` hold it;
+/// members leaving the role get `user`. Returns how many members changed.
+pub async fn set_group_members(
+    db: &dyn Database,
+    organization_id: i64,
+    role: &str,
+    members: &BTreeSet<i64>,
+) -> Result<usize, ScimError> {
+    let current: BTreeSet<i64> = group_members(db, organization_id, role)
+        .await?
+        .into_iter()
+        .map(|member| member.id)
+        .collect();
+    let added: Vec<i64> = members.difference(&current).copied().collect();
+    // Everyone holds some role, so nobody can leave the default one.
+    let removed: Vec<i64> = if role == USER_ROLE {
+        Vec::new()
+    } else {
+        current.difference(members).copied().collect()
+    };
+
+    let joining = d1_query_all::<MemberRoleRow>(
+        db,
+        "SELECT user_id AS id, role FROM memberships WHERE organization_id = ?1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await?
+    .into_iter()
+    .filter(|member| added.contains(&member.id))
+    .collect::<Vec<_>>();
+    if let Some(unknown) = added
+        .iter()
+        .find(|id| !joining.iter().any(|member| member.id == **id))
+    {
+        return Err(ScimError::InvalidValue(format!(
+            "Unknown member: {unknown}"
+        )));
+    }
+
+    let leaving_admin: BTreeSet<i64> = if role == ADMIN_ROLE {
+        removed.iter().copied().collect()
+    } else {
+        joining
+            .iter()
+            .filter(|member| member.role == ADMIN_ROLE)
+            .map(|member| member.id)
+            .collect()
+    };
+    if !leaving_admin.is_empty() {
+        ensure_admin_remains(db, organization_id, &leaving_admin).await?;
+    }
+
+    let statements: Vec<D1Statement> = added
+        .iter()
+        .map(|id| (*id, role))
+        .chain(removed.iter().map(|id| (*id, USER_ROLE)))
+        .map(|(id, role)| {
+            D1Statement::new(
+                "UPDATE memberships SET role = ?3 WHERE organization_id = ?1 AND user_id = ?2",
+                vec![
+                    D1Param::Integer(organization_id),
+                    D1Param::Integer(id),
+                    D1Param::Text(role.to_string()),
+                ],
+            )
+        })
+        .collect();
+    if !statements.is_empty() {
+        d1_batch(db, &statements).await?;
+    }
+    Ok(statements.len())
+}
+
+/// Starts a group's membership for a patch from its current members.
+pub async fn current_group_member_ids(
+    db: &dyn Database,
+    organization_id: i64,
+    role: &str,
+) -> Result<BTreeSet<i64>, ScimError> {
+    Ok(group_members(db, organization_id, role)
+        .await?
+        .into_iter()
+        .map(|member| member.id)
+        .collect())
+}
+
+/// Parses the optional `count` and 1-based `startIndex` query parameters.
+pub fn page_params(start_index: Option<&str>, count: Option<&str>) -> (i64, i64) {
+    let start_index = start_index
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let count = count
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(0, MAX_PAGE_SIZE);
+    (start_index, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Filter, ScimError, UserAttributes, group_members_from_resource, page_params, parse_filter,
+        patch_members,
+    };
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn parses_equality_filters() {
+        assert_eq!(
+            parse_filter(r#"userName eq "jane@acme.example""#).expect("valid"),
+            Filter {
+                attribute: "username".to_string(),
+                value: "jane@acme.example".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_filter(r#"externalId EQ "a \"b\"""#)
+                .expect("valid")
+                .value,
+            r#"a "b""#
+        );
+        assert!(matches!(
+            parse_filter(r#"userName sw "j""#),
+            Err(ScimError::InvalidFilter(_))
+        ));
+        assert!(parse_filter("userName eq jane").is_err());
+        assert_eq!(page_params(Some("0"), Some("1000")), (1, 200));
+        assert_eq!(page_params(None, None), (1, 100));
+    }
+
+    #[test]
+    fn reads_user_resources_and_patches() {
+        let mut user = UserAttributes::from_resource(&json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+            "userName": "jane@acme.example",
+            "externalId": "00u1",
+            "name": { "givenName": "Jane", "familyName": "Doe" },
+            "emails": [
+                { "value": "other@acme.example" },
+                { "value": "jane@acme.example", "primary": true }
+            ]
+        }))
+        .expect("valid");
+        assert_eq!(user.display_name, "Jane Doe");
+        assert_eq!(user.email.as_deref(), Some("jane@acme.example"));
+        assert!(user.active);
+        assert!(UserAttributes::from_resource(&json!({ "displayName": "x" })).is_err());
+
+        user.apply_patch(&json!({
+            "Operations": [
+                { "op": "Replace", "path": "active", "value": "False" },
+                { "op": "replace", "value": { "displayName": "Jane D.", "title": "CTO" } },
+                { "op": "replace", "path": "emails[type eq \"work\"].value", "value": "jd@acme.example" },
+                { "op": "remove", "path": "externalId" }
+            ]
+        }))
+        .expect("patch");
+        assert!(!user.active);
+        assert_eq!(user.display_name, "Jane D.");
+        assert_eq!(user.email.as_deref(), Some("jd@acme.example"));
+        assert_eq!(user.external_id, None);
+        assert!(
+            user.apply_patch(&json!({ "Operations": [{ "op": "remove", "path": "userName" }] }))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn patches_group_members() {
+        let mut members = BTreeSet::from([1, 2]);
+        patch_members(
+            "manager",
+            &mut members,
+            &json!({
+                "Operations": [
+                    { "op": "add", "path": "members", "value": [{ "value": "3" }] },
+                    { "op": "remove", "path": "members[value eq \"1\"]" }
+                ]
+            }),
+        )
+        .expect("patch");
+        assert_eq!(members, BTreeSet::from([2, 3]));
+
+        patch_members(
+            "manager",
+            &mut members,
+            &json!({ "Operations": [{ "op": "replace", "value": { "displayName": "manager", "members": [] } }] }),
+        )
+        .expect("replace");
+        assert!(members.is_empty());
+        assert!(matches!(
+            patch_members(
+                "manager",
+                &mut members,
+                &json!({ "Operations": [{ "op": "replace", "path": "displayName", "value": "leads" }] }),
+            ),
+            Err(ScimError::Mutability(_))
+        ));
+        assert_eq!(
+            group_members_from_resource("manager", &json!({ "members": [{ "value": "7" }] }))
+                .expect("valid"),
+            BTreeSet::from([7])
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Per-organization settings.
+//!
+//! Defaults that used to be constants in the handlers (the first day of the
+//! week, the status of new tasks, the statuses the task report shows, which
+//! statuses count as finished, how long invitations last and which passwords
+//! are accepted) are stored on the organization and read through [`load`].
+
+use crate::db::Database;
+use crate::models::{D1Param, ModelError, OrganizationSettings, PasswordPolicy, d1_query_one};
+use chrono::Weekday;
+
+pub const TASK_STATUSES: &[&str] = &["todo", "doing", "done"];
+
+/// Valid values of `week_start_day`, in `chrono`'s order.
+pub const WEEKDAYS: &[&str] = &[
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Upper bound for both the organization default and `expires_in_days` on a
+/// single invitation.
+pub const MAX_INVITATION_EXPIRATION_DAYS: i64 = 30;
+
+/// Organizations can raise the minimum password length but not lower it.
+pub const MIN_PASSWORD_LENGTH: i64 = 8;
+pub const MAX_PASSWORD_LENGTH: i64 = 128;
+
+pub async fn load(
+    db: &dyn Database,
+    organization_id: i64,
+) -> Result<Option<OrganizationSettings>, ModelError> {
+    d1_query_one::<OrganizationSettings>(
+        db,
+        "SELECT name, slug, timezone, week_start_day, default_task_status, report_statuses,
+                closed_task_statuses, invitation_expiration_days,
+                password_min_length AS min_length,
+                password_require_uppercase AS require_uppercase,
+                password_require_lowercase AS require_lowercase,
+                password_require_digit AS require_digit,
+                password_require_symbol AS require_symbol
+         FROM organizations
+         WHERE id = ?1
+         LIMIT 1",
+        &[D1Param::Integer(organization_id)],
+    )
+    .await
+}
+
+/// The organization's password policy, or the default one if it is gone.
+pub async fn password_policy(
+    db: &dyn Database,
+    organization_id: i64,
+) -> Result<PasswordPolicy, ModelError> {
+    Ok(load(db, organization_id)
+        .await?
+        .map(|settings| settings.password_policy)
+        .unwrap_or_default())
+}
+
+pub fn parse_weekday(name: &str) -> Option<Weekday> {
+    WEEKDAYS
+        .iter()
+        .position(|day| *day == name)
+        .and_then(|index| Weekday::try_from(index as u8).ok())
+}
+
+pub fn is_task_status(status: &str) -> bool {
+    TASK_STATUSES.contains(&status)
+}
+
+impl OrganizationSettings {
+    pub fn week_start(&self) -> Weekday {
+        parse_weekday(&self.week_start_day).unwrap_or(Weekday::Mon)
+    }
+
+    /// `closed_task_statuses` as one JSON array parameter, for queries that
+    /// test `status IN (SELECT value FROM json_each(?))`.
+    pub fn closed_statuses_param(&self) -> D1Param {
+        D1Param::Text(serde_json::Value::from(self.closed_task_statuses.clone()).to_string())
+    }
+}
+
+impl Default for PasswordPolicy {
+    /// The policy organizations start with.
+    fn default() -> Self {
+        Self {
+            min_length: MIN_PASSWORD_LENGTH,
+            require_uppercase: 1,
+            require_lowercase: 1,
+            require_digit: 1,
+            require_symbol: 1,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn allows(&self, password: &str) -> bool {
+        let has = |class: fn(&char) -> bool| password.chars().any(|c| class(&c));
+        password.chars().count() as i64 >= self.min_length
+            && (self.require_uppercase == 0 || has(char::is_ascii_uppercase))
+            && (self.require_lowercase == 0 || has(char::is_ascii_lowercase))
+            && (self.require_digit == 0 || has(char::is_ascii_digit))
+            && (self.require_symbol == 0 || has(char::is_ascii_punctuation))
+    }
+
+    /// The character classes a password must contain, as English nouns.
+    pub fn required_classes(&self) -> Vec<&'static str> {
+        [
+            (self.require_uppercase, "uppercase"),
+            (self.require_lowercase, "lowercase"),
+            (self.require_digit, "number"),
+            (self.require_symbol, "symbol"),
+        ]
+        .into_iter()
+        .filter(|(required, _)| *required == 1)
+        .map(|(_, class)| class)
+        .collect()
+    }
+
+    /// Explains the policy to someone whose password was rejected.
+    pub fn describe(&self) -> String {
+        let mut message = format!("Password must be at least {} characters", self.min_length);
+        match self.required_classes().as_slice() {
+            [] => {}
+            [only] => message.push_str(&format!(" and include {only}")),
+            [first, second] => message.push_str(&format!(" and include {first} and {second}")),
+            [rest @ .., last] => {
+                message.push_str(&format!(" and include {}, and {last}", rest.join(", ")))
+            }
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_weekday;
+    use crate::models::PasswordPolicy;
+    use chrono::Weekday;
+
+    #[test]
+    fn policy_checks_length_and_required_classes() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.allows("Abcd1234!"));
+        assert!(!policy.allows("abcd1234!"));
+        assert_eq!(
+            policy.describe(),
+            "Password must be at least 8 characters and include uppercase, lowercase, number, and symbol"
+        );
+
+        let relaxed = PasswordPolicy {
+            min_length: 12,
+            require_uppercase: 0,
+            require_lowercase: 0,
+            require_digit: 1,
+            require_symbol: 0,
+        };
+        assert!(relaxed.allows("correct horse 1"));
+        assert!(!relaxed.allows("correct horse"));
+        assert!(!relaxed.allows("short 1"));
+        assert_eq!(
+            relaxed.describe(),
+            "Password must be at least 12 characters and include number"
+        );
+    }
+
+    #[test]
+    fn weekdays_parse_from_lowercase_names() {
+        assert_eq!(parse_weekday("monday"), Some(Weekday::Mon));
+        assert_eq!(parse_weekday("sunday"), Some(Weekday::Sun));
+        assert_eq!(parse_weekday("Sunday"), None);
+        assert_eq!(parse_weekday("sun"), None);
+    }
+}
@@ -0,0 +1,404 @@
+//! Failed-attempt tracking with exponential lockout for unauthenticated
+//! endpoints (login, password reset requests, token lookups).
+//!
+//! Counters live behind [`AttemptStore`] so they can be kept in D1 or a
+//! Durable Object; [`MemoryAttemptStore`] backs tests. Every function takes
+//! the Unix time explicitly.
+
+use crate::db::Database;
+use crate::models::{D1Param, FromD1Row, ModelError, d1_execute, d1_query_all, d1_query_one};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Counter state for one key, e.g. `login:account:alice` or `login:ip:203.0.113.7`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromD1Row)]
+pub struct AttemptState {
+    pub failures: i64,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+#[async_trait(?Send)]
+pub trait AttemptStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, ModelError>;
+
+    /// Adds one failure and returns the new count. Counts whose last failure
+    /// is older than `reset_before` start over from one.
+    async fn increment(&self, key: &str, now: i64, reset_before: i64) -> Result<i64, ModelError>;
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), ModelError>;
+
+    async fn clear(&self, key: &str) -> Result<(), ModelError>;
+}
+
+#[async_trait(?Send)]
+impl<T: AttemptStore + ?Sized> AttemptStore for Arc<T> {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, ModelError> {
+        (**self).get(key).await
+    }
+
+    async fn increment(&self, key: &str, now: i64, reset_before: i64) -> Result<i64, ModelError> {
+        (**self).increment(key, now, reset_before).await
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), ModelError> {
+        (**self).lock(key, until).await
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), ModelError> {
+        (**self).clear(key).await
+    }
+}
+
+/// How many failures are tolerated and how long the resulting lockouts last.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    /// Failures allowed before the first lockout.
+    pub free_attempts: i64,
+    /// Length of the first lockout; each further failure doubles it.
+    pub base_lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    /// Quiet period after which the failure count starts over.
+    pub reset_after_seconds: i64,
+}
+
+impl LockoutPolicy {
+    /// Lockout triggered by the `failures`-th consecutive failure, if any.
+    pub fn lockout_seconds(&self, failures: i64) -> Option<i64> {
+        let over = failures - self.free_attempts;
+        if over <= 0 {
+            return None;
+        }
+        let doublings = (over - 1).min(32) as u32;
+        Some(
+            self.base_lockout_seconds
+                .saturating_mul(1_i64 << doublings)
+                .min(self.max_lockout_seconds),
+        )
+    }
+}
+
+/// Per-account limit: a handful of typos, then 30 s doubling up to an hour.
+pub const ACCOUNT_POLICY: LockoutPolicy = LockoutPolicy {
+    free_attempts: 5,
+    base_lockout_seconds: 30,
+    max_lockout_seconds: 60 * 60,
+    reset_after_seconds: 24 * 60 * 60,
+};
+
+/// Per-IP limit; looser because offices and mobile carriers share addresses.
+pub const IP_POLICY: LockoutPolicy = LockoutPolicy {
+    free_attempts: 20,
+    base_lockout_seconds: 60,
+    max_lockout_seconds: 60 * 60,
+    reset_after_seconds: 24 * 60 * 60,
+};
+
+/// Seconds until `key` may try again, or `None` if it is not locked.
+pub async fn retry_after(
+    store: &dyn AttemptStore,
+    key: &str,
+    now: i64,
+) -> Result<Option<i64>, ModelError> {
+    Ok(store
+        .get(key)
+        .await?
+        .and_then(|state| state.locked_until)
+        .filter(|until| *until > now)
+        .map(|until| until - now))
+}
+
+/// Records a failed attempt and returns the lockout it triggered, if any.
+pub async fn record_failure(
+    store: &dyn AttemptStore,
+    key: &str,
+    policy: &LockoutPolicy,
+    now: i64,
+) -> Result<Option<i64>, ModelError> {
+    let failures = store
+        .increment(key, now, now - policy.reset_after_seconds)
+        .await?;
+    let lockout = policy.lockout_seconds(failures);
+    if let Some(seconds) = lockout {
+        store.lock(key, now + seconds).await?;
+    }
+    Ok(lockout)
+}
+
+pub async fn clear(store: &dyn AttemptStore, key: &str) -> Result<(), ModelError> {
+    store.clear(key).await
+}
+
+/// The account and client-IP keys charged together for one request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttemptKeys {
+    pub account: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl AttemptKeys {
+    /// Builds keys under `scope` (e.g. `login`); account identifiers are
+    /// compared case-insensitively.
+    pub fn new(scope: &str, account: Option<&str>, ip: Option<&str>) -> Self {
+        fn present(value: Option<&str>) -> Option<&str> {
+            value.map(str::trim).filter(|v| !v.is_empty())
+        }
+        Self {
+            account: present(account)
+                .map(|account| format!("{scope}:account:{}", account.to_lowercase())),
+            ip: present(ip).map(|ip| format!("{scope}:ip:{ip}")),
+        }
+    }
+
+    /// Seconds until every key is unlocked, or `None` if none is locked.
+    pub async fn retry_after(
+        &self,
+        store: &dyn AttemptStore,
+        now: i64,
+    ) -> Result<Option<i64>, ModelError> {
+        let mut longest = None;
+        for key in self.account.iter().chain(self.ip.iter()) {
+            longest = longest.max(retry_after(store, key, now).await?);
+        }
+        Ok(longest)
+    }
+
+    /// Charges a failure to both keys and returns the account lockout it
+    /// triggered, if any.
+    pub async fn record_failure(
+        &self,
+        store: &dyn AttemptStore,
+        now: i64,
+    ) -> Result<Option<i64>, ModelError> {
+        if let Some(ip) = &self.ip {
+            record_failure(store, ip, &IP_POLICY, now).await?;
+        }
+        match &self.account {
+            Some(account) => record_failure(store, account, &ACCOUNT_POLICY, now).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Forgets the account's failures after a success. The IP counter is
+    /// kept so one valid login cannot reset an attacker's budget.
+    pub async fn clear_account(&self, store: &dyn AttemptStore) -> Result<(), ModelError> {
+        match &self.account {
+            Some(account) => clear(store, account).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counters stored in the `auth_throttle` table.
+pub struct D1AttemptStore {
+    db: Arc<dyn Database>,
+}
+
+impl D1AttemptStore {
+    pub fn new(db: Arc<dyn Database>) -> Self {
+        Self { db }
+    }
+}
+
+#[derive(FromD1Row)]
+struct FailuresRow {
+    failures: i64,
+}
+
+#[async_trait(?Send)]
+impl AttemptStore for D1AttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, ModelError> {
+        d1_query_one::<AttemptState>(
+            &self.db,
+            "SELECT failures, last_failure_at, locked_until FROM auth_throttle WHERE key = ?1",
+            &[D1Param::Text(key.to_string())],
+        )
+        .await
+    }
+
+    async fn increment(&self, key: &str, now: i64, reset_before: i64) -> Result<i64, ModelError> {
+        // A single upsert, so concurrent failures are never lost.
+        d1_query_all::<FailuresRow>(
+            &self.db,
+            "INSERT INTO auth_throttle (key, failures, last_failure_at) VALUES (?1, 1, ?2)
+             ON CONFLICT(key) DO UPDATE SET
+                 failures = CASE WHEN last_failure_at < ?3 THEN 1 ELSE failures + 1 END,
+                 last_failure_at = ?2
+             RETURNING failures",
+            &[
+                D1Param::Text(key.to_string()),
+                D1Param::Integer(now),
+                D1Param::Integer(reset_before),
+            ],
+        )
+        .await?
+        .first()
+        .map(|row| row.failures)
+        .ok_or_else(|| ModelError::Database("auth_throttle upsert returned no row".to_string()))
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), ModelError> {
+        d1_execute(
+            &self.db,
+            "UPDATE auth_throttle SET locked_until = ?2 WHERE key = ?1",
+            &[D1Param::Text(key.to_string()), D1Param::Integer(until)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), ModelError> {
+        d1_execute(
+            &self.db,
+            "DELETE FROM auth_throttle WHERE key = ?1",
+            &[D1Param::Text(key.to_string())],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Process-local counters for tests and single-instance development.
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    entries: Mutex<HashMap<String, AttemptState>>,
+}
+
+impl MemoryAttemptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> Result<MutexGuard<'_, HashMap<String, AttemptState>>, ModelError> {
+        self.entries
+            .lock()
+            .map_err(|_| ModelError::Database("attempt store mutex poisoned".to_string()))
+    }
+}
+
+#[async_trait(?Send)]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, ModelError> {
+        Ok(self.entries()?.get(key).copied())
+    }
+
+    async fn increment(&self, key: &str, now: i64, reset_before: i64) -> Result<i64, ModelError> {
+        let mut entries = self.entries()?;
+        let state = entries.entry(key.to_string()).or_default();
+        state.failures = if state.last_failure_at < reset_before {
+            1
+        } else {
+            state.failures + 1
+        };
+        state.last_failure_at = now;
+        Ok(state.failures)
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), ModelError> {
+        if let Some(state) = self.entries()?.get_mut(key) {
+            state.locked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), ModelError> {
+        self.entries()?.remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ACCOUNT_POLICY, AttemptKeys, AttemptStore, MemoryAttemptStore, clear, record_failure,
+        retry_after,
+    };
+    use futures::executor::block_on;
+
+    #[test]
+    fn lockouts_double_up_to_the_cap() {
+        let policy = ACCOUNT_POLICY;
+        assert_eq!(policy.lockout_seconds(5), None);
+        assert_eq!(policy.lockout_seconds(6), Some(30));
+        assert_eq!(policy.lockout_seconds(7), Some(60));
+        assert_eq!(policy.lockout_seconds(9), Some(240));
+        assert_eq!(policy.lockout_seconds(100), Some(3600));
+    }
+
+    #[test]
+    fn memory_store_locks_and_clears() {
+        let store = MemoryAttemptStore::new();
+        let key = "login:account:alice";
+        let now = 1_700_000_000;
+
+        for i in 0..5 {
+            let lockout = block_on(record_failure(&store, key, &ACCOUNT_POLICY, now + i));
+            assert_eq!(lockout.expect("record"), None);
+        }
+        assert_eq!(
+            block_on(record_failure(&store, key, &ACCOUNT_POLICY, now + 5)).expect("record"),
+            Some(30)
+        );
+        assert_eq!(
+            block_on(retry_after(&store, key, now + 15)).expect("check"),
+            Some(20)
+        );
+        assert_eq!(
+            block_on(retry_after(&store, key, now + 35)).expect("check"),
+            None
+        );
+
+        // Failing again right after the lockout escalates it.
+        assert_eq!(
+            block_on(record_failure(&store, key, &ACCOUNT_POLICY, now + 40)).expect("record"),
+            Some(60)
+        );
+
+        // A day without failures starts the count over.
+        let later = now + 40 + ACCOUNT_POLICY.reset_after_seconds + 1;
+        assert_eq!(
+            block_on(record_failure(&store, key, &ACCOUNT_POLICY, later)).expect("record"),
+            None
+        );
+
+        block_on(clear(&store, key)).expect("clear");
+        assert_eq!(block_on(store.get(key)).expect("get"), None);
+    }
+
+    #[test]
+    fn attempt_keys_lock_the_account_before_the_ip() {
+        let store = MemoryAttemptStore::new();
+        let keys = AttemptKeys::new("login", Some(" Alice "), Some("203.0.113.7"));
+        assert_eq!(keys.account.as_deref(), Some("login:account:alice"));
+        let now = 1_700_000_000;
+
+        for _ in 0..5 {
+            assert_eq!(
+                block_on(keys.record_failure(&store, now)).expect("record"),
+                None
+            );
+        }
+        assert_eq!(
+            block_on(keys.record_failure(&store, now)).expect("record"),
+            Some(30)
+        );
+        assert_eq!(
+            block_on(keys.retry_after(&store, now + 10)).expect("check"),
+            Some(20)
+        );
+
+        // Another account from the same address is still allowed.
+        let other = AttemptKeys::new("login", Some("bob"), Some("203.0.113.7"));
+        assert_eq!(
+            block_on(other.retry_after(&store, now)).expect("check"),
+            None
+        );
+
+        block_on(keys.clear_account(&store)).expect("clear");
+        let ip_state = block_on(store.get("login:ip:203.0.113.7"))
+            .expect("get")
+            .expect("ip state");
+        assert_eq!(ip_state.failures, 6);
+    }
+}
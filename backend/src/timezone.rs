@@ -0,0 +1,165 @@
+//! Local days and weeks for date filters and statistics.
+//!
+//! Timestamps are stored in UTC and SQLite has no timezone database, so a
+//! local day is turned into the UTC range it covers here and queries compare
+//! `datetime(column)` against that range. The range follows the zone's rules
+//! for that date, so a day across a DST change is 23 or 25 hours long.
+
+use crate::db::Database;
+use crate::models::{D1Param, FromD1Row, ModelError, d1_query_one};
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Used by organizations created before timezones were configurable.
+pub const DEFAULT_TIMEZONE: Tz = chrono_tz::Asia::Tokyo;
+
+/// SQLite's `datetime()` format, which the bounds are compared in.
+const SQL_DATETIME: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(FromD1Row)]
+struct TimezoneRow {
+    timezone: Option<String>,
+}
+
+/// Falls back to [`DEFAULT_TIMEZONE`] for a missing or unknown name.
+pub fn parse(name: Option<&str>) -> Tz {
+    name.and_then(|name| name.parse::<Tz>().ok())
+        .unwrap_or(DEFAULT_TIMEZONE)
+}
+
+/// The member's own timezone if they set one, otherwise the organization's.
+pub async fn for_member(
+    db: &dyn Database,
+    organization_id: i64,
+    user_id: i64,
+) -> Result<Tz, ModelError> {
+    let row = d1_query_one::<TimezoneRow>(
+        db,
+        "SELECT COALESCE(NULLIF(u.timezone, ''), o.timezone) AS timezone
+         FROM organizations o
+         LEFT JOIN memberships m ON m.organization_id = o.id AND m.user_id = ?2
+         LEFT JOIN users u ON u.id = m.user_id
+         WHERE o.id = ?1
+         LIMIT 1",
+        &[D1Param::Integer(organization_id), D1Param::Integer(user_id)],
+    )
+    .await?;
+    Ok(parse(row.and_then(|row| row.timezone).as_deref()))
+}
+
+pub fn today(tz: Tz) -> NaiveDate {
+    Utc::now().with_timezone(&tz).date_naive()
+}
+
+/// Parses a `YYYY-MM-DD` query parameter.
+pub fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").ok()
+}
+
+/// The UTC instant `date` begins at in `tz`. Where a DST change skips
+/// midnight, the day begins at the first local time that exists.
+pub fn day_start(tz: Tz, date: NaiveDate) -> String {
+    let midnight = date.and_time(NaiveTime::MIN);
+    (0..=24 * 4)
+        .find_map(|quarter| {
+            tz.from_local_datetime(&(midnight + Duration::minutes(15 * quarter)))
+                .earliest()
+        })
+        .map(|start| start.with_timezone(&Utc).naive_utc())
+        .unwrap_or(midnight)
+        .format(SQL_DATETIME)
+        .to_string()
+}
+
+/// The half-open UTC range `[start, end)` covering the local days
+/// `first..=last`.
+pub fn days_range(tz: Tz, first: NaiveDate, last: NaiveDate) -> (String, String) {
+    (
+        day_start(tz, first),
+        day_start(tz, last + Duration::days(1)),
+    )
+}
+
+pub fn day_range(tz: Tz, date: NaiveDate) -> (String, String) {
+    days_range(tz, date, date)
+}
+
+/// The first day of the week containing `date`, for weeks starting on
+/// `first_day`.
+pub fn week_start(date: NaiveDate, first_day: Weekday) -> NaiveDate {
+    date - Duration::days(i64::from(date.weekday().days_since(first_day)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{day_range, day_start, parse, parse_date, week_start};
+    use chrono::{NaiveDate, Weekday};
+    use chrono_tz::Tz;
+
+    fn date(value: &str) -> NaiveDate {
+        parse_date(value).expect("valid date")
+    }
+
+    #[test]
+    fn days_follow_the_zone_offset() {
+        let tokyo: Tz = "Asia/Tokyo".parse().unwrap();
+        assert_eq!(
+            day_range(tokyo, date("2026-03-10")),
+            (
+                "2026-03-09 15:00:00".to_string(),
+                "2026-03-10 15:00:00".to_string()
+            )
+        );
+        assert_eq!(parse(Some("Mars/Olympus")), tokyo);
+        assert_eq!(parse(None), tokyo);
+        assert_eq!(parse_date("2026-02-30"), None);
+    }
+
+    #[test]
+    fn days_across_dst_changes_are_shorter_or_longer() {
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        // Clocks spring forward on 2026-03-08 and fall back on 2026-11-01.
+        assert_eq!(
+            day_range(new_york, date("2026-03-08")),
+            (
+                "2026-03-08 05:00:00".to_string(),
+                "2026-03-09 04:00:00".to_string()
+            )
+        );
+        assert_eq!(
+            day_range(new_york, date("2026-11-01")),
+            (
+                "2026-11-01 04:00:00".to_string(),
+                "2026-11-02 05:00:00".to_string()
+            )
+        );
+
+        // São Paulo skipped midnight itself on 2018-11-04.
+        let sao_paulo: Tz = "America/Sao_Paulo".parse().unwrap();
+        assert_eq!(
+            day_start(sao_paulo, date("2018-11-04")),
+            "2018-11-04 03:00:00"
+        );
+    }
+
+    #[test]
+    fn weeks_start_on_the_configured_day() {
+        // 2026-03-15 is a Sunday.
+        assert_eq!(
+            week_start(date("2026-03-15"), Weekday::Mon),
+            date("2026-03-09")
+        );
+        assert_eq!(
+            week_start(date("2026-03-09"), Weekday::Mon),
+            date("2026-03-09")
+        );
+        assert_eq!(
+            week_start(date("2026-03-15"), Weekday::Sun),
+            date("2026-03-15")
+        );
+        assert_eq!(
+            week_start(date("2026-03-14"), Weekday::Sun),
+            date("2026-03-08")
+        );
+    }
+}
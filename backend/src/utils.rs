@@ -1,6 +1,13 @@
+use crate::models::PasswordPolicy;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 use std::sync::LazyLock;
 
+static LOCALE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z]{2,3}(-[A-Z][a-z]{3})?(-([A-Z]{2}|[0-9]{3}))?$").unwrap());
+
 static EMAIL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r"^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$").unwrap()
 });
@@ -10,8 +17,7 @@ pub fn is_valid_email(email: &str) -> bool {
 }
 
 pub fn is_valid_username(username: &str) -> bool {
-    let len = username.len();
-    if len < 3 || len > 30 {
+    if !(3..=30).contains(&username.len()) {
         return false;
     }
     username
@@ -19,34 +25,90 @@ pub fn is_valid_username(username: &str) -> bool {
         .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
 
-pub fn is_secure_password(password: &str) -> bool {
-    if password.len() < 8 {
-        return false;
-    }
+/// Display names: 1-50 characters without control characters. Callers trim
+/// surrounding whitespace first.
+pub fn is_valid_display_name(name: &str) -> bool {
+    (1..=50).contains(&name.chars().count()) && !name.chars().any(char::is_control)
+}
+
+/// Avatar URLs must be absolute `https` URLs the browser can load directly.
+pub fn is_valid_avatar_url(url: &str) -> bool {
+    url.len() <= 2048
+        && url
+            .strip_prefix("https://")
+            .is_some_and(|rest| !rest.is_empty() && !rest.starts_with('/'))
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// BCP 47 language tags of the form `language[-Script][-REGION]`,
+/// e.g. `ja`, `en-US` or `zh-Hant-TW`.
+pub fn is_valid_locale(locale: &str) -> bool {
+    LOCALE_REGEX.is_match(locale)
+}
 
-    let mut has_upper = false;
-    let mut has_lower = false;
-    let mut has_digit = false;
-    let mut has_symbol = false;
-
-    for c in password.chars() {
-        if c.is_ascii_uppercase() {
-            has_upper = true;
-        } else if c.is_ascii_lowercase() {
-            has_lower = true;
-        } else if c.is_ascii_digit() {
-            has_digit = true;
-        } else if c.is_ascii_punctuation() {
-            has_symbol = true;
+/// IANA time zone names such as `Asia/Tokyo`.
+pub fn is_valid_timezone(timezone: &str) -> bool {
+    timezone.parse::<chrono_tz::Tz>().is_ok()
+}
+
+/// Organization slugs: 3-40 lowercase ASCII letters, digits and inner hyphens.
+pub fn is_valid_slug(slug: &str) -> bool {
+    (3..=40).contains(&slug.len())
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Derives a slug candidate from an organization name.
+///
+/// Returns `None` when the name has too few ASCII letters or digits to form a
+/// valid slug (e.g. names written in Japanese).
+pub fn slugify(name: &str) -> Option<String> {
+    let mut slug = String::new();
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= 40 {
+            break;
         }
     }
+    let slug = slug.trim_end_matches('-').to_string();
+    is_valid_slug(&slug).then_some(slug)
+}
+
+/// Whether `password` meets the default [`PasswordPolicy`]; organizations can
+/// change theirs, see [`crate::settings::password_policy`].
+pub fn is_secure_password(password: &str) -> bool {
+    PasswordPolicy::default().allows(password)
+}
 
-    has_upper && has_lower && has_digit && has_symbol
+/// Generates an opaque bearer secret (refresh tokens, API tokens).
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    bytes[..16].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    bytes[16..].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// SHA-256 hex digest used to store bearer secrets; only the hash is persisted.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{is_secure_password, is_valid_username};
+    use super::{
+        generate_token, hash_token, is_secure_password, is_valid_avatar_url, is_valid_display_name,
+        is_valid_locale, is_valid_slug, is_valid_timezone, is_valid_username, slugify,
+    };
 
     #[test]
     fn accepts_ascii_alphanumeric_and_allowed_symbols() {
@@ -113,4 +175,58 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn validates_organization_slugs() {
+        assert!(is_valid_slug("acme"));
+        assert!(is_valid_slug("acme-2024"));
+        assert!(!is_valid_slug("ac"));
+        assert!(!is_valid_slug("Acme"));
+        assert!(!is_valid_slug("-acme"));
+        assert!(!is_valid_slug("acme_inc"));
+    }
+
+    #[test]
+    fn slugifies_organization_names() {
+        assert_eq!(slugify("Acme, Inc."), Some("acme-inc".to_string()));
+        assert_eq!(slugify("  My  Team 2 "), Some("my-team-2".to_string()));
+        assert_eq!(slugify("株式会社テスト"), None);
+    }
+
+    #[test]
+    fn hashes_generated_tokens() {
+        let token = generate_token();
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn validates_profile_fields() {
+        assert!(is_valid_display_name("山田 太郎"));
+        assert!(!is_valid_display_name(""));
+        assert!(!is_valid_display_name("tab\tname"));
+        assert!(!is_valid_display_name(&"a".repeat(51)));
+
+        assert!(is_valid_avatar_url("https://cdn.example.com/a.png"));
+        assert!(!is_valid_avatar_url("http://cdn.example.com/a.png"));
+        assert!(!is_valid_avatar_url("javascript:alert(1)"));
+        assert!(!is_valid_avatar_url("https:///path"));
+        assert!(!is_valid_avatar_url("https://example.com/a b.png"));
+
+        for locale in ["ja", "en-US", "zh-Hant-TW", "es-419"] {
+            assert!(is_valid_locale(locale), "expected valid: {locale}");
+        }
+        for locale in ["", "EN", "en_US", "english"] {
+            assert!(!is_valid_locale(locale), "expected invalid: {locale}");
+        }
+
+        assert!(is_valid_timezone("Asia/Tokyo"));
+        assert!(is_valid_timezone("America/New_York"));
+        assert!(!is_valid_timezone("+09:00"));
+        assert!(!is_valid_timezone("Mars/Olympus"));
+    }
 }
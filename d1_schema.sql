@@ -1,7 +1,20 @@
 PRAGMA foreign_keys = OFF;
 
+DROP TABLE IF EXISTS schema_migrations;
+DROP TABLE IF EXISTS organization_restores;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS login_challenges;
+DROP TABLE IF EXISTS auth_throttle;
+DROP TABLE IF EXISTS api_tokens;
+DROP TABLE IF EXISTS scim_tokens;
+DROP TABLE IF EXISTS sso_providers;
+DROP TABLE IF EXISTS sso_login_states;
+DROP TABLE IF EXISTS user_identities;
+DROP TABLE IF EXISTS roles;
 DROP TABLE IF EXISTS display_group_members;
 DROP TABLE IF EXISTS display_groups;
+DROP TABLE IF EXISTS invitation_redemptions;
 DROP TABLE IF EXISTS invitations;
 DROP TABLE IF EXISTS password_resets;
 DROP TABLE IF EXISTS notifications;
@@ -11,6 +24,7 @@ DROP TABLE IF EXISTS task_tags;
 DROP TABLE IF EXISTS task_time_logs;
 DROP TABLE IF EXISTS tasks;
 DROP TABLE IF EXISTS tags;
+DROP TABLE IF EXISTS memberships;
 DROP TABLE IF EXISTS users;
 DROP TABLE IF EXISTS organizations;
 
@@ -20,10 +34,29 @@ PRAGMA foreign_keys = ON;
 CREATE TABLE organizations (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     name TEXT NOT NULL,
-    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    slug TEXT,
+    require_admin_2fa INTEGER NOT NULL DEFAULT 0 CHECK (require_admin_2fa IN (0, 1)),
+    password_login_disabled INTEGER NOT NULL DEFAULT 0 CHECK (password_login_disabled IN (0, 1)),
+    timezone TEXT NOT NULL DEFAULT 'Asia/Tokyo',
+    week_start_day TEXT NOT NULL DEFAULT 'monday'
+        CHECK (week_start_day IN ('monday', 'tuesday', 'wednesday', 'thursday', 'friday', 'saturday', 'sunday')),
+    default_task_status TEXT NOT NULL DEFAULT 'todo',
+    report_statuses TEXT NOT NULL DEFAULT '["doing","done"]',
+    invitation_expiration_days INTEGER NOT NULL DEFAULT 7,
+    password_min_length INTEGER NOT NULL DEFAULT 8,
+    password_require_uppercase INTEGER NOT NULL DEFAULT 1 CHECK (password_require_uppercase IN (0, 1)),
+    password_require_lowercase INTEGER NOT NULL DEFAULT 1 CHECK (password_require_lowercase IN (0, 1)),
+    password_require_digit INTEGER NOT NULL DEFAULT 1 CHECK (password_require_digit IN (0, 1)),
+    password_require_symbol INTEGER NOT NULL DEFAULT 1 CHECK (password_require_symbol IN (0, 1)),
+    closed_task_statuses TEXT NOT NULL DEFAULT '["done"]'
 );
 
+CREATE UNIQUE INDEX idx_organizations_slug ON organizations (slug);
+
 -- Users & Auth
+-- `organization_id` is the organization the account was created in; the
+-- organizations it belongs to, with its role in each, are in `memberships`.
 CREATE TABLE users (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     organization_id INTEGER NOT NULL DEFAULT 1,
@@ -35,13 +68,38 @@ CREATE TABLE users (
     email_verification_token TEXT,
     password_hash TEXT NOT NULL,
     avatar_url TEXT,
-    role TEXT NOT NULL DEFAULT 'user',
     created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    totp_secret TEXT,
+    totp_enabled INTEGER NOT NULL DEFAULT 0 CHECK (totp_enabled IN (0, 1)),
+    totp_last_used_step INTEGER,
+    deletion_scheduled_at TEXT,
+    locale TEXT,
+    timezone TEXT,
+    avatar_key TEXT,
+    external_id TEXT,
+    deleted_at TEXT,
     UNIQUE (organization_id, username)
 );
 
 CREATE INDEX idx_users_org_username ON users (organization_id, username);
 CREATE INDEX idx_users_email_token ON users (email_verification_token);
+CREATE UNIQUE INDEX idx_users_external_id ON users (organization_id, external_id);
+
+CREATE TABLE memberships (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    organization_id INTEGER NOT NULL,
+    role TEXT NOT NULL DEFAULT 'user',
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    deactivated_at TEXT,
+    manager_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    UNIQUE (user_id, organization_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_memberships_organization ON memberships (organization_id, role);
+CREATE INDEX idx_memberships_manager ON memberships (organization_id, manager_id);
 
 CREATE TABLE password_resets (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -62,11 +120,17 @@ CREATE TABLE tasks (
     progress_rate INTEGER NOT NULL DEFAULT 0 CHECK (progress_rate BETWEEN 0 AND 100),
     created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    due_date TEXT,
+    estimated_minutes INTEGER CHECK (estimated_minutes IS NULL OR estimated_minutes > 0),
+    estimate_exceeded_at TEXT,
+    creation_key TEXT,
     FOREIGN KEY (member_id) REFERENCES users(id) ON DELETE CASCADE
 );
 
 CREATE INDEX idx_tasks_org_member ON tasks (organization_id, member_id);
 CREATE INDEX idx_tasks_status ON tasks (organization_id, status);
+CREATE INDEX idx_tasks_due_date ON tasks (organization_id, due_date);
+CREATE UNIQUE INDEX idx_tasks_creation_key ON tasks (creation_key);
 
 -- Task Time Logs
 CREATE TABLE task_time_logs (
@@ -159,6 +223,30 @@ CREATE TABLE invitations (
     token TEXT NOT NULL UNIQUE,
     role TEXT NOT NULL DEFAULT 'user',
     expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    email TEXT,
+    max_uses INTEGER NOT NULL DEFAULT 1 CHECK (max_uses >= 1),
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    revoked_at TEXT
+);
+
+CREATE TABLE invitation_redemptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invitation_id INTEGER NOT NULL,
+    user_id INTEGER,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (invitation_id) REFERENCES invitations(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_invitation_redemptions_invitation ON invitation_redemptions (invitation_id);
+
+CREATE TABLE join_verifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invitation_id INTEGER NOT NULL REFERENCES invitations(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TEXT NOT NULL,
     created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
 
@@ -169,6 +257,7 @@ CREATE TABLE display_groups (
     user_id INTEGER NOT NULL,
     name TEXT NOT NULL,
     created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    creation_key TEXT,
     FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
 );
 
@@ -181,3 +270,183 @@ CREATE TABLE display_group_members (
 );
 
 CREATE INDEX idx_display_groups_user ON display_groups (user_id);
+CREATE UNIQUE INDEX idx_display_groups_creation_key ON display_groups (creation_key);
+
+-- Sessions
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    previous_refresh_token_hash TEXT,
+    user_agent TEXT,
+    ip_address TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TEXT NOT NULL,
+    revoked_at TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_sessions_user ON sessions (user_id);
+CREATE INDEX idx_sessions_previous_token ON sessions (previous_refresh_token_hash);
+
+-- Two-Factor Authentication
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_recovery_codes_user ON recovery_codes (user_id);
+
+CREATE TABLE login_challenges (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Brute-force Protection
+CREATE TABLE auth_throttle (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at INTEGER NOT NULL,
+    locked_until INTEGER
+);
+
+-- Personal Access Tokens
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    last_used_at TEXT,
+    revoked_at TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_api_tokens_user ON api_tokens (user_id);
+
+-- Single Sign-On
+CREATE TABLE sso_providers (
+    organization_id INTEGER PRIMARY KEY,
+    issuer TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    client_secret TEXT NOT NULL,
+    allowed_domains TEXT NOT NULL DEFAULT '[]',
+    enabled INTEGER NOT NULL DEFAULT 0 CHECK (enabled IN (0, 1)),
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+CREATE TABLE sso_login_states (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    state_hash TEXT NOT NULL UNIQUE,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE user_identities (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (organization_id, issuer, subject),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_user_identities_user ON user_identities (user_id);
+
+-- SCIM Provisioning
+CREATE TABLE scim_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_by INTEGER NOT NULL,
+    last_used_at TEXT,
+    revoked_at TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_scim_tokens_org ON scim_tokens (organization_id);
+
+-- Roles
+CREATE TABLE roles (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    permissions TEXT NOT NULL DEFAULT '[]',
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (organization_id, name),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+-- Archive restores that have not finished; see archive::restore.
+CREATE TABLE organization_restores (
+    organization_id INTEGER PRIMARY KEY,
+    importer_id INTEGER NOT NULL,
+    started_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+-- Schema Migrations
+-- Every migration in backend/migrations is already reflected above.
+CREATE TABLE schema_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO schema_migrations (version, name) VALUES
+    (20260215000000, 'init'),
+    (20260222000000, 'add_description_to_tasks'),
+    (20260223000000, 'email_verification'),
+    (20260224000000, 'add_pending_email'),
+    (20260225000000, 'fix_duration_rounding'),
+    (20260226000000, 'add_password_resets'),
+    (20260301000000, 'add_organization_slug'),
+    (20260302000000, 'add_sessions'),
+    (20260303000000, 'add_two_factor'),
+    (20260304000000, 'add_auth_throttle'),
+    (20260305000000, 'add_api_tokens'),
+    (20260306000000, 'add_sso'),
+    (20260307000000, 'add_roles'),
+    (20260308000000, 'manageable_invitations'),
+    (20260309000000, 'account_deletion'),
+    (20260310000000, 'user_deactivation'),
+    (20260311000000, 'user_preferences'),
+    (20260312000000, 'avatar_uploads'),
+    (20260313000000, 'scim_provisioning'),
+    (20260314000000, 'organization_timezone'),
+    (20260315000000, 'organization_settings'),
+    (20260316000000, 'memberships'),
+    (20260323000000, 'task_due_dates'),
+    (20260324000000, 'closed_task_statuses'),
+    (20260325000000, 'organization_restores'),
+    (20260326000000, 'creation_keys'),
+    (20260327000000, 'anonymized_accounts'),
+    (20260328000000, 'team_managers'),
+    (20260329000000, 'join_verifications');
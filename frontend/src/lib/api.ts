@@ -1,4 +1,5 @@
 import { PUBLIC_API_BASE_URL } from '$env/static/public';
+import type { Paginated } from './types';
 
 const DEFAULT_API_BASE_URL = 'http://localhost:3000';
 
@@ -8,6 +9,18 @@ export interface ApiFetchOptions extends RequestInit {
   token?: string | null;
 }
 
+type RefreshHandler = () => Promise<string | null>;
+
+let refreshHandler: RefreshHandler | null = null;
+
+/**
+ * Registers the callback used to obtain a new access token after a `401`.
+ * It resolves to the new token, or `null` when the session cannot be renewed.
+ */
+export function setRefreshHandler(handler: RefreshHandler | null) {
+  refreshHandler = handler;
+}
+
 export function buildApiUrl(path: string): string {
   if (/^https?:\/\//.test(path)) {
     return path;
@@ -16,7 +29,25 @@ export function buildApiUrl(path: string): string {
   return `${API_BASE_URL}${path.startsWith('/') ? path : `/${path}`}`;
 }
 
-export function apiFetch(path: string, options: ApiFetchOptions = {}): Promise<Response> {
+/**
+ * Authenticated requests that fail with `401` are retried once after
+ * refreshing the access token through the registered refresh handler.
+ */
+export async function apiFetch(path: string, options: ApiFetchOptions = {}): Promise<Response> {
+  const res = await sendRequest(path, options);
+  if (res.status !== 401 || !refreshHandler) return res;
+
+  const headers = new Headers(options.headers ?? {});
+  if (!options.token && !headers.has('Authorization')) return res;
+
+  const token = await refreshHandler();
+  if (!token) return res;
+
+  headers.delete('Authorization');
+  return sendRequest(path, { ...options, headers, token });
+}
+
+function sendRequest(path: string, options: ApiFetchOptions): Promise<Response> {
   const { token, headers, body, ...rest } = options;
   const mergedHeaders = new Headers(headers ?? {});
 
@@ -37,3 +68,33 @@ export function apiFetch(path: string, options: ApiFetchOptions = {}): Promise<R
     headers: mergedHeaders
   });
 }
+
+const FETCH_ALL_PAGE_LIMIT = 200;
+
+/**
+ * Follows `next_cursor` through every page of a list endpoint.
+ * Resolves to the first non-OK response as-is, or to a synthetic 200 response
+ * whose body is the concatenated `items` array.
+ */
+export async function apiFetchAll(path: string, options: ApiFetchOptions = {}): Promise<Response> {
+  const items: unknown[] = [];
+  let cursor: string | null = null;
+
+  do {
+    const url = new URL(buildApiUrl(path));
+    url.searchParams.set('limit', String(FETCH_ALL_PAGE_LIMIT));
+    if (cursor) url.searchParams.set('cursor', cursor);
+
+    const res = await apiFetch(url.toString(), options);
+    if (!res.ok) return res;
+
+    const page: Paginated<unknown> = await res.json();
+    items.push(...page.items);
+    cursor = page.next_cursor;
+  } while (cursor);
+
+  return new Response(JSON.stringify(items), {
+    status: 200,
+    headers: { 'Content-Type': 'application/json' }
+  });
+}
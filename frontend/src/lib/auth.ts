@@ -1,6 +1,6 @@
 import { get, writable } from 'svelte/store';
 import type { Writable } from 'svelte/store';
-import { apiFetch } from './api';
+import { apiFetch, buildApiUrl, setRefreshHandler } from './api';
 import type { AuthState, User } from './types';
 
 const isBrowser = typeof window !== 'undefined';
@@ -22,6 +22,7 @@ function parseStoredUser(rawValue: string | null): User | null {
 }
 
 const initialToken = isBrowser ? localStorage.getItem('auth_token') : null;
+const initialRefreshToken = isBrowser ? localStorage.getItem('auth_refresh_token') : null;
 const initialUser = isBrowser
   ? parseStoredUser(localStorage.getItem('auth_user'))
   : null;
@@ -36,6 +37,7 @@ const initialUser = isBrowser
  */
 export const auth: Writable<AuthState> = writable<AuthState>({
   token: initialToken,
+  refreshToken: initialRefreshToken,
   user: initialUser,
   initialized: false,
 });
@@ -47,18 +49,69 @@ if (isBrowser) {
     if (value.token && value.user) {
       localStorage.setItem('auth_token', value.token);
       localStorage.setItem('auth_user', JSON.stringify(value.user));
+      if (value.refreshToken) {
+        localStorage.setItem('auth_refresh_token', value.refreshToken);
+      }
     } else {
       localStorage.removeItem('auth_token');
+      localStorage.removeItem('auth_refresh_token');
       localStorage.removeItem('auth_user');
     }
   });
 }
 
+let pendingRefresh: Promise<string | null> | null = null;
+
+/**
+ * Exchanges the stored refresh token for a new token pair.
+ * Concurrent callers share one request, since each refresh token is single-use.
+ */
+async function refreshAccessToken(): Promise<string | null> {
+  const { refreshToken } = get(auth);
+  if (!refreshToken) return null;
+
+  pendingRefresh ??= (async () => {
+    try {
+      const res = await fetch(buildApiUrl('/api/auth/refresh'), {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ refresh_token: refreshToken })
+      });
+      if (!res.ok) {
+        auth.update((state) => ({ ...state, token: null, refreshToken: null, user: null }));
+        return null;
+      }
+
+      const data: { token: string; refresh_token: string } = await res.json();
+      auth.update((state) => ({ ...state, token: data.token, refreshToken: data.refresh_token }));
+      return data.token;
+    } catch {
+      return null;
+    } finally {
+      pendingRefresh = null;
+    }
+  })();
+
+  return pendingRefresh;
+}
+
+if (isBrowser) {
+  setRefreshHandler(refreshAccessToken);
+}
+
 /**
- * Clears current authentication state for sign-out.
+ * Ends the server-side session and clears current authentication state.
  */
 export function logout() {
-  auth.update((state) => ({ ...state, token: null, user: null }));
+  const { token } = get(auth);
+  if (token) {
+    // Best effort: the local state is cleared even if the session is already gone.
+    fetch(buildApiUrl('/api/auth/logout'), {
+      method: 'POST',
+      headers: { Authorization: `Bearer ${token}` }
+    }).catch(() => undefined);
+  }
+  auth.update((state) => ({ ...state, token: null, refreshToken: null, user: null }));
 }
 
 /**
@@ -85,3 +138,58 @@ export async function updateEmail(email: string): Promise<Response> {
     body: JSON.stringify({ email })
   });
 }
+
+export interface ProfileChanges {
+  name?: string;
+  avatar_url?: string;
+  locale?: string;
+  timezone?: string;
+}
+
+/**
+ * Updates the authenticated user's profile; an empty string clears an
+ * optional field. The stored user is replaced with the server's copy.
+ */
+export async function updateProfile(changes: ProfileChanges): Promise<Response> {
+  const { token } = get(auth);
+
+  const res = await apiFetch('/api/users/me', {
+    method: 'PATCH',
+    token,
+    body: JSON.stringify(changes)
+  });
+  if (res.ok) {
+    const user: User = await res.clone().json();
+    auth.update((state) => ({ ...state, user }));
+  }
+  return res;
+}
+
+/** Uploads a PNG, JPEG or WebP image as the authenticated user's avatar. */
+export async function uploadAvatar(file: File): Promise<Response> {
+  const { token } = get(auth);
+
+  const res = await apiFetch('/api/users/me/avatar', {
+    method: 'PUT',
+    token,
+    headers: { 'Content-Type': file.type || 'application/octet-stream' },
+    body: file
+  });
+  if (res.ok) {
+    const { avatar_url }: { avatar_url: string } = await res.clone().json();
+    auth.update((state) => (state.user ? { ...state, user: { ...state.user, avatar_url } } : state));
+  }
+  return res;
+}
+
+/** Removes the authenticated user's avatar. */
+export async function removeAvatar(): Promise<Response> {
+  const { token } = get(auth);
+
+  const res = await apiFetch('/api/users/me/avatar', { method: 'DELETE', token });
+  if (res.ok) {
+    auth.update((state) => (state.user ? { ...state, user: { ...state.user, avatar_url: undefined } } : state));
+  }
+  return res;
+}
+
@@ -1,5 +1,5 @@
 export type TaskStatus = 'todo' | 'doing' | 'done';
-export type UserRole = 'admin' | 'user';
+export type UserRole = 'admin' | 'manager' | 'user';
 
 export interface Task {
     id: number;
@@ -9,6 +9,8 @@ export interface Task {
     description?: string | null;
     status: TaskStatus;
     progress_rate: number;
+    due_date?: string | null; // YYYY-MM-DD
+    estimated_minutes?: number | null;
     tags?: string[];
     start_at: string; // ISO 8601 string
     end_at: string;   // ISO 8601 string
@@ -29,12 +31,15 @@ export interface TaskTimeLog {
     task_status?: TaskStatus;
     task_progress_rate?: number;
     task_tags?: string[];
+    task_due_date?: string | null;
+    task_estimated_minutes?: number | null;
     total_duration_minutes?: number;
 }
 
 export interface TaskReportRow extends Task {
     user_name: string;
     total_duration_minutes: number;
+    estimate_variance_minutes?: number | null;
 }
 
 export interface User {
@@ -45,7 +50,11 @@ export interface User {
     email?: string;
     email_verified: number;
     avatar_url?: string;
+    locale?: string | null;
+    timezone?: string | null;
     role: UserRole;
+    deactivated_at?: string | null;
+    manager_id?: number | null;
     time_logs?: TaskTimeLog[];
 }
 
@@ -74,12 +83,54 @@ export interface Invitation {
     id: number;
     organization_id: number;
     org_name?: string | null;
+    org_slug?: string | null;
     token: string;
     role: UserRole;
+    email?: string | null;
+    max_uses: number;
+    use_count: number;
     expires_at: string;
     created_at: string;
 }
 
+export interface UserImportRow {
+    row: number;
+    name: string;
+    username: string;
+    email: string | null;
+    role: UserRole;
+}
+
+export interface UserImportError {
+    row: number;
+    field: 'name' | 'username' | 'email' | 'role';
+    message: string;
+}
+
+export interface UserImportReport {
+    dry_run: boolean;
+    rows: UserImportRow[];
+    errors: UserImportError[];
+    created: number;
+    emailed: number;
+}
+
+export interface InvitationSummary {
+    id: number;
+    token: string;
+    role: UserRole;
+    email: string | null;
+    max_uses: number;
+    use_count: number;
+    status: 'active' | 'used' | 'expired' | 'revoked';
+    created_by: number | null;
+    created_by_name: string | null;
+    redeemed_by: number[];
+    expires_at: string;
+    revoked_at: string | null;
+    created_at: string;
+}
+
 export interface Notification {
     id: number;
     organization_id: number;
@@ -93,15 +144,15 @@ export interface Notification {
     created_at: string;
 }
 
-export interface PaginatedNotifications {
-    items: Notification[];
-    total: number;
-    page: number;
-    total_pages: number;
+export interface Paginated<T> {
+    items: T[];
+    next_cursor: string | null;
+    limit: number;
 }
 
 export interface AuthState {
     token: string | null;
+    refreshToken: string | null;
     user: User | null;
     initialized: boolean;
 }
@@ -142,3 +193,45 @@ export interface PersonalAnalyticsResponse {
     report_stats: ReportStats;
     heatmap: HeatmapDay[];
 }
+
+export interface OrganizationSummary {
+    slug: string;
+    name: string;
+}
+
+/** One of the organizations the signed-in account belongs to. */
+export interface Membership {
+    organization_id: number;
+    slug?: string | null;
+    name: string;
+    role: UserRole;
+    current: number;
+}
+
+export interface LoginResponse {
+    token: string;
+    refresh_token: string;
+    user: User;
+    organization_slug?: string | null;
+    two_factor_setup_required?: boolean;
+}
+
+export interface ApiToken {
+    id: number;
+    name: string;
+    scopes: string[];
+    expires_at: string;
+    last_used_at?: string | null;
+    created_at: string;
+}
+
+export interface TwoFactorStatus {
+    enabled: number;
+    recovery_codes_remaining: number;
+    required: number;
+}
+
+export interface TwoFactorSetup {
+    secret: string;
+    otpauth_uri: string;
+}